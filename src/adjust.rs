@@ -1,22 +1,249 @@
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
+use derive_new::new;
+use itertools::Itertools;
 use log::{debug, info};
-use rust_htslib::bam::record::{Aux, AuxArray};
+use rayon::prelude::*;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Aux;
 use rust_htslib::bam::{self, Read};
+use rustc_hash::FxHashMap;
 
+use crate::calibrate::CalibrationMap;
 use crate::errs::{InputError, RunError};
 use crate::mod_bam::{
-    collapse_mod_probs, format_mm_ml_tag, CollapseMethod, EdgeFilter,
-    ModBaseInfo,
+    collapse_mod_probs, format_mm_ml_tag, push_mod_tags, BaseModProbs,
+    CollapseMethod, EdgeFilter, ModBaseInfo, SeqPosBaseModProbs, ML_TAGS,
+    MM_TAGS, MN_TAG,
 };
 use crate::mod_base_code::DnaBase;
+use crate::motif_bed::RegexMotif;
+use crate::position_filter::StrandedPositionFilter;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
-    get_forward_sequence, get_query_name_string, get_spinner,
-    record_is_secondary,
+    get_aligned_pairs_forward, get_forward_sequence, get_query_name_string,
+    get_spinner, record_is_secondary, Strand,
 };
 
+/// Removes base modification calls by their sequence context. Motifs are
+/// matched in the read sequence, or in the reference sequence at the aligned
+/// position when reference sequences are given. Calls aligned to the masked
+/// positions are also removed.
+#[derive(new)]
+pub struct ContextFilter {
+    motifs: Vec<RegexMotif>,
+    invert_motifs: bool,
+    // target id to (uppercase) reference sequence
+    reference_sequences: Option<FxHashMap<u32, Vec<u8>>>,
+    filter_mismatches: bool,
+    masked_positions: Option<StrandedPositionFilter>,
+}
+
+impl ContextFilter {
+    fn motif_matches_read(
+        &self,
+        forward_sequence: &[u8],
+        revcomp_sequence: Option<&[u8]>,
+        position: usize,
+    ) -> bool {
+        match revcomp_sequence {
+            Some(revcomp_sequence) => {
+                let position = forward_sequence.len() - 1 - position;
+                self.motifs
+                    .iter()
+                    .any(|motif| motif.matches_at(revcomp_sequence, position))
+            }
+            None => self
+                .motifs
+                .iter()
+                .any(|motif| motif.matches_at(forward_sequence, position)),
+        }
+    }
+
+    fn motif_matches_reference(
+        &self,
+        reference_sequence: &[u8],
+        ref_position: usize,
+        reference_strand: Strand,
+    ) -> bool {
+        match reference_strand {
+            Strand::Positive => self.motifs.iter().any(|motif| {
+                motif.matches_at(reference_sequence, ref_position)
+            }),
+            Strand::Negative => self.motifs.iter().any(|motif| {
+                let start = ref_position.saturating_sub(motif.length);
+                let end = std::cmp::min(
+                    ref_position + motif.length + 1,
+                    reference_sequence.len(),
+                );
+                let window = bio::alphabets::dna::revcomp(
+                    &reference_sequence[start..end],
+                );
+                motif.matches_at(&window, end - 1 - ref_position)
+            }),
+        }
+    }
+
+    /// Remove the calls that are not in the requested sequence context.
+    /// Positions without a call should be made explicit (with
+    /// `add_implicit_mod_calls`) beforehand. Returning None means that all
+    /// of the positions were filtered out.
+    fn filter_positions(
+        &self,
+        seq_pos_mod_probs: SeqPosBaseModProbs,
+        mod_strand: Strand,
+        forward_sequence: &[u8],
+        record: &bam::Record,
+        aligned_pairs: &FxHashMap<usize, u64>,
+    ) -> Result<Option<SeqPosBaseModProbs>, RunError> {
+        let reference_sequence = match self.reference_sequences.as_ref() {
+            Some(sequences) if !record.is_unmapped() && record.tid() >= 0 => {
+                let seq =
+                    sequences.get(&(record.tid() as u32)).ok_or_else(|| {
+                        RunError::new_failed(format!(
+                            "did not find reference sequence for target id {}",
+                            record.tid()
+                        ))
+                    })?;
+                Some(seq.as_slice())
+            }
+            _ => None,
+        };
+        let use_reference = self.reference_sequences.is_some();
+        let revcomp_sequence = if !use_reference
+            && !self.motifs.is_empty()
+            && mod_strand == Strand::Negative
+        {
+            Some(bio::alphabets::dna::revcomp(forward_sequence))
+        } else {
+            None
+        };
+        let reference_strand = match (mod_strand, record.is_reverse()) {
+            (Strand::Positive, false) | (Strand::Negative, true) => {
+                Strand::Positive
+            }
+            (Strand::Positive, true) | (Strand::Negative, false) => {
+                Strand::Negative
+            }
+        };
+
+        let skip_mode = seq_pos_mod_probs.skip_mode;
+        let probs = seq_pos_mod_probs
+            .pos_to_base_mod_probs
+            .into_iter()
+            .filter(|(q_pos, _)| {
+                let ref_pos = aligned_pairs.get(q_pos).map(|&r| r as usize);
+                let motif_keep = if self.motifs.is_empty() {
+                    true
+                } else if use_reference {
+                    match (reference_sequence, ref_pos) {
+                        (Some(seq), Some(r)) if r < seq.len() => {
+                            self.motif_matches_reference(
+                                seq,
+                                r,
+                                reference_strand,
+                            ) != self.invert_motifs
+                        }
+                        // the motif can't be checked at unaligned positions,
+                        // they're removed even when the filter is inverted
+                        _ => false,
+                    }
+                } else {
+                    self.motif_matches_read(
+                        forward_sequence,
+                        revcomp_sequence.as_deref(),
+                        *q_pos,
+                    ) != self.invert_motifs
+                };
+                let mismatch_keep = if self.filter_mismatches {
+                    match (reference_sequence, ref_pos) {
+                        (Some(seq), Some(r)) if r < seq.len() => {
+                            let ref_base = if record.is_reverse() {
+                                bio::alphabets::dna::complement(seq[r])
+                            } else {
+                                seq[r]
+                            };
+                            forward_sequence
+                                .get(*q_pos)
+                                .map(|b| {
+                                    b.to_ascii_uppercase()
+                                        == ref_base.to_ascii_uppercase()
+                                })
+                                .unwrap_or(false)
+                        }
+                        _ => false,
+                    }
+                } else {
+                    true
+                };
+                let mask_keep = match (self.masked_positions.as_ref(), ref_pos)
+                {
+                    (Some(masked_positions), Some(r)) => !masked_positions
+                        .contains(record.tid(), r as u64, reference_strand),
+                    _ => true,
+                };
+                motif_keep && mismatch_keep && mask_keep
+            })
+            .collect::<FxHashMap<usize, BaseModProbs>>();
+        if probs.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(SeqPosBaseModProbs::new(probs, skip_mode)))
+        }
+    }
+
+    fn requires_alignment(&self) -> bool {
+        self.reference_sequences.is_some() || self.masked_positions.is_some()
+    }
+}
+
+/// Restricts the edits made by `adjust-mods` to a subset of the reads, by
+/// read name and/or by overlap with regions of the reference.
+#[derive(new)]
+pub struct ReadScope {
+    read_ids: Option<HashSet<String>>,
+    regions: Option<StrandedPositionFilter>,
+    strip_unselected: bool,
+}
+
+impl ReadScope {
+    fn contains(&self, record: &bam::Record) -> bool {
+        let read_id_keep = self
+            .read_ids
+            .as_ref()
+            .map(|read_ids| {
+                get_query_name_string(record)
+                    .map(|name| read_ids.contains(&name))
+                    .unwrap_or(false)
+            })
+            .unwrap_or(true);
+        let region_keep = self
+            .regions
+            .as_ref()
+            .map(|regions| {
+                !record.is_unmapped()
+                    && record.tid() >= 0
+                    && regions.overlaps_not_stranded(
+                        record.tid() as u32,
+                        record.reference_start() as u64,
+                        record.reference_end() as u64,
+                    )
+            })
+            .unwrap_or(true);
+        read_id_keep && region_keep
+    }
+}
+
+fn strip_mod_tags(mut record: bam::Record) -> bam::Record {
+    for tag in MM_TAGS.iter().chain(ML_TAGS.iter()).chain([MN_TAG].iter()) {
+        // not all of the tags will be present
+        let _ = record.remove_aux(tag.as_bytes());
+    }
+    record
+}
+
 pub fn record_is_valid(record: &bam::Record) -> Result<(), RunError> {
     if record_is_secondary(&record) {
         return Err(RunError::new_skipped("not primary"));
@@ -27,11 +254,96 @@ pub fn record_is_valid(record: &bam::Record) -> Result<(), RunError> {
     Ok(())
 }
 
+/// Where the adjusted MM and ML tags are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagDestination {
+    /// Replace the MM and ML tags.
+    Replace,
+    /// Replace the MM and ML tags, keeping the original tags under these
+    /// names.
+    KeepOriginal { mm_tag: String, ml_tag: String },
+    /// Leave the MM and ML tags unchanged and write the adjusted tags under
+    /// these names.
+    Separate { mm_tag: String, ml_tag: String },
+}
+
+impl TagDestination {
+    pub(crate) fn parse_tag_names(
+        raw: &[String],
+    ) -> anyhow::Result<(String, String)> {
+        if raw.len() != 2 {
+            bail!("expected two tag names, got {}", raw.len())
+        }
+        for tag in raw {
+            let valid = tag.len() == 2
+                && tag.chars().next().unwrap().is_ascii_alphabetic()
+                && tag.chars().all(|c| c.is_ascii_alphanumeric());
+            if !valid {
+                bail!(
+                    "invalid tag name {tag}, must be a letter followed by a \
+                     letter or digit"
+                )
+            }
+            if MM_TAGS.contains(&tag.as_str())
+                || ML_TAGS.contains(&tag.as_str())
+                || tag == MN_TAG
+            {
+                bail!("cannot use {tag} as an alternate modification tag")
+            }
+        }
+        if raw[0] == raw[1] {
+            bail!("MM and ML tag names must be different")
+        }
+        Ok((raw[0].to_owned(), raw[1].to_owned()))
+    }
+}
+
+fn remove_mod_tags(
+    record: &mut bam::Record,
+    mm_style: &str,
+    ml_style: &str,
+) -> Result<(), RunError> {
+    record.remove_aux(mm_style.as_bytes()).map_err(|e| {
+        RunError::new_failed(format!(
+            "failed to remove MM tag, {}",
+            e.to_string()
+        ))
+    })?;
+    record.remove_aux(ml_style.as_bytes()).map_err(|e| {
+        RunError::new_failed(format!(
+            "failed to remove ML tag, {}",
+            e.to_string()
+        ))
+    })?;
+    Ok(())
+}
+
+fn copy_mod_tags(
+    record: &mut bam::Record,
+    mm_style: &str,
+    ml_style: &str,
+    mm_tag: &str,
+    ml_tag: &str,
+) -> Result<(), RunError> {
+    let raw_mm = match record.aux(mm_style.as_bytes()) {
+        Ok(Aux::String(mm)) => mm.to_owned(),
+        _ => return Err(RunError::new_input_error("invalid MM tag")),
+    };
+    let raw_ml = match record.aux(ml_style.as_bytes()) {
+        Ok(Aux::ArrayU8(ml)) => ml.iter().collect::<Vec<u8>>(),
+        _ => return Err(RunError::new_input_error("invalid ML tag")),
+    };
+    push_mod_tags(record, mm_tag, ml_tag, &raw_mm, &raw_ml)
+}
+
 pub fn adjust_mod_probs(
     mut record: bam::Record,
     methods: &[CollapseMethod],
     caller: Option<&MultipleThresholdModCaller>,
     edge_filter: Option<&EdgeFilter>,
+    calibration: Option<&CalibrationMap>,
+    context_filter: Option<&ContextFilter>,
+    tag_destination: &TagDestination,
 ) -> Result<bam::Record, RunError> {
     let _ok = record_is_valid(&record)?;
 
@@ -44,19 +356,77 @@ pub fn adjust_mod_probs(
 
     let record_name = get_query_name_string(&record)
         .unwrap_or("FAILED-UTF8-DECODE".to_string());
+    let forward_sequence = if calibration.is_some() || context_filter.is_some()
+    {
+        Some(get_forward_sequence(&record)?)
+    } else {
+        None
+    };
+    let aligned_pairs = match context_filter {
+        Some(context_filter) if context_filter.requires_alignment() => {
+            get_aligned_pairs_forward(&record)
+                .filter_map(|pair| pair.ok())
+                .collect::<FxHashMap<usize, u64>>()
+        }
+        _ => FxHashMap::default(),
+    };
+    let codes_to_remove = methods
+        .iter()
+        .flat_map(|method| method.get_codes_to_remove())
+        .collect::<HashSet<char>>();
     let (converters, mod_prob_iter) = mod_base_info.into_iter_base_mod_probs();
     for (base, strand, seq_pos_mod_probs) in mod_prob_iter {
         let converter = converters.get(&base).unwrap();
+        // calibration maps are fit on the unmodified probabilities, so they
+        // are applied before any other adjustment
+        let seq_pos_mod_probs = match (calibration, &forward_sequence) {
+            (Some(calibration), Some(forward_sequence)) => calibration
+                .calibrate_seq_pos_base_mod_probs(
+                    seq_pos_mod_probs,
+                    base,
+                    strand,
+                    forward_sequence,
+                ),
+            _ => seq_pos_mod_probs,
+        };
+        let seq_pos_mod_probs = match (context_filter, &forward_sequence) {
+            (Some(context_filter), Some(forward_sequence)) => {
+                // make the implied canonical calls explicit before removing
+                // positions, otherwise the removed positions would be
+                // interpreted as canonical
+                let seq_pos_mod_probs = seq_pos_mod_probs
+                    .add_implicit_mod_calls(
+                        forward_sequence,
+                        base,
+                        &codes_to_remove,
+                        None,
+                    );
+                match context_filter.filter_positions(
+                    seq_pos_mod_probs,
+                    strand,
+                    forward_sequence.as_bytes(),
+                    &record,
+                    &aligned_pairs,
+                )? {
+                    Some(x) => x,
+                    None => {
+                        debug!(
+                            "all base mod positions for record {record_name} \
+                             and canonical base {base} were filtered out by \
+                             sequence context"
+                        );
+                        continue;
+                    }
+                }
+            }
+            _ => seq_pos_mod_probs,
+        };
         let filtered_seq_pos_mod_probs = if let Some(edge_filter) = edge_filter
         {
             let forward_sequence = get_forward_sequence(&record)?;
             match seq_pos_mod_probs
                 .edge_filter_positions(edge_filter, record.seq_len())
                 .map(|mod_probs| {
-                    let codes_to_remove = methods
-                        .iter()
-                        .flat_map(|method| method.get_codes_to_remove())
-                        .collect::<HashSet<char>>();
                     mod_probs.add_implicit_mod_calls(
                         &forward_sequence,
                         base,
@@ -105,41 +475,110 @@ pub fn adjust_mod_probs(
         }
     }
 
-    record.remove_aux(mm_style.as_bytes()).map_err(|e| {
-        RunError::new_failed(format!(
-            "failed to remove MM tag, {}",
-            e.to_string()
-        ))
-    })?;
-    record.remove_aux(ml_style.as_bytes()).map_err(|e| {
-        RunError::new_failed(format!(
-            "failed to remove ML tag, {}",
-            e.to_string()
-        ))
-    })?;
-
-    let mm = Aux::String(&mm_agg);
-    let ml_arr: AuxArray<u8> = {
-        let sl = &ml_agg;
-        sl.into()
+    let (mm_tag, ml_tag) = match tag_destination {
+        TagDestination::Replace => {
+            remove_mod_tags(&mut record, mm_style, ml_style)?;
+            (mm_style, ml_style)
+        }
+        TagDestination::KeepOriginal { mm_tag, ml_tag } => {
+            copy_mod_tags(&mut record, mm_style, ml_style, mm_tag, ml_tag)?;
+            remove_mod_tags(&mut record, mm_style, ml_style)?;
+            (mm_style, ml_style)
+        }
+        TagDestination::Separate { mm_tag, ml_tag } => {
+            (mm_tag.as_str(), ml_tag.as_str())
+        }
     };
-    let ml = Aux::ArrayU8(ml_arr);
-    record.push_aux(mm_style.as_bytes(), mm).map_err(|e| {
-        RunError::new_failed(format!("failed to add MM tag, {}", e.to_string()))
-    })?;
-    record.push_aux(ml_style.as_bytes(), ml).map_err(|e| {
-        RunError::new_failed(format!("failed to add ML tag, {}", e.to_string()))
-    })?;
+    push_mod_tags(&mut record, mm_tag, ml_tag, &mm_agg, &ml_agg)?;
 
     Ok(record)
 }
 
+pub(crate) const ADJUST_BATCH_SIZE: usize = 5_000;
+
+/// Reads records on a separate thread and processes batches of them in
+/// parallel, `write` is called with the processed records (and the read name)
+/// in the same order as the input. Records that fail to be read from the BAM
+/// are passed to `write` as failures. The `threads` are split between BGZF
+/// decompression in the reader and processing the records.
+pub(crate) fn process_records_in_order<F, W>(
+    reader: &mut bam::Reader,
+    threads: usize,
+    batch_size: usize,
+    process: F,
+    mut write: W,
+) -> anyhow::Result<()>
+where
+    F: Fn(bam::Record) -> Result<bam::Record, RunError> + Sync,
+    W: FnMut(String, Result<bam::Record, RunError>) -> anyhow::Result<()>,
+{
+    if batch_size == 0 {
+        bail!("batch size must be greater than zero")
+    }
+    let reader_threads = std::cmp::min(threads / 2, 16);
+    let pool_threads = std::cmp::max(threads - reader_threads, 1);
+    debug!(
+        "using {reader_threads} threads to read and {pool_threads} to \
+         process records"
+    );
+    if reader_threads > 0 {
+        reader.set_threads(reader_threads)?;
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(pool_threads)
+        .build()
+        .context("failed to make thread pool")?;
+    let (batch_snd, batch_rcv) = std::sync::mpsc::sync_channel(2);
+    std::thread::scope(|scope| {
+        // reading the next batch overlaps with processing the current one
+        scope.spawn(move || {
+            for batch in &reader.records().chunks(batch_size) {
+                if batch_snd.send(batch.collect::<Vec<_>>()).is_err() {
+                    // receiver has stopped, e.g. failing fast
+                    break;
+                }
+            }
+        });
+        for batch in batch_rcv {
+            let processed = pool.install(|| {
+                batch
+                    .into_par_iter()
+                    .map(|result| match result {
+                        Ok(record) => {
+                            let record_name = get_query_name_string(&record)
+                                .unwrap_or("???".to_owned());
+                            (record_name, process(record))
+                        }
+                        Err(e) => (
+                            "???".to_owned(),
+                            Err(RunError::new_failed(format!(
+                                "failed to read record, {}",
+                                e.to_string()
+                            ))),
+                        ),
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for (record_name, result) in processed {
+                write(record_name, result)?;
+            }
+        }
+        Ok(())
+    })
+}
+
 pub fn adjust_modbam(
     reader: &mut bam::Reader,
     writer: &mut bam::Writer,
     collapse_methods: &[CollapseMethod],
     threshold_caller: Option<&MultipleThresholdModCaller>,
     edge_filter: Option<&EdgeFilter>,
+    calibration: Option<&CalibrationMap>,
+    context_filter: Option<&ContextFilter>,
+    read_scope: Option<&ReadScope>,
+    tag_destination: &TagDestination,
+    threads: usize,
+    batch_size: usize,
     fail_fast: bool,
     verb: &'static str,
     suppress_progress: bool,
@@ -152,16 +591,34 @@ pub fn adjust_modbam(
     let mut total = 0usize;
     let mut total_failed = 0usize;
     let mut total_skipped = 0usize;
-    for (i, result) in reader.records().enumerate() {
-        if let Ok(record) = result {
-            let record_name =
-                get_query_name_string(&record).unwrap_or("???".to_owned());
-            match adjust_mod_probs(
+    let total_unselected = AtomicUsize::new(0);
+    process_records_in_order(
+        reader,
+        threads,
+        batch_size,
+        |record| match read_scope {
+            Some(read_scope) if !read_scope.contains(&record) => {
+                total_unselected.fetch_add(1, Ordering::Relaxed);
+                record_is_valid(&record).map(|_| {
+                    if read_scope.strip_unselected {
+                        strip_mod_tags(record)
+                    } else {
+                        record
+                    }
+                })
+            }
+            _ => adjust_mod_probs(
                 record,
                 &collapse_methods,
                 threshold_caller,
                 edge_filter,
-            ) {
+                calibration,
+                context_filter,
+                tag_destination,
+            ),
+        },
+        |record_name, adjusted| {
+            match adjusted {
                 Err(RunError::BadInput(InputError(err)))
                 | Err(RunError::Failed(err)) => {
                     if fail_fast {
@@ -187,25 +644,29 @@ pub fn adjust_modbam(
                         }
                     } else {
                         spinner.inc(1);
-                        total = i;
+                        total += 1;
                     }
                 }
             }
-        } else {
-            if fail_fast {
-                let err = result.err().unwrap().to_string();
-                return Err(anyhow!("{}", err));
-            }
-            total_failed += 1;
-        }
-    }
+            Ok(())
+        },
+    )?;
     spinner.finish_and_clear();
 
+    if let Some(read_scope) = read_scope {
+        let action = if read_scope.strip_unselected {
+            "had modification tags removed"
+        } else {
+            "were not adjusted"
+        };
+        info!(
+            "{} records outside of the selection {action}",
+            total_unselected.load(Ordering::Relaxed)
+        );
+    }
     info!(
         "done, {} records processed, {} failed, {} skipped",
-        total + 1,
-        total_failed,
-        total_skipped
+        total, total_failed, total_skipped
     );
     Ok(())
 }
@@ -0,0 +1,870 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, ValueEnum};
+use log::{debug, info, warn};
+use rust_htslib::bam::{self, Read};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::logging::init_logging;
+use crate::mod_bam::{BaseModProbs, SeqPosBaseModProbs};
+use crate::motif_bed::RegexMotif;
+use crate::read_ids_to_base_mod_probs::{KmerWindow, ReadsBaseModProfile};
+use crate::reads_sampler::record_sampler::RecordSampler;
+use crate::util::Strand;
+
+/// Number of probability bins, one for each ML tag value.
+const N_BINS: usize = 256;
+/// K-mer column value for the map used for all sequence contexts.
+const ALL_CONTEXTS: &str = "*";
+
+#[derive(Args)]
+pub struct Calibrate {
+    /// modBAM of a fully unmodified control sample, e.g. PCR or whole-genome
+    /// amplified DNA.
+    unmodified_bam: PathBuf,
+    /// Output calibration map, a TSV that can be passed to `adjust-mods`,
+    /// `pileup`, and `extract` with --calibration.
+    out_calibration: PathBuf,
+    /// modBAM of a fully modified control sample, e.g. M.SssI-treated DNA.
+    /// Without a modified control, probabilities at or below the
+    /// --control-quantile of the unmodified control are calibrated to 0 and
+    /// higher probabilities are linearly rescaled.
+    #[arg(long, requires = "modified_codes")]
+    modified_bam: Option<PathBuf>,
+    /// Comma-separated modification codes present at every site in the
+    /// modified control (e.g. m). Calls for other codes in the modified
+    /// control are used as unmodified examples.
+    #[arg(long, value_delimiter = ',', requires = "modified_bam")]
+    modified_codes: Option<Vec<char>>,
+    /// Only use calls in the modified control at this sequence motif (in
+    /// the read) and offset, e.g. CG 0 for M.SssI-treated DNA.
+    #[arg(long, num_args = 2, requires = "modified_bam")]
+    modified_motif: Option<Vec<String>>,
+    /// Calibration method, isotonic regression or Platt scaling. Platt
+    /// scaling requires --modified-bam.
+    #[arg(long, value_enum, default_value_t = CalibrationMethod::Isotonic)]
+    method: CalibrationMethod,
+    /// Also fit a calibration map for each k-mer of this (odd) size centered
+    /// on the modified base, in addition to the map for all contexts.
+    #[arg(long)]
+    kmer_size: Option<usize>,
+    /// Minimum number of calls with a k-mer in each control sample needed to
+    /// fit a map for the k-mer, calls at other k-mers use the map for all
+    /// contexts.
+    #[arg(long, requires = "kmer_size", default_value_t = 1000)]
+    min_kmer_calls: u64,
+    /// Quantile of the unmodified control probabilities treated as
+    /// background when no modified control is provided.
+    #[arg(long, conflicts_with = "modified_bam", default_value_t = 0.99)]
+    control_quantile: f32,
+    /// Approximate maximum number of reads to use from each control sample.
+    #[arg(short = 'n', long, default_value_t = 10_042)]
+    num_reads: usize,
+    /// Number of threads to use reading BAM files.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended.
+    #[arg(long)]
+    log_filepath: Option<PathBuf>,
+    /// Overwrite the output calibration map if present.
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CalibrationMethod {
+    /// Isotonic regression, a monotonic piecewise-linear map.
+    Isotonic,
+    /// Platt scaling, a logistic regression on the log-odds.
+    Platt,
+}
+
+impl Calibrate {
+    pub(crate) fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.out_calibration.exists() && !self.force {
+            bail!(
+                "refusing to overwrite {:?}, use --force",
+                &self.out_calibration
+            )
+        }
+        if self.control_quantile <= 0f32 || self.control_quantile >= 1f32 {
+            bail!("--control-quantile must be in (0, 1)")
+        }
+        if self.modified_bam.is_none()
+            && self.method == CalibrationMethod::Platt
+        {
+            bail!("Platt scaling requires a modified control, --modified-bam")
+        }
+        let kmer_window =
+            self.kmer_size.map(KmerWindow::from_kmer_size).transpose()?;
+        let modified_motif = self
+            .modified_motif
+            .as_ref()
+            .map(|parts| {
+                let offset = parts[1].parse::<usize>().with_context(|| {
+                    format!("failed to parse motif offset {}", &parts[1])
+                })?;
+                RegexMotif::parse_string(&parts[0], offset)
+            })
+            .transpose()?;
+
+        let mut counter = CallCounter::new(
+            self.kmer_size,
+            modified_motif.as_ref().map(|m| m.length).unwrap_or(0),
+        );
+        info!(
+            "counting calls in unmodified control {:?}",
+            &self.unmodified_bam
+        );
+        self.count_calls(&self.unmodified_bam, &mut counter, None)?;
+        if let Some(modified_bam) = self.modified_bam.as_ref() {
+            let modified_codes = self
+                .modified_codes
+                .as_ref()
+                .map(|codes| codes.iter().copied().collect::<FxHashSet<char>>())
+                .unwrap_or_default();
+            info!("counting calls in modified control {modified_bam:?}");
+            self.count_calls(
+                modified_bam,
+                &mut counter,
+                Some((&modified_codes, modified_motif.as_ref())),
+            )?;
+        }
+
+        let calibration_map = counter.fit(
+            self.method,
+            self.modified_bam.is_some(),
+            self.control_quantile,
+            self.min_kmer_calls,
+            kmer_window,
+        )?;
+        let n_kmer_maps = calibration_map.n_kmer_maps();
+        info!(
+            "fit {} calibration maps ({} per k-mer)",
+            calibration_map.code_maps.len() + n_kmer_maps,
+            n_kmer_maps
+        );
+        calibration_map.write(&self.out_calibration)?;
+        Ok(())
+    }
+
+    /// Count the calls in one of the controls, `modified` has the codes
+    /// present in the modified control and the optional motif.
+    fn count_calls(
+        &self,
+        bam_fp: &PathBuf,
+        counter: &mut CallCounter,
+        modified: Option<(&FxHashSet<char>, Option<&RegexMotif>)>,
+    ) -> anyhow::Result<()> {
+        let mut reader = bam::Reader::from_path(bam_fp)
+            .with_context(|| format!("failed to open {bam_fp:?}"))?;
+        reader.set_threads(self.threads)?;
+        let record_sampler =
+            RecordSampler::new_from_options(None, Some(self.num_reads), None);
+        let reads_profiles =
+            ReadsBaseModProfile::process_records_with_kmer_window(
+                reader.records(),
+                !self.suppress_progress,
+                record_sampler,
+                None,
+                None,
+                &counter.sampling_window(),
+                false,
+                None,
+            )?;
+        debug!(
+            "used {} reads, {} skipped, {} failed",
+            reads_profiles.profiles.len(),
+            reads_profiles.num_skips,
+            reads_profiles.num_fails
+        );
+        for mod_profile in reads_profiles
+            .profiles
+            .iter()
+            .flat_map(|read_profile| read_profile.profile.iter())
+            .filter(|mod_profile| !mod_profile.inferred)
+        {
+            let kmer = mod_profile.query_kmer().into_bytes();
+            let is_modified = match modified {
+                Some((codes, motif)) => {
+                    let at_motif = motif
+                        .map(|m| m.matches_at(&kmer, counter.flank))
+                        .unwrap_or(true);
+                    if !at_motif {
+                        continue;
+                    }
+                    codes.contains(&mod_profile.raw_mod_code)
+                }
+                None => false,
+            };
+            counter.add(
+                mod_profile.canonical_base,
+                mod_profile.raw_mod_code,
+                &kmer,
+                mod_profile.q_mod,
+                is_modified,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Number of calls in each probability bin for unmodified and modified
+/// examples.
+#[derive(Clone)]
+struct BinnedCalls {
+    unmodified: Vec<u64>,
+    modified: Vec<u64>,
+}
+
+impl BinnedCalls {
+    fn new() -> Self {
+        Self {
+            unmodified: vec![0; N_BINS],
+            modified: vec![0; N_BINS],
+        }
+    }
+
+    fn add(&mut self, prob: f32, is_modified: bool) {
+        let bin = std::cmp::min((prob * N_BINS as f32) as usize, N_BINS - 1);
+        if is_modified {
+            self.modified[bin] += 1;
+        } else {
+            self.unmodified[bin] += 1;
+        }
+    }
+
+    fn n_unmodified(&self) -> u64 {
+        self.unmodified.iter().sum()
+    }
+
+    fn n_modified(&self) -> u64 {
+        self.modified.iter().sum()
+    }
+
+    fn bin_center(bin: usize) -> f32 {
+        (bin as f32 + 0.5) / N_BINS as f32
+    }
+
+    /// Bins with calls as (probability, fraction modified, weight). The
+    /// modified and unmodified calls are weighted so that both classes have
+    /// the same total weight.
+    fn weighted_points(&self) -> Vec<(f32, f64, f64)> {
+        let class_weight = |n: u64| if n > 0 { 0.5 / n as f64 } else { 0f64 };
+        let w_unmod = class_weight(self.n_unmodified());
+        let w_mod = class_weight(self.n_modified());
+        (0..N_BINS)
+            .filter_map(|bin| {
+                let unmod = self.unmodified[bin] as f64 * w_unmod;
+                let modified = self.modified[bin] as f64 * w_mod;
+                let weight = unmod + modified;
+                if weight > 0f64 {
+                    Some((Self::bin_center(bin), modified / weight, weight))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn fit(
+        &self,
+        method: CalibrationMethod,
+        control_quantile: f32,
+    ) -> Calibrator {
+        if self.n_modified() == 0 {
+            Calibrator::Isotonic(self.background_knots(control_quantile))
+        } else {
+            match method {
+                CalibrationMethod::Isotonic => {
+                    Calibrator::Isotonic(fit_isotonic(&self.weighted_points()))
+                }
+                CalibrationMethod::Platt => {
+                    let (a, b) = fit_platt(&self.weighted_points());
+                    Calibrator::Platt { a, b }
+                }
+            }
+        }
+    }
+
+    /// Probabilities at or below the `quantile` of the unmodified calls
+    /// map to 0, higher probabilities are linearly rescaled to (0, 1].
+    fn background_knots(&self, quantile: f32) -> Vec<(f32, f32)> {
+        let total = self.n_unmodified() as f64;
+        let mut cumulative = 0u64;
+        let mut background = 1f32;
+        for (bin, count) in self.unmodified.iter().enumerate() {
+            cumulative += count;
+            if cumulative as f64 >= total * quantile as f64 {
+                background = (bin + 1) as f32 / N_BINS as f32;
+                break;
+            }
+        }
+        let background = background.min((N_BINS - 1) as f32 / N_BINS as f32);
+        vec![(0f32, 0f32), (background, 0f32), (1f32, 1f32)]
+    }
+}
+
+/// Pool adjacent violators, `points` are sorted by probability.
+fn fit_isotonic(points: &[(f32, f64, f64)]) -> Vec<(f32, f32)> {
+    // blocks of (weighted sum, weight, first x, last x)
+    let mut blocks: Vec<(f64, f64, f32, f32)> = Vec::new();
+    for &(x, y, w) in points {
+        blocks.push((y * w, w, x, x));
+        while blocks.len() > 1 {
+            let (wy_b, w_b, _, x_end) = blocks[blocks.len() - 1];
+            let (wy_a, w_a, x_start, _) = blocks[blocks.len() - 2];
+            if wy_a / w_a > wy_b / w_b {
+                blocks.pop();
+                let last = blocks.len() - 1;
+                blocks[last] = (wy_a + wy_b, w_a + w_b, x_start, x_end);
+            } else {
+                break;
+            }
+        }
+    }
+    blocks
+        .into_iter()
+        .flat_map(|(wy, w, x_start, x_end)| {
+            let y = (wy / w) as f32;
+            if x_start == x_end {
+                vec![(x_start, y)]
+            } else {
+                vec![(x_start, y), (x_end, y)]
+            }
+        })
+        .collect()
+}
+
+/// Fit `sigmoid(a * logit(p) + b)` by Newton's method.
+fn fit_platt(points: &[(f32, f64, f64)]) -> (f32, f32) {
+    let features = points
+        .iter()
+        .map(|(x, y, w)| (logit(*x as f64), *y, *w))
+        .collect::<Vec<(f64, f64, f64)>>();
+    let (mut a, mut b) = (1f64, 0f64);
+    for _ in 0..100 {
+        let (mut g_a, mut g_b) = (0f64, 0f64);
+        let (mut h_aa, mut h_ab, mut h_bb) = (1e-9, 0f64, 1e-9);
+        for (f, y, w) in features.iter() {
+            let p = sigmoid(a * f + b);
+            let r = w * (p - y);
+            g_a += r * f;
+            g_b += r;
+            let s = w * p * (1f64 - p);
+            h_aa += s * f * f;
+            h_ab += s * f;
+            h_bb += s;
+        }
+        let det = h_aa * h_bb - h_ab * h_ab;
+        if det.abs() < 1e-18 {
+            break;
+        }
+        let step_a = (h_bb * g_a - h_ab * g_b) / det;
+        let step_b = (h_aa * g_b - h_ab * g_a) / det;
+        a -= step_a;
+        b -= step_b;
+        if step_a.abs() < 1e-8 && step_b.abs() < 1e-8 {
+            break;
+        }
+    }
+    (a as f32, b as f32)
+}
+
+fn logit(p: f64) -> f64 {
+    let p = p.clamp(1e-6, 1f64 - 1e-6);
+    (p / (1f64 - p)).ln()
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1f64 / (1f64 + (-x).exp())
+}
+
+/// Aggregates calls from the control samples by primary base, modification
+/// code, and optionally k-mer.
+struct CallCounter {
+    kmer_size: Option<usize>,
+    /// Flank of the k-mers collected from the reads, large enough for the
+    /// calibration k-mers and the modified control motif.
+    flank: usize,
+    code_calls: FxHashMap<(char, char), BinnedCalls>,
+    kmer_calls: FxHashMap<(char, char, Vec<u8>), BinnedCalls>,
+}
+
+impl CallCounter {
+    fn new(kmer_size: Option<usize>, motif_length: usize) -> Self {
+        let flank = std::cmp::max(kmer_size.unwrap_or(1) / 2, motif_length);
+        Self {
+            kmer_size,
+            flank,
+            code_calls: FxHashMap::default(),
+            kmer_calls: FxHashMap::default(),
+        }
+    }
+
+    fn sampling_window(&self) -> KmerWindow {
+        KmerWindow::new(self.flank, self.flank)
+    }
+
+    fn add(
+        &mut self,
+        primary_base: char,
+        mod_code: char,
+        kmer: &[u8],
+        prob: f32,
+        is_modified: bool,
+    ) {
+        self.code_calls
+            .entry((primary_base, mod_code))
+            .or_insert_with(BinnedCalls::new)
+            .add(prob, is_modified);
+        if let Some(kmer_size) = self.kmer_size {
+            let start = self.flank - kmer_size / 2;
+            let kmer = kmer[start..start + kmer_size].to_vec();
+            self.kmer_calls
+                .entry((primary_base, mod_code, kmer))
+                .or_insert_with(BinnedCalls::new)
+                .add(prob, is_modified);
+        }
+    }
+
+    fn fit(
+        self,
+        method: CalibrationMethod,
+        with_modified_control: bool,
+        control_quantile: f32,
+        min_kmer_calls: u64,
+        kmer_window: Option<KmerWindow>,
+    ) -> anyhow::Result<CalibrationMap> {
+        let mut code_maps = FxHashMap::default();
+        for ((primary_base, mod_code), binned) in self.code_calls {
+            if binned.n_unmodified() == 0 {
+                bail!(
+                    "no unmodified examples for {primary_base} {mod_code}, \
+                     check the control samples"
+                )
+            }
+            if with_modified_control && binned.n_modified() == 0 {
+                warn!(
+                    "no modified examples for {primary_base} {mod_code}, \
+                     using the unmodified control only"
+                );
+            }
+            code_maps.insert(
+                (primary_base, mod_code),
+                binned.fit(method, control_quantile),
+            );
+        }
+        let kmer_maps = self
+            .kmer_calls
+            .into_iter()
+            .filter(|(_, binned)| {
+                binned.n_unmodified() >= min_kmer_calls
+                    && (!with_modified_control
+                        || binned.n_modified() >= min_kmer_calls)
+            })
+            .fold(
+                KmerMaps::default(),
+                |mut acc, ((primary_base, mod_code, kmer), binned)| {
+                    acc.entry((primary_base, mod_code))
+                        .or_default()
+                        .insert(kmer, binned.fit(method, control_quantile));
+                    acc
+                },
+            );
+        Ok(CalibrationMap {
+            kmer_window,
+            code_maps,
+            kmer_maps,
+        })
+    }
+}
+
+/// Monotonic map from a predicted to a calibrated probability.
+#[derive(Debug, Clone, PartialEq)]
+enum Calibrator {
+    /// Piecewise linear through the knots (sorted by predicted probability),
+    /// constant past the first and last knots.
+    Isotonic(Vec<(f32, f32)>),
+    /// `sigmoid(a * logit(p) + b)`.
+    Platt { a: f32, b: f32 },
+}
+
+impl Calibrator {
+    fn apply(&self, prob: f32) -> f32 {
+        match self {
+            Self::Isotonic(knots) => {
+                let idx = knots.partition_point(|(x, _)| *x < prob);
+                if idx == 0 {
+                    knots[0].1
+                } else if idx == knots.len() {
+                    knots[knots.len() - 1].1
+                } else {
+                    let (x0, y0) = knots[idx - 1];
+                    let (x1, y1) = knots[idx];
+                    y0 + (y1 - y0) * (prob - x0) / (x1 - x0)
+                }
+            }
+            Self::Platt { a, b } => {
+                sigmoid(*a as f64 * logit(prob as f64) + *b as f64) as f32
+            }
+        }
+    }
+
+    fn method_name(&self) -> &'static str {
+        match self {
+            Self::Isotonic(_) => "isotonic",
+            Self::Platt { .. } => "platt",
+        }
+    }
+
+    fn parameters(&self) -> String {
+        match self {
+            Self::Isotonic(knots) => knots
+                .iter()
+                .map(|(x, y)| format!("{x:.6}:{y:.6}"))
+                .collect::<Vec<String>>()
+                .join(","),
+            Self::Platt { a, b } => format!("{a:.6},{b:.6}"),
+        }
+    }
+
+    fn parse(method: &str, parameters: &str) -> anyhow::Result<Self> {
+        let parse_float = |raw: &str| {
+            raw.parse::<f32>()
+                .with_context(|| format!("invalid number {raw}"))
+        };
+        match method {
+            "isotonic" => {
+                let knots = parameters
+                    .split(',')
+                    .map(|knot| {
+                        let (x, y) = knot
+                            .split_once(':')
+                            .ok_or_else(|| anyhow!("invalid knot {knot}"))?;
+                        Ok((parse_float(x)?, parse_float(y)?))
+                    })
+                    .collect::<anyhow::Result<Vec<(f32, f32)>>>()?;
+                if knots.windows(2).any(|w| w[0].0 > w[1].0) {
+                    bail!("isotonic knots must be sorted")
+                }
+                Ok(Self::Isotonic(knots))
+            }
+            "platt" => {
+                let (a, b) = parameters.split_once(',').ok_or_else(|| {
+                    anyhow!("invalid Platt parameters {parameters}")
+                })?;
+                Ok(Self::Platt {
+                    a: parse_float(a)?,
+                    b: parse_float(b)?,
+                })
+            }
+            _ => bail!("unknown calibration method {method}"),
+        }
+    }
+}
+
+/// Per-k-mer calibration maps for each primary base and modification code,
+/// keyed by k-mer so they can be looked up with a slice of the sequence.
+type KmerMaps = FxHashMap<(char, char), FxHashMap<Vec<u8>, Calibrator>>;
+
+/// Calibration maps for each primary base and modification code, with
+/// optional maps for individual k-mers. Made with `modkit calibrate`.
+pub struct CalibrationMap {
+    kmer_window: Option<KmerWindow>,
+    code_maps: FxHashMap<(char, char), Calibrator>,
+    kmer_maps: KmerMaps,
+}
+
+impl CalibrationMap {
+    const HEADER: &'static str =
+        "primary_base\tmod_code\tkmer\tmethod\tparameters";
+
+    pub(crate) fn from_file(fp: &PathBuf) -> anyhow::Result<Self> {
+        let fh = File::open(fp)
+            .with_context(|| format!("failed to open calibration {fp:?}"))?;
+        let mut code_maps = FxHashMap::default();
+        let mut kmer_maps = KmerMaps::default();
+        let mut kmer_size = None;
+        for (i, line) in BufReader::new(fh).lines().enumerate() {
+            let line = line?;
+            if i == 0 {
+                if line != Self::HEADER {
+                    bail!("{fp:?} is not a calibration map, unexpected header")
+                }
+                continue;
+            }
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            if parts.len() != 5 {
+                bail!("invalid calibration line {line}")
+            }
+            let as_char = |raw: &str| {
+                let mut chars = raw.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err(anyhow!("invalid calibration line {line}")),
+                }
+            };
+            let primary_base = as_char(parts[0])?;
+            let mod_code = as_char(parts[1])?;
+            let calibrator = Calibrator::parse(parts[3], parts[4])
+                .with_context(|| format!("invalid calibration line {line}"))?;
+            if parts[2] == ALL_CONTEXTS {
+                code_maps.insert((primary_base, mod_code), calibrator);
+            } else {
+                let k = parts[2].len();
+                if *kmer_size.get_or_insert(k) != k {
+                    bail!("calibration k-mers must all be the same size")
+                }
+                kmer_maps
+                    .entry((primary_base, mod_code))
+                    .or_default()
+                    .insert(parts[2].as_bytes().to_vec(), calibrator);
+            }
+        }
+        let kmer_window =
+            kmer_size.map(KmerWindow::from_kmer_size).transpose()?;
+        Ok(Self {
+            kmer_window,
+            code_maps,
+            kmer_maps,
+        })
+    }
+
+    fn n_kmer_maps(&self) -> usize {
+        self.kmer_maps.values().map(|maps| maps.len()).sum()
+    }
+
+    fn write(&self, fp: &PathBuf) -> anyhow::Result<()> {
+        let fh = File::create(fp)
+            .with_context(|| format!("failed to create {fp:?}"))?;
+        let mut writer = BufWriter::new(fh);
+        writeln!(writer, "{}", Self::HEADER)?;
+        let code_rows = self.code_maps.iter().map(|((base, code), c)| {
+            (*base, *code, ALL_CONTEXTS.to_owned(), c)
+        });
+        let kmer_rows =
+            self.kmer_maps.iter().flat_map(|((base, code), maps)| {
+                maps.iter().map(|(kmer, c)| {
+                    (*base, *code, String::from_utf8_lossy(kmer).to_string(), c)
+                })
+            });
+        let mut rows = code_rows.chain(kmer_rows).collect::<Vec<_>>();
+        rows.sort_by(|(a, x, k, _), (b, y, l, _)| (a, x, k).cmp(&(b, y, l)));
+        for (primary_base, mod_code, kmer, calibrator) in rows {
+            writeln!(
+                writer,
+                "{primary_base}\t{mod_code}\t{kmer}\t{}\t{}",
+                calibrator.method_name(),
+                calibrator.parameters()
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Calibrate each modification probability, the per-k-mer map is used
+    /// when there is one for `kmer`. Codes without a map are unchanged. If
+    /// the calibrated probabilities sum to more than 1 they're scaled down
+    /// to sum to 1.
+    pub(crate) fn calibrate_base_mod_probs(
+        &self,
+        primary_base: char,
+        kmer: Option<&[u8]>,
+        base_mod_probs: &BaseModProbs,
+    ) -> BaseModProbs {
+        let mut probs = base_mod_probs
+            .iter_probs()
+            .map(|(mod_code, prob)| {
+                let calibrator = kmer
+                    .and_then(|kmer| {
+                        self.kmer_maps
+                            .get(&(primary_base, *mod_code))
+                            .and_then(|maps| maps.get(kmer))
+                    })
+                    .or_else(|| self.code_maps.get(&(primary_base, *mod_code)));
+                let calibrated = calibrator
+                    .map(|c| c.apply(*prob).clamp(0f32, 1f32))
+                    .unwrap_or(*prob);
+                (*mod_code, calibrated)
+            })
+            .collect::<FxHashMap<char, f32>>();
+        let total = probs.values().sum::<f32>();
+        if total > 1f32 {
+            probs.values_mut().for_each(|p| *p /= total);
+        }
+        BaseModProbs::new(probs)
+    }
+
+    /// Calibrate the probabilities for one primary base and strand of a read,
+    /// `forward_seq` is used to look up the per-k-mer maps. Implicitly
+    /// canonical positions ('.' mode) are made explicit first so that they
+    /// are calibrated the same as explicit canonical calls, the result is in
+    /// '?' mode.
+    pub(crate) fn calibrate_seq_pos_base_mod_probs(
+        &self,
+        seq_pos_base_mod_probs: SeqPosBaseModProbs,
+        primary_base: char,
+        strand: Strand,
+        forward_seq: &str,
+    ) -> SeqPosBaseModProbs {
+        let seq_pos_base_mod_probs = seq_pos_base_mod_probs
+            .add_implicit_mod_calls(
+                forward_seq,
+                primary_base,
+                &HashSet::new(),
+                None,
+            );
+        let pos_to_base_mod_probs = seq_pos_base_mod_probs
+            .pos_to_base_mod_probs
+            .into_iter()
+            .map(|(pos, probs)| {
+                let kmer = self.kmer_window.as_ref().map(|w| {
+                    w.get_mod_strand_kmer(forward_seq.as_bytes(), pos, strand)
+                });
+                let calibrated = self.calibrate_base_mod_probs(
+                    primary_base,
+                    kmer.as_ref().map(|k| k.as_slice()),
+                    &probs,
+                );
+                (pos, calibrated)
+            })
+            .collect();
+        SeqPosBaseModProbs::new(
+            pos_to_base_mod_probs,
+            seq_pos_base_mod_probs.skip_mode,
+        )
+    }
+}
+
+#[cfg(test)]
+mod calibrate_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::calibrate::{
+        fit_isotonic, fit_platt, BinnedCalls, CalibrationMap, Calibrator,
+    };
+    use crate::mod_bam::{BaseModProbs, SeqPosBaseModProbs, SkipMode};
+    use crate::util::Strand;
+
+    #[test]
+    fn test_fit_isotonic_pools_violators() {
+        let points = vec![
+            (0.1, 0.0, 1.0),
+            (0.3, 0.5, 1.0),
+            (0.5, 0.1, 1.0),
+            (0.9, 1.0, 2.0),
+        ];
+        let knots = fit_isotonic(&points);
+        assert_eq!(knots, vec![(0.1, 0.0), (0.3, 0.3), (0.5, 0.3), (0.9, 1.0)]);
+        let calibrator = Calibrator::Isotonic(knots);
+        assert_eq!(calibrator.apply(0.05), 0.0);
+        assert_eq!(calibrator.apply(0.4), 0.3);
+        assert!((calibrator.apply(0.7) - 0.65).abs() < 1e-6);
+        assert_eq!(calibrator.apply(0.95), 1.0);
+    }
+
+    #[test]
+    fn test_fit_platt_separable_direction() {
+        let mut binned = BinnedCalls::new();
+        for _ in 0..100 {
+            binned.add(0.2, false);
+            binned.add(0.4, false);
+            binned.add(0.6, true);
+            binned.add(0.3, true);
+            binned.add(0.8, true);
+        }
+        let (a, b) = fit_platt(&binned.weighted_points());
+        assert!(a > 0f32);
+        let calibrator = Calibrator::Platt { a, b };
+        assert!(calibrator.apply(0.8) > calibrator.apply(0.2));
+    }
+
+    #[test]
+    fn test_background_knots() {
+        let mut binned = BinnedCalls::new();
+        for i in 0..100 {
+            binned.add(i as f32 / 400f32, false);
+        }
+        // no modified calls, all probabilities in [0, 0.25)
+        let knots = binned.background_knots(0.99);
+        assert_eq!(knots.len(), 3);
+        let calibrator = Calibrator::Isotonic(knots);
+        assert_eq!(calibrator.apply(0.2), 0.0);
+        assert!(calibrator.apply(0.9) > 0.8);
+    }
+
+    #[test]
+    fn test_calibration_map_roundtrip_and_apply() {
+        let fp = std::env::temp_dir().join("test_calibration_map.tsv");
+        std::fs::write(
+            &fp,
+            "primary_base\tmod_code\tkmer\tmethod\tparameters\n\
+             C\tm\t*\tisotonic\t0.0:0.0,1.0:0.5\n\
+             C\tm\tACG\tplatt\t1.0,0.0\n\
+             C\th\t*\tisotonic\t0.0:0.0,1.0:1.0\n",
+        )
+        .unwrap();
+        let calibration = CalibrationMap::from_file(&fp).unwrap();
+        let probs: BaseModProbs = BaseModProbs::new(
+            [('m', 0.8), ('h', 0.1)]
+                .into_iter()
+                .collect::<FxHashMap<char, f32>>(),
+        );
+        let calibrated =
+            calibration.calibrate_base_mod_probs('C', Some(b"TCA"), &probs);
+        let get = |probs: &BaseModProbs, code: char| {
+            probs
+                .iter_probs()
+                .find(|(c, _)| **c == code)
+                .map(|(_, p)| *p)
+                .unwrap()
+        };
+        assert!((get(&calibrated, 'm') - 0.4).abs() < 1e-6);
+        assert!((get(&calibrated, 'h') - 0.1).abs() < 1e-6);
+        // identity Platt map for the k-mer
+        let calibrated =
+            calibration.calibrate_base_mod_probs('C', Some(b"ACG"), &probs);
+        assert!((get(&calibrated, 'm') - 0.8).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_calibrate_implicit_calls() {
+        let fp = std::env::temp_dir().join("test_calibrate_implicit.tsv");
+        // zero probability maps to 0.1
+        std::fs::write(
+            &fp,
+            "primary_base\tmod_code\tkmer\tmethod\tparameters\n\
+             C\tm\t*\tisotonic\t0.0:0.1,1.0:0.9\n",
+        )
+        .unwrap();
+        let calibration = CalibrationMap::from_file(&fp).unwrap();
+        // C positions are 1, 5, and 9, the call at 5 is explicitly canonical
+        let seq = "ACGTACGTACG";
+        let pos_to_probs = [(1, 1f32), (5, 0f32)]
+            .into_iter()
+            .map(|(pos, prob)| (pos, BaseModProbs::new_init('m', prob)))
+            .collect::<FxHashMap<usize, BaseModProbs>>();
+        let calibrated = calibration.calibrate_seq_pos_base_mod_probs(
+            SeqPosBaseModProbs::new(pos_to_probs, SkipMode::ProbModified),
+            'C',
+            Strand::Positive,
+            seq,
+        );
+        assert_eq!(calibrated.skip_mode, SkipMode::Ambiguous);
+        // the implied canonical call at 9 is calibrated like the explicit one
+        let explicit = calibrated.pos_to_base_mod_probs.get(&5).unwrap();
+        let implicit = calibrated.pos_to_base_mod_probs.get(&9).unwrap();
+        assert_eq!(explicit, &BaseModProbs::new_init('m', 0.1));
+        assert_eq!(implicit, explicit);
+    }
+}
@@ -1,16 +1,24 @@
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::num::ParseFloatError;
 use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context, Result as AnyhowResult};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use bio::io::fasta::Reader as FastaReader;
 use clap::{Args, Subcommand, ValueEnum};
 use histo_fp::Histogram;
 use log::{debug, info};
 use rust_htslib::bam;
 use rust_htslib::bam::record::{Aux, AuxArray};
 use rust_htslib::bam::Read;
+use rustc_hash::FxHashMap;
 
-use crate::adjust::{adjust_modbam, record_is_valid};
+use crate::adjust::{
+    adjust_modbam, process_records_in_order, record_is_valid, ContextFilter,
+    ReadScope, TagDestination, ADJUST_BATCH_SIZE,
+};
+use crate::calibrate::{Calibrate, CalibrationMap};
 use crate::command_utils::{
     get_bam_writer, get_serial_reader, get_threshold_from_options,
     parse_edge_filter_input, parse_per_mod_thresholds, parse_thresholds,
@@ -20,24 +28,25 @@ use crate::dmr::subcommands::BedMethylDmr;
 use crate::errs::{InputError, RunError};
 use crate::extract_mods::ExtractMods;
 use crate::logging::init_logging;
+use crate::merge_tags::MergeTags;
 use crate::mod_bam::{
     format_mm_ml_tag, CollapseMethod, ModBaseInfo, RawModCode, SkipMode,
     ML_TAGS, MM_TAGS,
 };
 use crate::mod_base_code::ModCode;
 use crate::monoid::Moniod;
-use crate::motif_bed::motif_bed;
+use crate::motif_bed::{motif_bed, RegexMotif};
 use crate::pileup::subcommand::{DuplexModBamPileup, ModBamPileup};
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
+use crate::read_matrix::ReadMatrix;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
 use crate::reads_sampler::record_sampler::RecordSampler;
 use crate::record_processor::RecordProcessor;
-use crate::repair_tags::RepairTags;
+use crate::repair_tags::{RepairSupplementary, RepairTags};
 use crate::summarize::{sampled_reads_to_summary, ModSummary};
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::{calc_thresholds_per_base, Percentiles};
-use crate::util;
 use crate::util::{add_modkit_pg_records, get_targets, get_ticker, Region};
 use crate::writers::{
     MultiTableWriter, OutWriter, SampledProbs, TableWriter, TsvWriter,
@@ -72,13 +81,31 @@ pub enum Commands {
     /// tab-separated values table.
     Extract(ExtractMods),
     /// Repair MM and ML tags in one bam with the correct tags from another. To use
-    /// this command, both modBAMs _must_ be sorted by read name. The "donor" modBAM's
+    /// this command, both modBAMs _must_ be sorted by read name (unless
+    /// --index-donor is used). The "donor" modBAM's
     /// reads must be a superset of the acceptor's reads. Extra reads in the donor are
     /// allowed, and multiple reads with the same name (secondary, etc.) are allowed in
     /// the acceptor. Reads with an empty SEQ field cannot be repaired and will be
     /// rejected. Reads where there is an ambiguous alignment of the acceptor to the
     /// donor will be rejected (and logged). See the full documentation for details.
     Repair(RepairTags),
+    /// Write MM and ML tags on supplementary and secondary records using the
+    /// tags on the primary record of the same read. Records that are
+    /// hard-clipped or have no SEQ are repaired using the clipping in the
+    /// CIGAR, missing SEQ is filled in from the primary record, and the MN tag
+    /// is set so that the records can be used by `pileup`.
+    RepairSupplementary(RepairSupplementary),
+    /// Merge the MM and ML tags of the same reads in two modBAMs, for example
+    /// reads basecalled with a 5mC/5hmC model and a 6mA model. Both modBAMs
+    /// _must_ be sorted by read name and the sequences must match. The output
+    /// records have the union of the base modification calls, see --conflict
+    /// for how calls for the same position and modification code are resolved.
+    MergeTags(MergeTags),
+    /// Fit a calibration map for base modification probabilities from an
+    /// unmodified control sample and, optionally, a fully modified control
+    /// sample. The map is applied with the --calibration option of
+    /// `adjust-mods`, `pileup`, and `extract`.
+    Calibrate(Calibrate),
     /// Perform DMR test on a set of regions. Output a BED file of regions
     /// with the score column indicating the magnitude of the difference. Find the schema and
     /// description of fields can in the README as well as a description of the model and method.
@@ -89,6 +116,10 @@ pub enum Commands {
     /// genomic motif positions. This command produces a bedMethyl file, the schema can be
     /// found in the online documentation.
     PileupHemi(DuplexModBamPileup),
+    /// Export a matrix of reads by motif positions in a region. Each cell
+    /// contains the base modification probability (or thresholded call) of the
+    /// read at the position, "NA" when the read does not cover the position.
+    ReadMatrix(ReadMatrix),
 }
 
 impl Commands {
@@ -103,8 +134,12 @@ impl Commands {
             Self::CallMods(x) => x.run(),
             Self::Extract(x) => x.run(),
             Self::Repair(x) => x.run(),
+            Self::RepairSupplementary(x) => x.run(),
+            Self::MergeTags(x) => x.run(),
+            Self::Calibrate(x) => x.run(),
             Self::Dmr(x) => x.run(),
             Self::PileupHemi(x) => x.run(),
+            Self::ReadMatrix(x) => x.run(),
         }
     }
 }
@@ -154,6 +189,10 @@ pub struct Adjust {
     /// Number of threads to use.
     #[arg(short, long, default_value_t = 4)]
     threads: usize,
+    /// Number of records to process in parallel at a time, larger batches
+    /// use more memory.
+    #[arg(long, default_value_t = ADJUST_BATCH_SIZE, hide_short_help = true)]
+    batch_size: usize,
     /// Fast fail, stop processing at the first invalid sequence record. Default
     /// behavior is to continue and report failed/skipped records at the end.
     #[arg(short, long = "ff", default_value_t = false)]
@@ -175,6 +214,60 @@ pub struct Adjust {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    /// Calibrate the base modification probabilities with a calibration map
+    /// made by `modkit calibrate`. Calibration is applied before --ignore,
+    /// --convert, and --edge-filter.
+    #[arg(long)]
+    calibration: Option<PathBuf>,
+    /// Keep only base modification calls at the sequence motifs provided.
+    /// The first argument should be the sequence motif and the second
+    /// argument is the 0-based offset to the base in the motif, for example
+    /// --motif CG 0 will keep calls at the C of CG motifs. This argument can
+    /// be passed multiple times. Motifs are matched in the read sequence
+    /// unless --ref is provided, then they are matched in the reference
+    /// sequence at the aligned position and calls at unaligned positions are
+    /// removed.
+    #[arg(long, action = clap::ArgAction::Append, num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Keep only base modification calls at CpG motifs, shorthand for
+    /// --motif CG 0.
+    #[arg(long, default_value_t = false)]
+    cpg: bool,
+    /// Invert the motif filter, remove base modification calls at the
+    /// --motif (or --cpg) motifs and keep all others. With --ref, calls at
+    /// unaligned positions are still removed.
+    #[arg(long, default_value_t = false)]
+    invert_motif: bool,
+    /// Path to reference FASTA, used to match --motif in the reference
+    /// sequence and with --filter-mismatches. Unmapped records will have
+    /// all of their base modification calls removed. (alias: ref)
+    #[arg(long, alias = "ref")]
+    reference: Option<PathBuf>,
+    /// Remove base modification calls where the read base does not match the
+    /// reference base, or that are not aligned to the reference (e.g.
+    /// insertions and soft-clipped bases). Requires --ref.
+    #[arg(long, requires = "reference", default_value_t = false)]
+    filter_mismatches: bool,
+    /// BED file with regions to mask, base modification calls aligned to
+    /// positions in these regions are removed (for example, blacklisted
+    /// repeats). The strand column is respected, "." masks both strands.
+    /// (alias: mask-bed)
+    #[arg(long, alias = "mask-bed")]
+    exclude_bed: Option<PathBuf>,
+    /// File with read names, one per line. Only these reads are adjusted,
+    /// other reads are written unchanged (see --strip-unselected).
+    #[arg(long)]
+    include_reads: Option<PathBuf>,
+    /// BED file with regions, only reads with alignments overlapping these
+    /// regions are adjusted, other reads are written unchanged (see
+    /// --strip-unselected). When used with --include-reads, reads must be in
+    /// the list _and_ overlap the regions.
+    #[arg(long)]
+    include_bed: Option<PathBuf>,
+    /// Remove the MM, ML, and MN tags from reads that are not selected with
+    /// --include-reads or --include-bed, instead of writing them unchanged.
+    #[arg(long, default_value_t = false)]
+    strip_unselected: bool,
     /// Output SAM format instead of BAM.
     #[arg(long, default_value_t = false)]
     output_sam: bool,
@@ -184,11 +277,166 @@ pub struct Adjust {
 }
 
 impl Adjust {
+    fn parse_motifs(&self) -> AnyhowResult<Vec<RegexMotif>> {
+        let mut raw_motif_parts = self.motif.clone().unwrap_or(Vec::new());
+        if raw_motif_parts.len() % 2 != 0 {
+            bail!("illegal number of parts for motif")
+        }
+        if self.cpg {
+            if raw_motif_parts.chunks(2).any(|motif| motif == ["CG", "0"]) {
+                info!("CG 0 motif already, ignoring --cpg");
+            } else {
+                raw_motif_parts
+                    .extend_from_slice(&["CG".to_string(), "0".to_string()]);
+            }
+        }
+        raw_motif_parts
+            .chunks(2)
+            .map(|c| {
+                let focus_base = c[1].parse::<usize>().map_err(|e| {
+                    anyhow!("failed to parse motif offset {}, {e}", &c[1])
+                })?;
+                RegexMotif::parse_string(c[0].as_str(), focus_base)
+            })
+            .collect()
+    }
+
+    fn load_context_filter(
+        &self,
+        header: &bam::HeaderView,
+        chrom_to_tid: &HashMap<&str, u32>,
+    ) -> AnyhowResult<Option<ContextFilter>> {
+        let motifs = self.parse_motifs()?;
+        if motifs.is_empty() && self.invert_motif {
+            bail!("--invert-motif requires --motif or --cpg")
+        }
+        let masked_positions = self
+            .exclude_bed
+            .as_ref()
+            .map(|fp| {
+                info!(
+                    "removing base modification calls in regions from {fp:?}"
+                );
+                StrandedPositionFilter::from_bed_file(
+                    fp,
+                    chrom_to_tid,
+                    self.suppress_progress,
+                )
+            })
+            .transpose()?;
+        if motifs.is_empty()
+            && !self.filter_mismatches
+            && masked_positions.is_none()
+        {
+            if self.reference.is_some() {
+                info!(
+                    "no --motif, --cpg, or --filter-mismatches, ignoring --ref"
+                );
+            }
+            return Ok(None);
+        }
+        for motif in motifs.iter() {
+            if self.invert_motif {
+                info!("removing base modification calls at {motif} motifs");
+            } else {
+                info!("keeping only base modification calls at {motif} motifs");
+            }
+        }
+        let reference_sequences = self
+            .reference
+            .as_ref()
+            .map(|fp| -> AnyhowResult<FxHashMap<u32, Vec<u8>>> {
+                let name_to_tid = (0..header.target_count())
+                    .filter_map(|tid| {
+                        String::from_utf8(header.tid2name(tid).to_vec())
+                            .ok()
+                            .map(|name| (name, tid))
+                    })
+                    .collect::<HashMap<String, u32>>();
+                let sequences = FastaReader::from_file(fp)
+                    .with_context(|| format!("failed to read {fp:?}"))?
+                    .records()
+                    .filter_map(|r| r.ok())
+                    .filter_map(|record| {
+                        name_to_tid.get(record.id()).map(|&tid| {
+                            (tid, record.seq().to_ascii_uppercase())
+                        })
+                    })
+                    .collect::<FxHashMap<u32, Vec<u8>>>();
+                if sequences.is_empty() {
+                    bail!(
+                        "did not find any of the modBAM contigs in the \
+                         reference"
+                    )
+                }
+                info!("loaded {} reference sequences", sequences.len());
+                Ok(sequences)
+            })
+            .transpose()?;
+        Ok(Some(ContextFilter::new(
+            motifs,
+            self.invert_motif,
+            reference_sequences,
+            self.filter_mismatches,
+            masked_positions,
+        )))
+    }
+
+    fn load_read_scope(
+        &self,
+        chrom_to_tid: &HashMap<&str, u32>,
+    ) -> AnyhowResult<Option<ReadScope>> {
+        if self.include_reads.is_none() && self.include_bed.is_none() {
+            if self.strip_unselected {
+                bail!(
+                    "--strip-unselected requires --include-reads or \
+                     --include-bed"
+                )
+            }
+            return Ok(None);
+        }
+        let read_ids = self
+            .include_reads
+            .as_ref()
+            .map(|fp| -> AnyhowResult<HashSet<String>> {
+                let fh = File::open(fp)
+                    .with_context(|| format!("failed to open {fp:?}"))?;
+                let read_ids = BufReader::new(fh)
+                    .lines()
+                    .collect::<Result<Vec<String>, _>>()?
+                    .into_iter()
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty())
+                    .collect::<HashSet<String>>();
+                if read_ids.is_empty() {
+                    bail!("did not find any read names in {fp:?}")
+                }
+                info!("loaded {} read names to adjust", read_ids.len());
+                Ok(read_ids)
+            })
+            .transpose()?;
+        let regions = self
+            .include_bed
+            .as_ref()
+            .map(|fp| {
+                StrandedPositionFilter::from_bed_file(
+                    fp,
+                    chrom_to_tid,
+                    self.suppress_progress,
+                )
+            })
+            .transpose()?;
+        Ok(Some(ReadScope::new(
+            read_ids,
+            regions,
+            self.strip_unselected,
+        )))
+    }
+
     pub fn run(&self) -> AnyhowResult<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         let mut reader = get_serial_reader(self.in_bam.as_str())?;
         let threads = self.threads;
-        reader.set_threads(threads)?;
         let mut header = bam::Header::from_template(reader.header());
         add_modkit_pg_records(&mut header);
         let mut out_bam =
@@ -253,9 +501,33 @@ impl Adjust {
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
 
-        let methods = if edge_filter.is_none() && methods.is_empty() {
-            bail!("no edge-filter, ignore, or convert was provided, no work to do. Provide \
-            --edge-filter, --ignore, or --convert option to use modkit adjust-mods")
+        let calibration = self
+            .calibration
+            .as_ref()
+            .map(CalibrationMap::from_file)
+            .transpose()?;
+
+        let targets = get_targets(reader.header(), None);
+        let chrom_to_tid = targets
+            .iter()
+            .map(|reference_record| {
+                (reference_record.name.as_str(), reference_record.tid)
+            })
+            .collect::<HashMap<&str, u32>>();
+        let context_filter =
+            self.load_context_filter(reader.header(), &chrom_to_tid)?;
+        let read_scope = self.load_read_scope(&chrom_to_tid)?;
+
+        let methods = if edge_filter.is_none()
+            && methods.is_empty()
+            && calibration.is_none()
+            && context_filter.is_none()
+            && !self.strip_unselected
+        {
+            bail!("no edge-filter, ignore, convert, calibration, or context filter was provided, \
+            no work to do. Provide --edge-filter, --ignore, --convert, --calibration, --motif, \
+            --cpg, --filter-mismatches, --exclude-bed, or --strip-unselected option to use \
+            modkit adjust-mods")
         } else {
             methods
         };
@@ -266,6 +538,12 @@ impl Adjust {
             &methods,
             None,
             edge_filter.as_ref(),
+            calibration.as_ref(),
+            context_filter.as_ref(),
+            read_scope.as_ref(),
+            &TagDestination::Replace,
+            threads,
+            self.batch_size,
             self.fail_fast,
             "Adjusting modBAM",
             self.suppress_progress,
@@ -865,6 +1143,10 @@ pub struct Update {
     /// Number of threads to use.
     #[arg(short, long, default_value_t = 4)]
     threads: usize,
+    /// Number of records to process in parallel at a time, larger batches
+    /// use more memory.
+    #[arg(long, default_value_t = ADJUST_BATCH_SIZE, hide_short_help = true)]
+    batch_size: usize,
     /// Output debug logs to file at this path.
     #[arg(long)]
     log_filepath: Option<PathBuf>,
@@ -923,7 +1205,6 @@ impl Update {
         let _handle = init_logging(self.log_filepath.as_ref());
         let threads = self.threads;
         let mut reader = get_serial_reader(&self.in_bam)?;
-        reader.set_threads(threads)?;
         let mut header = bam::Header::from_template(reader.header());
         add_modkit_pg_records(&mut header);
 
@@ -936,14 +1217,14 @@ impl Update {
         let mut total_failed = 0usize;
         let mut total_skipped = 0usize;
 
-        for (i, result) in reader.records().enumerate() {
-            if let Ok(record) = result {
-                let record_name = util::get_query_name_string(&record)
-                    .unwrap_or("???".to_owned());
-                match update_mod_tags(
-                    record,
-                    self.mode.map(|m| m.to_skip_mode()),
-                ) {
+        let new_mode = self.mode.map(|m| m.to_skip_mode());
+        process_records_in_order(
+            &mut reader,
+            threads,
+            self.batch_size,
+            |record| update_mod_tags(record, new_mode),
+            |record_name, updated| {
+                match updated {
                     Err(RunError::BadInput(InputError(err)))
                     | Err(RunError::Failed(err)) => {
                         debug!("read {} failed, {}", record_name, err);
@@ -958,14 +1239,13 @@ impl Update {
                             total_failed += 1;
                         } else {
                             spinner.inc(1);
-                            total = i;
+                            total += 1;
                         }
                     }
                 }
-            } else {
-                total_failed += 1;
-            }
-        }
+                Ok(())
+            },
+        )?;
 
         spinner.finish_and_clear();
 
@@ -1006,6 +1286,10 @@ pub struct CallMods {
     /// Number of threads to use while processing chunks concurrently.
     #[arg(short, long, default_value_t = 4)]
     threads: usize,
+    /// Number of records to process in parallel at a time, larger batches
+    /// use more memory.
+    #[arg(long, default_value_t = ADJUST_BATCH_SIZE, hide_short_help = true)]
+    batch_size: usize,
     // /// Interval chunk size to process concurrently. Smaller interval chunk
     // /// sizes will use less memory but incur more overhead. Only used when
     // /// provided an indexed BAM.
@@ -1117,18 +1401,47 @@ pub struct CallMods {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    /// Keep the original MM and ML tags under these tag names, for example
+    /// --keep-original-tags XM XL. The MM and ML tags are replaced with the
+    /// calls as usual.
+    #[arg(long, num_args = 2, value_names = ["MM_TAG", "ML_TAG"])]
+    keep_original_tags: Option<Vec<String>>,
+    /// Write the calls to these tags instead of replacing the MM and ML tags,
+    /// for example --output-tags XM XL. The MM and ML tags are left unchanged.
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["MM_TAG", "ML_TAG"],
+        conflicts_with = "keep_original_tags"
+    )]
+    output_tags: Option<Vec<String>>,
     /// Output SAM format instead of BAM.
     #[arg(long, default_value_t = false)]
     output_sam: bool,
 }
 
 impl CallMods {
+    fn tag_destination(&self) -> AnyhowResult<TagDestination> {
+        match (&self.keep_original_tags, &self.output_tags) {
+            (Some(raw), _) => {
+                let (mm_tag, ml_tag) = TagDestination::parse_tag_names(raw)?;
+                info!(
+                    "keeping original MM and ML tags as {mm_tag} and {ml_tag}"
+                );
+                Ok(TagDestination::KeepOriginal { mm_tag, ml_tag })
+            }
+            (None, Some(raw)) => {
+                let (mm_tag, ml_tag) = TagDestination::parse_tag_names(raw)?;
+                info!("writing calls to {mm_tag} and {ml_tag} tags");
+                Ok(TagDestination::Separate { mm_tag, ml_tag })
+            }
+            (None, None) => Ok(TagDestination::Replace),
+        }
+    }
+
     pub fn run(&self) -> AnyhowResult<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         let mut reader = get_serial_reader(&self.in_bam)?;
-
-        let threads = self.threads;
-        reader.set_threads(threads)?;
         let mut header = bam::Header::from_template(reader.header());
         add_modkit_pg_records(&mut header);
         let mut out_bam =
@@ -1139,6 +1452,7 @@ impl CallMods {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let tag_destination = self.tag_destination()?;
 
         let per_mod_thresholds =
             if let Some(raw_per_mod_thresholds) = &self.mod_thresholds {
@@ -1192,6 +1506,12 @@ impl CallMods {
             &[],
             Some(&caller),
             edge_filter.as_ref(),
+            None,
+            None,
+            None,
+            &tag_destination,
+            self.threads,
+            self.batch_size,
             self.fail_fast,
             "Calling Mods",
             self.suppress_progress,
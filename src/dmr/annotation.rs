@@ -0,0 +1,553 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use derive_new::new;
+use log::{debug, error, info};
+
+use crate::dmr::util::DmrInterval;
+use crate::position_filter::Iv;
+use crate::util::StrandRule;
+
+/// Regions that can be derived from a GTF/GFF3 annotation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub(crate) enum AnnotationFeature {
+    /// The transcription start site of each gene, plus and minus the flank.
+    promoter,
+    /// Each gene from start to end.
+    gene_body,
+    /// Each distinct exon.
+    exon,
+    /// The intron between the first and second exon of each transcript.
+    first_intron,
+}
+
+impl AnnotationFeature {
+    fn default_flank(&self) -> u64 {
+        match self {
+            Self::promoter => 1000,
+            _ => 0,
+        }
+    }
+}
+
+/// A single feature line from a GTF or GFF3 file, with 0-based, half-open
+/// coordinates.
+#[derive(new, Debug, PartialEq, Eq)]
+struct AnnotationRecord {
+    chrom: String,
+    feature_type: String,
+    start: u64,
+    stop: u64,
+    strand: StrandRule,
+    attributes: HashMap<String, String>,
+}
+
+/// Parse the attributes column, both the GTF (`key "value";`) and GFF3
+/// (`key=value;`) styles are accepted.
+fn parse_attributes(raw: &str) -> HashMap<String, String> {
+    raw.split(';')
+        .map(|attr| attr.trim())
+        .filter(|attr| !attr.is_empty())
+        .filter_map(|attr| {
+            let (key, value) = match (attr.find('='), attr.find(' ')) {
+                (Some(eq), Some(space)) if eq < space => attr.split_at(eq),
+                (Some(eq), None) => attr.split_at(eq),
+                (_, Some(space)) => attr.split_at(space),
+                (None, None) => return None,
+            };
+            let value = value[1..].trim().trim_matches('"');
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+impl AnnotationRecord {
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let parts = line.trim_end().split('\t').collect::<Vec<&str>>();
+        if parts.len() < 9 {
+            bail!(
+                "annotation lines need 9 tab-separated columns, got {}",
+                parts.len()
+            )
+        }
+        // GTF and GFF3 are 1-based and closed
+        let start = parts[3]
+            .parse::<u64>()
+            .with_context(|| format!("invalid start {}", parts[3]))?;
+        let stop = parts[4]
+            .parse::<u64>()
+            .with_context(|| format!("invalid end {}", parts[4]))?;
+        if start < 1 || stop < start {
+            bail!("invalid feature coordinates {}-{}", start, stop)
+        }
+        let strand = match parts[6] {
+            "+" => StrandRule::Positive,
+            "-" => StrandRule::Negative,
+            _ => StrandRule::Both,
+        };
+        Ok(Self::new(
+            parts[0].to_string(),
+            parts[2].to_string(),
+            start - 1,
+            stop,
+            strand,
+            parse_attributes(parts[8]),
+        ))
+    }
+
+    fn get_attribute(&self, keys: &[&str]) -> Option<&String> {
+        keys.iter().find_map(|k| self.attributes.get(*k))
+    }
+
+    fn name(&self) -> String {
+        self.get_attribute(&["gene_name", "Name", "gene_id", "ID"])
+            .cloned()
+            .unwrap_or_else(|| {
+                format!("{}:{}-{}", self.chrom, self.start, self.stop)
+            })
+    }
+
+    /// The transcripts this feature belongs to, GFF3 features can have more
+    /// than one comma-separated `Parent`.
+    fn transcript_ids(&self) -> Vec<&str> {
+        match self.attributes.get("transcript_id") {
+            Some(transcript_id) => vec![transcript_id.as_str()],
+            None => self
+                .attributes
+                .get("Parent")
+                .map(|parents| {
+                    parents.split(',').filter(|p| !p.is_empty()).collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    fn is_gene(&self) -> bool {
+        self.feature_type == "gene"
+    }
+
+    fn is_exon(&self) -> bool {
+        self.feature_type == "exon"
+    }
+}
+
+/// Resolves the gene a feature belongs to. GTF features carry the gene
+/// attributes themselves, GFF3 features only point to their `Parent` (e.g.
+/// exon to transcript to gene) so the parents are followed by `ID`.
+struct GeneLookup<'a> {
+    by_id: HashMap<&'a str, &'a AnnotationRecord>,
+}
+
+impl<'a> GeneLookup<'a> {
+    /// Guard against cycles in malformed annotations.
+    const MAX_DEPTH: usize = 8;
+
+    fn new(records: &'a [AnnotationRecord]) -> Self {
+        let by_id = records
+            .iter()
+            .filter_map(|r| r.attributes.get("ID").map(|id| (id.as_str(), r)))
+            .collect();
+        Self { by_id }
+    }
+
+    /// The name of the gene the `record` belongs to, same as the name of the
+    /// gene record itself.
+    fn gene_name(&self, record: &AnnotationRecord) -> Option<String> {
+        let mut record = record;
+        for _ in 0..Self::MAX_DEPTH {
+            if record.is_gene() {
+                return Some(record.name());
+            }
+            if let Some(name) = record.get_attribute(&["gene_name", "gene_id"])
+            {
+                return Some(name.clone());
+            }
+            // all parents of a feature belong to the same gene
+            record = record
+                .attributes
+                .get("Parent")
+                .and_then(|parents| parents.split(',').next())
+                .and_then(|parent| self.by_id.get(parent))?;
+        }
+        None
+    }
+}
+
+fn make_interval(
+    chrom: &str,
+    start: u64,
+    stop: u64,
+    name: String,
+    strand: StrandRule,
+) -> DmrInterval {
+    DmrInterval::new(
+        Iv {
+            start,
+            stop,
+            val: (),
+        },
+        chrom.to_string(),
+        name,
+        strand,
+    )
+}
+
+fn flanked(record: &AnnotationRecord, flank: u64) -> (u64, u64) {
+    (record.start.saturating_sub(flank), record.stop + flank)
+}
+
+fn first_introns(
+    exons: &[&AnnotationRecord],
+    genes: &GeneLookup,
+    flank: u64,
+) -> Vec<DmrInterval> {
+    let mut by_transcript = HashMap::new();
+    for exon in exons {
+        let transcript_ids = exon.transcript_ids();
+        if transcript_ids.is_empty() {
+            debug!(
+                "exon at {}:{}-{} has no transcript, skipping",
+                exon.chrom, exon.start, exon.stop
+            );
+        }
+        for transcript_id in transcript_ids {
+            by_transcript
+                .entry(transcript_id)
+                .or_insert_with(Vec::new)
+                .push(*exon);
+        }
+    }
+    by_transcript
+        .into_iter()
+        .filter_map(|(transcript_id, mut exons)| {
+            if exons.len() < 2 {
+                return None;
+            }
+            exons.sort_by_key(|exon| exon.start);
+            let (first, second) = match exons[0].strand {
+                StrandRule::Negative => {
+                    (exons[exons.len() - 1], exons[exons.len() - 2])
+                }
+                _ => (exons[0], exons[1]),
+            };
+            let (start, stop) = if first.stop <= second.start {
+                (first.stop, second.start)
+            } else {
+                (second.stop, first.start)
+            };
+            if start >= stop {
+                debug!("transcript {transcript_id} has no first intron");
+                return None;
+            }
+            Some(make_interval(
+                &first.chrom,
+                start.saturating_sub(flank),
+                stop + flank,
+                genes
+                    .gene_name(first)
+                    .unwrap_or_else(|| transcript_id.to_string()),
+                first.strand,
+            ))
+        })
+        .collect()
+}
+
+/// Make regions from the features in a GTF or GFF3 annotation. `flank`
+/// defaults to 1000 bases for promoters and 0 otherwise. When `use_strand`
+/// is false the regions are not stranded.
+pub(super) fn parse_annotation_regions<P: AsRef<Path>>(
+    fp: P,
+    feature: AnnotationFeature,
+    flank: Option<u64>,
+    use_strand: bool,
+) -> anyhow::Result<Vec<DmrInterval>> {
+    let flank = flank.unwrap_or(feature.default_flank());
+    let mut failed_to_parse = 0usize;
+    let records = BufReader::new(File::open(fp)?)
+        .lines()
+        .filter_map(|r| match r {
+            Ok(l) if l.starts_with('#') || l.trim().is_empty() => None,
+            Ok(l) => match AnnotationRecord::parse(&l) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    debug!("failed to parse annotation line {l}, {e}");
+                    failed_to_parse += 1;
+                    None
+                }
+            },
+            Err(e) => {
+                error!(
+                    "error fetching line from annotation, {}",
+                    e.to_string()
+                );
+                None
+            }
+        })
+        .collect::<Vec<AnnotationRecord>>();
+    if failed_to_parse > 0 {
+        info!("failed to parse {failed_to_parse} annotation lines");
+    }
+
+    let genes = GeneLookup::new(&records);
+    let regions: Vec<DmrInterval> = match feature {
+        AnnotationFeature::promoter => records
+            .iter()
+            .filter(|r| r.is_gene())
+            .map(|gene| {
+                let tss = match gene.strand {
+                    StrandRule::Negative => gene.stop - 1,
+                    _ => gene.start,
+                };
+                make_interval(
+                    &gene.chrom,
+                    tss.saturating_sub(flank),
+                    tss + 1 + flank,
+                    gene.name(),
+                    gene.strand,
+                )
+            })
+            .collect(),
+        AnnotationFeature::gene_body => records
+            .iter()
+            .filter(|r| r.is_gene())
+            .map(|gene| {
+                let (start, stop) = flanked(gene, flank);
+                make_interval(
+                    &gene.chrom,
+                    start,
+                    stop,
+                    gene.name(),
+                    gene.strand,
+                )
+            })
+            .collect(),
+        AnnotationFeature::exon => {
+            // exons shared between transcripts are only reported once
+            let mut seen = BTreeSet::new();
+            records
+                .iter()
+                .filter(|r| r.is_exon())
+                .filter(|exon| {
+                    seen.insert((
+                        exon.chrom.clone(),
+                        exon.start,
+                        exon.stop,
+                        exon.strand as u8,
+                    ))
+                })
+                .map(|exon| {
+                    let (start, stop) = flanked(exon, flank);
+                    make_interval(
+                        &exon.chrom,
+                        start,
+                        stop,
+                        genes.gene_name(exon).unwrap_or_else(|| exon.name()),
+                        exon.strand,
+                    )
+                })
+                .collect()
+        }
+        AnnotationFeature::first_intron => {
+            let exons = records
+                .iter()
+                .filter(|r| r.is_exon())
+                .collect::<Vec<&AnnotationRecord>>();
+            let mut seen = BTreeSet::new();
+            first_introns(&exons, &genes, flank)
+                .into_iter()
+                .filter(|intron| {
+                    seen.insert((
+                        intron.chrom.clone(),
+                        intron.start(),
+                        intron.stop(),
+                        intron.strand as u8,
+                    ))
+                })
+                .collect()
+        }
+    };
+
+    let mut regions = regions;
+    if regions.is_empty() {
+        bail!("didn't find any {:?} regions in annotation", feature)
+    }
+    regions.sort();
+    if use_strand {
+        Ok(regions)
+    } else {
+        Ok(regions
+            .into_iter()
+            .map(|roi| DmrInterval {
+                strand: StrandRule::Both,
+                ..roi
+            })
+            .collect())
+    }
+}
+
+/// Un-stranded regions from an annotation as chrom, start, and stop, for
+/// outputs aggregated over regions outside of `dmr`.
+pub(crate) fn parse_annotation_region_coordinates<P: AsRef<Path>>(
+    fp: P,
+    feature: AnnotationFeature,
+    flank: Option<u64>,
+) -> anyhow::Result<Vec<(String, u64, u64)>> {
+    let mut coordinates = parse_annotation_regions(fp, feature, flank, false)?
+        .into_iter()
+        .map(|roi| {
+            let (start, stop) = (roi.start(), roi.stop());
+            (roi.chrom, start, stop)
+        })
+        .collect::<Vec<(String, u64, u64)>>();
+    // features on opposite strands with the same coordinates are the same
+    // region once the strand is removed
+    coordinates.dedup();
+    Ok(coordinates)
+}
+
+#[cfg(test)]
+mod dmr_annotation_tests {
+    use crate::dmr::annotation::{
+        parse_annotation_region_coordinates, parse_annotation_regions,
+        parse_attributes, AnnotationFeature, AnnotationRecord,
+    };
+    use crate::util::StrandRule;
+
+    #[test]
+    fn test_parse_annotation_attributes() {
+        let gtf =
+            r#"gene_id "ENSG01"; gene_name "ABC"; transcript_id "ENST01";"#;
+        let attrs = parse_attributes(gtf);
+        assert_eq!(attrs.get("gene_id").unwrap(), "ENSG01");
+        assert_eq!(attrs.get("gene_name").unwrap(), "ABC");
+        assert_eq!(attrs.get("transcript_id").unwrap(), "ENST01");
+        let gff = "ID=gene:ENSG01;Name=ABC;biotype=protein coding";
+        let attrs = parse_attributes(gff);
+        assert_eq!(attrs.get("ID").unwrap(), "gene:ENSG01");
+        assert_eq!(attrs.get("Name").unwrap(), "ABC");
+        assert_eq!(attrs.get("biotype").unwrap(), "protein coding");
+    }
+
+    #[test]
+    fn test_parse_annotation_record() {
+        let line = "chr20\tENSEMBL\tgene\t101\t200\t.\t-\t.\tgene_id \"ENSG01\"; gene_name \"ABC\";";
+        let record = AnnotationRecord::parse(line).unwrap();
+        assert_eq!(record.start, 100);
+        assert_eq!(record.stop, 200);
+        assert_eq!(record.strand, StrandRule::Negative);
+        assert_eq!(record.name(), "ABC");
+        assert!(record.is_gene());
+        assert!(AnnotationRecord::parse("chr20\tENSEMBL\tgene\t101").is_err());
+        assert_eq!(AnnotationFeature::promoter.default_flank(), 1000);
+        assert_eq!(AnnotationFeature::exon.default_flank(), 0);
+
+        let gff =
+            "chr20\tENSEMBL\texon\t101\t200\t.\t+\t.\tParent=tx1,tx2;Name=ABC";
+        let record = AnnotationRecord::parse(gff).unwrap();
+        assert_eq!(record.transcript_ids(), vec!["tx1", "tx2"]);
+    }
+
+    #[test]
+    fn test_annotation_regions() {
+        let fp = "tests/resources/dmr_annotation_snip.gtf";
+        let promoters = parse_annotation_regions(
+            fp,
+            AnnotationFeature::promoter,
+            None,
+            true,
+        )
+        .unwrap();
+        let obs = promoters
+            .iter()
+            .map(|r| (r.name.as_str(), r.start(), r.stop(), r.strand))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            obs,
+            vec![
+                ("GENE_A", 0, 1101, StrandRule::Positive),
+                ("GENE_B", 4999, 7000, StrandRule::Negative),
+            ]
+        );
+        let promoters = parse_annotation_regions(
+            fp,
+            AnnotationFeature::promoter,
+            Some(10),
+            false,
+        )
+        .unwrap();
+        assert_eq!(promoters[0].start(), 90);
+        assert_eq!(promoters[0].stop(), 111);
+        assert!(promoters.iter().all(|r| r.strand == StrandRule::Both));
+
+        let genes = parse_annotation_regions(
+            fp,
+            AnnotationFeature::gene_body,
+            None,
+            true,
+        )
+        .unwrap();
+        assert_eq!((genes[0].start(), genes[0].stop()), (100, 2000));
+        assert_eq!((genes[1].start(), genes[1].stop()), (3000, 6000));
+
+        // the exons shared by the two GENE_A transcripts are only reported once
+        let exons =
+            parse_annotation_regions(fp, AnnotationFeature::exon, None, true)
+                .unwrap();
+        assert_eq!(exons.len(), 6);
+
+        let introns = parse_annotation_regions(
+            fp,
+            AnnotationFeature::first_intron,
+            None,
+            true,
+        )
+        .unwrap();
+        let obs = introns
+            .iter()
+            .map(|r| (r.name.as_str(), r.start(), r.stop()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            obs,
+            vec![
+                ("GENE_A", 300, 800),
+                ("GENE_A", 300, 1500),
+                ("GENE_B", 5000, 5800)
+            ]
+        );
+
+        // GFF3 exons and introns are named by the gene through their
+        // transcript, same as with GTF
+        let gff = "tests/resources/dmr_annotation_snip.gff3";
+        for feature in [
+            AnnotationFeature::promoter,
+            AnnotationFeature::gene_body,
+            AnnotationFeature::exon,
+            AnnotationFeature::first_intron,
+        ] {
+            let from_gtf =
+                parse_annotation_regions(fp, feature, None, true).unwrap();
+            let from_gff =
+                parse_annotation_regions(gff, feature, None, true).unwrap();
+            assert_eq!(from_gtf, from_gff, "{feature:?}");
+        }
+
+        let coordinates = parse_annotation_region_coordinates(
+            fp,
+            AnnotationFeature::gene_body,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            coordinates,
+            genes
+                .iter()
+                .map(|r| (r.chrom.clone(), r.start(), r.stop()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
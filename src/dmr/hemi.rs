@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use derive_new::new;
+use itertools::Itertools;
+use log::{debug, info};
+use rv::prelude::*;
+
+use crate::dmr::util::{open_text_or_gzip, DmrInterval};
+use crate::position_filter::Iv;
+use crate::util::StrandRule;
+
+/// A single row from a `pileup-hemi` bedMethyl, the count of one duplex
+/// pattern (e.g. "m,-,C") at a position.
+#[derive(new, Debug, Clone, PartialEq, Eq)]
+pub(super) struct HemiBedMethylLine {
+    chrom: String,
+    start: u64,
+    stop: u64,
+    pattern: String,
+    count: usize,
+}
+
+impl HemiBedMethylLine {
+    /// Parse a row, columns 1-4 are tab-separated and the count of the
+    /// pattern is the 12th column, the remaining columns may be tab or space
+    /// separated.
+    pub(super) fn parse(line: &str) -> anyhow::Result<Self> {
+        let parts = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        if parts.len() < 12 {
+            bail!(
+                "pileup-hemi rows should have at least 12 columns, got {}",
+                parts.len()
+            )
+        }
+        let start = parts[1]
+            .parse::<u64>()
+            .with_context(|| format!("invalid start {}", parts[1]))?;
+        let stop = parts[2]
+            .parse::<u64>()
+            .with_context(|| format!("invalid stop {}", parts[2]))?;
+        let pattern = parts[3];
+        if pattern.split(',').count() != 3 {
+            bail!(
+                "invalid duplex pattern {pattern}, should be of the form \
+                <pos strand mod>,<neg strand mod>,<base>"
+            )
+        }
+        let count = parts[11]
+            .parse::<usize>()
+            .with_context(|| format!("invalid count {}", parts[11]))?;
+        Ok(Self::new(
+            parts[0].to_string(),
+            start,
+            stop,
+            pattern.to_string(),
+            count,
+        ))
+    }
+}
+
+/// Rows of a `pileup-hemi` bedMethyl grouped by contig and sorted by
+/// position.
+pub(super) struct HemiBedMethyl {
+    records: HashMap<String, Vec<HemiBedMethylLine>>,
+}
+
+impl HemiBedMethyl {
+    pub(super) fn read<P: AsRef<Path>>(fp: P) -> anyhow::Result<Self> {
+        let fp = fp.as_ref();
+        let mut failed_to_parse = 0usize;
+        let mut records = HashMap::new();
+        for line in open_text_or_gzip(fp)?.lines() {
+            let line = line.with_context(|| {
+                format!("failed to read line from {:?}", fp)
+            })?;
+            match HemiBedMethylLine::parse(&line) {
+                Ok(hemi_line) => records
+                    .entry(hemi_line.chrom.clone())
+                    .or_insert_with(Vec::new)
+                    .push(hemi_line),
+                Err(_) => failed_to_parse += 1,
+            }
+        }
+        if records.is_empty() {
+            bail!("failed to parse any pileup-hemi lines from {:?}", fp);
+        }
+        if failed_to_parse > 0 {
+            debug!("failed to parse {} lines from {:?}", failed_to_parse, fp);
+        }
+        records
+            .values_mut()
+            .for_each(|lines: &mut Vec<HemiBedMethylLine>| {
+                lines.sort_by(|a, b| (a.start, a.stop).cmp(&(b.start, b.stop)))
+            });
+        info!(
+            "loaded {} pileup-hemi records from {:?}",
+            records.values().map(|lines| lines.len()).sum::<usize>(),
+            fp
+        );
+
+        Ok(Self { records })
+    }
+
+    fn contigs(&self) -> impl Iterator<Item = &String> {
+        self.records.keys()
+    }
+
+    /// Every position with at least one pattern count, as a region.
+    fn sites(&self, chrom: &str) -> BTreeSet<(u64, u64)> {
+        self.records
+            .get(chrom)
+            .map(|lines| lines.iter().map(|l| (l.start, l.stop)).collect())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn get_counts(
+        &self,
+        dmr_interval: &DmrInterval,
+    ) -> PatternCounts {
+        let mut counts = PatternCounts::default();
+        if let Some(lines) = self.records.get(&dmr_interval.chrom) {
+            let first =
+                lines.partition_point(|l| l.stop <= dmr_interval.start());
+            let last = lines.partition_point(|l| l.start < dmr_interval.stop());
+            lines[first..last.max(first)]
+                .iter()
+                .filter(|l| dmr_interval.interval.overlap(l.start, l.stop))
+                .for_each(|l| counts.add(&l.pattern, l.count));
+        }
+        counts
+    }
+}
+
+/// Make a region for every site in either of the pileup-hemi outputs, sorted
+/// by contig and position.
+pub(super) fn get_all_sites(
+    a: &HemiBedMethyl,
+    b: &HemiBedMethyl,
+) -> Vec<DmrInterval> {
+    a.contigs()
+        .chain(b.contigs())
+        .collect::<BTreeSet<&String>>()
+        .into_iter()
+        .flat_map(|chrom| {
+            let mut sites = a.sites(chrom);
+            sites.extend(b.sites(chrom));
+            sites.into_iter().map(move |(start, stop)| {
+                DmrInterval::new(
+                    Iv {
+                        start,
+                        stop,
+                        val: (),
+                    },
+                    chrom.to_string(),
+                    format!("{}:{}-{}", chrom, start, stop),
+                    StrandRule::Both,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Counts of each duplex pattern over a site or region.
+#[derive(Debug, Default, Clone)]
+pub(super) struct PatternCounts {
+    counts: BTreeMap<String, usize>,
+}
+
+impl PatternCounts {
+    fn add(&mut self, pattern: &str, count: usize) {
+        *self.counts.entry(pattern.to_string()).or_insert(0) += count;
+    }
+
+    fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        let mut combined = self.clone();
+        other
+            .counts
+            .iter()
+            .for_each(|(pattern, count)| combined.add(pattern, *count));
+        combined
+    }
+
+    fn categorical_trials(
+        &self,
+        patterns_to_index: &HashMap<&str, usize>,
+    ) -> anyhow::Result<Vec<usize>> {
+        self.counts
+            .iter()
+            .try_fold(Vec::new(), |mut acc, (pattern, count)| {
+                let index = *patterns_to_index.get(pattern.as_str())?;
+                acc.extend(std::iter::repeat(index).take(*count));
+                Some(acc)
+            })
+            .ok_or_else(|| anyhow!("failed to make categorical trials"))
+    }
+
+    fn string_counts(&self) -> String {
+        if self.counts.is_empty() {
+            ".".to_string()
+        } else {
+            self.counts
+                .iter()
+                .map(|(pattern, count)| format!("{pattern}:{count}"))
+                .join(";")
+        }
+    }
+
+    fn string_percentages(&self) -> String {
+        if self.counts.is_empty() {
+            ".".to_string()
+        } else {
+            let total = self.total() as f32;
+            self.counts
+                .iter()
+                .map(|(pattern, count)| {
+                    format!("{pattern}:{:.2}", *count as f32 / total * 100f32)
+                })
+                .join(";")
+        }
+    }
+}
+
+fn pattern_llk(
+    counts: &PatternCounts,
+    prior: &Dirichlet,
+    patterns_to_index: &HashMap<&str, usize>,
+) -> anyhow::Result<f64> {
+    let xs = counts.categorical_trials(patterns_to_index)?;
+    let data = DataOrSuffStat::Data(&xs);
+    let posterior = prior.posterior(&data);
+    Ok(posterior.ln_m(&data))
+}
+
+/// Log-likelihood ratio of the duplex pattern counts of two samples modeled
+/// separately versus together with a Dirichlet-multinomial over the
+/// patterns observed in either sample.
+pub(super) fn llk_ratio_patterns(
+    a: &PatternCounts,
+    b: &PatternCounts,
+) -> anyhow::Result<f64> {
+    let patterns_to_index = a
+        .counts
+        .keys()
+        .chain(b.counts.keys())
+        .map(|p| p.as_str())
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| (p, i))
+        .collect::<HashMap<&str, usize>>();
+    if patterns_to_index.len() < 2 {
+        return Ok(0f64);
+    }
+    let prior = Dirichlet::jeffreys(patterns_to_index.len())?;
+    let llk_a = pattern_llk(a, &prior, &patterns_to_index)?;
+    let llk_b = pattern_llk(b, &prior, &patterns_to_index)?;
+    let llk_combined = pattern_llk(&a.combine(b), &prior, &patterns_to_index)?;
+    Ok(llk_a + llk_b - llk_combined)
+}
+
+/// Duplex pattern counts of two samples over a site or region.
+#[derive(Debug)]
+pub(super) struct HemiModificationCounts {
+    interval: DmrInterval,
+    a_counts: PatternCounts,
+    b_counts: PatternCounts,
+    pub(crate) score: f64,
+}
+
+impl HemiModificationCounts {
+    pub(super) fn new(
+        interval: DmrInterval,
+        a_counts: PatternCounts,
+        b_counts: PatternCounts,
+    ) -> anyhow::Result<Self> {
+        if a_counts.total() == 0 && b_counts.total() == 0 {
+            bail!("no duplex patterns in either sample for {interval}")
+        }
+        let score = llk_ratio_patterns(&a_counts, &b_counts)?;
+        Ok(Self {
+            interval,
+            a_counts,
+            b_counts,
+            score,
+        })
+    }
+
+    pub(super) fn to_row(&self) -> String {
+        let sep = '\t';
+        format!(
+            "\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}\n\
+        ",
+            self.interval.chrom,
+            self.interval.start(),
+            self.interval.stop(),
+            self.interval.name,
+            self.score,
+            self.a_counts.string_counts(),
+            self.a_counts.total(),
+            self.b_counts.string_counts(),
+            self.b_counts.total(),
+            self.a_counts.string_percentages(),
+            self.b_counts.string_percentages(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod dmr_hemi_tests {
+    use crate::dmr::hemi::{
+        get_all_sites, llk_ratio_patterns, HemiBedMethyl, HemiBedMethylLine,
+        PatternCounts,
+    };
+
+    fn make_counts(counts: &[(&str, usize)]) -> PatternCounts {
+        let mut pattern_counts = PatternCounts::default();
+        for (pattern, count) in counts {
+            pattern_counts.add(pattern, *count);
+        }
+        pattern_counts
+    }
+
+    #[test]
+    fn test_parse_hemi_line() {
+        let line = "chr20\t22614392\t22614393\tm,m,C\t4\t.\t22614392\t22614393\t255,0,0\t4 25.00 1 3 3 0 0 0 0";
+        let obs = HemiBedMethylLine::parse(line).unwrap();
+        let expected = HemiBedMethylLine::new(
+            "chr20".to_string(),
+            22614392,
+            22614393,
+            "m,m,C".to_string(),
+            1,
+        );
+        assert_eq!(obs, expected);
+        // regular bedMethyl rows aren't duplex patterns
+        let line = "chr20\t10034963\t10034964\tm\t19\t-\t10034963\t10034964\t255,0,0\t19\t94.74\t18\t1\t0\t0\t1\t0\t2";
+        assert!(HemiBedMethylLine::parse(line).is_err());
+    }
+
+    #[test]
+    fn test_llk_ratio_patterns() {
+        let a = make_counts(&[("m,m,C", 20), ("m,-,C", 2), ("-,-,C", 10)]);
+        let b = make_counts(&[("m,m,C", 19), ("m,-,C", 3), ("-,-,C", 11)]);
+        let c = make_counts(&[("m,m,C", 2), ("m,-,C", 15), ("-,m,C", 15)]);
+        let similar = llk_ratio_patterns(&a, &b).unwrap();
+        let different = llk_ratio_patterns(&a, &c).unwrap();
+        assert!(different > similar);
+        let only_one = make_counts(&[("-,-,C", 10)]);
+        assert_eq!(llk_ratio_patterns(&only_one, &only_one).unwrap(), 0f64);
+    }
+
+    #[test]
+    fn test_hemi_sites_and_counts() {
+        let hemi =
+            HemiBedMethyl::read("tests/resources/duplex_hemi.bed").unwrap();
+        let sites = get_all_sites(&hemi, &hemi);
+        assert!(!sites.is_empty());
+        assert!(sites.windows(2).all(|w| w[0] < w[1]));
+        let site = sites.iter().find(|site| site.start() == 22614392).unwrap();
+        let counts = hemi.get_counts(site);
+        assert_eq!(counts.string_counts(), "-,-,C:3;m,m,C:1");
+        assert_eq!(counts.total(), 4);
+    }
+}
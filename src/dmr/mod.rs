@@ -1,5 +1,8 @@
+pub(crate) mod annotation;
+mod hemi;
 mod model;
 mod multi_sample;
 mod pairwise;
 pub mod subcommands;
+mod tiling;
 mod util;
@@ -30,13 +30,20 @@ impl AggregatedCounts {
         })
     }
 
+    pub(super) fn empty() -> Self {
+        Self {
+            mod_code_counts: HashMap::new(),
+            total: 0,
+        }
+    }
+
     fn get_canonical_counts(&self) -> usize {
         // safe because we check at creation, could be more careful if there
         // was a chance that &mut self was available.
         self.total - self.mod_code_counts.values().sum::<usize>()
     }
 
-    fn combine(&self, other: &Self) -> Self {
+    pub(super) fn combine(&self, other: &Self) -> Self {
         let total = self.total + other.total;
         let mut counts = self.mod_code_counts.clone();
         other.mod_code_counts.iter().for_each(|(mod_code, count)| {
@@ -137,8 +144,17 @@ impl ModificationCounts {
         })
     }
 
-    pub(super) fn to_row(&self) -> anyhow::Result<String> {
+    pub(super) fn interval(&self) -> &DmrInterval {
+        &self.interval
+    }
+
+    pub(super) fn to_row(&self, with_strand: bool) -> anyhow::Result<String> {
         let sep = '\t';
+        let strand = if with_strand {
+            format!("{sep}{}", self.interval.strand_char())
+        } else {
+            String::new()
+        };
         let line = format!(
             "\
         {}{sep}\
@@ -151,7 +167,7 @@ impl ModificationCounts {
         {}{sep}\
         {}{sep}\
         {}{sep}\
-        {}\n\
+        {}{}\n\
         ",
             self.interval.chrom,
             self.start,
@@ -164,6 +180,98 @@ impl ModificationCounts {
             self.exp_counts.total,
             self.control_counts.string_percentages(),
             self.exp_counts.string_percentages(),
+            strand,
+        );
+        Ok(line)
+    }
+}
+
+/// Counts for two or more (possibly combined) samples over a region, used
+/// for the group-vs-group, one-vs-rest and omnibus comparisons.
+#[derive(Debug)]
+pub(super) struct MultiSampleCounts {
+    interval: DmrInterval,
+    labels: Vec<String>,
+    counts: Vec<AggregatedCounts>,
+    pub(crate) score: f64,
+}
+
+impl MultiSampleCounts {
+    pub(super) fn new(
+        interval: DmrInterval,
+        labels: Vec<String>,
+        counts: Vec<AggregatedCounts>,
+    ) -> anyhow::Result<Self> {
+        if labels.len() != counts.len() {
+            bail!(
+                "number of labels ({}) and counts ({}) must match",
+                labels.len(),
+                counts.len()
+            )
+        }
+        let score = llk_ratio_multi(&counts)?;
+        Ok(Self {
+            interval,
+            labels,
+            counts,
+            score,
+        })
+    }
+
+    /// Header line for the rows made with `to_row`.
+    pub(super) fn header(with_strand: bool) -> String {
+        let mut header = [
+            "#chrom",
+            "start",
+            "end",
+            "name",
+            "score",
+            "labels",
+            "counts",
+            "totals",
+            "fractions",
+        ]
+        .join("\t");
+        if with_strand {
+            header.push_str("\tstrand");
+        }
+        header.push('\n');
+        header
+    }
+
+    pub(super) fn to_row(&self, with_strand: bool) -> anyhow::Result<String> {
+        let sep = '\t';
+        let strand = if with_strand {
+            format!("{sep}{}", self.interval.strand_char())
+        } else {
+            String::new()
+        };
+        let counts = self.counts.iter().map(|c| c.string_counts()).join(";");
+        let totals = self.counts.iter().map(|c| c.total).join(",");
+        let percentages =
+            self.counts.iter().map(|c| c.string_percentages()).join(";");
+        let line = format!(
+            "\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{sep}\
+        {}{}\n\
+        ",
+            self.interval.chrom,
+            self.interval.start(),
+            self.interval.stop(),
+            self.interval.name,
+            self.score,
+            self.labels.join(","),
+            counts,
+            totals,
+            percentages,
+            strand,
         );
         Ok(line)
     }
@@ -275,9 +383,60 @@ pub(super) fn llk_ratio(
     }
 }
 
+/// Log-likelihood ratio for any number of samples, the likelihood of each
+/// sample modeled separately versus all samples modeled together. With two
+/// samples this is the same as `llk_ratio`.
+pub(super) fn llk_ratio_multi(
+    counts: &[AggregatedCounts],
+) -> anyhow::Result<f64> {
+    if counts.len() < 2 {
+        bail!("need at least 2 samples to compare, got {}", counts.len())
+    }
+    let all_mods = counts
+        .iter()
+        .flat_map(|c| c.mod_code_counts.keys().copied())
+        .collect::<HashSet<char>>();
+    let combined_counts = counts
+        .iter()
+        .fold(AggregatedCounts::empty(), |acc, c| acc.combine(c));
+    match all_mods.len() {
+        0 => Ok(0f64),
+        1 => {
+            let raw_mod_code =
+                all_mods.into_iter().take(1).collect::<Vec<char>>()[0];
+            let llk = |c: &AggregatedCounts| {
+                let methyls =
+                    *c.mod_code_counts.get(&raw_mod_code).unwrap_or(&0);
+                beta_llk(methyls, c.get_canonical_counts())
+            };
+            let llk_separate = counts.iter().map(|c| llk(c)).sum::<f64>();
+            Ok(llk_separate - llk(&combined_counts))
+        }
+        _ => {
+            let mods_to_index = all_mods
+                .into_iter()
+                .sorted()
+                .enumerate()
+                .map(|(i, c)| (c, i + 1))
+                .collect::<HashMap<char, usize>>();
+            let k = mods_to_index.len() + 1;
+            let prior = Dirichlet::jeffreys(k)?;
+            let llk_separate = counts
+                .iter()
+                .map(|c| dirichlet_llk(c, &prior, &mods_to_index))
+                .sum::<anyhow::Result<f64>>()?;
+            let llk_combined =
+                dirichlet_llk(&combined_counts, &prior, &mods_to_index)?;
+            Ok(llk_separate - llk_combined)
+        }
+    }
+}
+
 #[cfg(test)]
 mod dmr_model_tests {
-    use crate::dmr::model::{llk_beta, llk_dirichlet, AggregatedCounts};
+    use crate::dmr::model::{
+        llk_beta, llk_dirichlet, llk_ratio, llk_ratio_multi, AggregatedCounts,
+    };
     use itertools::Itertools;
     use rand::prelude::*;
     use rand::rngs::StdRng;
@@ -342,4 +501,32 @@ mod dmr_model_tests {
         let llk_b = llk_dirichlet(&control, &exp).unwrap();
         assert!(llk_a > llk_b);
     }
+
+    #[test]
+    fn test_llk_ratio_multi() {
+        let mut rng: StdRng = StdRng::seed_from_u64(42);
+        let a = methyl_sample(0.9, 1000, &mut rng);
+        let b = methyl_sample(0.1, 1000, &mut rng);
+        let pairwise = llk_ratio(&a, &b).unwrap();
+        let multi = llk_ratio_multi(&[a, b]).unwrap();
+        assert!((pairwise - multi).abs() < 1e-9);
+
+        let a = hydroxy_sample(&[0.1, 0.3, 0.6], 1000, &mut rng);
+        let b = hydroxy_sample(&[0.1, 0.6, 0.3], 1000, &mut rng);
+        let pairwise = llk_dirichlet(&a, &b).unwrap();
+        let multi = llk_ratio_multi(&[a, b]).unwrap();
+        assert!((pairwise - multi).abs() < 1e-9);
+
+        let same = (0..3)
+            .map(|_| methyl_sample(0.5, 1000, &mut rng))
+            .collect::<Vec<AggregatedCounts>>();
+        let mut different = (0..2)
+            .map(|_| methyl_sample(0.5, 1000, &mut rng))
+            .collect::<Vec<AggregatedCounts>>();
+        different.push(methyl_sample(0.9, 1000, &mut rng));
+        let llk_same = llk_ratio_multi(&same).unwrap();
+        let llk_different = llk_ratio_multi(&different).unwrap();
+        assert!(llk_different > llk_same);
+        assert!(llk_ratio_multi(&same[0..1]).is_err());
+    }
 }
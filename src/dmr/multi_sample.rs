@@ -1,15 +1,21 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 
 use crate::mod_base_code::DnaBase;
-use anyhow::bail;
+use anyhow::{anyhow, bail, Context};
 use bio::io::fasta::Reader as FastaReader;
 use derive_new::new;
+use indexmap::IndexMap;
 use indicatif::{MultiProgress, ProgressIterator};
+use itertools::Itertools;
 use log::debug;
 use rayon::prelude::*;
 use rustc_hash::FxHashSet;
 
+use crate::dmr::model::{AggregatedCounts, MultiSampleCounts};
+use crate::dmr::util::DmrInterval;
 use crate::util::{get_ticker, Strand};
 
 fn factorial(n: usize) -> anyhow::Result<usize> {
@@ -35,8 +41,166 @@ pub(super) fn n_choose_2(n: usize) -> anyhow::Result<usize> {
 #[derive(new, Debug)]
 pub(super) struct DmrSample {
     pub(super) bedmethyl_fp: PathBuf,
-    pub(super) index: PathBuf,
+    /// User-specified tabix index, when not given the index is expected next
+    /// to the bedMethyl or an indexed temporary copy of the bedMethyl is made.
+    pub(super) index: Option<PathBuf>,
     pub(super) name: String,
+    /// Group label, defaults to the sample name when samples are given with
+    /// --sample instead of a sample sheet.
+    pub(super) group: String,
+}
+
+/// A row in a sample sheet, tab-separated columns are the path to the bedMethyl,
+/// the sample name, the group label, and optionally the path to the tabix index.
+#[derive(new, Debug, Eq, PartialEq)]
+pub(super) struct SampleSheetRow {
+    pub(super) bedmethyl_fp: PathBuf,
+    pub(super) name: String,
+    pub(super) group: String,
+    pub(super) index: Option<PathBuf>,
+}
+
+impl SampleSheetRow {
+    fn parse_line(line: &str) -> anyhow::Result<Self> {
+        let parts = line.trim_end().split('\t').collect::<Vec<&str>>();
+        match parts.as_slice() {
+            [path, name, group] => Ok(Self::new(
+                Path::new(path).to_path_buf(),
+                name.to_string(),
+                group.to_string(),
+                None,
+            )),
+            [path, name, group, index] => Ok(Self::new(
+                Path::new(path).to_path_buf(),
+                name.to_string(),
+                group.to_string(),
+                Some(Path::new(index).to_path_buf()),
+            )),
+            _ => bail!(
+                "illegal sample sheet line {line}, should be tab-separated \
+                <path> <name> <group> and optionally <index>"
+            ),
+        }
+    }
+}
+
+pub(super) fn parse_sample_sheet<P: AsRef<Path>>(
+    fp: P,
+) -> anyhow::Result<Vec<SampleSheetRow>> {
+    let rows = BufReader::new(File::open(fp)?)
+        .lines()
+        .enumerate()
+        .filter_map(|(i, r)| match r {
+            Ok(l) if l.trim().is_empty() || l.starts_with('#') => None,
+            Ok(l) => Some(
+                SampleSheetRow::parse_line(&l)
+                    .with_context(|| format!("sample sheet line {}", i + 1)),
+            ),
+            Err(e) => Some(Err(anyhow!(
+                "error fetching line from sample sheet, {}",
+                e.to_string()
+            ))),
+        })
+        .collect::<anyhow::Result<Vec<SampleSheetRow>>>()?;
+    if rows.is_empty() {
+        bail!("didn't parse any samples from sample sheet")
+    } else {
+        Ok(rows)
+    }
+}
+
+/// One comparison between two or more "units", each unit is one or more
+/// samples (indices into the sample list) whose counts are combined.
+#[derive(Debug, Eq, PartialEq)]
+pub(super) struct Comparison {
+    labels: Vec<String>,
+    members: Vec<Vec<usize>>,
+}
+
+impl Comparison {
+    fn groups(samples: &[DmrSample]) -> IndexMap<&str, Vec<usize>> {
+        samples.iter().enumerate().fold(
+            IndexMap::new(),
+            |mut acc, (idx, sample)| {
+                acc.entry(sample.group.as_str())
+                    .or_insert(Vec::new())
+                    .push(idx);
+                acc
+            },
+        )
+    }
+
+    /// Each pair of groups, the samples in each group are combined.
+    pub(super) fn group_vs_group(
+        samples: &[DmrSample],
+    ) -> anyhow::Result<Vec<Self>> {
+        let groups = Self::groups(samples);
+        if groups.len() < 2 {
+            bail!(
+                "need at least 2 groups to compare, got {}, groups are set \
+                with the --sample-sheet",
+                groups.len()
+            )
+        }
+        Ok(groups
+            .iter()
+            .combinations(2)
+            .map(|pair| {
+                let (a_label, a_members) = pair[0];
+                let (b_label, b_members) = pair[1];
+                Self {
+                    labels: vec![a_label.to_string(), b_label.to_string()],
+                    members: vec![a_members.clone(), b_members.clone()],
+                }
+            })
+            .collect())
+    }
+
+    /// Each sample compared to all of the other samples combined.
+    pub(super) fn one_vs_rest(samples: &[DmrSample]) -> Vec<Self> {
+        (0..samples.len())
+            .map(|idx| {
+                let rest = (0..samples.len())
+                    .filter(|&other| other != idx)
+                    .collect::<Vec<usize>>();
+                Self {
+                    labels: vec![samples[idx].name.clone(), "rest".to_string()],
+                    members: vec![vec![idx], rest],
+                }
+            })
+            .collect()
+    }
+
+    /// All groups in a single test, when every sample is its own group this is a
+    /// test across all samples.
+    pub(super) fn omnibus(samples: &[DmrSample]) -> Self {
+        let (labels, members) = Self::groups(samples)
+            .into_iter()
+            .map(|(label, members)| (label.to_string(), members))
+            .unzip();
+        Self { labels, members }
+    }
+
+    pub(super) fn score(
+        &self,
+        dmr_interval: &DmrInterval,
+        sample_counts: &[AggregatedCounts],
+    ) -> anyhow::Result<MultiSampleCounts> {
+        let counts = self
+            .members
+            .iter()
+            .map(|idxs| {
+                idxs.iter()
+                    .filter_map(|&i| sample_counts.get(i))
+                    .fold(AggregatedCounts::empty(), |acc, c| acc.combine(c))
+            })
+            .collect::<Vec<AggregatedCounts>>();
+        MultiSampleCounts::new(
+            dmr_interval.clone(),
+            self.labels.clone(),
+            counts,
+        )
+    }
 }
 
 pub(super) fn get_reference_modified_base_positions(
@@ -132,3 +296,73 @@ pub(super) fn get_reference_modified_base_positions(
 
     Ok((positive_hits, negative_hits))
 }
+
+#[cfg(test)]
+mod dmr_multi_sample_tests {
+    use std::path::Path;
+
+    use crate::dmr::multi_sample::{Comparison, DmrSample, SampleSheetRow};
+
+    fn sample(name: &str, group: &str) -> DmrSample {
+        DmrSample::new(
+            Path::new(&format!("{name}.bed.gz")).to_path_buf(),
+            None,
+            name.to_string(),
+            group.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_parse_sample_sheet_line() {
+        let row = SampleSheetRow::parse_line("a.bed.gz\ta\tcase").unwrap();
+        let expected = SampleSheetRow::new(
+            Path::new("a.bed.gz").to_path_buf(),
+            "a".to_string(),
+            "case".to_string(),
+            None,
+        );
+        assert_eq!(row, expected);
+        let row =
+            SampleSheetRow::parse_line("a.bed.gz\ta\tcase\ta.tbi\n").unwrap();
+        assert_eq!(row.index, Some(Path::new("a.tbi").to_path_buf()));
+        assert!(SampleSheetRow::parse_line("a.bed.gz\ta").is_err());
+        assert!(SampleSheetRow::parse_line("a.bed.gz a case").is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let samples = vec![
+            sample("a", "case"),
+            sample("b", "control"),
+            sample("c", "case"),
+            sample("d", "control"),
+        ];
+        let comparisons = Comparison::group_vs_group(&samples).unwrap();
+        assert_eq!(
+            comparisons,
+            vec![Comparison {
+                labels: vec!["case".to_string(), "control".to_string()],
+                members: vec![vec![0, 2], vec![1, 3]],
+            }]
+        );
+
+        let comparisons = Comparison::one_vs_rest(&samples);
+        assert_eq!(comparisons.len(), 4);
+        assert_eq!(
+            comparisons[1],
+            Comparison {
+                labels: vec!["b".to_string(), "rest".to_string()],
+                members: vec![vec![1], vec![0, 2, 3]],
+            }
+        );
+
+        let omnibus = Comparison::omnibus(&samples);
+        assert_eq!(omnibus.members, vec![vec![0, 2], vec![1, 3]]);
+
+        let ungrouped = vec![sample("a", "a"), sample("b", "b")];
+        assert_eq!(Comparison::group_vs_group(&ungrouped).unwrap().len(), 1);
+        assert_eq!(Comparison::omnibus(&ungrouped).labels.len(), 2);
+        let one_group = vec![sample("a", "x"), sample("b", "x")];
+        assert!(Comparison::group_vs_group(&one_group).is_err());
+    }
+}
@@ -2,8 +2,10 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::bail;
+use crossbeam_channel::Receiver;
 use indicatif::ProgressBar;
 use log::{debug, error};
 use noodles::bgzf;
@@ -12,17 +14,27 @@ use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::dmr::model::{AggregatedCounts, ModificationCounts};
-use crate::dmr::util::{BedMethylLine, DmrInterval, DmrIntervalIter};
-use crate::position_filter::{Iv, StrandedPositionFilter};
-use crate::util::Strand;
+use crate::dmr::util::{
+    BedMethylLine, BedMethylSource, DmrInterval, DmrIntervalIter,
+};
+use crate::position_filter::StrandedPositionFilter;
+use crate::util::{Strand, StrandRule};
 
 fn aggregate_counts(
     bm_lines: &[BedMethylLine],
     chrom_id: u32,
+    strand: StrandRule,
     position_filter: &StrandedPositionFilter,
 ) -> anyhow::Result<AggregatedCounts> {
     let grouped_by_position: FxHashMap<u64, Vec<&BedMethylLine>> = bm_lines
         .iter()
+        // stranded regions only use bedMethyl records on the same strand,
+        // strand-combined records (strand '.') are skipped
+        .filter(|bm_line| match (strand, bm_line.strand) {
+            (StrandRule::Both, _) => true,
+            (StrandRule::Positive, '+') | (StrandRule::Negative, '-') => true,
+            _ => false,
+        })
         .filter(|bm_line| match bm_line.strand {
             '+' => position_filter.contains(
                 chrom_id as i32,
@@ -85,10 +97,10 @@ fn aggregate_counts(
     AggregatedCounts::try_new(counts_per_code, total)
 }
 
-fn get_mod_counts_for_condition(
+pub(super) fn get_mod_counts_for_condition(
     reader: &mut bgzf::Reader<File>,
     chunks: &[IndexChunk],
-    interval: &Iv,
+    dmr_interval: &DmrInterval,
     chrom_id: u32,
     position_filter: &StrandedPositionFilter,
     filename: &PathBuf,
@@ -123,11 +135,9 @@ fn get_mod_counts_for_condition(
         failed_to_parse += n_fail;
         successfully_parsed += lines.len();
 
-        bedmethyl_lines.extend(
-            lines
-                .into_iter()
-                .filter(|bml| interval.overlap(bml.start(), bml.stop())),
-        );
+        bedmethyl_lines.extend(lines.into_iter().filter(|bml| {
+            dmr_interval.interval.overlap(bml.start(), bml.stop())
+        }));
     }
 
     if successfully_parsed == 0 {
@@ -141,44 +151,25 @@ fn get_mod_counts_for_condition(
         );
     }
 
-    aggregate_counts(&bedmethyl_lines, chrom_id, position_filter)
+    aggregate_counts(
+        &bedmethyl_lines,
+        chrom_id,
+        dmr_interval.strand,
+        position_filter,
+    )
 }
 
 pub(super) fn get_modification_counts(
-    control_bedmethyl: &PathBuf,
-    exp_bedmethyl: &PathBuf,
-    control_chunks: &[IndexChunk],
-    exp_chunks: &[IndexChunk],
+    control: &BedMethylSource,
+    exp: &BedMethylSource,
     dmr_interval: DmrInterval,
     position_filter: &StrandedPositionFilter,
     chrom_id: u32,
 ) -> anyhow::Result<ModificationCounts> {
-    let mut control_reader =
-        File::open(control_bedmethyl).map(bgzf::Reader::new)?;
-    let mut exp_bed_reader =
-        File::open(exp_bedmethyl).map(bgzf::Reader::new)?;
-    if control_chunks.len() != 1 {
-        debug!("more than 1 control chunk?, got {}", control_chunks.len());
-    }
-    if exp_chunks.len() != 1 {
-        debug!("more than 1 exp chunk?, got {}", exp_chunks.len());
-    }
-    let control_counts = get_mod_counts_for_condition(
-        &mut control_reader,
-        &control_chunks,
-        &dmr_interval.interval,
-        chrom_id,
-        &position_filter,
-        &control_bedmethyl,
-    )?;
-    let experimental_counts = get_mod_counts_for_condition(
-        &mut exp_bed_reader,
-        &exp_chunks,
-        &dmr_interval.interval,
-        chrom_id,
-        &position_filter,
-        &exp_bedmethyl,
-    )?;
+    let control_counts =
+        control.get_counts(&dmr_interval, chrom_id, position_filter)?;
+    let experimental_counts =
+        exp.get_counts(&dmr_interval, chrom_id, position_filter)?;
 
     ModificationCounts::new(
         dmr_interval.start(),
@@ -189,17 +180,14 @@ pub(super) fn get_modification_counts(
     )
 }
 
-pub(super) fn run_pairwise_dmr(
-    control_bed_fp: &PathBuf,
-    exp_bed_fp: &PathBuf,
+fn spawn_pairwise_dmr(
+    control: Arc<BedMethylSource>,
+    exp: Arc<BedMethylSource>,
     dmr_interval_iter: DmrIntervalIter,
-    position_filter: StrandedPositionFilter,
-    mut writer: Box<dyn std::io::Write>,
+    position_filter: Arc<StrandedPositionFilter>,
     pb: ProgressBar,
-) -> anyhow::Result<usize> {
+) -> Receiver<anyhow::Result<ModificationCounts>> {
     let (snd, rcv) = crossbeam_channel::bounded(1000);
-    let control_bedmethyl_fp = control_bed_fp.clone();
-    let exp_bedmethyl_fp = exp_bed_fp.clone();
 
     std::thread::spawn(move || {
         for chunks in dmr_interval_iter {
@@ -210,10 +198,8 @@ pub(super) fn run_pairwise_dmr(
                         .into_par_iter()
                         .map(|dmr_chunk| {
                             get_modification_counts(
-                                &control_bedmethyl_fp,
-                                &exp_bedmethyl_fp,
-                                &dmr_chunk.control_chunks,
-                                &dmr_chunk.exp_chunks,
+                                &control,
+                                &exp,
                                 dmr_chunk.dmr_interval,
                                 &position_filter,
                                 dmr_chunk.chrom_id,
@@ -248,11 +234,31 @@ pub(super) fn run_pairwise_dmr(
         pb.finish_and_clear();
     });
 
+    rcv
+}
+
+pub(super) fn run_pairwise_dmr(
+    control: Arc<BedMethylSource>,
+    exp: Arc<BedMethylSource>,
+    dmr_interval_iter: DmrIntervalIter,
+    position_filter: Arc<StrandedPositionFilter>,
+    mut writer: Box<dyn std::io::Write>,
+    pb: ProgressBar,
+    with_strand: bool,
+) -> anyhow::Result<usize> {
+    let rcv = spawn_pairwise_dmr(
+        control,
+        exp,
+        dmr_interval_iter,
+        position_filter,
+        pb,
+    );
+
     let mut success_count = 0;
     for result in rcv {
         match result {
             Ok(counts) => {
-                writer.write(counts.to_row()?.as_bytes())?;
+                writer.write(counts.to_row(with_strand)?.as_bytes())?;
                 success_count += 1;
             }
             Err(e) => {
@@ -263,3 +269,24 @@ pub(super) fn run_pairwise_dmr(
 
     Ok(success_count)
 }
+
+/// Score all of the regions and collect the results (in the same order as
+/// the regions) instead of writing them.
+pub(super) fn collect_pairwise_dmr(
+    control: Arc<BedMethylSource>,
+    exp: Arc<BedMethylSource>,
+    dmr_interval_iter: DmrIntervalIter,
+    position_filter: Arc<StrandedPositionFilter>,
+    pb: ProgressBar,
+) -> Vec<ModificationCounts> {
+    spawn_pairwise_dmr(control, exp, dmr_interval_iter, position_filter, pb)
+        .into_iter()
+        .filter_map(|result| match result {
+            Ok(counts) => Some(counts),
+            Err(e) => {
+                debug!("unexpected error, {}", e.to_string());
+                None
+            }
+        })
+        .collect()
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
@@ -6,43 +6,98 @@ use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context};
 use bio::io::fasta::Reader as FastaReader;
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
 use indicatif::{MultiProgress, ProgressIterator};
 use itertools::Itertools;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use noodles::csi::Index as CsiIndex;
 use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
+use crate::dmr::annotation::{parse_annotation_regions, AnnotationFeature};
+use crate::dmr::hemi::{get_all_sites, HemiBedMethyl, HemiModificationCounts};
+use crate::dmr::model::{AggregatedCounts, MultiSampleCounts};
 use crate::dmr::multi_sample::{
-    get_reference_modified_base_positions, n_choose_2, DmrSample,
+    get_reference_modified_base_positions, n_choose_2, parse_sample_sheet,
+    Comparison, DmrSample,
+};
+use crate::dmr::pairwise::{collect_pairwise_dmr, run_pairwise_dmr};
+use crate::dmr::tiling::{merge_windows, tile_windows, WindowSize};
+use crate::dmr::util::{
+    parse_roi_bed, BedMethylSource, DmrInterval, DmrIntervalIter,
 };
-use crate::dmr::pairwise::run_pairwise_dmr;
-use crate::dmr::util::{parse_roi_bed, DmrIntervalIter};
 use crate::logging::init_logging;
 use crate::mod_base_code::DnaBase;
+use crate::motif_bed::{MotifLocations, RegexMotif};
 use crate::position_filter::{GenomeLapper, Iv, StrandedPositionFilter};
 use crate::util::{
     get_master_progress_bar, get_subroutine_progress_bar, get_ticker,
+    StrandRule,
 };
 
+/// Parse the regions BED or make the regions from an annotation, regions
+/// without a strand are split into one region per strand when
+/// `split_strands` is set. Also returns whether the output should have a
+/// strand column.
+fn load_regions(
+    regions_bed: Option<&PathBuf>,
+    regions_annotation: Option<&PathBuf>,
+    annotation_feature: AnnotationFeature,
+    flank: Option<u64>,
+    ignore_annotation_strand: bool,
+    split_strands: bool,
+) -> anyhow::Result<(Vec<DmrInterval>, bool)> {
+    let regions = match (regions_bed, regions_annotation) {
+        (Some(fp), None) => parse_roi_bed(fp)?,
+        (None, Some(fp)) => parse_annotation_regions(
+            fp,
+            annotation_feature,
+            flank,
+            !ignore_annotation_strand,
+        )?,
+        _ => bail!("need either a regions BED or an annotation"),
+    };
+    info!("loaded {} regions", regions.len());
+    let with_strand = split_strands
+        || regions.iter().any(|roi| roi.strand != StrandRule::Both);
+    if split_strands {
+        let regions = regions
+            .into_iter()
+            .flat_map(|roi| roi.split_strands())
+            .collect::<Vec<DmrInterval>>();
+        info!("testing {} stranded regions", regions.len());
+        Ok((regions, with_strand))
+    } else {
+        Ok((regions, with_strand))
+    }
+}
+
 #[derive(Subcommand)]
 pub enum BedMethylDmr {
     /// Compare regions in a pair of samples (for example, tumor and normal or
-    /// control and experiment). A sample is input as a bgzip pileup bedMethyl
-    /// (produced by pileup, for example) that has an associated tabix index.
-    /// Output is a BED file with the score column indicating the magnitude of
+    /// control and experiment). A sample is input as a pileup bedMethyl
+    /// (produced by pileup, for example), preferably bgzip compressed with an
+    /// associated tabix index. Sorted plain text or gzip compressed bedMethyl
+    /// files without an index are copied to an indexed temporary file. Output is a BED file with the score column indicating the magnitude of
     /// the difference in methylation between the two samples. See the online
     /// documentation for additional details.
     Pair(PairwiseDmr),
     /// Compare regions between all pairs of samples (for example a trio sample
-    /// set or haplotyped trio sample set). As with `pair` all inputs must be bgzip
-    /// compressed bedMethyl files with associated tabix indices. Each sample
+    /// set or haplotyped trio sample set). As with `pair` inputs are bedMethyl files,
+    /// bgzip compressed with tabix indices or otherwise copied to indexed temporary files. Each sample
     /// must be assigned a name. Output is a directory of BED files with the score column
     /// indicating the magnitude of the difference in methylation between the
-    /// two samples indicated in the file name. See the online documentation for
-    /// additional details.
+    /// two samples indicated in the file name. Samples can also be assigned to groups
+    /// with a sample sheet and compared group-vs-group, one-vs-rest, or with a single
+    /// omnibus test, see --mode. See the online documentation for additional details.
     Multi(MultiSampleDmr),
+    /// Compare the patterns of duplex modification calls (for example
+    /// hemi-methylation) between a pair of samples. Inputs are the outputs of
+    /// pileup-hemi, plain text or gzip compressed, and are read into memory.
+    /// Each site, or each region when a regions BED is given, is scored by
+    /// the difference in the distribution of duplex patterns between the two
+    /// samples. See the online documentation for additional details.
+    PairHemi(HemiDmr),
 }
 
 impl BedMethylDmr {
@@ -50,20 +105,23 @@ impl BedMethylDmr {
         match self {
             Self::Pair(x) => x.run(),
             Self::Multi(x) => x.run(),
+            Self::PairHemi(x) => x.run(),
         }
     }
 }
 
 #[derive(Args)]
 pub struct PairwiseDmr {
-    /// Bgzipped bedMethyl file for the first (usually control) sample. There should be
-    /// a tabix index with the same name and .tbi next to this file or the --index-a option
-    /// must be provided.
+    /// BedMethyl file for the first (usually control) sample. When the file is bgzip
+    /// compressed with a tabix index (with the same name and .tbi next to this file or
+    /// given with the --index-a option) the index will be used. Otherwise the file, plain
+    /// text or gzip compressed and sorted, is copied to an indexed temporary file.
     #[arg(short = 'a')]
     control_bed_methyl: PathBuf,
-    /// Bgzipped bedMethyl file for the second (usually experimental) sample. There should be
-    /// a tabix index with the same name and .tbi next to this file or the --index-b option
-    /// must be provided.
+    /// BedMethyl file for the second (usually experimental) sample. When the file is bgzip
+    /// compressed with a tabix index (with the same name and .tbi next to this file or
+    /// given with the --index-b option) the index will be used. Otherwise the file, plain
+    /// text or gzip compressed and sorted, is copied to an indexed temporary file.
     #[arg(short = 'b')]
     exp_bed_methyl: PathBuf,
     /// Path to file to direct output, optional, no argument will direct output to stdout.
@@ -71,9 +129,72 @@ pub struct PairwiseDmr {
     out_path: Option<String>,
     /// Regions BED file over which to compare methylation levels. Should be tab-separated (spaces
     /// allowed in the "name" column). Requires chrom, chromStart and chromEnd. The Name column is
-    /// optional. Strand is currently ignored.
-    #[arg(long, short = 'r')]
-    regions_bed: PathBuf,
+    /// optional. When the strand column (6th) is '+' or '-' only bedMethyl records on the same
+    /// strand are used for the region.
+    #[arg(
+        long,
+        short = 'r',
+        required_unless_present_any = ["regions_annotation", "window_size", "window_positions"]
+    )]
+    regions_bed: Option<PathBuf>,
+    /// GTF or GFF3 annotation to derive the regions from, instead of a regions BED. Regions
+    /// are named with the gene name (or ID) and have the strand of the feature.
+    #[arg(long, alias = "gtf", alias = "gff", conflicts_with = "regions_bed")]
+    regions_annotation: Option<PathBuf>,
+    /// Tile the genome into windows of this many bases instead of using a regions BED
+    /// or annotation. Windows without any modified base positions are skipped.
+    #[arg(
+        long,
+        conflicts_with_all = ["regions_bed", "regions_annotation", "window_positions"]
+    )]
+    window_size: Option<u64>,
+    /// Tile the genome into windows each containing this many motif sites (see
+    /// --window-motif) instead of using a regions BED or annotation.
+    #[arg(
+        long,
+        conflicts_with_all = ["regions_bed", "regions_annotation"],
+        requires = "window_motif"
+    )]
+    window_positions: Option<usize>,
+    /// Motif and offset of the sites to count with --window-positions, for example
+    /// --window-motif CG 0 for CpGs. Palindromic motifs (such as CG) count each site once,
+    /// not once per strand. With --window-size, windows without any motif sites are skipped.
+    #[arg(long, num_args = 2, value_names = ["MOTIF", "OFFSET"])]
+    window_motif: Option<Vec<String>>,
+    /// Step between the starts of consecutive windows, in bases with --window-size or in
+    /// positions with --window-positions. Default is the window size, so windows don't
+    /// overlap.
+    #[arg(long)]
+    window_step: Option<u64>,
+    /// Only tile these contigs, default is to tile all contigs in the bedMethyl.
+    #[arg(long, num_args = 1..)]
+    window_contigs: Vec<String>,
+    /// Merge overlapping or adjacent windows with a score of at least this value into
+    /// regions, the merged regions are scored again and output instead of the windows.
+    #[arg(long)]
+    merge_min_score: Option<f64>,
+    /// Feature of the --regions-annotation to use as regions.
+    #[arg(
+        long,
+        value_enum,
+        requires = "regions_annotation",
+        default_value_t = AnnotationFeature::promoter
+    )]
+    annotation_feature: AnnotationFeature,
+    /// Number of bases to extend the annotation regions by on both sides, for promoters
+    /// the region is the TSS plus and minus this many bases. Default is 1000 for promoters
+    /// and 0 for other features.
+    #[arg(long, requires = "regions_annotation")]
+    flank: Option<u64>,
+    /// Don't use the strand of the annotation features, use bedMethyl records from both
+    /// strands (for example for CpG methylation).
+    #[arg(long, requires = "regions_annotation", default_value_t = false)]
+    ignore_annotation_strand: bool,
+    /// Test the positive and negative strands separately for regions without a strand,
+    /// the default is to combine the strands. When regions are stranded or this option is
+    /// used, a strand column is added to the output.
+    #[arg(long, default_value_t = false)]
+    split_strands: bool,
     /// Path to reference fasta for the pileup.
     #[arg(long = "ref")]
     reference_fasta: PathBuf,
@@ -236,6 +357,84 @@ impl PairwiseDmr {
                 .map(|idx| (idx, index_path))
         }
     }
+    /// Load a bedMethyl, using the tabix index if one is specified or found
+    /// next to the bedMethyl, otherwise making an indexed temporary copy.
+    fn load_bedmethyl(
+        bedmethyl_path: &PathBuf,
+        specified_index: Option<&PathBuf>,
+    ) -> anyhow::Result<BedMethylSource> {
+        let default_index = bedmethyl_path
+            .to_str()
+            .map(|fp| Path::new(&format!("{}.tbi", fp)).to_path_buf());
+        let has_index = specified_index.is_some()
+            || default_index.map(|fp| fp.exists()).unwrap_or(false);
+        if has_index {
+            let (index, _) = Self::load_index(bedmethyl_path, specified_index)?;
+            BedMethylSource::indexed(bedmethyl_path, index)
+        } else {
+            info!(
+                "did not find tabix index for {}, making an indexed copy",
+                bedmethyl_path.to_string_lossy()
+            );
+            BedMethylSource::from_unindexed(
+                bedmethyl_path,
+                &std::env::temp_dir(),
+            )
+        }
+    }
+
+    fn get_window_size(&self) -> anyhow::Result<Option<WindowSize>> {
+        let window_size = match (self.window_size, self.window_positions) {
+            (Some(bases), None) => Some(WindowSize::Bases(bases)),
+            (None, Some(positions)) => Some(WindowSize::Positions(positions)),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                bail!("cannot use both --window-size and --window-positions")
+            }
+        };
+        let tiling_options_used = self.window_step.is_some()
+            || !self.window_contigs.is_empty()
+            || self.merge_min_score.is_some()
+            || self.window_motif.is_some();
+        if window_size.is_none() && tiling_options_used {
+            bail!(
+                "--window-step, --window-contigs, --window-motif, and \
+                --merge-min-score require --window-size or --window-positions"
+            )
+        }
+        Ok(window_size)
+    }
+
+    /// Find the --window-motif sites at the modified base positions.
+    fn get_window_motif_locations(
+        &self,
+        contig_lookup: &HashMap<String, usize>,
+        position_filter: &StrandedPositionFilter,
+        multi_pb: &MultiProgress,
+    ) -> anyhow::Result<Option<MotifLocations>> {
+        let raw_motif = match self.window_motif.as_ref() {
+            Some(raw_motif) => raw_motif,
+            None => return Ok(None),
+        };
+        let offset = raw_motif[1].parse::<usize>().with_context(|| {
+            format!("failed to parse motif offset {}", &raw_motif[1])
+        })?;
+        let regex_motif = RegexMotif::parse_string(&raw_motif[0], offset)?;
+        let name_to_tid = contig_lookup
+            .iter()
+            .map(|(name, id)| (name.as_str(), *id as u32))
+            .collect::<HashMap<&str, u32>>();
+        MotifLocations::from_fasta(
+            &self.reference_fasta,
+            regex_motif,
+            &name_to_tid,
+            self.mask,
+            Some(position_filter),
+            multi_pb,
+        )
+        .map(Some)
+    }
+
     fn check_modified_bases(&self) -> anyhow::Result<()> {
         Self::validate_modified_bases(&self.modified_bases)
     }
@@ -277,10 +476,14 @@ impl PairwiseDmr {
         }
 
         // initial checks
-        let (control_index, _) =
-            Self::load_index(&self.control_bed_methyl, self.index_a.as_ref())?;
-        let (exp_index, _) =
-            Self::load_index(&self.exp_bed_methyl, self.index_b.as_ref())?;
+        let control = Self::load_bedmethyl(
+            &self.control_bed_methyl,
+            self.index_a.as_ref(),
+        )
+        .map(Arc::new)?;
+        let exp =
+            Self::load_bedmethyl(&self.exp_bed_methyl, self.index_b.as_ref())
+                .map(Arc::new)?;
 
         let writer: Box<dyn Write> = {
             match self.out_path.as_ref() {
@@ -306,27 +509,7 @@ impl PairwiseDmr {
             }
         };
 
-        let regions_of_interest = parse_roi_bed(&self.regions_bed)?;
-        info!("loaded {} regions", regions_of_interest.len());
-
-        let control_contig_lookup = control_index
-            .header()
-            .ok_or_else(|| anyhow!("failed to get control tabix header"))?
-            .reference_sequence_names()
-            .iter()
-            .enumerate()
-            .map(|(idx, r)| (r.to_owned(), idx))
-            .collect::<HashMap<String, usize>>();
-        let control_contig_lookup = Arc::new(control_contig_lookup);
-
-        let exp_contig_lookup = exp_index
-            .header()
-            .ok_or_else(|| anyhow!("failed to get exp tabix header"))?
-            .reference_sequence_names()
-            .iter()
-            .enumerate()
-            .map(|(idx, r)| (r.to_owned(), idx))
-            .collect::<HashMap<String, usize>>();
+        let control_contig_lookup = Arc::new(control.contig_lookup().clone());
 
         let motifs = self
             .modified_bases
@@ -334,39 +517,102 @@ impl PairwiseDmr {
             .map(|c| DnaBase::parse(*c))
             .collect::<anyhow::Result<Vec<DnaBase>>>()?;
 
-        let position_filter = self.get_stranded_position_filter(
-            control_contig_lookup.clone(),
-            &mpb,
-            &motifs,
-        )?;
+        let position_filter = self
+            .get_stranded_position_filter(
+                control_contig_lookup.clone(),
+                &mpb,
+                &motifs,
+            )
+            .map(Arc::new)?;
+
+        let (regions_of_interest, with_strand) =
+            if let Some(window_size) = self.get_window_size()? {
+                let motif_locations = self.get_window_motif_locations(
+                    &control_contig_lookup,
+                    &position_filter,
+                    &mpb,
+                )?;
+                let windows = tile_windows(
+                    &position_filter,
+                    motif_locations.as_ref(),
+                    &control_contig_lookup,
+                    &self.window_contigs,
+                    window_size,
+                    self.window_step,
+                )?;
+                (windows, false)
+            } else {
+                load_regions(
+                    self.regions_bed.as_ref(),
+                    self.regions_annotation.as_ref(),
+                    self.annotation_feature,
+                    self.flank,
+                    self.ignore_annotation_strand,
+                    self.split_strands,
+                )?
+            };
 
         let chunk_size = (self.threads as f32 * 1.5f32).floor() as usize;
         info!("processing {chunk_size} regions concurrently");
 
+        let regions_of_interest = if let Some(min_score) = self.merge_min_score
+        {
+            let pb =
+                mpb.add(get_master_progress_bar(regions_of_interest.len()));
+            pb.set_message("windows processed");
+            let failures = mpb.add(get_ticker());
+            failures.set_message("windows failed to process");
+            let dmr_interval_iter = DmrIntervalIter::new(
+                control.clone(),
+                exp.clone(),
+                regions_of_interest.into_iter().collect(),
+                chunk_size,
+                failures.clone(),
+            );
+            let window_counts = collect_pairwise_dmr(
+                control.clone(),
+                exp.clone(),
+                dmr_interval_iter,
+                position_filter.clone(),
+                pb,
+            );
+            let merged = merge_windows(
+                window_counts
+                    .iter()
+                    .map(|counts| (counts.interval(), counts.score)),
+                min_score,
+            );
+            info!(
+                "merged {} windows into {} regions with score >= {min_score}",
+                window_counts.len(),
+                merged.len()
+            );
+            merged
+        } else {
+            regions_of_interest
+        };
+
         let pb = mpb.add(get_master_progress_bar(regions_of_interest.len()));
         pb.set_message("regions processed");
         let failures = mpb.add(get_ticker());
         failures.set_message("regions failed to process");
 
         let dmr_interval_iter = DmrIntervalIter::new(
-            &self.control_bed_methyl,
-            &self.exp_bed_methyl,
-            control_contig_lookup.clone(),
-            exp_contig_lookup,
-            control_index,
-            exp_index,
+            control.clone(),
+            exp.clone(),
             regions_of_interest.into_iter().collect(),
             chunk_size,
             failures.clone(),
         );
 
         let success_count = run_pairwise_dmr(
-            &self.control_bed_methyl,
-            &self.exp_bed_methyl,
+            control,
+            exp,
             dmr_interval_iter,
             position_filter,
             writer,
             pb,
+            with_strand,
         )?;
 
         info!(
@@ -379,6 +625,26 @@ impl PairwiseDmr {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+enum MultiSampleMode {
+    pairwise,
+    groups,
+    one_vs_rest,
+    omnibus,
+}
+
+impl MultiSampleMode {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::pairwise => "pairwise",
+            Self::groups => "groups",
+            Self::one_vs_rest => "one_vs_rest",
+            Self::omnibus => "omnibus",
+        }
+    }
+}
+
 #[derive(Args)]
 pub struct MultiSampleDmr {
     /// Two or more named samples to compare. Two arguments are required <path> <name>.
@@ -389,11 +655,52 @@ pub struct MultiSampleDmr {
     /// given to the -s/--sample argument.
     #[arg(short = 'i', long = "index", num_args = 2)]
     indices: Vec<String>,
+    /// Tab-separated sample sheet to use instead of -s/--sample. Each line should
+    /// have the path to the bedMethyl, the sample name, and the group label (e.g.
+    /// "case" or "control"). A fourth column with the path to the tabix index is
+    /// optional. Lines starting with '#' are ignored.
+    #[arg(long, conflicts_with_all = ["samples", "indices"])]
+    sample_sheet: Option<PathBuf>,
+    /// Comparisons to perform. "pairwise" compares every pair of samples and
+    /// writes one file per pair. "groups" compares every pair of groups (from the
+    /// --sample-sheet) combining the counts of the samples within each group.
+    /// "one-vs-rest" compares each sample to all other samples combined.
+    /// "omnibus" performs a single test across all groups (or all samples when no
+    /// groups are given). All modes other than "pairwise" write a single table.
+    #[arg(long, value_enum, default_value_t = MultiSampleMode::pairwise)]
+    mode: MultiSampleMode,
     /// Regions BED file over which to compare methylation levels. Should be tab-separated (spaces
     /// allowed in the "name" column). Requires chrom, chromStart and chromEnd. The Name column is
-    /// optional. Strand is currently ignored.
-    #[arg(long, short = 'r')]
-    regions_bed: PathBuf,
+    /// optional. When the strand column (6th) is '+' or '-' only bedMethyl records on the same
+    /// strand are used for the region.
+    #[arg(long, short = 'r', required_unless_present = "regions_annotation")]
+    regions_bed: Option<PathBuf>,
+    /// GTF or GFF3 annotation to derive the regions from, instead of a regions BED. Regions
+    /// are named with the gene name (or ID) and have the strand of the feature.
+    #[arg(long, alias = "gtf", alias = "gff", conflicts_with = "regions_bed")]
+    regions_annotation: Option<PathBuf>,
+    /// Feature of the --regions-annotation to use as regions.
+    #[arg(
+        long,
+        value_enum,
+        requires = "regions_annotation",
+        default_value_t = AnnotationFeature::promoter
+    )]
+    annotation_feature: AnnotationFeature,
+    /// Number of bases to extend the annotation regions by on both sides, for promoters
+    /// the region is the TSS plus and minus this many bases. Default is 1000 for promoters
+    /// and 0 for other features.
+    #[arg(long, requires = "regions_annotation")]
+    flank: Option<u64>,
+    /// Don't use the strand of the annotation features, use bedMethyl records from both
+    /// strands (for example for CpG methylation).
+    #[arg(long, requires = "regions_annotation", default_value_t = false)]
+    ignore_annotation_strand: bool,
+    /// Test the positive and negative strands separately for regions without a strand,
+    /// the default is to combine the strands. When regions are stranded or this option is
+    /// used, a strand column is added to the output.
+    #[arg(long, default_value_t = false)]
+    split_strands: bool,
     /// Directory to place output DMR results in BED format.
     #[arg(short = 'o', long)]
     out_dir: PathBuf,
@@ -436,6 +743,20 @@ impl MultiSampleDmr {
         } else {
             self.out_dir.join(format!("{}_{}.bed", a_name, b_name))
         };
+        self.create_file(fp)
+    }
+
+    fn get_combined_writer(&self) -> anyhow::Result<Box<BufWriter<File>>> {
+        let label = self.mode.label();
+        let fp = if let Some(p) = self.prefix.as_ref() {
+            self.out_dir.join(format!("{}_{}.bed", p, label))
+        } else {
+            self.out_dir.join(format!("{}.bed", label))
+        };
+        self.create_file(fp)
+    }
+
+    fn create_file(&self, fp: PathBuf) -> anyhow::Result<Box<BufWriter<File>>> {
         if fp.exists() && !self.force {
             bail!(
                 "refusing to overwrite {:?}",
@@ -495,59 +816,127 @@ impl MultiSampleDmr {
         })
     }
 
-    pub fn run(&self) -> anyhow::Result<()> {
-        let _handle = init_logging(self.log_filepath.as_ref());
-        let _pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(self.threads)
-            .build_global()?;
-
-        PairwiseDmr::validate_modified_bases(&self.modified_bases)?;
-        let indices = self.indices
-            .chunks(2)
-            .filter_map(|raw| {
-                if raw.len() != 2 {
-                    error!("illegal index pair {:?}, should be length 2 of the form <path> <name>", raw);
+    fn load_sample(
+        fp: PathBuf,
+        name: String,
+        group: String,
+        specified_index: Option<PathBuf>,
+    ) -> Option<DmrSample> {
+        if fp.exists() {
+            match specified_index {
+                Some(index_fp) if !index_fp.exists() => {
+                    error!(
+                        "index for {name} at {} not found",
+                        index_fp.to_str().unwrap_or("failed decode")
+                    );
                     None
-                } else {
-                    let fp = Path::new(raw[0].as_str()).to_path_buf();
-                    let name = raw[1].to_string();
-                    if fp.exists() {
-                        Some((name, fp))
-                    } else {
-                        error!("index for {name} at {} not found", &raw[0]);
-                        None
-                    }
                 }
-            })
-            .collect::<HashMap<String, PathBuf>>();
+                _ => Some(DmrSample::new(fp, specified_index, name, group)),
+            }
+        } else {
+            error!(
+                "bedMethyl for {name} at {} not found",
+                fp.to_str().unwrap_or("failed decode")
+            );
+            None
+        }
+    }
 
-        let samples = self.samples
-            .chunks(2)
-            .filter_map(|raw| {
-                if raw.len() != 2 {
-                    error!("illegal sample pair {:?}, should be length 2 of the form <path> <name>", raw);
-                    None
-                } else {
-                    let fp = Path::new(raw[0].as_str()).to_path_buf();
-                    let name = raw[1].to_string();
-                    if fp.exists() {
-                        let specified_index = indices.get(&name);
-                        if let Ok((_, index_fp)) = PairwiseDmr::load_index(&fp, specified_index) {
-                            Some(DmrSample::new(fp, index_fp, name))
+    fn collect_samples(&self) -> anyhow::Result<Vec<DmrSample>> {
+        let samples = if let Some(sample_sheet) = self.sample_sheet.as_ref() {
+            parse_sample_sheet(sample_sheet)?
+                .into_iter()
+                .filter_map(|row| {
+                    Self::load_sample(
+                        row.bedmethyl_fp,
+                        row.name,
+                        row.group,
+                        row.index,
+                    )
+                })
+                .collect::<Vec<DmrSample>>()
+        } else {
+            let indices = self.indices
+                .chunks(2)
+                .filter_map(|raw| {
+                    if raw.len() != 2 {
+                        error!("illegal index pair {:?}, should be length 2 of the form <path> <name>", raw);
+                        None
+                    } else {
+                        let fp = Path::new(raw[0].as_str()).to_path_buf();
+                        let name = raw[1].to_string();
+                        if fp.exists() {
+                            Some((name, fp))
                         } else {
-                            error!("failed to load tabix index for {name}");
+                            error!("index for {name} at {} not found", &raw[0]);
                             None
                         }
-                    } else {
-                        error!("bedMethyl for {name} at {} not found", &raw[0]);
+                    }
+                })
+                .collect::<HashMap<String, PathBuf>>();
+
+            self.samples
+                .chunks(2)
+                .filter_map(|raw| {
+                    if raw.len() != 2 {
+                        error!("illegal sample pair {:?}, should be length 2 of the form <path> <name>", raw);
                         None
+                    } else {
+                        let fp = Path::new(raw[0].as_str()).to_path_buf();
+                        let name = raw[1].to_string();
+                        let specified_index = indices.get(&name).cloned();
+                        Self::load_sample(fp, name.clone(), name, specified_index)
                     }
-                }
-            }).collect::<Vec<DmrSample>>();
+                }).collect::<Vec<DmrSample>>()
+        };
 
         if samples.len() < 2 {
             bail!("failed to collect at least 2 samples");
         }
+        let mut names = FxHashSet::default();
+        for sample in samples.iter() {
+            if !names.insert(sample.name.as_str()) {
+                bail!("sample names must be unique, got {} twice", &sample.name)
+            }
+        }
+
+        Ok(samples)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let _pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build_global()?;
+
+        PairwiseDmr::validate_modified_bases(&self.modified_bases)?;
+        let samples = self.collect_samples()?;
+        let sources = samples
+            .iter()
+            .map(|sample| {
+                PairwiseDmr::load_bedmethyl(
+                    &sample.bedmethyl_fp,
+                    sample.index.as_ref(),
+                )
+                .map(Arc::new)
+                .with_context(|| {
+                    format!("failed to load bedMethyl for {}", &sample.name)
+                })
+            })
+            .collect::<anyhow::Result<Vec<Arc<BedMethylSource>>>>()?;
+        let comparisons = match self.mode {
+            MultiSampleMode::pairwise => None,
+            MultiSampleMode::groups => {
+                Some(Comparison::group_vs_group(&samples)?)
+            }
+            MultiSampleMode::one_vs_rest => {
+                Some(Comparison::one_vs_rest(&samples))
+            }
+            MultiSampleMode::omnibus => {
+                Some(vec![Comparison::omnibus(&samples)])
+            }
+        };
+
         let motifs = self
             .modified_bases
             .iter()
@@ -555,16 +944,30 @@ impl MultiSampleDmr {
             .collect::<anyhow::Result<Vec<DnaBase>>>()
             .context("failed to parse modified base")?;
 
-        let regions_of_interest = parse_roi_bed(&self.regions_bed)?;
-        info!("loaded {} regions", regions_of_interest.len());
+        let (regions_of_interest, with_strand) = load_regions(
+            self.regions_bed.as_ref(),
+            self.regions_annotation.as_ref(),
+            self.annotation_feature,
+            self.flank,
+            self.ignore_annotation_strand,
+            self.split_strands,
+        )?;
 
         let chunk_size = (self.threads as f32 * 1.5f32).floor() as usize;
         info!("processing {chunk_size} regions concurrently");
 
+        if !self.out_dir.exists() {
+            info!(
+                "creating output directory {}",
+                self.out_dir.to_str().unwrap_or("failed to parse")
+            );
+            std::fs::create_dir_all(&self.out_dir)?;
+        }
+
         let mpb = MultiProgress::new();
-        let sample_pb =
-            mpb.add(get_master_progress_bar(n_choose_2(samples.len())?));
-        let n_regions = regions_of_interest.len();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
         let (positive_positions, negative_positions) =
             get_reference_modified_base_positions(
                 &self.reference_fasta,
@@ -573,13 +976,55 @@ impl MultiSampleDmr {
                 mpb.clone(),
             )?;
 
+        if let Some(comparisons) = comparisons {
+            self.run_combined(
+                &samples,
+                &sources,
+                &comparisons,
+                regions_of_interest,
+                with_strand,
+                &positive_positions,
+                &negative_positions,
+                chunk_size,
+                &mpb,
+            )
+        } else {
+            self.run_pairwise(
+                &samples,
+                &sources,
+                regions_of_interest,
+                with_strand,
+                &positive_positions,
+                &negative_positions,
+                chunk_size,
+                &mpb,
+            )
+        }
+    }
+
+    fn run_pairwise(
+        &self,
+        samples: &[DmrSample],
+        sources: &[Arc<BedMethylSource>],
+        regions_of_interest: Vec<DmrInterval>,
+        with_strand: bool,
+        positive_positions: &HashMap<String, Vec<u64>>,
+        negative_positions: &HashMap<String, Vec<u64>>,
+        chunk_size: usize,
+        mpb: &MultiProgress,
+    ) -> anyhow::Result<()> {
+        let sample_pb =
+            mpb.add(get_master_progress_bar(n_choose_2(samples.len())?));
+        let n_regions = regions_of_interest.len();
+
         for pair in samples
             .iter()
+            .zip(sources.iter())
             .combinations(2)
             .progress_with(sample_pb.clone())
         {
-            let a = pair[0];
-            let b = pair[1];
+            let (a, a_source) = pair[0];
+            let (b, b_source) = pair[1];
             sample_pb
                 .set_message(format!("comparing {} and {}", a.name, b.name));
             let pb = mpb.add(get_subroutine_progress_bar(n_regions));
@@ -587,46 +1032,20 @@ impl MultiSampleDmr {
             let failures = mpb.add(get_ticker());
             failures.set_message("regions failed to process");
 
-            let (a_index, _) =
-                PairwiseDmr::load_index(&a.bedmethyl_fp, Some(&a.index))?;
-            let (b_index, _) =
-                PairwiseDmr::load_index(&b.bedmethyl_fp, Some(&b.index))?;
-            let control_contig_lookup = a_index
-                .header()
-                .ok_or_else(|| {
-                    anyhow!("failed to get {} tabix header", &a.name)
-                })?
-                .reference_sequence_names()
-                .iter()
-                .enumerate()
-                .map(|(idx, r)| (r.to_owned(), idx))
-                .collect::<HashMap<String, usize>>();
-            let control_contig_lookup = Arc::new(control_contig_lookup);
-
-            let exp_contig_lookup = b_index
-                .header()
-                .ok_or_else(|| {
-                    anyhow!("failed to get {} tabix header", b.name)
-                })?
-                .reference_sequence_names()
-                .iter()
-                .enumerate()
-                .map(|(idx, r)| (r.to_owned(), idx))
-                .collect::<HashMap<String, usize>>();
-
-            let position_filter = self.get_stranded_position_filter(
-                &positive_positions,
-                &negative_positions,
-                control_contig_lookup.clone(),
-            )?;
+            let control_contig_lookup =
+                Arc::new(a_source.contig_lookup().clone());
+
+            let position_filter = self
+                .get_stranded_position_filter(
+                    positive_positions,
+                    negative_positions,
+                    control_contig_lookup.clone(),
+                )
+                .map(Arc::new)?;
 
             let dmr_interval_iter = DmrIntervalIter::new(
-                &a.bedmethyl_fp,
-                &b.bedmethyl_fp,
-                control_contig_lookup.clone(),
-                exp_contig_lookup,
-                a_index,
-                b_index,
+                a_source.clone(),
+                b_source.clone(),
                 regions_of_interest.clone().into_iter().collect(),
                 chunk_size,
                 failures.clone(),
@@ -634,12 +1053,13 @@ impl MultiSampleDmr {
 
             let writer = self.get_writer(&a.name, &b.name)?;
             let success_count = run_pairwise_dmr(
-                &a.bedmethyl_fp,
-                &b.bedmethyl_fp,
+                a_source.clone(),
+                b_source.clone(),
                 dmr_interval_iter,
                 position_filter,
                 writer,
                 pb,
+                with_strand,
             )?;
             debug!(
                 "{} regions processed successfully and {} regions failed for pair {} {}",
@@ -651,4 +1071,254 @@ impl MultiSampleDmr {
 
         Ok(())
     }
+
+    fn run_combined(
+        &self,
+        samples: &[DmrSample],
+        sources: &[Arc<BedMethylSource>],
+        comparisons: &[Comparison],
+        regions_of_interest: Vec<DmrInterval>,
+        with_strand: bool,
+        positive_positions: &HashMap<String, Vec<u64>>,
+        negative_positions: &HashMap<String, Vec<u64>>,
+        chunk_size: usize,
+        mpb: &MultiProgress,
+    ) -> anyhow::Result<()> {
+        // the samples may have different contigs, so the position filter uses
+        // IDs from all of the contig names combined
+        let name_to_id = sources
+            .iter()
+            .flat_map(|source| source.contig_lookup().keys().cloned())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, name)| (name, idx))
+            .collect::<HashMap<String, usize>>();
+        let name_to_id = Arc::new(name_to_id);
+        let position_filter = self.get_stranded_position_filter(
+            positive_positions,
+            negative_positions,
+            name_to_id.clone(),
+        )?;
+
+        let mut writer = self.get_combined_writer()?;
+        writer.write(MultiSampleCounts::header(with_strand).as_bytes())?;
+        let pb = mpb.add(get_master_progress_bar(regions_of_interest.len()));
+        pb.set_message("regions processed");
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions failed to process");
+
+        let mut success_count = 0usize;
+        for regions in regions_of_interest.chunks(chunk_size) {
+            let results = regions
+                .par_iter()
+                .map(|dmr_interval| {
+                    let chrom_id = *name_to_id
+                        .get(&dmr_interval.chrom)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "didn't find chrom {} in any sample",
+                                &dmr_interval.chrom
+                            )
+                        })? as u32;
+                    // leaving a sample out would change the counts of its
+                    // group, so the region is skipped when any sample fails
+                    let sample_counts = samples
+                        .iter()
+                        .zip(sources.iter())
+                        .map(|(sample, source)| {
+                            source
+                                .get_counts(
+                                    dmr_interval,
+                                    chrom_id,
+                                    &position_filter,
+                                )
+                                .with_context(|| {
+                                    format!(
+                                        "skipping region {dmr_interval}, \
+                                        failed to get counts for sample {}",
+                                        &sample.name
+                                    )
+                                })
+                        })
+                        .collect::<anyhow::Result<Vec<AggregatedCounts>>>()?;
+                    comparisons
+                        .iter()
+                        .map(|comparison| {
+                            comparison.score(dmr_interval, &sample_counts)
+                        })
+                        .collect::<anyhow::Result<Vec<MultiSampleCounts>>>()
+                })
+                .collect::<Vec<anyhow::Result<Vec<MultiSampleCounts>>>>();
+            for result in results {
+                pb.inc(1);
+                match result {
+                    Ok(rows) => {
+                        for row in rows {
+                            writer
+                                .write(row.to_row(with_strand)?.as_bytes())?;
+                        }
+                        success_count += 1;
+                    }
+                    Err(e) => {
+                        failures.inc(1);
+                        debug!("{e:#}");
+                    }
+                }
+            }
+        }
+        pb.finish_and_clear();
+
+        info!(
+            "{} regions processed successfully and {} regions failed",
+            success_count,
+            failures.position()
+        );
+        if failures.position() > 0 {
+            warn!(
+                "{} regions were skipped because a sample failed, see the \
+                log for the samples and errors",
+                failures.position()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct HemiDmr {
+    /// Output of pileup-hemi for the first (usually control) sample, plain
+    /// text or gzip compressed.
+    #[arg(short = 'a')]
+    control_hemi_bed: PathBuf,
+    /// Output of pileup-hemi for the second (usually experimental) sample,
+    /// plain text or gzip compressed.
+    #[arg(short = 'b')]
+    exp_hemi_bed: PathBuf,
+    /// Path to file to direct output, optional, no argument will direct output to stdout.
+    #[arg(short = 'o', long)]
+    out_path: Option<String>,
+    /// Regions BED file over which to compare the duplex patterns. Requires chrom,
+    /// chromStart and chromEnd, the name column is optional. Default is to compare
+    /// each site in either sample. The strand of the regions is ignored, duplex
+    /// patterns already include both strands.
+    #[arg(long, short = 'r')]
+    regions_bed: Option<PathBuf>,
+    /// File to write logs to, it's recommended to use this option.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Don't show progress bars
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+}
+
+impl HemiDmr {
+    fn get_writer(&self) -> anyhow::Result<Box<dyn Write>> {
+        match self.out_path.as_ref() {
+            None => Ok(Box::new(BufWriter::new(std::io::stdout()))),
+            Some(fp) => {
+                let p = Path::new(fp);
+                if let Some(parent) = p.parent() {
+                    if !parent.exists() {
+                        info!(
+                            "creating output directory {}",
+                            parent.to_str().unwrap_or("failed to parse")
+                        );
+                        std::fs::create_dir_all(parent)?;
+                    }
+                }
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                } else {
+                    let fh = File::create(p)?;
+                    Ok(Box::new(BufWriter::new(fh)))
+                }
+            }
+        }
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        for fp in [&self.control_hemi_bed, &self.exp_hemi_bed] {
+            if !fp.exists() {
+                bail!(
+                    "input file {} not found",
+                    fp.to_str().unwrap_or("UTF-8-decode failure")
+                )
+            }
+        }
+        let _pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build_global()?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let control = HemiBedMethyl::read(&self.control_hemi_bed)?;
+        let exp = HemiBedMethyl::read(&self.exp_hemi_bed)?;
+        let mut writer = self.get_writer()?;
+
+        let regions_of_interest = match self.regions_bed.as_ref() {
+            Some(fp) => {
+                let regions = parse_roi_bed(fp)?;
+                info!("loaded {} regions", regions.len());
+                regions
+            }
+            None => {
+                let sites = get_all_sites(&control, &exp);
+                info!("comparing {} sites", sites.len());
+                sites
+            }
+        };
+
+        let chunk_size = (self.threads as f32 * 1.5f32).floor() as usize;
+        let pb = mpb.add(get_master_progress_bar(regions_of_interest.len()));
+        pb.set_message("regions processed");
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions failed to process");
+
+        let mut success_count = 0usize;
+        for regions in regions_of_interest.chunks(chunk_size) {
+            let results = regions
+                .par_iter()
+                .map(|dmr_interval| {
+                    HemiModificationCounts::new(
+                        dmr_interval.clone(),
+                        control.get_counts(dmr_interval),
+                        exp.get_counts(dmr_interval),
+                    )
+                })
+                .collect::<Vec<anyhow::Result<HemiModificationCounts>>>();
+            for result in results {
+                pb.inc(1);
+                match result {
+                    Ok(counts) => {
+                        writer.write(counts.to_row().as_bytes())?;
+                        success_count += 1;
+                    }
+                    Err(e) => {
+                        failures.inc(1);
+                        debug!("unexpected error, {}", e.to_string());
+                    }
+                }
+            }
+        }
+        pb.finish_and_clear();
+
+        info!(
+            "{} regions processed successfully and {} regions failed",
+            success_count,
+            failures.position()
+        );
+
+        Ok(())
+    }
 }
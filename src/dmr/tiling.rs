@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use log::{debug, info, warn};
+
+use crate::dmr::util::DmrInterval;
+use crate::motif_bed::MotifLocations;
+use crate::position_filter::{Iv, StrandedPositionFilter};
+use crate::util::StrandRule;
+
+/// Size of the windows used to tile the genome, either a fixed number of
+/// bases or a fixed number of modified base positions (e.g. CpGs).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum WindowSize {
+    Bases(u64),
+    Positions(usize),
+}
+
+fn make_window(chrom: &str, start: u64, stop: u64) -> DmrInterval {
+    DmrInterval::new(
+        Iv {
+            start,
+            stop,
+            val: (),
+        },
+        chrom.to_string(),
+        format!("{}:{}-{}", chrom, start, stop),
+        StrandRule::Both,
+    )
+}
+
+/// Sorted, de-duplicated modified base positions on either strand.
+fn get_positions(
+    position_filter: &StrandedPositionFilter,
+    chrom_id: u32,
+) -> Vec<u64> {
+    let mut positions = position_filter
+        .pos_positions
+        .get(&chrom_id)
+        .into_iter()
+        .chain(position_filter.neg_positions.get(&chrom_id))
+        .flat_map(|lp| lp.iter().map(|iv| iv.start))
+        .collect::<Vec<u64>>();
+    positions.sort();
+    positions.dedup();
+    positions
+}
+
+/// Sorted positions of the motif sites, one per site. For palindromic motifs
+/// (e.g. CG) the negative strand hit is the same site as the positive strand
+/// hit, so only the positive strand position is kept.
+fn get_motif_positions(
+    motif_locations: &MotifLocations,
+    chrom_id: u32,
+) -> Vec<u64> {
+    let palindrome = motif_locations.motif().is_palendrome();
+    let mut positions = motif_locations
+        .targets_to_positions()
+        .get(&chrom_id)
+        .into_iter()
+        .flat_map(|positions| positions.iter())
+        .filter(|(_, strand_rule)| {
+            !(palindrome && **strand_rule == StrandRule::Negative)
+        })
+        .map(|(pos, _)| *pos as u64)
+        .collect::<Vec<u64>>();
+    positions.sort();
+    positions
+}
+
+fn tile_positions(
+    chrom: &str,
+    positions: &[u64],
+    size: WindowSize,
+    step: usize,
+) -> Vec<DmrInterval> {
+    match size {
+        WindowSize::Bases(size) => {
+            let last = match positions.last() {
+                Some(last) => *last,
+                None => return Vec::new(),
+            };
+            (0..=last)
+                .step_by(step)
+                .filter_map(|start| {
+                    let stop = start + size;
+                    // only keep windows with at least one position
+                    let first = positions.partition_point(|p| *p < start);
+                    positions
+                        .get(first)
+                        .filter(|p| **p < stop)
+                        .map(|_| make_window(chrom, start, stop))
+                })
+                .collect()
+        }
+        WindowSize::Positions(count) => {
+            if positions.len() < count || count == 0 {
+                return Vec::new();
+            }
+            (0..=(positions.len() - count))
+                .step_by(step)
+                .map(|i| {
+                    make_window(
+                        chrom,
+                        positions[i],
+                        positions[i + count - 1] + 1,
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+/// Tile the contigs into windows. `step` is in bases for `WindowSize::Bases`
+/// and in positions for `WindowSize::Positions`, when `None` the windows
+/// don't overlap. The positions are the sites in `motif_locations` when
+/// given, otherwise the modified base positions in `position_filter`, which
+/// has a position for each base on either strand. Windows are made over all contigs in `contig_lookup`, or
+/// only `contigs` when not empty, in the order of the contig IDs.
+pub(super) fn tile_windows(
+    position_filter: &StrandedPositionFilter,
+    motif_locations: Option<&MotifLocations>,
+    contig_lookup: &HashMap<String, usize>,
+    contigs: &[String],
+    size: WindowSize,
+    step: Option<u64>,
+) -> anyhow::Result<Vec<DmrInterval>> {
+    let step = match (size, step) {
+        (_, Some(0))
+        | (WindowSize::Bases(0), _)
+        | (WindowSize::Positions(0), _) => {
+            bail!("window size and step must be greater than 0")
+        }
+        (_, Some(step)) => step as usize,
+        (WindowSize::Bases(size), None) => size as usize,
+        (WindowSize::Positions(count), None) => count,
+    };
+    if matches!(size, WindowSize::Positions(_)) && motif_locations.is_none() {
+        bail!("tiling windows by positions requires a motif")
+    }
+    for contig in contigs {
+        if !contig_lookup.contains_key(contig) {
+            warn!("contig {contig} not found in bedMethyl, will not be tiled");
+        }
+    }
+    let mut contig_ids = contig_lookup
+        .iter()
+        .filter(|(name, _)| contigs.is_empty() || contigs.contains(name))
+        .map(|(name, id)| (*id, name.as_str()))
+        .collect::<Vec<(usize, &str)>>();
+    contig_ids.sort();
+
+    let windows = contig_ids
+        .into_iter()
+        .flat_map(|(chrom_id, chrom)| {
+            let positions = match motif_locations {
+                Some(motif_locations) => {
+                    get_motif_positions(motif_locations, chrom_id as u32)
+                }
+                None => get_positions(position_filter, chrom_id as u32),
+            };
+            let windows = tile_positions(chrom, &positions, size, step);
+            debug!(
+                "made {} windows over {} positions on {chrom}",
+                windows.len(),
+                positions.len()
+            );
+            windows
+        })
+        .collect::<Vec<DmrInterval>>();
+    if windows.is_empty() {
+        bail!("didn't make any windows, check the contigs and modified bases")
+    }
+    info!("made {} windows", windows.len());
+    Ok(windows)
+}
+
+/// Merge windows with a score of at least `min_score` that overlap or are
+/// adjacent into regions. Windows must be sorted by contig and start.
+pub(super) fn merge_windows<'a>(
+    windows: impl Iterator<Item = (&'a DmrInterval, f64)>,
+    min_score: f64,
+) -> Vec<DmrInterval> {
+    let (mut merged, current) =
+        windows.filter(|(_, score)| *score >= min_score).fold(
+            (Vec::new(), None::<(String, u64, u64)>),
+            |(mut merged, current), (window, _)| match current {
+                Some((chrom, start, stop))
+                    if chrom == window.chrom && window.start() <= stop =>
+                {
+                    (merged, Some((chrom, start, stop.max(window.stop()))))
+                }
+                Some((chrom, start, stop)) => {
+                    merged.push(make_window(&chrom, start, stop));
+                    (
+                        merged,
+                        Some((
+                            window.chrom.clone(),
+                            window.start(),
+                            window.stop(),
+                        )),
+                    )
+                }
+                None => (
+                    merged,
+                    Some((window.chrom.clone(), window.start(), window.stop())),
+                ),
+            },
+        );
+    if let Some((chrom, start, stop)) = current {
+        merged.push(make_window(&chrom, start, stop));
+    }
+    merged
+}
+
+#[cfg(test)]
+mod dmr_tiling_tests {
+    use indicatif::MultiProgress;
+
+    use crate::dmr::tiling::{
+        get_motif_positions, merge_windows, tile_positions, WindowSize,
+    };
+    use crate::motif_bed::{MotifLocations, RegexMotif};
+
+    #[test]
+    fn test_tile_positions() {
+        let positions = [10u64, 11, 30, 31, 32, 90];
+        let windows =
+            tile_positions("chr1", &positions, WindowSize::Bases(20), 20);
+        let obs = windows
+            .iter()
+            .map(|w| (w.start(), w.stop()))
+            .collect::<Vec<(u64, u64)>>();
+        // the windows without any positions are skipped
+        assert_eq!(obs, vec![(0, 20), (20, 40), (80, 100)]);
+        assert_eq!(windows[0].name, "chr1:0-20");
+
+        let windows =
+            tile_positions("chr1", &positions, WindowSize::Positions(3), 2);
+        let obs = windows
+            .iter()
+            .map(|w| (w.start(), w.stop()))
+            .collect::<Vec<(u64, u64)>>();
+        assert_eq!(obs, vec![(10, 31), (30, 33)]);
+        assert!(tile_positions(
+            "chr1",
+            &positions[..2],
+            WindowSize::Positions(3),
+            1
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_motif_positions() {
+        // the C at 4 isn't in a CpG and the G at 2 is the negative strand of
+        // the CpG at 1
+        let seq = "ACGTCAACGTTCGA".to_string();
+        let mpb = MultiProgress::new();
+        mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let cpg = MotifLocations::from_sequences(
+            RegexMotif::parse_string("CG", 0).unwrap(),
+            None,
+            &[(seq, 0)],
+            &mpb,
+        )
+        .unwrap();
+        let positions = get_motif_positions(&cpg, 0);
+        assert_eq!(positions, vec![1, 7, 11]);
+        assert!(get_motif_positions(&cpg, 1).is_empty());
+        let windows =
+            tile_positions("chr1", &positions, WindowSize::Positions(2), 1);
+        let obs = windows
+            .iter()
+            .map(|w| (w.start(), w.stop()))
+            .collect::<Vec<(u64, u64)>>();
+        assert_eq!(obs, vec![(1, 8), (7, 12)]);
+    }
+
+    #[test]
+    fn test_merge_windows() {
+        let windows = tile_positions(
+            "chr1",
+            &(0u64..100).collect::<Vec<u64>>(),
+            WindowSize::Bases(20),
+            10,
+        );
+        let scores = [0.0, 5.0, 6.0, 0.0, 0.0, 0.0, 7.0, 0.0, 0.0, 8.0];
+        assert_eq!(windows.len(), scores.len());
+        let merged =
+            merge_windows(windows.iter().zip(scores.iter().copied()), 1.0);
+        let obs = merged
+            .iter()
+            .map(|w| (w.start(), w.stop()))
+            .collect::<Vec<(u64, u64)>>();
+        assert_eq!(obs, vec![(10, 40), (60, 80), (90, 110)]);
+        assert!(merge_windows(windows.iter().zip(scores), 10.0).is_empty());
+    }
+}
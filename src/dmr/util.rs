@@ -1,33 +1,44 @@
 use std::cmp::Ordering;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CString;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
 use derive_new::new;
+use flate2::read::MultiGzDecoder;
 use indicatif::ProgressBar;
-use log::{debug, error};
+use log::{debug, error, info};
 use nom::character::complete::{multispace1, none_of, one_of};
 use nom::multi::{many0, many1};
 use nom::IResult;
+use noodles::bgzf;
 use noodles::csi::index::{
     reference_sequence::bin::Chunk as IndexChunk, Index as CsiIndex,
 };
+use rust_htslib::htslib;
 
+use crate::dmr::model::AggregatedCounts;
+use crate::dmr::pairwise::get_mod_counts_for_condition;
 use crate::parsing_utils::{
     consume_char, consume_char_from_list, consume_digit, consume_float,
     consume_string, consume_string_spaces,
 };
-use crate::position_filter::Iv;
+use crate::position_filter::{Iv, StrandedPositionFilter};
+use crate::util::StrandRule;
 
 #[derive(new, Clone, Debug, Eq, PartialEq)]
 pub(super) struct DmrInterval {
     pub(super) interval: Iv,
     pub(super) chrom: String,
     pub(super) name: String,
+    /// Strand from the 6th column of the regions BED, `Both` when absent or
+    /// not one of '+' or '-'.
+    pub(super) strand: StrandRule,
 }
 
 impl DmrInterval {
@@ -36,7 +47,7 @@ impl DmrInterval {
         let (rest, start) = consume_digit(rest)?;
         let (rest, stop) = consume_digit(rest)?;
 
-        let (rest, interval, name) = many0(one_of(" \t\r\n"))(rest)
+        let (rest, interval, name, strand) = many0(one_of(" \t\r\n"))(rest)
             .and_then(|(rest, _)| consume_string_spaces(rest))
             .map(|(rest, name)| {
                 let interval = Iv {
@@ -44,7 +55,18 @@ impl DmrInterval {
                     stop,
                     val: (),
                 };
-                (rest, interval, name)
+                // the strand is the 6th tab-separated column, the score
+                // column before it may be empty
+                let strand = match line
+                    .trim_end_matches(['\r', '\n'])
+                    .split('\t')
+                    .nth(5)
+                {
+                    Some("+") => StrandRule::Positive,
+                    Some("-") => StrandRule::Negative,
+                    _ => StrandRule::Both,
+                };
+                (rest, interval, name, strand)
             })
             .unwrap_or_else(|_| {
                 let interval = Iv {
@@ -53,7 +75,7 @@ impl DmrInterval {
                     val: (),
                 };
                 let name = format!("{}:{}-{}", chrom, start, stop);
-                (rest, interval, name)
+                (rest, interval, name, StrandRule::Both)
             });
 
         Ok((
@@ -62,6 +84,7 @@ impl DmrInterval {
                 interval,
                 chrom,
                 name,
+                strand,
             },
         ))
     }
@@ -80,6 +103,29 @@ impl DmrInterval {
         self.interval.stop
     }
 
+    pub(super) fn strand_char(&self) -> char {
+        match self.strand {
+            StrandRule::Positive => '+',
+            StrandRule::Negative => '-',
+            StrandRule::Both => '.',
+        }
+    }
+
+    /// Split a region without a strand into a positive-strand and a
+    /// negative-strand region, stranded regions are returned as-is.
+    pub(super) fn split_strands(self) -> Vec<Self> {
+        match self.strand {
+            StrandRule::Both => [StrandRule::Positive, StrandRule::Negative]
+                .into_iter()
+                .map(|strand| Self {
+                    strand,
+                    ..self.clone()
+                })
+                .collect(),
+            _ => vec![self],
+        }
+    }
+
     pub(super) fn get_index_chunks(
         &self,
         index: &CsiIndex,
@@ -115,21 +161,244 @@ impl Display for DmrInterval {
     }
 }
 
+/// Open a plain text or gzip (including bgzip) compressed file for reading
+/// lines.
+pub(super) fn open_text_or_gzip<P: AsRef<Path>>(
+    path: P,
+) -> anyhow::Result<Box<dyn BufRead>> {
+    let path = path.as_ref();
+    let mut fh = File::open(path)
+        .with_context(|| format!("failed to open {:?}", path))?;
+    let mut magic = [0u8; 2];
+    let is_gzip = fh.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    let fh = File::open(path)?;
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(fh))))
+    } else {
+        Ok(Box::new(BufReader::new(fh)))
+    }
+}
+
+/// Number of unindexed bedMethyl files copied so far, keeps the names of the
+/// temporary copies unique.
+static N_TEMP_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+/// A bedMethyl input to `dmr`. Bgzip-compressed files with a tabix index are
+/// queried with the index, other (plain text or gzip-compressed) files are
+/// copied to a temporary bgzip-compressed file, which is indexed and removed
+/// when the source is dropped.
+pub(super) struct BedMethylSource {
+    path: PathBuf,
+    /// The bgzip-compressed file queried with the index, the temporary copy
+    /// for unindexed inputs.
+    bgzf_path: PathBuf,
+    contig_lookup: HashMap<String, usize>,
+    index: CsiIndex,
+    temp_files: Vec<PathBuf>,
+}
+
+impl BedMethylSource {
+    pub(super) fn indexed(
+        path: &PathBuf,
+        index: CsiIndex,
+    ) -> anyhow::Result<Self> {
+        let contig_lookup = index
+            .header()
+            .ok_or_else(|| {
+                anyhow!("failed to get tabix header for {:?}", path)
+            })?
+            .reference_sequence_names()
+            .iter()
+            .enumerate()
+            .map(|(idx, r)| (r.to_owned(), idx))
+            .collect::<HashMap<String, usize>>();
+        Ok(Self {
+            path: path.clone(),
+            bgzf_path: path.clone(),
+            contig_lookup,
+            index,
+            temp_files: Vec::new(),
+        })
+    }
+
+    /// Copy a sorted, plain text or gzip-compressed, bedMethyl into a
+    /// temporary bgzip-compressed file in `temp_dir` and index it. Lines
+    /// that can't be parsed are skipped.
+    pub(super) fn from_unindexed(
+        path: &PathBuf,
+        temp_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "bedmethyl".to_string());
+        let copy_path = temp_dir.join(format!(
+            "modkit_dmr_{}_{}_{}.bgz",
+            std::process::id(),
+            N_TEMP_COPIES.fetch_add(1, AtomicOrdering::SeqCst),
+            file_name
+        ));
+        let index_path = PathBuf::from(format!("{}.tbi", copy_path.display()));
+        let mut temp_copy = TempCopy {
+            temp_files: vec![copy_path.clone(), index_path.clone()],
+        };
+
+        let reader = open_text_or_gzip(path)?;
+        let mut writer = File::create(&copy_path)
+            .map(bgzf::Writer::new)
+            .with_context(|| format!("failed to create {:?}", &copy_path))?;
+        let mut failed_to_parse = 0usize;
+        let mut n_records = 0usize;
+        let mut finished_contigs = HashSet::new();
+        let mut last: Option<(String, u64)> = None;
+        for line in reader.lines() {
+            let line = line.with_context(|| {
+                format!("failed to read line from {:?}", path)
+            })?;
+            let bm_line = match BedMethylLine::parse(&line) {
+                Ok(bm_line) => bm_line,
+                Err(_) => {
+                    failed_to_parse += 1;
+                    continue;
+                }
+            };
+            let sorted = match last.as_ref() {
+                Some((chrom, start)) if *chrom == bm_line.chrom => {
+                    *start <= bm_line.start()
+                }
+                Some((chrom, _)) => {
+                    finished_contigs.insert(chrom.clone());
+                    !finished_contigs.contains(&bm_line.chrom)
+                }
+                None => true,
+            };
+            if !sorted {
+                bail!(
+                    "{:?} is not sorted, sort it by chrom and start, for \
+                    example with sort -k1,1 -k2,2n, or bgzip and index it \
+                    with tabix",
+                    path
+                );
+            }
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+            n_records += 1;
+            last = Some((bm_line.chrom.clone(), bm_line.start()));
+        }
+        writer.flush()?;
+        // BGZF EOF block is written when the writer is closed
+        drop(writer);
+        if n_records == 0 {
+            bail!("failed to parse any bedMethyl lines from {:?}", path);
+        }
+        if failed_to_parse > 0 {
+            debug!("failed to parse {} lines from {:?}", failed_to_parse, path);
+        }
+
+        let fp = CString::new(copy_path.to_string_lossy().as_bytes())?;
+        let ret = unsafe {
+            htslib::tbx_index_build(fp.as_ptr(), 0, &htslib::tbx_conf_bed)
+        };
+        if ret < 0 {
+            bail!("failed to build tabix index for {:?}", &copy_path)
+        }
+        let index = noodles::tabix::read(&index_path).with_context(|| {
+            format!("failed to read index at {:?}", &index_path)
+        })?;
+        info!(
+            "copied {} bedMethyl records from {:?} to indexed file {:?}",
+            n_records, path, &copy_path
+        );
+        let mut source = Self::indexed(&copy_path, index)?;
+        source.path = path.clone();
+        source.temp_files = std::mem::take(&mut temp_copy.temp_files);
+        Ok(source)
+    }
+
+    pub(super) fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub(super) fn contig_lookup(&self) -> &HashMap<String, usize> {
+        &self.contig_lookup
+    }
+
+    pub(super) fn has_contig(&self, name: &str) -> bool {
+        self.contig_lookup.contains_key(name)
+    }
+
+    /// Get the aggregated counts over `dmr_interval`. `chrom_id` is the ID
+    /// used by the `position_filter`, which may differ from the ID used by
+    /// this source.
+    pub(super) fn get_counts(
+        &self,
+        dmr_interval: &DmrInterval,
+        chrom_id: u32,
+        position_filter: &StrandedPositionFilter,
+    ) -> anyhow::Result<AggregatedCounts> {
+        let tid =
+            self.contig_lookup.get(&dmr_interval.chrom).ok_or_else(|| {
+                anyhow!(
+                    "didn't find chrom id for {} in {:?} tabix header",
+                    &dmr_interval.chrom,
+                    &self.path
+                )
+            })?;
+        let chunks = dmr_interval.get_index_chunks(&self.index, *tid)?;
+        if chunks.len() != 1 {
+            debug!(
+                "more than 1 chunk for {:?}?, got {}",
+                &self.path,
+                chunks.len()
+            );
+        }
+        let mut reader = File::open(&self.bgzf_path).map(bgzf::Reader::new)?;
+        get_mod_counts_for_condition(
+            &mut reader,
+            &chunks,
+            dmr_interval,
+            chrom_id,
+            position_filter,
+            &self.path,
+        )
+    }
+}
+
+impl Drop for BedMethylSource {
+    fn drop(&mut self) {
+        remove_temp_files(&mut self.temp_files);
+    }
+}
+
+/// Removes the temporary copy of an unindexed bedMethyl if an error stops
+/// `BedMethylSource::from_unindexed` before the source is made.
+struct TempCopy {
+    temp_files: Vec<PathBuf>,
+}
+
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        remove_temp_files(&mut self.temp_files);
+    }
+}
+
+fn remove_temp_files(temp_files: &mut Vec<PathBuf>) {
+    for fp in temp_files.drain(..).filter(|fp| fp.exists()) {
+        if let Err(e) = std::fs::remove_file(&fp) {
+            error!("failed to remove temp file {:?}, {}", &fp, e.to_string());
+        }
+    }
+}
+
 #[derive(new)]
 pub(super) struct DmrChunk {
     pub(super) chrom_id: u32,
-    pub(super) control_chunks: Vec<IndexChunk>,
-    pub(super) exp_chunks: Vec<IndexChunk>,
     pub(super) dmr_interval: DmrInterval,
 }
 
 pub(super) struct DmrIntervalIter {
-    control_fn: String,
-    exp_fn: String,
-    control_contig_lookup: Arc<HashMap<String, usize>>,
-    exp_contig_lookup: HashMap<String, usize>,
-    control_index: CsiIndex,
-    exp_index: CsiIndex,
+    control: Arc<BedMethylSource>,
+    exp: Arc<BedMethylSource>,
     regions_of_interest: VecDeque<DmrInterval>,
     chunk_size: usize,
     failures: ProgressBar,
@@ -137,32 +406,15 @@ pub(super) struct DmrIntervalIter {
 
 impl DmrIntervalIter {
     pub(super) fn new(
-        control_path: &PathBuf,
-        exp_path: &PathBuf,
-        control_contig_lookup: Arc<HashMap<String, usize>>,
-        exp_contig_lookup: HashMap<String, usize>,
-        control_index: CsiIndex,
-        exp_index: CsiIndex,
+        control: Arc<BedMethylSource>,
+        exp: Arc<BedMethylSource>,
         rois: VecDeque<DmrInterval>,
         chunk_size: usize,
         failure_counter: ProgressBar,
     ) -> Self {
-        let control_fn = control_path
-            .to_str()
-            .map(|s| s.to_owned())
-            .unwrap_or_else(|| format!("'a' failed path decode"));
-        let exp_fn = exp_path
-            .to_str()
-            .map(|s| s.to_owned())
-            .unwrap_or_else(|| format!("'a' failed path decode"));
-
         Self {
-            control_fn,
-            exp_fn,
-            control_contig_lookup,
-            exp_contig_lookup,
-            control_index,
-            exp_index,
+            control,
+            exp,
             regions_of_interest: rois,
             chunk_size,
             failures: failure_counter,
@@ -177,69 +429,31 @@ impl Iterator for DmrIntervalIter {
         let mut chunks = Self::Item::with_capacity(self.chunk_size);
         loop {
             if let Some(dmr_interval) = self.regions_of_interest.pop_front() {
-                let (control_chr_id, exp_chr_id) = match (
-                    self.control_contig_lookup.get(&dmr_interval.chrom),
-                    self.exp_contig_lookup.get(&dmr_interval.chrom),
+                let control_chr_id = match (
+                    self.control.contig_lookup().get(&dmr_interval.chrom),
+                    self.exp.has_contig(&dmr_interval.chrom),
                 ) {
-                    (Some(control_chr_id), Some(exp_chr_id)) => {
-                        (*control_chr_id, *exp_chr_id)
-                    }
+                    (Some(control_chr_id), true) => *control_chr_id,
                     (None, _) => {
                         self.failures.inc(1);
-                        // todo change "control" and "experimental" to the filepaths
                         debug!(
-                            "didn't find chrom id for {} in {} tabix header",
-                            &self.control_fn, &dmr_interval.chrom
+                            "didn't find chrom id for {} in {:?}",
+                            &dmr_interval.chrom,
+                            self.control.path()
                         );
                         continue;
                     }
-                    (_, None) => {
+                    (_, false) => {
                         self.failures.inc(1);
                         debug!(
-                            "didn't find chrom id for {} in {} tabix header",
-                            &self.exp_fn, &dmr_interval.chrom
+                            "didn't find chrom id for {} in {:?}",
+                            &dmr_interval.chrom,
+                            self.exp.path()
                         );
                         continue;
                     }
                 };
-                let control_chunks = dmr_interval
-                    .get_index_chunks(&self.control_index, control_chr_id);
-                let exp_chunks =
-                    dmr_interval.get_index_chunks(&self.exp_index, exp_chr_id);
-                let (control_chunks, exp_chunks) =
-                    match (control_chunks, exp_chunks) {
-                        (Ok(control_chunks), Ok(exp_chunks)) => {
-                            (control_chunks, exp_chunks)
-                        }
-                        (Err(e), _) => {
-                            self.failures.inc(1);
-                            debug!(
-                                "failed to index into {} bedMethyl \
-                            for region {}, {}",
-                                &self.control_fn,
-                                dmr_interval,
-                                e.to_string()
-                            );
-                            continue;
-                        }
-                        (_, Err(e)) => {
-                            self.failures.inc(1);
-                            debug!(
-                                "failed to index into {} bedMethyl \
-                            for chrom id {}, {}",
-                                &self.exp_fn,
-                                exp_chr_id,
-                                e.to_string()
-                            );
-                            continue;
-                        }
-                    };
-                let chunk = DmrChunk::new(
-                    control_chr_id as u32,
-                    control_chunks,
-                    exp_chunks,
-                    dmr_interval,
-                );
+                let chunk = DmrChunk::new(control_chr_id as u32, dmr_interval);
                 chunks.push(chunk);
                 if chunks.len() >= self.chunk_size {
                     break;
@@ -258,7 +472,7 @@ impl Iterator for DmrIntervalIter {
     }
 }
 
-#[derive(new, Debug, PartialEq, Eq)]
+#[derive(new, Debug, Clone, PartialEq, Eq)]
 pub(super) struct BedMethylLine {
     pub(super) chrom: String,
     pub(super) interval: Iv,
@@ -348,8 +562,18 @@ pub(super) fn parse_roi_bed<P: AsRef<Path>>(
 
 #[cfg(test)]
 mod dmr_util_tests {
-    use crate::dmr::util::{parse_roi_bed, BedMethylLine, DmrInterval};
-    use crate::position_filter::Iv;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+
+    use flate2::read::MultiGzDecoder;
+    use rustc_hash::FxHashMap;
+
+    use crate::dmr::util::{
+        parse_roi_bed, BedMethylLine, BedMethylSource, DmrInterval,
+    };
+    use crate::position_filter::{GenomeLapper, Iv, StrandedPositionFilter};
+    use crate::util::StrandRule;
 
     #[test]
     fn test_parse_bedmethyl_line() {
@@ -402,6 +626,7 @@ mod dmr_util_tests {
             },
             "chr20".to_string(),
             "CpG: 39 359".to_string(),
+            StrandRule::Both,
         );
         assert_eq!(obs, expected);
         let obs = DmrInterval::parse_str("chr20\t279148\t279507\tCpGby_any_other_name\t39\t260\t21.7\t72.4\t0.83").unwrap();
@@ -413,6 +638,7 @@ mod dmr_util_tests {
             },
             "chr20".to_string(),
             "CpGby_any_other_name".to_string(),
+            StrandRule::Both,
         );
         assert_eq!(obs, expected);
         let obs = DmrInterval::parse_str("chr20\t279148\t279507\t").unwrap();
@@ -424,12 +650,47 @@ mod dmr_util_tests {
             },
             "chr20".to_string(),
             "chr20:279148-279507".to_string(),
+            StrandRule::Both,
         );
         assert_eq!(obs, expected);
         let obs = DmrInterval::parse_str("chr20\t279148\t279507 ").unwrap();
         assert_eq!(obs, expected);
     }
 
+    #[test]
+    fn test_parse_rois_stranded() {
+        let obs =
+            DmrInterval::parse_str("chr20\t279148\t279507\tr1\t0\t-").unwrap();
+        assert_eq!(obs.name, "r1");
+        assert_eq!(obs.strand, StrandRule::Negative);
+        assert_eq!(obs.strand_char(), '-');
+        let obs = DmrInterval::parse_str("chr20\t279148\t279507\tr1\t0\t+\n")
+            .unwrap();
+        assert_eq!(obs.strand, StrandRule::Positive);
+        // empty score column
+        let obs =
+            DmrInterval::parse_str("chr20\t279148\t279507\tr1\t\t-").unwrap();
+        assert_eq!(obs.name, "r1");
+        assert_eq!(obs.strand, StrandRule::Negative);
+        // CpG island table, 6th column is not a strand
+        let obs = DmrInterval::parse_str(
+            "chr20\t279148\t279507\tCpG: 39 359\t39\t260\t21.7\t72.4\t0.83",
+        )
+        .unwrap();
+        assert_eq!(obs.strand, StrandRule::Both);
+        let obs = DmrInterval::parse_str("chr20\t279148\t279507\tr1").unwrap();
+        assert_eq!(obs.strand, StrandRule::Both);
+
+        let split = obs.clone().split_strands();
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].strand, StrandRule::Positive);
+        assert_eq!(split[1].strand, StrandRule::Negative);
+        assert!(split.iter().all(|roi| roi.interval == obs.interval));
+        let stranded =
+            DmrInterval::parse_str("chr20\t279148\t279507\tr1\t0\t-").unwrap();
+        assert_eq!(stranded.clone().split_strands(), vec![stranded]);
+    }
+
     #[test]
     fn test_roi_parsing() {
         let fp = "tests/resources/sim_cpg_regions.bed";
@@ -443,6 +704,7 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "r1".to_string(),
+                strand: StrandRule::Both,
             },
             DmrInterval {
                 interval: Iv {
@@ -452,6 +714,7 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "r2".to_string(),
+                strand: StrandRule::Both,
             },
             DmrInterval {
                 interval: Iv {
@@ -461,6 +724,7 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "r3".to_string(),
+                strand: StrandRule::Both,
             },
         ]
         .to_vec();
@@ -480,6 +744,7 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "chr20:10172120-10172545".to_string(),
+                strand: StrandRule::Both,
             },
             DmrInterval {
                 interval: Iv {
@@ -489,6 +754,7 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "chr20:10217487-10218336".to_string(),
+                strand: StrandRule::Both,
             },
             DmrInterval {
                 interval: Iv {
@@ -498,9 +764,100 @@ mod dmr_util_tests {
                 },
                 chrom: "chr20".to_string(),
                 name: "chr20:10034963-10035266".to_string(),
+                strand: StrandRule::Both,
             },
         ]
         .to_vec();
         assert_eq!(rois, expected);
     }
+
+    #[test]
+    fn test_unindexed_bedmethyl_matches_indexed() {
+        let fp = Path::new("tests/resources/lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz").to_path_buf();
+        let index =
+            noodles::tabix::read(format!("{}.tbi", fp.to_str().unwrap()))
+                .unwrap();
+        let indexed = BedMethylSource::indexed(&fp, index).unwrap();
+        let chrom_id = *indexed.contig_lookup().get("chr20").unwrap() as u32;
+        let temp_dir =
+            std::env::temp_dir().join("test_unindexed_bedmethyl_copies");
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        // plain text copy of the same records
+        let plain_fp =
+            std::env::temp_dir().join("test_unindexed_bedmethyl.bed");
+        let mut plain = String::new();
+        MultiGzDecoder::new(File::open(&fp).unwrap())
+            .read_to_string(&mut plain)
+            .unwrap();
+        std::fs::write(&plain_fp, plain).unwrap();
+
+        let rois =
+            parse_roi_bed("tests/resources/sim_cpg_regions.bed").unwrap();
+        let everywhere = || {
+            let lapper = GenomeLapper::new(vec![Iv {
+                start: 0,
+                stop: u32::MAX as u64,
+                val: (),
+            }]);
+            let mut lappers = FxHashMap::default();
+            lappers.insert(chrom_id, lapper);
+            lappers
+        };
+        let position_filter = StrandedPositionFilter {
+            pos_positions: everywhere(),
+            neg_positions: everywhere(),
+        };
+        // gzip-compressed without the index and plain text
+        for unindexed_fp in [&fp, &plain_fp] {
+            let unindexed =
+                BedMethylSource::from_unindexed(unindexed_fp, &temp_dir)
+                    .unwrap();
+            assert!(unindexed.has_contig("chr20"));
+            assert_eq!(unindexed.path(), unindexed_fp);
+            for roi in rois.iter() {
+                let expected = indexed
+                    .get_counts(roi, chrom_id, &position_filter)
+                    .unwrap();
+                let obs = unindexed
+                    .get_counts(roi, chrom_id, &position_filter)
+                    .unwrap();
+                assert_eq!(obs.to_string(), expected.to_string());
+            }
+            // the temporary copy and index are removed with the source
+            assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 2);
+            drop(unindexed);
+            assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_unsorted_unindexed_bedmethyl() {
+        let record = |chrom: &str, start: u64| {
+            format!(
+                "{chrom}\t{start}\t{}\tm\t10\t+\t{start}\t{}\t255,0,0\t\
+                10\t50.00\t5\t5\t0\t0\t0\t0\t0\n",
+                start + 1,
+                start + 1
+            )
+        };
+        let temp_dir =
+            std::env::temp_dir().join("test_unsorted_bedmethyl_copies");
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        let fp = std::env::temp_dir().join("test_unsorted_bedmethyl.bed");
+        for (records, sorted) in [
+            (vec![("chr1", 10), ("chr1", 20), ("chr2", 5)], true),
+            (vec![("chr1", 20), ("chr1", 10)], false),
+            (vec![("chr1", 10), ("chr2", 5), ("chr1", 20)], false),
+        ] {
+            let lines = records
+                .into_iter()
+                .map(|(chrom, start)| record(chrom, start))
+                .collect::<String>();
+            std::fs::write(&fp, lines).unwrap();
+            let source = BedMethylSource::from_unindexed(&fp, &temp_dir);
+            assert_eq!(source.is_ok(), sorted);
+            drop(source);
+            assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        }
+    }
 }
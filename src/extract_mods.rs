@@ -1,43 +1,58 @@
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 
 use crate::command_utils::{
-    get_serial_reader, parse_edge_filter_input, using_stream,
+    get_serial_reader, get_threshold_from_options, parse_edge_filter_input,
+    parse_per_mod_thresholds, parse_thresholds, using_stream,
 };
+use anyhow::{anyhow, bail};
 use bio::io::fasta::Reader as FastaReader;
-use clap::Args;
+use clap::{ArgGroup, Args};
 use crossbeam_channel::{bounded, Sender};
 use derive_new::new;
 use indicatif::{MultiProgress, ParallelProgressIterator, ProgressIterator};
+use itertools::Itertools;
 use log::{debug, error, info};
 use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use rust_htslib::bam::{self, FetchDefinition, Read};
+use rustc_hash::FxHashSet;
 
+use crate::calibrate::CalibrationMap;
 use crate::errs::RunError;
 use crate::interval_chunks::IntervalChunks;
 use crate::logging::init_logging;
 use crate::mod_bam::{CollapseMethod, EdgeFilter, TrackingModRecordIter};
 use crate::mod_base_code::ModCode;
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::{
-    ModProfile, ReadBaseModProfile, ReadsBaseModProfile,
+    KmerWindow, ModProfile, ReadBaseModProfile, ReadsBaseModProfile,
 };
+use crate::read_summary::ReadSummary;
 use crate::reads_sampler::record_sampler::RecordSampler;
-use crate::reads_sampler::sample_reads_from_interval;
 use crate::reads_sampler::sampling_schedule::SamplingSchedule;
 use crate::record_processor::WithRecords;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
     get_master_progress_bar, get_reference_mod_strand, get_spinner,
-    get_subroutine_progress_bar, get_targets, get_ticker, ReferenceRecord,
-    Region, Strand,
+    get_subroutine_progress_bar, get_targets, get_ticker, parse_partition_tags,
+    ReferenceRecord, Region, SamTag, Strand,
 };
 use crate::writers::{
-    OutwriterWithMemory, TsvWriter, TsvWriterWithContigNames,
+    ExtractRowFormatter, OutwriterWithMemory, ParquetExtractWriter,
+    ReadSummaryWriter, SortedBgzfExtractWriter, TsvWriter,
+    TsvWriterWithContigNames,
 };
 
 #[derive(Args)]
+#[command(group(
+    ArgGroup::new("thresholded_outputs")
+        .args(["read_summary", "call_codes"])
+        .multiple(true)
+))]
 pub struct ExtractMods {
     /// Path to modBAM file to extract read-level information from, or one of `-` or
     /// `stdin` to specify a stream from standard input. If a file is used it may
@@ -74,6 +89,34 @@ pub struct ExtractMods {
     #[arg(long, alias = "ref")]
     reference: Option<PathBuf>,
 
+    /// Output base modification calls at only the sequence motifs provided.
+    /// The first argument should be the sequence motif and the second
+    /// argument is the 0-based offset to the base in the motif, for example
+    /// --motif CGCG 0 will output calls at the first C of CGCG motifs on the
+    /// top strand and the corresponding C on the bottom strand. This argument
+    /// can be passed multiple times. A `motif` column is added to the output
+    /// with the motifs matching each site. Requires a reference sequence and
+    /// implicitly only includes mapped sites.
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        num_args = 2,
+        requires = "reference"
+    )]
+    motif: Option<Vec<String>>,
+    /// Output calls at only CpG motifs, shorthand for --motif CG 0. Requires
+    /// a reference sequence.
+    #[arg(long, requires = "reference", default_value_t = false)]
+    cpg: bool,
+    /// Respect soft masking in the reference FASTA when finding motifs.
+    #[arg(
+        long,
+        requires = "reference",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    mask: bool,
+
     /// BED file with regions to include (alias: include-positions). Implicitly
     /// only includes mapped sites.
     #[arg(long, alias = "include-positions")]
@@ -95,6 +138,18 @@ pub struct ExtractMods {
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
 
+    /// Size of the sequence context in the `ref_kmer` and `query_kmer`
+    /// columns, must be odd. The k-mer is centered on the base.
+    #[arg(long, default_value_t = 5, hide_short_help = true)]
+    kmer_size: usize,
+    /// Asymmetric sequence context for the `ref_kmer` and `query_kmer`
+    /// columns, given as the number of bases upstream and downstream of the
+    /// base on the forward strand, e.g. 4,6. The same window is used for both
+    /// k-mers, the query k-mer is then reverse complemented for
+    /// negative-strand base modifications.
+    #[arg(long, conflicts_with = "kmer_size", hide_short_help = true)]
+    kmer_window: Option<String>,
+
     /// Ignore a modified base class  _in_situ_ by redistributing base modification
     /// probability equally across other options. For example, if collapsing 'h',
     /// with 'm' and canonical options, half of the probability of 'h' will be added to
@@ -121,24 +176,239 @@ pub struct ExtractMods {
     /// details see the SAM spec: https://samtools.github.io/hts-specs/SAMtags.pdf.
     #[arg(long, hide_short_help = true)]
     ignore_implicit: bool,
+
+    /// Output one row per read summarizing the base modification calls on the
+    /// read (number of sites, mean modification probability, fraction of
+    /// passing calls that are modified, etc.) instead of one row per base
+    /// modification call. See the online documentation for a description of
+    /// the columns.
+    #[arg(long, default_value_t = false)]
+    read_summary: bool,
+    /// BED file with regions to additionally summarize each read over. Adds
+    /// columns to the read summary table using only the sites overlapping
+    /// these regions.
+    #[arg(long, requires = "read_summary")]
+    summary_regions: Option<PathBuf>,
+    /// Add a column to the read summary table with the value of this SAM tag
+    /// (e.g. HP). This argument can be passed multiple times.
+    #[arg(
+        long,
+        requires = "read_summary",
+        action = clap::ArgAction::Append
+    )]
+    partition_tag: Option<Vec<String>>,
+
+    /// Add `call_code` and `fail` columns to the output with the base
+    /// modification call at each site, made with a pass threshold the same way
+    /// as `pileup`. The threshold is estimated from the reads unless
+    /// --filter-threshold or --no-filtering is passed.
+    #[arg(long, conflicts_with = "read_summary", default_value_t = false)]
+    call_codes: bool,
+    /// Include supplementary alignments when their MM/ML tags are valid for
+    /// the stored SEQ, either the MN tag matches the SEQ length or, without an
+    /// MN tag, the alignment is not hard-clipped. Rows from supplementary
+    /// alignments have the same read_id as the primary alignment. Secondary
+    /// alignments are always excluded.
+    #[arg(long, conflicts_with = "read_summary", default_value_t = false)]
+    include_supplementary: bool,
+    /// Calibrate the base modification probabilities with a calibration map
+    /// made by `modkit calibrate` before writing them. With --call-codes, the
+    /// pass threshold must be given with --filter-threshold (or use
+    /// --no-filtering).
+    #[arg(long, conflicts_with = "read_summary")]
+    calibration: Option<PathBuf>,
+    /// Write the output table as a Parquet file with typed columns instead of
+    /// a TSV. Requires an output file path.
+    #[arg(long, conflicts_with = "read_summary", default_value_t = false)]
+    parquet: bool,
+    /// Write the output table sorted by reference position, BGZF-compressed
+    /// and with a tabix index on the `chrom` and `ref_position` columns.
+    /// Requires an output file path, only sites aligned to the reference are
+    /// written.
+    #[arg(
+        long,
+        conflicts_with_all = ["read_summary", "parquet"],
+        default_value_t = false
+    )]
+    bgzf: bool,
+
+    /// Number of reads to use when estimating the filter threshold.
+    #[arg(long, default_value_t = 10_042, hide_short_help = true)]
+    sample_num_reads: usize,
+    /// Sample this fraction of the reads when estimating the filter threshold.
+    #[arg(long, hide_short_help = true)]
+    sampling_frac: Option<f64>,
+    /// Set a random seed for deterministic sampling when estimating the
+    /// filter threshold, the default is non-deterministic.
+    #[arg(long, requires = "sampling_frac", hide_short_help = true)]
+    seed: Option<u64>,
+    /// Do not perform any filtering, all base modification calls pass. See
+    /// filtering.md for details on filtering. Only used with --read-summary
+    /// or --call-codes.
+    #[arg(
+        group = "thresholds",
+        long,
+        requires = "thresholded_outputs",
+        default_value_t = false
+    )]
+    no_filtering: bool,
+    /// Filter out modified base calls where the probability of the predicted
+    /// variant is below this confidence percentile. For example, 0.1 will
+    /// filter out the 10% lowest confidence modification calls. Only used
+    /// with --read-summary or --call-codes.
+    #[arg(
+        group = "thresholds",
+        short = 'p',
+        long,
+        requires = "thresholded_outputs",
+        default_value_t = 0.1,
+        hide_short_help = true
+    )]
+    filter_percentile: f32,
+    /// Specify the filter threshold globally or per-base, the same as for
+    /// `pileup`. For example --filter-threshold C:0.75 or --filter-threshold
+    /// 0.8. Required when reading from a stream. Only used with
+    /// --read-summary or --call-codes.
+    #[arg(
+        long,
+        group = "thresholds",
+        requires = "thresholded_outputs",
+        action = clap::ArgAction::Append
+    )]
+    filter_threshold: Option<Vec<String>>,
+    /// Specify a passing threshold to use for a base modification, independent
+    /// of the threshold for the primary sequence base or the default. For
+    /// example, to set the pass threshold for 5hmC to 0.8 use
+    /// `--mod-threshold h:0.8`. Only used with --read-summary or
+    /// --call-codes.
+    #[arg(
+        long,
+        alias = "mod-threshold",
+        requires = "thresholded_outputs",
+        action = clap::ArgAction::Append
+    )]
+    mod_thresholds: Option<Vec<String>>,
 }
 
 type ReferenceAndIntervals = Vec<(ReferenceRecord, IntervalChunks)>;
+// read summaries, number of skipped records, number of failed records
+type SummaryBatch = (Vec<ReadSummary>, usize, usize);
 
 impl ExtractMods {
     fn using_stdin(&self) -> bool {
         using_stream(&self.in_bam)
     }
 
+    fn get_kmer_window(&self) -> anyhow::Result<KmerWindow> {
+        match self.kmer_window.as_ref() {
+            Some(raw) => KmerWindow::parse_str(raw),
+            None => KmerWindow::from_kmer_size(self.kmer_size),
+        }
+    }
+
+    fn parse_motifs(&self) -> anyhow::Result<Option<Vec<RegexMotif>>> {
+        let mut raw_motif_parts = self.motif.clone().unwrap_or(Vec::new());
+        if raw_motif_parts.len() % 2 != 0 {
+            bail!("illegal number of parts for motif")
+        }
+        if raw_motif_parts.chunks(2).counts().values().any(|&n| n > 1) {
+            bail!("cannot have the same motif more than once")
+        }
+        if self.cpg {
+            if raw_motif_parts.chunks(2).any(|motif| motif == ["CG", "0"]) {
+                info!("CG 0 motif already, ignoring --cpg");
+            } else {
+                raw_motif_parts
+                    .extend_from_slice(&["CG".to_string(), "0".to_string()]);
+            }
+        }
+        if raw_motif_parts.is_empty() {
+            return Ok(None);
+        }
+        raw_motif_parts
+            .chunks(2)
+            .map(|c| {
+                let focus_base = c[1].parse::<usize>().map_err(|e| {
+                    anyhow!("failed to parse motif offset {}, {e}", &c[1])
+                })?;
+                RegexMotif::parse_string(c[0].as_str(), focus_base)
+            })
+            .collect::<anyhow::Result<Vec<RegexMotif>>>()
+            .map(Some)
+    }
+
+    /// Find the motif positions in the reference sequences, returns None when
+    /// no motifs were requested.
+    fn load_motif_locations(
+        &self,
+        chrom_to_seq: &HashMap<String, Vec<u8>>,
+        name_to_tid: &HashMap<&str, u32>,
+        pool: &ThreadPool,
+    ) -> anyhow::Result<Option<MultipleMotifLocations>> {
+        let regex_motifs = match self.parse_motifs()? {
+            Some(regex_motifs) => regex_motifs,
+            None => return Ok(None),
+        };
+        let master_progress = MultiProgress::new();
+        if self.suppress_progress {
+            master_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        let sequences =
+            chrom_to_seq
+                .iter()
+                .filter_map(|(name, seq)| {
+                    name_to_tid.get(name.as_str()).and_then(|tid| {
+                        String::from_utf8(seq.to_vec())
+                            .map(|s| {
+                                if self.mask {
+                                    s
+                                } else {
+                                    s.to_ascii_uppercase()
+                                }
+                            })
+                            .ok()
+                            .map(|s| (s, *tid))
+                    })
+                })
+                .collect::<Vec<(String, u32)>>();
+        if sequences.is_empty() {
+            bail!("did not find any of the modBAM contigs in the reference")
+        }
+        let motif_locations = pool.install(|| {
+            regex_motifs
+                .into_par_iter()
+                .map(|regex_motif| {
+                    info!("filtering to {regex_motif} motifs");
+                    MotifLocations::from_sequences(
+                        regex_motif,
+                        None,
+                        &sequences,
+                        &master_progress,
+                    )
+                })
+                .collect::<anyhow::Result<Vec<MotifLocations>>>()
+        })?;
+
+        Ok(Some(MultipleMotifLocations::new(motif_locations)))
+    }
+
     fn load_regions(
         &self,
         name_to_tid: &HashMap<&str, u32>,
         region: Option<&Region>,
+        motif_locations: Option<Arc<MultipleMotifLocations>>,
     ) -> anyhow::Result<(Option<ReferenceAndIntervals>, ReferencePositionFilter)>
     {
         let include_unmapped = if self.include_bed.is_some() {
             info!("specifying include-only BED outputs only mapped sites");
             false
+        } else if motif_locations.is_some() {
+            info!("filtering to motifs outputs only mapped sites");
+            false
+        } else if self.bgzf {
+            info!("sorted and indexed output contains only mapped sites");
+            false
         } else {
             !self.mapped_only
         };
@@ -172,15 +442,33 @@ impl ExtractMods {
                     info!("found BAM index, processing reads in {} base pair chunks", self.interval_size);
                     let reference_records =
                         get_targets(reader.header(), region);
+                    // contigs without a hit for any of the motifs (e.g.
+                    // missing from the reference) cannot have any calls
+                    let references_with_hits =
+                        motif_locations.as_ref().map(|mls| {
+                            mls.motif_locations
+                                .iter()
+                                .flat_map(|ml| ml.references_with_hits())
+                                .collect::<FxHashSet<u32>>()
+                        });
                     let reference_and_intervals = reference_records
                         .into_iter()
+                        .filter(|reference_record| {
+                            references_with_hits
+                                .as_ref()
+                                .map(|tids| {
+                                    tids.contains(&reference_record.tid)
+                                })
+                                .unwrap_or(true)
+                        })
                         .map(|reference_record| {
                             let interval_chunks =
-                                IntervalChunks::new_without_motifs(
+                                IntervalChunks::new_with_multiple_motifs(
                                     reference_record.start,
                                     reference_record.length,
                                     self.interval_size,
                                     reference_record.tid,
+                                    motif_locations.as_deref(),
                                 );
                             (reference_record, interval_chunks)
                         })
@@ -201,6 +489,7 @@ impl ExtractMods {
         let reference_position_filter = ReferencePositionFilter::new(
             include_positions,
             exclude_positions,
+            motif_locations,
             include_unmapped,
         );
 
@@ -225,6 +514,29 @@ impl ExtractMods {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let kmer_window = self.get_kmer_window()?;
+        if self.calibration.is_some()
+            && self.call_codes
+            && self.filter_threshold.is_none()
+            && !self.no_filtering
+        {
+            bail!(
+                "the pass threshold is not estimated from calibrated \
+                 probabilities, use --filter-threshold or --no-filtering with \
+                 --calibration"
+            )
+        }
+        let calibration = self
+            .calibration
+            .as_ref()
+            .map(CalibrationMap::from_file)
+            .transpose()?;
+        if self.parquet && matches!(self.out_path.as_str(), "stdout" | "-") {
+            bail!("Parquet output requires an output file path")
+        }
+        if self.bgzf && matches!(self.out_path.as_str(), "stdout" | "-") {
+            bail!("sorted and indexed output requires an output file path")
+        }
 
         let mut reader = get_serial_reader(&self.in_bam)?;
         let header = reader.header().to_owned();
@@ -274,8 +586,42 @@ impl ExtractMods {
             .map(|raw_region| Region::parse_str(raw_region, &header))
             .transpose()?;
 
-        let (references_and_intervals, reference_position_filter) =
-            self.load_regions(&name_to_tid, region.as_ref())?;
+        let motif_locations = self
+            .load_motif_locations(&chrom_to_seq, &name_to_tid, &pool)?
+            .map(Arc::new);
+        let (references_and_intervals, reference_position_filter) = self
+            .load_regions(
+                &name_to_tid,
+                region.as_ref(),
+                motif_locations.clone(),
+            )?;
+
+        if self.read_summary {
+            return self.run_read_summary(
+                pool,
+                reader,
+                tid_to_name.clone(),
+                &name_to_tid,
+                region.as_ref(),
+                references_and_intervals,
+                reference_position_filter,
+                collapse_method,
+                edge_filter,
+            );
+        }
+        let threshold_caller = if self.call_codes {
+            let caller = pool.install(|| {
+                self.get_threshold_caller(
+                    region.as_ref(),
+                    collapse_method.as_ref(),
+                    edge_filter.as_ref(),
+                    &reference_position_filter,
+                )
+            })?;
+            Some(caller)
+        } else {
+            None
+        };
 
         // allowed to use the sampling schedule if there is an index, if
         // asked for num_reads with no index, scan first N reads
@@ -315,6 +661,7 @@ impl ExtractMods {
         let n_reads = self.num_reads;
         let threads = self.threads;
         let mapped_only = self.mapped_only;
+        let include_supplementary = self.include_supplementary;
         let in_bam = self.in_bam.clone();
 
         thread::spawn(move || {
@@ -379,9 +726,7 @@ impl ExtractMods {
                                             sampling_schedule.get_record_sampler(&reference_record, total_interval_length, start, end)
                                     }).unwrap_or(RecordSampler::new_passthrough());
 
-                                    let batch_result = sample_reads_from_interval::<
-                                        ReadsBaseModProfile,
-                                    >(
+                                    let batch_result = Self::process_interval(
                                         &bam_fp,
                                         reference_record.tid,
                                         start,
@@ -389,8 +734,9 @@ impl ExtractMods {
                                         record_sampler,
                                         collapse_method.as_ref(),
                                         edge_filter.as_ref(),
-                                        None,
-                                        false,
+                                        &kmer_window,
+                                        include_supplementary,
+                                        calibration.as_ref(),
                                     ).map(|reads_base_mod_profile| {
                                         reference_position_filter.filter_read_base_mod_probs(reads_base_mod_profile)
                                     });
@@ -433,7 +779,10 @@ impl ExtractMods {
                                     n_unmapped_reads,
                                     collapse_method.as_ref(),
                                     edge_filter.as_ref(),
+                                    &kmer_window,
+                                    false,
                                     false,
+                                    calibration.as_ref(),
                                     "unmapped "
                                 );
                                 let _ = snd.send(Ok(ReadsBaseModProfile::new(Vec::new(), skip, fail)));
@@ -452,7 +801,10 @@ impl ExtractMods {
                         n_reads,
                         collapse_method.as_ref(),
                             edge_filter.as_ref(),
+                            &kmer_window,
                             mapped_only,
+                            include_supplementary,
+                            calibration.as_ref(),
                             "",
                     );
                     let _ = snd.send(Ok(ReadsBaseModProfile::new(Vec::new(), skip, fail)));
@@ -460,16 +812,58 @@ impl ExtractMods {
             })
         });
 
+        let mut extra_columns = Vec::new();
+        if motif_locations.is_some() {
+            extra_columns.push("motif");
+        }
+        if threshold_caller.is_some() {
+            extra_columns.extend(["call_code", "fail"]);
+        }
+        let header = ModProfile::header(&extra_columns);
         let mut writer: Box<dyn OutwriterWithMemory<ReadsBaseModProfile>> =
             match self.out_path.as_str() {
+                out_path if self.parquet => {
+                    let writer = ParquetExtractWriter::new(
+                        out_path,
+                        self.force,
+                        tid_to_name,
+                        chrom_to_seq,
+                        kmer_window,
+                        threshold_caller,
+                        motif_locations,
+                    )?;
+                    Box::new(writer)
+                }
+                out_path if self.bgzf => {
+                    let writer = SortedBgzfExtractWriter::new(
+                        out_path,
+                        self.force,
+                        header,
+                        ExtractRowFormatter::new(
+                            tid_to_name,
+                            chrom_to_seq,
+                            HashSet::new(),
+                            HashSet::new(),
+                            kmer_window,
+                            threshold_caller,
+                            motif_locations,
+                        ),
+                    )?;
+                    Box::new(writer)
+                }
                 "stdout" | "-" => {
-                    let tsv_writer =
-                        TsvWriter::new_stdout(Some(ModProfile::header()));
+                    let tsv_writer = TsvWriter::new_stdout(Some(header));
                     let writer = TsvWriterWithContigNames::new(
                         tsv_writer,
-                        tid_to_name,
-                        chrom_to_seq,
-                        HashSet::new(),
+                        ExtractRowFormatter::new(
+                            tid_to_name,
+                            chrom_to_seq,
+                            HashSet::new(),
+                            HashSet::new(),
+                            kmer_window,
+                            threshold_caller,
+                            motif_locations,
+                        ),
                     );
                     Box::new(writer)
                 }
@@ -477,13 +871,19 @@ impl ExtractMods {
                     let tsv_writer = TsvWriter::new_file(
                         &self.out_path,
                         self.force,
-                        Some(ModProfile::header()),
+                        Some(header),
                     )?;
                     let writer = TsvWriterWithContigNames::new(
                         tsv_writer,
-                        tid_to_name,
-                        chrom_to_seq,
-                        HashSet::new(),
+                        ExtractRowFormatter::new(
+                            tid_to_name,
+                            chrom_to_seq,
+                            HashSet::new(),
+                            HashSet::new(),
+                            kmer_window,
+                            threshold_caller,
+                            motif_locations,
+                        ),
                     );
                     Box::new(writer)
                 }
@@ -516,6 +916,7 @@ impl ExtractMods {
                 }
             }
         }
+        writer.finish()?;
         n_failed.finish_and_clear();
         n_skipped.finish_and_clear();
         n_used.finish_and_clear();
@@ -530,6 +931,308 @@ impl ExtractMods {
         Ok(())
     }
 
+    fn get_threshold_caller(
+        &self,
+        region: Option<&Region>,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        reference_position_filter: &ReferencePositionFilter,
+    ) -> anyhow::Result<MultipleThresholdModCaller> {
+        let per_mod_thresholds = self
+            .mod_thresholds
+            .as_ref()
+            .map(|raw_per_mod_thresholds| {
+                parse_per_mod_thresholds(raw_per_mod_thresholds)
+            })
+            .transpose()?;
+        if let Some(raw_threshold) = &self.filter_threshold {
+            parse_thresholds(raw_threshold, per_mod_thresholds)
+        } else if self.no_filtering {
+            info!("not performing filtering");
+            Ok(MultipleThresholdModCaller::new_passthrough())
+        } else if self.using_stdin() {
+            bail!(
+                "cannot estimate the filter threshold from a stream, \
+                use --filter-threshold or --no-filtering"
+            )
+        } else {
+            get_threshold_from_options(
+                &PathBuf::from(&self.in_bam),
+                self.threads,
+                self.interval_size,
+                self.sampling_frac,
+                self.sample_num_reads,
+                self.no_filtering,
+                self.filter_percentile,
+                self.seed,
+                region,
+                per_mod_thresholds,
+                edge_filter,
+                collapse_method,
+                reference_position_filter.include_pos.as_ref(),
+                !reference_position_filter.include_unmapped,
+                self.suppress_progress,
+            )
+        }
+    }
+
+    fn run_read_summary(
+        &self,
+        pool: ThreadPool,
+        mut reader: bam::Reader,
+        tid_to_name: HashMap<u32, String>,
+        name_to_tid: &HashMap<&str, u32>,
+        region: Option<&Region>,
+        references_and_intervals: Option<ReferenceAndIntervals>,
+        reference_position_filter: ReferencePositionFilter,
+        collapse_method: Option<CollapseMethod>,
+        edge_filter: Option<EdgeFilter>,
+    ) -> anyhow::Result<()> {
+        let threshold_caller = pool.install(|| {
+            self.get_threshold_caller(
+                region,
+                collapse_method.as_ref(),
+                edge_filter.as_ref(),
+                &reference_position_filter,
+            )
+        })?;
+        let partition_tags = self
+            .partition_tag
+            .as_ref()
+            .map(|raw_tags| parse_partition_tags(raw_tags))
+            .transpose()?
+            .unwrap_or(Vec::new());
+        let summary_regions = self
+            .summary_regions
+            .as_ref()
+            .map(|fp| {
+                StrandedPositionFilter::from_bed_file(
+                    fp,
+                    name_to_tid,
+                    self.suppress_progress,
+                )
+            })
+            .transpose()?;
+        let header = ReadSummary::header(
+            &self.partition_tag.clone().unwrap_or(Vec::new()),
+            summary_regions.is_some(),
+        );
+        let mut writer: Box<dyn OutwriterWithMemory<Vec<ReadSummary>>> =
+            match self.out_path.as_str() {
+                "stdout" | "-" => Box::new(ReadSummaryWriter::new(
+                    TsvWriter::new_stdout(Some(header)),
+                    tid_to_name,
+                    HashSet::new(),
+                )),
+                _ => Box::new(ReadSummaryWriter::new(
+                    TsvWriter::new_file(
+                        &self.out_path,
+                        self.force,
+                        Some(header),
+                    )?,
+                    tid_to_name,
+                    HashSet::new(),
+                )),
+            };
+
+        let summarizer = ReadSummarizer::new(
+            reference_position_filter,
+            collapse_method,
+            edge_filter,
+            threshold_caller,
+            partition_tags,
+            summary_regions,
+        );
+
+        let multi_prog = MultiProgress::new();
+        if self.suppress_progress {
+            multi_prog.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        let n_failed = multi_prog.add(get_ticker());
+        n_failed.set_message("~records failed");
+        let n_skipped = multi_prog.add(get_ticker());
+        n_skipped.set_message("~records skipped");
+        let n_rows = multi_prog.add(get_ticker());
+        n_rows.set_message("reads written");
+
+        let (snd, rcv) = bounded(1_000);
+        let in_bam = self.in_bam.clone();
+        let threads = self.threads;
+        let mapped_only = self.mapped_only;
+        let n_reads = self.num_reads;
+        let progress = multi_prog.clone();
+        thread::spawn(move || {
+            pool.install(|| {
+                if let Some(reference_and_intervals) = references_and_intervals
+                {
+                    drop(reader);
+                    let bam_fp = std::path::Path::new(&in_bam).to_path_buf();
+                    let master_progress = progress.add(
+                        get_master_progress_bar(reference_and_intervals.len()),
+                    );
+                    master_progress.set_message("contigs");
+                    for (reference_record, interval_chunks) in
+                        reference_and_intervals
+                    {
+                        let interval_chunks = interval_chunks
+                            .filter(|(start, end)| {
+                                summarizer
+                                    .reference_position_filter
+                                    .include_pos
+                                    .as_ref()
+                                    .map(|pf| {
+                                        pf.overlaps_not_stranded(
+                                            reference_record.tid,
+                                            *start as u64,
+                                            *end as u64,
+                                        )
+                                    })
+                                    .unwrap_or(true)
+                            })
+                            .collect::<Vec<(u32, u32)>>();
+                        let interval_pb = progress.add(
+                            get_subroutine_progress_bar(interval_chunks.len()),
+                        );
+                        interval_pb.set_message(format!(
+                            "processing {}",
+                            &reference_record.name
+                        ));
+                        interval_chunks
+                            .into_par_iter()
+                            .progress_with(interval_pb)
+                            .for_each(|(start, end)| {
+                                let reader =
+                                    bam::IndexedReader::from_path(&bam_fp)
+                                        .and_then(|mut reader| {
+                                            reader
+                                                .fetch(FetchDefinition::Region(
+                                                    reference_record.tid as i32,
+                                                    start as i64,
+                                                    end as i64,
+                                                ))
+                                                .map(|_| reader)
+                                        });
+                                match reader {
+                                    Ok(mut reader) => summarizer
+                                        .summarize_records(
+                                            reader.records(),
+                                            true,
+                                            None,
+                                            &snd,
+                                        ),
+                                    Err(e) => {
+                                        let _ = snd.send(Err(anyhow!(e)));
+                                    }
+                                }
+                            });
+                        master_progress.inc(1);
+                    }
+                    if summarizer.reference_position_filter.include_unmapped {
+                        let reader = bam::IndexedReader::from_path(&bam_fp)
+                            .and_then(|mut reader| {
+                                reader
+                                    .fetch(FetchDefinition::Unmapped)
+                                    .map(|_| reader)
+                            })
+                            .and_then(|mut reader| {
+                                reader.set_threads(threads).map(|_| reader)
+                            });
+                        match reader {
+                            Ok(mut reader) => summarizer.summarize_records(
+                                reader.records(),
+                                false,
+                                None,
+                                &snd,
+                            ),
+                            Err(e) => {
+                                error!(
+                                    "failed to get indexed reader for unmapped \
+                                    read processing, {}",
+                                    e.to_string()
+                                );
+                            }
+                        }
+                    }
+                } else {
+                    match reader.set_threads(threads) {
+                        Ok(_) => summarizer.summarize_records(
+                            reader.records(),
+                            mapped_only,
+                            n_reads,
+                            &snd,
+                        ),
+                        Err(e) => {
+                            let _ = snd.send(Err(anyhow!(e)));
+                        }
+                    }
+                }
+            })
+        });
+
+        for result in rcv {
+            match result {
+                Ok((mut summaries, num_skipped, num_failed)) => {
+                    n_failed.inc(num_failed as u64);
+                    n_skipped.inc(num_skipped as u64);
+                    if let Some(n) = n_reads {
+                        let remaining =
+                            n.checked_sub(writer.num_reads()).unwrap_or(0);
+                        summaries.truncate(remaining);
+                    }
+                    match writer.write(summaries) {
+                        Ok(n) => n_rows.inc(n),
+                        Err(e) => {
+                            error!("failed to write {}", e.to_string());
+                        }
+                    }
+                }
+                Err(e) => {
+                    debug!("failed to summarize reads, {}", e.to_string());
+                }
+            }
+        }
+        n_failed.finish_and_clear();
+        n_skipped.finish_and_clear();
+        n_rows.finish_and_clear();
+        info!(
+            "wrote {} reads, skipped ~{} reads, failed ~{} reads",
+            writer.num_reads(),
+            n_skipped.position(),
+            n_failed.position()
+        );
+        Ok(())
+    }
+
+    fn process_interval(
+        bam_fp: &PathBuf,
+        chrom_tid: u32,
+        start: u32,
+        end: u32,
+        record_sampler: RecordSampler,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        kmer_window: &KmerWindow,
+        include_supplementary: bool,
+        calibration: Option<&CalibrationMap>,
+    ) -> anyhow::Result<ReadsBaseModProfile> {
+        let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
+        bam_reader.fetch(FetchDefinition::Region(
+            chrom_tid as i32,
+            start as i64,
+            end as i64,
+        ))?;
+        ReadsBaseModProfile::process_records_with_kmer_window(
+            bam_reader.records(),
+            false,
+            record_sampler,
+            collapse_method,
+            edge_filter,
+            kmer_window,
+            include_supplementary,
+            calibration,
+        )
+    }
+
     fn process_records_to_chan<'a, T: Read>(
         records: bam::Records<T>,
         multi_pb: &MultiProgress,
@@ -538,10 +1241,14 @@ impl ExtractMods {
         n_reads: Option<usize>,
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
+        kmer_window: &KmerWindow,
         only_mapped: bool,
+        include_supplementary: bool,
+        calibration: Option<&CalibrationMap>,
         message: &'static str,
     ) -> (usize, usize) {
-        let mut mod_iter = TrackingModRecordIter::new(records, false);
+        let mut mod_iter =
+            TrackingModRecordIter::new(records, false, include_supplementary);
         let pb = multi_pb.add(get_spinner());
         pb.set_message(format!("{message}records processed"));
         for (record, read_id, mod_base_info) in &mut mod_iter {
@@ -554,6 +1261,8 @@ impl ExtractMods {
                 mod_base_info,
                 collapse_method,
                 edge_filter,
+                kmer_window,
+                calibration,
             ) {
                 Ok(mod_profile) => {
                     ReadsBaseModProfile::new(vec![mod_profile], 0, 0)
@@ -597,9 +1306,104 @@ impl ExtractMods {
 struct ReferencePositionFilter {
     include_pos: Option<StrandedPositionFilter>,
     exclude_pos: Option<StrandedPositionFilter>,
+    motif_locations: Option<Arc<MultipleMotifLocations>>,
     include_unmapped: bool,
 }
 
+/// Per-read processing for `extract --read-summary`.
+#[derive(new)]
+struct ReadSummarizer {
+    reference_position_filter: ReferencePositionFilter,
+    collapse_method: Option<CollapseMethod>,
+    edge_filter: Option<EdgeFilter>,
+    threshold_caller: MultipleThresholdModCaller,
+    partition_tags: Vec<SamTag>,
+    summary_regions: Option<StrandedPositionFilter>,
+}
+
+impl ReadSummarizer {
+    const BATCH_SIZE: usize = 10_000;
+
+    /// Summarize the reads, sending batches of read summaries along with
+    /// the number of skipped and failed records to the writer. Stops after
+    /// `n_reads` reads have been summarized, when provided.
+    fn summarize_records<T: Read>(
+        &self,
+        records: bam::Records<T>,
+        only_mapped: bool,
+        n_reads: Option<usize>,
+        snd: &Sender<anyhow::Result<SummaryBatch>>,
+    ) {
+        let mut mod_iter = TrackingModRecordIter::new(records, false, false);
+        let mut summaries = Vec::new();
+        let mut n_summarized = 0usize;
+        let mut n_skipped = 0usize;
+        let mut n_failed = 0usize;
+        for (record, read_id, mod_base_info) in &mut mod_iter {
+            if record.is_unmapped() && only_mapped {
+                continue;
+            }
+            match ReadBaseModProfile::process_record(
+                &record,
+                &read_id,
+                mod_base_info,
+                self.collapse_method.as_ref(),
+                self.edge_filter.as_ref(),
+                &KmerWindow::default(),
+                None,
+            ) {
+                Ok(read_base_mod_profile) => {
+                    let read_base_mod_profile = self
+                        .reference_position_filter
+                        .filter_read_base_mod_profile(read_base_mod_profile);
+                    let read_summary = ReadSummary::from_record(
+                        &record,
+                        read_base_mod_profile,
+                        &self.partition_tags,
+                        &self.threshold_caller,
+                        self.summary_regions.as_ref(),
+                    );
+                    if read_summary.is_empty() {
+                        n_skipped += 1;
+                    } else {
+                        summaries.push(read_summary);
+                        n_summarized += 1;
+                    }
+                }
+                Err(RunError::Skipped(_)) => n_skipped += 1,
+                Err(_) => n_failed += 1,
+            }
+            if summaries.len() >= Self::BATCH_SIZE {
+                let batch = std::mem::take(&mut summaries);
+                Self::send_batch(snd, (batch, n_skipped, n_failed));
+                n_skipped = 0;
+                n_failed = 0;
+            }
+            if n_reads.map(|n| n_summarized >= n).unwrap_or(false) {
+                debug!("stopping after summarizing {n_summarized} reads");
+                break;
+            }
+        }
+        Self::send_batch(
+            snd,
+            (
+                summaries,
+                mod_iter.num_skipped + n_skipped,
+                mod_iter.num_failed + n_failed,
+            ),
+        );
+    }
+
+    fn send_batch(
+        snd: &Sender<anyhow::Result<SummaryBatch>>,
+        batch: SummaryBatch,
+    ) {
+        if let Err(e) = snd.send(Ok(batch)) {
+            error!("failed to send results to writer, {}", e.to_string());
+        }
+    }
+}
+
 impl ReferencePositionFilter {
     fn keep(
         &self,
@@ -624,8 +1428,53 @@ impl ReferencePositionFilter {
                 filt.contains(chrom_id as i32, position, reference_mod_strand)
             })
             .unwrap_or(false);
+        let motif_hit = self
+            .motif_locations
+            .as_ref()
+            .map(|mls| {
+                mls.motif_idxs_for_position(
+                    chrom_id,
+                    position as u32,
+                    reference_mod_strand,
+                )
+                .is_some()
+            })
+            .unwrap_or(true);
 
-        include_hit && !exclude_hit
+        include_hit && !exclude_hit && motif_hit
+    }
+
+    fn filter_read_base_mod_profile(
+        &self,
+        read_base_mod_profile: ReadBaseModProfile,
+    ) -> ReadBaseModProfile {
+        let read_name = read_base_mod_profile.record_name;
+        let chrom_id = read_base_mod_profile.chrom_id;
+        let profile = read_base_mod_profile
+            .profile
+            .into_par_iter()
+            .filter(|mod_profile| {
+                match (
+                    chrom_id,
+                    mod_profile.ref_position,
+                    mod_profile.alignment_strand,
+                ) {
+                    (Some(chrom_id), Some(ref_pos), Some(strand)) => self.keep(
+                        chrom_id,
+                        ref_pos as u64,
+                        strand,
+                        mod_profile.mod_strand,
+                    ),
+                    _ => self.include_unmapped,
+                }
+            })
+            .collect::<Vec<ModProfile>>();
+        ReadBaseModProfile::new(
+            read_name,
+            chrom_id,
+            profile,
+            read_base_mod_profile.supplementary_start,
+        )
     }
 
     fn filter_read_base_mod_probs(
@@ -638,30 +1487,7 @@ impl ReferencePositionFilter {
             .profiles
             .into_par_iter()
             .map(|read_base_mod_profile| {
-                let read_name = read_base_mod_profile.record_name;
-                let chrom_id = read_base_mod_profile.chrom_id;
-                let profile = read_base_mod_profile
-                    .profile
-                    .into_par_iter()
-                    .filter(|mod_profile| {
-                        match (
-                            chrom_id,
-                            mod_profile.ref_position,
-                            mod_profile.alignment_strand,
-                        ) {
-                            (Some(chrom_id), Some(ref_pos), Some(strand)) => {
-                                self.keep(
-                                    chrom_id,
-                                    ref_pos as u64,
-                                    strand,
-                                    mod_profile.mod_strand,
-                                )
-                            }
-                            _ => self.include_unmapped,
-                        }
-                    })
-                    .collect::<Vec<ModProfile>>();
-                ReadBaseModProfile::new(read_name, chrom_id, profile)
+                self.filter_read_base_mod_profile(read_base_mod_profile)
             })
             .collect::<Vec<ReadBaseModProfile>>();
         let empty = profiles
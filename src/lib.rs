@@ -14,12 +14,16 @@ pub mod threshold_mod_caller;
 pub mod thresholds;
 pub mod writers;
 
+mod calibrate;
 pub(crate) mod command_utils;
 mod dmr;
 mod extract_mods;
+mod merge_tags;
 pub(crate) mod parsing_utils;
 mod read_cache;
 mod read_ids_to_base_mod_probs;
+mod read_matrix;
+mod read_summary;
 /// Module contains functions for parallel processing
 /// of individual reads and aggregating the results.
 mod reads_sampler;
@@ -0,0 +1,417 @@
+use crate::logging::init_logging;
+use crate::mod_bam::{
+    format_mm_ml_tag, push_mod_tags, remove_all_mod_tags, BaseModProbs,
+    DeltaListConverter, ModBaseInfo, SeqPosBaseModProbs, SkipMode,
+};
+use crate::repair_tags::{RecordPair, ZipRecordsIter};
+use crate::util::{
+    get_forward_sequence, get_query_name_string, get_ticker, Strand,
+};
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, ValueEnum};
+use indicatif::MultiProgress;
+use itertools::Itertools;
+use log::{debug, error, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+use rustc_hash::FxHashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Number of record pairs merged in parallel before the merged records are
+/// written, in order.
+const MERGE_BATCH_SIZE: usize = 1000;
+
+#[derive(Args)]
+pub struct MergeTags {
+    /// First modBAM, must be sorted by read name. The output records are
+    /// copies of the records in this modBAM with the merged MM/ML tags.
+    #[arg(long = "bam-a", short = 'a')]
+    bam_a: PathBuf,
+    /// Second modBAM, must be sorted by read name. Every read in the first
+    /// modBAM must be present in this modBAM, extra reads are allowed.
+    #[arg(long = "bam-b", short = 'b')]
+    bam_b: PathBuf,
+    /// Output modBAM location.
+    #[arg(long, short = 'o', alias = "output")]
+    output_bam: PathBuf,
+    /// How to resolve a base modification probability for the same position
+    /// and modification code present in both modBAMs.
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::PreferA)]
+    conflict: ConflictPolicy,
+    /// File to write logs to, it is recommended to use this option as some
+    /// reads may be rejected and logged here.
+    #[arg(long)]
+    log_filepath: Option<PathBuf>,
+    /// The number of threads to use.
+    #[arg(long, short = 't', default_value_t = 4)]
+    threads: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the probability from the first modBAM.
+    PreferA,
+    /// Keep the probability from the second modBAM.
+    PreferB,
+    /// Keep the larger of the two probabilities.
+    Max,
+}
+
+impl ConflictPolicy {
+    fn resolve(&self, prob_a: f32, prob_b: f32) -> f32 {
+        match self {
+            Self::PreferA => prob_a,
+            Self::PreferB => prob_b,
+            Self::Max => prob_a.max(prob_b),
+        }
+    }
+}
+
+impl MergeTags {
+    pub(crate) fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+
+        let reader_threads = {
+            let half = self.threads / 2;
+            std::cmp::min(half, 16)
+        };
+        let threads_per_reader = std::cmp::max(reader_threads / 2, 1);
+        let pool_threads =
+            self.threads.checked_sub(reader_threads).unwrap_or(1);
+
+        let mut a_records = bam::Reader::from_path(&self.bam_a)?;
+        a_records.set_threads(threads_per_reader)?;
+        let mut b_records = bam::Reader::from_path(&self.bam_b)?;
+        b_records.set_threads(threads_per_reader)?;
+        let header = bam::Header::from_template(a_records.header());
+        let mut writer = bam::Writer::from_path(
+            &self.output_bam,
+            &header,
+            bam::Format::Bam,
+        )?;
+        info!(
+            "merging base modification calls in {} and {}",
+            &self.bam_a.to_str().unwrap_or_else(|| "??"),
+            &self.bam_b.to_str().unwrap_or_else(|| "??")
+        );
+
+        let master_progress = MultiProgress::new();
+        let b_ticker = master_progress.add(get_ticker());
+        b_ticker.set_message("~records processed from B");
+        let a_ticker = master_progress.add(get_ticker());
+        a_ticker.set_message("~records processed from A");
+        let written_ticker = master_progress
+            .add(get_ticker())
+            .with_message("~records written");
+
+        // the zip iterator requires the "acceptor" reads to be a subset of
+        // the "donor" reads, the output records come from A
+        let (pair_snd, pair_rcv) = std::sync::mpsc::sync_channel(1000);
+        std::thread::spawn(move || {
+            let pair_iter = ZipRecordsIter::new(
+                b_records.records(),
+                a_records.records(),
+                b_ticker,
+                a_ticker,
+            );
+            for pair in pair_iter {
+                if let Err(e) = pair_snd.send(pair) {
+                    error!(
+                        "failed to send record pair on channel, {}",
+                        e.to_string()
+                    );
+                }
+            }
+        });
+
+        let (merged_snd, merged_rcv) = std::sync::mpsc::sync_channel(1000);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(pool_threads)
+            .build()
+            .context("failed to make thread pool")?;
+        let conflict = self.conflict;
+        std::thread::spawn(move || {
+            pool.install(|| {
+                for batch in &pair_rcv.into_iter().chunks(MERGE_BATCH_SIZE) {
+                    let merged = batch
+                        .collect::<Vec<RecordPair>>()
+                        .into_par_iter()
+                        .map(|pair| merge_record_pair(pair, conflict))
+                        .collect::<Vec<_>>();
+                    for res in merged {
+                        if let Err(e) = merged_snd.send(res) {
+                            error!(
+                                "failed to send merged record on channel, {}",
+                                e.to_string()
+                            );
+                        }
+                    }
+                }
+            })
+        });
+
+        let mut n_merged = 0usize;
+        let mut n_failed = 0usize;
+        for res in merged_rcv {
+            match res {
+                Ok(record) => {
+                    if let Err(e) = writer.write(&record) {
+                        error!("failed to write record {}", e.to_string());
+                        n_failed += 1;
+                    } else {
+                        written_ticker.inc(1);
+                        n_merged += 1;
+                    }
+                }
+                Err(e) => {
+                    debug!("record failed to be merged: {}", e.to_string());
+                    n_failed += 1;
+                }
+            }
+        }
+
+        info!("finished, merged {n_merged} records, {n_failed} failed.");
+        Ok(())
+    }
+}
+
+/// Combine the calls for one primary base and strand from two records. The
+/// merged calls are in '?' mode unless both inputs use '.' (or implied '.')
+/// mode. When only one input is in '?' mode, the implied canonical calls of
+/// the other are made explicit first so they aren't lost. When the summed
+/// modification probabilities at a position exceed 1 they're scaled down to
+/// sum to 1.
+fn merge_seq_pos_base_mod_probs(
+    a: SeqPosBaseModProbs,
+    b: SeqPosBaseModProbs,
+    conflict: ConflictPolicy,
+    forward_sequence: &str,
+    primary_base: char,
+) -> SeqPosBaseModProbs {
+    let (a, b) = match (a.skip_mode, b.skip_mode) {
+        (SkipMode::Ambiguous, SkipMode::Ambiguous) => (a, b),
+        (SkipMode::Ambiguous, _) | (_, SkipMode::Ambiguous) => {
+            let make_explicit = |probs: SeqPosBaseModProbs| {
+                probs.add_implicit_mod_calls(
+                    forward_sequence,
+                    primary_base,
+                    &HashSet::new(),
+                    None,
+                )
+            };
+            (make_explicit(a), make_explicit(b))
+        }
+        _ => (a, b),
+    };
+    let skip_mode = match (a.skip_mode, b.skip_mode) {
+        (SkipMode::ImplicitProbModified, SkipMode::ImplicitProbModified) => {
+            SkipMode::ImplicitProbModified
+        }
+        (SkipMode::Ambiguous, _) | (_, SkipMode::Ambiguous) => {
+            SkipMode::Ambiguous
+        }
+        _ => SkipMode::ProbModified,
+    };
+
+    let mut pos_to_probs = a
+        .pos_to_base_mod_probs
+        .into_iter()
+        .map(|(pos, probs)| {
+            let probs = probs
+                .iter_probs()
+                .map(|(code, prob)| (*code, *prob))
+                .collect::<FxHashMap<char, f32>>();
+            (pos, probs)
+        })
+        .collect::<FxHashMap<usize, FxHashMap<char, f32>>>();
+    for (pos, probs_b) in b.pos_to_base_mod_probs {
+        let probs = pos_to_probs.entry(pos).or_insert(FxHashMap::default());
+        for (code, prob_b) in probs_b.iter_probs() {
+            probs
+                .entry(*code)
+                .and_modify(|prob_a| {
+                    *prob_a = conflict.resolve(*prob_a, *prob_b)
+                })
+                .or_insert(*prob_b);
+        }
+    }
+
+    let pos_to_base_mod_probs = pos_to_probs
+        .into_iter()
+        .map(|(pos, mut probs)| {
+            let total = probs.values().sum::<f32>();
+            if total > 1f32 {
+                probs.values_mut().for_each(|p| *p /= total);
+            }
+            (pos, BaseModProbs::new(probs))
+        })
+        .collect::<FxHashMap<usize, BaseModProbs>>();
+
+    SeqPosBaseModProbs::new(pos_to_base_mod_probs, skip_mode)
+}
+
+fn merge_record_pair(
+    record_pair: RecordPair,
+    conflict: ConflictPolicy,
+) -> anyhow::Result<bam::Record> {
+    let read_name = get_query_name_string(&record_pair.acceptor)
+        .unwrap_or_else(|e| {
+            format!("failed to parse query name, {}", e.to_string())
+        });
+    let seq_a = get_forward_sequence(&record_pair.acceptor).map_err(|e| {
+        anyhow!(
+            "sequence in A for record {read_name} failed, {}",
+            e.to_string()
+        )
+    })?;
+    let seq_b = get_forward_sequence(&record_pair.donor).map_err(|e| {
+        anyhow!(
+            "sequence in B for record {read_name} failed, {}",
+            e.to_string()
+        )
+    })?;
+    if seq_a != seq_b {
+        bail!("sequences for record {read_name} do not match")
+    }
+    let modbase_info_a = ModBaseInfo::new_from_record(&record_pair.acceptor)
+        .map_err(|e| {
+            anyhow!("record {read_name} in A failed, {}", e.to_string())
+        })?;
+    let modbase_info_b = ModBaseInfo::new_from_record(&record_pair.donor)
+        .map_err(|e| {
+            anyhow!("record {read_name} in B failed, {}", e.to_string())
+        })?;
+    let mm_style = modbase_info_a.mm_style;
+    let ml_style = modbase_info_a.ml_style;
+
+    let (_, iter_a) = modbase_info_a.into_iter_base_mod_probs();
+    let mut merged = iter_a
+        .map(|(primary_base, strand, probs)| ((primary_base, strand), probs))
+        .collect::<FxHashMap<(char, Strand), SeqPosBaseModProbs>>();
+    let (_, iter_b) = modbase_info_b.into_iter_base_mod_probs();
+    for (primary_base, strand, probs_b) in iter_b {
+        let key = (primary_base, strand);
+        let probs = match merged.remove(&key) {
+            Some(probs_a) => merge_seq_pos_base_mod_probs(
+                probs_a,
+                probs_b,
+                conflict,
+                &seq_a,
+                primary_base,
+            ),
+            None => probs_b,
+        };
+        merged.insert(key, probs);
+    }
+
+    let mut mm_agg = String::new();
+    let mut ml_agg = Vec::new();
+    for ((primary_base, strand), seq_pos_base_mod_probs) in
+        merged.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b))
+    {
+        let converter = DeltaListConverter::new(&seq_a, primary_base);
+        let (mm, mut ml) =
+            format_mm_ml_tag(seq_pos_base_mod_probs, strand, &converter);
+        mm_agg.push_str(&mm);
+        ml_agg.extend_from_slice(&mut ml);
+    }
+
+    let mut merged_record = record_pair.acceptor;
+    remove_all_mod_tags(&mut merged_record);
+    push_mod_tags(&mut merged_record, mm_style, ml_style, &mm_agg, &ml_agg)?;
+    Ok(merged_record)
+}
+
+#[cfg(test)]
+mod merge_tags_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::merge_tags::{merge_seq_pos_base_mod_probs, ConflictPolicy};
+    use crate::mod_bam::{BaseModProbs, SeqPosBaseModProbs, SkipMode};
+
+    // the C positions are 1, 5, and 9
+    const SEQ: &str = "ACGTACGTACG";
+
+    fn seq_pos_probs(
+        calls: &[(usize, char, f32)],
+        skip_mode: SkipMode,
+    ) -> SeqPosBaseModProbs {
+        let mut pos_to_probs = FxHashMap::<usize, BaseModProbs>::default();
+        for (pos, code, prob) in calls {
+            pos_to_probs
+                .entry(*pos)
+                .or_insert(BaseModProbs::new(FxHashMap::default()))
+                .insert_base_mod_prob(*code, *prob);
+        }
+        SeqPosBaseModProbs::new(pos_to_probs, skip_mode)
+    }
+
+    #[test]
+    fn test_merge_seq_pos_base_mod_probs() {
+        let a = seq_pos_probs(
+            &[(1, 'm', 0.8), (5, 'm', 0.2)],
+            SkipMode::ProbModified,
+        );
+        let b = seq_pos_probs(
+            &[(1, 'h', 0.1), (5, 'm', 0.6), (9, 'h', 0.3)],
+            SkipMode::ProbModified,
+        );
+
+        let merged = merge_seq_pos_base_mod_probs(
+            a.clone(),
+            b.clone(),
+            ConflictPolicy::PreferA,
+            SEQ,
+            'C',
+        );
+        let expected = seq_pos_probs(
+            &[(1, 'm', 0.8), (1, 'h', 0.1), (5, 'm', 0.2), (9, 'h', 0.3)],
+            SkipMode::ProbModified,
+        );
+        assert_eq!(merged, expected);
+
+        let merged = merge_seq_pos_base_mod_probs(
+            a.clone(),
+            b.clone(),
+            ConflictPolicy::PreferB,
+            SEQ,
+            'C',
+        );
+        assert_eq!(
+            merged.pos_to_base_mod_probs.get(&5),
+            Some(&BaseModProbs::new_init('m', 0.6))
+        );
+        let merged =
+            merge_seq_pos_base_mod_probs(b, a, ConflictPolicy::Max, SEQ, 'C');
+        assert_eq!(
+            merged.pos_to_base_mod_probs.get(&5),
+            Some(&BaseModProbs::new_init('m', 0.6))
+        );
+    }
+
+    #[test]
+    fn test_merge_seq_pos_base_mod_probs_skip_mode_and_normalize() {
+        let a = seq_pos_probs(&[(1, 'm', 0.75)], SkipMode::Ambiguous);
+        let b = seq_pos_probs(&[(1, 'h', 0.5)], SkipMode::ProbModified);
+        let merged =
+            merge_seq_pos_base_mod_probs(a, b, ConflictPolicy::Max, SEQ, 'C');
+        assert_eq!(merged.skip_mode, SkipMode::Ambiguous);
+        let probs = merged.pos_to_base_mod_probs.get(&1).unwrap();
+        let total = probs.iter_probs().map(|(_, p)| *p).sum::<f32>();
+        assert!((total - 1f32).abs() < 1e-6);
+        let m = probs
+            .iter_probs()
+            .find(|(code, _)| **code == 'm')
+            .map(|(_, p)| *p)
+            .unwrap();
+        assert!((m - 0.6).abs() < 1e-6);
+        // the implied canonical calls from the '.' record are kept
+        for pos in [5, 9] {
+            assert_eq!(
+                merged.pos_to_base_mod_probs.get(&pos),
+                Some(&BaseModProbs::new_init('h', 0f32))
+            );
+        }
+    }
+}
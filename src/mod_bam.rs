@@ -18,12 +18,14 @@ use crate::mod_base_code::{DnaBase, ModCode};
 use crate::position_filter::StrandedPositionFilter;
 use crate::util;
 use crate::util::{
-    get_query_name_string, get_tag, record_is_secondary, Strand,
+    get_query_name_string, get_tag, record_is_secondary, skip_non_primary,
+    Strand,
 };
 
 pub(crate) struct TrackingModRecordIter<'a, T: bam::Read> {
     records: bam::Records<'a, T>,
     skip_unmapped: bool,
+    include_supplementary: bool,
     pub(crate) num_used: usize,
     pub(crate) num_skipped: usize,
     pub(crate) num_failed: usize,
@@ -33,10 +35,12 @@ impl<'a, T: bam::Read> TrackingModRecordIter<'a, T> {
     pub(crate) fn new(
         records: bam::Records<'a, T>,
         skip_unmapped: bool,
+        include_supplementary: bool,
     ) -> Self {
         Self {
             records,
             skip_unmapped,
+            include_supplementary,
             num_used: 0,
             num_skipped: 0,
             num_failed: 0,
@@ -55,7 +59,7 @@ impl<'a, T: bam::Read> Iterator for &mut TrackingModRecordIter<'a, T> {
                     let record_name =
                         String::from_utf8(record.qname().to_vec())
                             .unwrap_or("utf-decode-failed".to_string());
-                    if record_is_secondary(&record)
+                    if skip_non_primary(&record, self.include_supplementary)
                         || (record.is_unmapped() && self.skip_unmapped)
                     {
                         self.num_skipped += 1;
@@ -178,6 +182,23 @@ pub(crate) fn filter_records_iter<T: bam::Read>(
 
 pub const MM_TAGS: [&str; 2] = ["MM", "Mm"];
 pub const ML_TAGS: [&str; 2] = ["ML", "Ml"];
+/// Length of the SEQ the MM/ML tags were computed on, from the SAM spec.
+pub const MN_TAG: &str = "MN";
+
+/// Get the value of the `MN` tag, None if the tag is missing or isn't an
+/// integer.
+pub(crate) fn get_mn_tag(record: &bam::Record) -> Option<usize> {
+    let value = match record.aux(MN_TAG.as_bytes()) {
+        Ok(Aux::U8(x)) => x as i64,
+        Ok(Aux::U16(x)) => x as i64,
+        Ok(Aux::U32(x)) => x as i64,
+        Ok(Aux::I8(x)) => x as i64,
+        Ok(Aux::I16(x)) => x as i64,
+        Ok(Aux::I32(x)) => x as i64,
+        _ => return None,
+    };
+    usize::try_from(value).ok()
+}
 
 pub type RawModCode = char;
 
@@ -1104,6 +1125,44 @@ pub fn get_ml_tag_from_record(
     get_tag::<Vec<u16>>(&record, &ML_TAGS, &parse_ml_tag)
 }
 
+/// Remove the MM and ML tags in both styles (e.g. MM and Mm), tags that are
+/// not present are ignored.
+pub(crate) fn remove_all_mod_tags(record: &mut bam::Record) {
+    for tag in MM_TAGS.iter().chain(ML_TAGS.iter()) {
+        let _ = record.remove_aux(tag.as_bytes());
+    }
+}
+
+/// Add the `mm` and `ml` data to the record as the `mm_tag` and `ml_tag`
+/// tags, replacing the tags if they're already present.
+pub(crate) fn push_mod_tags(
+    record: &mut bam::Record,
+    mm_tag: &str,
+    ml_tag: &str,
+    mm: &str,
+    ml: &[u8],
+) -> Result<(), RunError> {
+    let _ = record.remove_aux(mm_tag.as_bytes());
+    let _ = record.remove_aux(ml_tag.as_bytes());
+    record
+        .push_aux(mm_tag.as_bytes(), Aux::String(mm))
+        .map_err(|e| {
+            RunError::new_failed(format!(
+                "failed to add {mm_tag} tag, {}",
+                e.to_string()
+            ))
+        })?;
+    record
+        .push_aux(ml_tag.as_bytes(), Aux::ArrayU8(ml.into()))
+        .map_err(|e| {
+            RunError::new_failed(format!(
+                "failed to add {ml_tag} tag, {}",
+                e.to_string()
+            ))
+        })?;
+    Ok(())
+}
+
 pub fn parse_raw_mod_tags(
     record: &bam::Record,
 ) -> Option<Result<RawModTags, RunError>> {
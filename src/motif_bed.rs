@@ -150,6 +150,25 @@ impl RegexMotif {
         self.reverse_offset as i32 - self.forward_offset as i32
     }
 
+    /// Check if the motif is found in `seq` (on the same strand) with the
+    /// motif's offset at `position`.
+    pub(crate) fn matches_at(&self, seq: &[u8], position: usize) -> bool {
+        let start = match position.checked_sub(self.forward_offset) {
+            Some(start) => start,
+            None => return false,
+        };
+        let end = start + self.length;
+        if end > seq.len() {
+            return false;
+        }
+        let window = String::from_utf8_lossy(&seq[start..end]);
+        self.forward_pattern
+            .inner
+            .find(&window)
+            .map(|m| m.start() == 0 && m.end() == self.length)
+            .unwrap_or(false)
+    }
+
     pub(crate) fn negative_strand_position(
         &self,
         positive_position: u32,
@@ -565,6 +584,19 @@ mod motif_bed_tests {
         assert_eq!(motif.offset(), -1);
     }
 
+    #[test]
+    fn test_regex_motif_matches_at() {
+        let seq = b"ACGTTCCAGGA";
+        let cpg = RegexMotif::parse_string("CG", 0).unwrap();
+        assert!(cpg.matches_at(seq, 1));
+        assert!(!cpg.matches_at(seq, 2));
+        assert!(!cpg.matches_at(seq, 10));
+        let dcm = RegexMotif::parse_string("CCWGG", 1).unwrap();
+        assert!(dcm.matches_at(seq, 6));
+        assert!(!dcm.matches_at(seq, 5));
+        assert!(!dcm.matches_at(seq, 0));
+    }
+
     #[test]
     fn test_motif_hits() {
         let seq = "AACGCGAACGCGA";
@@ -1,22 +1,31 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 
+use anyhow::{bail, Context};
 use derive_new::new;
-use log::debug;
+use indexmap::IndexSet;
+use itertools::Itertools;
+use log::{debug, info};
+use rust_htslib::bam::record::Aux;
 use rust_htslib::bam::{self, FetchDefinition, Read};
+use rust_lapper::{Interval, Lapper};
 use rustc_hash::FxHashMap;
 
-use crate::mod_bam::{DuplexModCall, EdgeFilter};
+use crate::dmr::annotation::{
+    parse_annotation_region_coordinates, AnnotationFeature,
+};
+use crate::mod_bam::{BaseModCall, DuplexModCall, EdgeFilter};
 use crate::motif_bed::MultipleMotifLocations;
 use crate::pileup::{
-    get_forward_read_base, get_motif_locations_for_region, PileupIter,
-    PileupNumericOptions,
+    get_forward_read_base, get_motif_locations_for_region, get_partition_key,
+    PartitionKey, PileupIter, PileupNumericOptions,
 };
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_cache::DuplexReadCache;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
-use crate::util::{record_is_secondary, Strand, StrandRule};
+use crate::util::{record_is_secondary, SamTag, Strand, StrandRule};
 
 /// Summarizes the duplex (hemi) methylation patterns for
 /// a genomic interval
@@ -24,12 +33,27 @@ use crate::util::{record_is_secondary, Strand, StrandRule};
 pub struct DuplexModBasePileup {
     /// name of the contig/chrom
     pub chrom_name: String,
-    /// duplex pattern counts per genomic position
-    pub pileup_counts: FxHashMap<u32, DuplexPileupFeatureCounts>,
+    /// duplex pattern counts per genomic position and partition
+    pub pileup_counts:
+        FxHashMap<u32, FxHashMap<PartitionKey, DuplexPileupFeatureCounts>>,
     /// number of records used
     pub processed_records: usize,
     /// number of records skipped
     pub skipped_records: usize,
+    /// partition keys encountered, indexed by `PartitionKey::Key`
+    pub(crate) partition_keys: IndexSet<String>,
+}
+
+impl DuplexModBasePileup {
+    pub fn iter_counts_sorted(
+        &self,
+    ) -> impl Iterator<
+        Item = (&u32, &FxHashMap<PartitionKey, DuplexPileupFeatureCounts>),
+    > {
+        self.pileup_counts
+            .iter()
+            .sorted_by(|(x, _), (y, _)| x.cmp(y))
+    }
 }
 
 #[derive(new, Debug, Eq, PartialEq, PartialOrd)]
@@ -201,6 +225,341 @@ impl DuplexFeatureVector {
     }
 }
 
+/// A region to aggregate duplex pattern counts over.
+#[derive(new, Debug)]
+pub(crate) struct DuplexRegion {
+    pub(crate) chrom: String,
+    pub(crate) start: u64,
+    pub(crate) stop: u64,
+}
+
+/// Duplex pattern counts summed over the positions in a region, for a single
+/// primary base.
+#[derive(Default, Debug)]
+pub(crate) struct RegionBaseCounts {
+    pattern_counts: BTreeMap<[char; 2], usize>,
+    pub(crate) valid_coverage: usize,
+    pub(crate) n_canonical: usize,
+    pub(crate) n_delete: usize,
+    pub(crate) n_fail: usize,
+    pub(crate) n_diff: usize,
+    pub(crate) n_nocall: usize,
+}
+
+impl RegionBaseCounts {
+    fn add(&mut self, patterns: &[DuplexPatternCounts], n_delete: usize) {
+        // the counts other than the pattern count are the same for every
+        // pattern at a position
+        if let Some(first) = patterns.first() {
+            self.valid_coverage += first.valid_coverage();
+            self.n_canonical += first.n_canonical;
+            self.n_fail += first.n_fail;
+            self.n_diff += first.n_diff;
+            self.n_nocall += first.n_nocall;
+            self.n_delete += n_delete;
+        }
+        for pattern in patterns {
+            *self.pattern_counts.entry(pattern.pattern).or_insert(0) +=
+                pattern.count;
+        }
+    }
+
+    pub(crate) fn pattern_count(&self, pattern: &[char; 2]) -> usize {
+        self.pattern_counts.get(pattern).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn n_other_pattern(&self, pattern: &[char; 2]) -> usize {
+        self.valid_coverage - self.pattern_count(pattern)
+    }
+
+    /// Convert to the counts for a single position so that regions are
+    /// written with the same row format as positions.
+    fn to_feature_counts(&self, base: char) -> DuplexPileupFeatureCounts {
+        let patterns = self
+            .pattern_counts
+            .keys()
+            .map(|pattern| {
+                DuplexPatternCounts::new(
+                    *pattern,
+                    self.pattern_count(pattern),
+                    self.n_other_pattern(pattern),
+                    self.n_diff,
+                    self.n_canonical,
+                    self.n_fail,
+                    self.n_nocall,
+                )
+            })
+            .collect::<Vec<DuplexPatternCounts>>();
+        DuplexPileupFeatureCounts::new(
+            HashMap::from([(base, patterns)]),
+            self.n_delete,
+        )
+    }
+}
+
+/// Duplex pattern counts aggregated over the regions in a BED file.
+pub struct DuplexRegionCounts {
+    regions: Vec<DuplexRegion>,
+    lappers: FxHashMap<String, Lapper<u64, usize>>,
+    counts: FxHashMap<usize, BTreeMap<char, RegionBaseCounts>>,
+}
+
+impl DuplexRegionCounts {
+    /// Parse the regions from a BED file, only the first three columns are
+    /// used.
+    pub fn from_bed_file<P: AsRef<Path>>(fp: P) -> anyhow::Result<Self> {
+        let fp = fp.as_ref();
+        let reader = BufReader::new(
+            std::fs::File::open(fp)
+                .with_context(|| format!("failed to open {:?}", fp))?,
+        );
+        let mut regions = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            if parts.len() < 3 {
+                bail!("invalid BED line, need at least 3 columns, {line}")
+            }
+            let start = parts[1]
+                .parse::<u64>()
+                .with_context(|| format!("invalid start {}", parts[1]))?;
+            let stop = parts[2]
+                .parse::<u64>()
+                .with_context(|| format!("invalid stop {}", parts[2]))?;
+            if stop <= start {
+                bail!("invalid BED line, stop must be after start, {line}")
+            }
+            regions.push(DuplexRegion::new(parts[0].to_string(), start, stop));
+        }
+        if regions.is_empty() {
+            bail!("didn't find any regions in {:?}", fp)
+        }
+        Ok(Self::from_regions(regions))
+    }
+
+    /// Make the regions from the features in a GTF or GFF3 annotation, the
+    /// regions are not stranded since duplex patterns cover both strands.
+    pub(crate) fn from_annotation<P: AsRef<Path>>(
+        fp: P,
+        feature: AnnotationFeature,
+        flank: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let regions = parse_annotation_region_coordinates(fp, feature, flank)?
+            .into_iter()
+            .map(|(chrom, start, stop)| DuplexRegion::new(chrom, start, stop))
+            .collect::<Vec<DuplexRegion>>();
+        Ok(Self::from_regions(regions))
+    }
+
+    fn from_regions(regions: Vec<DuplexRegion>) -> Self {
+        info!("aggregating duplex patterns over {} regions", regions.len());
+        let lappers = regions
+            .iter()
+            .enumerate()
+            .fold(
+                FxHashMap::<String, Vec<Interval<u64, usize>>>::default(),
+                |mut acc, (idx, region)| {
+                    acc.entry(region.chrom.clone()).or_default().push(
+                        Interval {
+                            start: region.start,
+                            stop: region.stop,
+                            val: idx,
+                        },
+                    );
+                    acc
+                },
+            )
+            .into_iter()
+            .map(|(chrom, ivs)| (chrom, Lapper::new(ivs)))
+            .collect();
+
+        Self {
+            regions,
+            lappers,
+            counts: FxHashMap::default(),
+        }
+    }
+
+    /// Add the counts at each position to the regions that contain it.
+    pub fn add(&mut self, pileup: &DuplexModBasePileup) {
+        let lapper = match self.lappers.get(&pileup.chrom_name) {
+            Some(lapper) => lapper,
+            None => return,
+        };
+        for (pos, partitioned_counts) in pileup.pileup_counts.iter() {
+            let pos = *pos as u64;
+            for region in lapper.find(pos, pos + 1) {
+                let region_counts = self.counts.entry(region.val).or_default();
+                for duplex_pileup_counts in partitioned_counts.values() {
+                    for (base, patterns) in &duplex_pileup_counts.pattern_counts
+                    {
+                        region_counts
+                            .entry(*base)
+                            .or_default()
+                            .add(patterns, duplex_pileup_counts.n_delete);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts for each region and primary base in the order of the regions
+    /// in the BED file. Regions without any counts are skipped.
+    pub(crate) fn iter_feature_counts(
+        &self,
+    ) -> impl Iterator<Item = (&DuplexRegion, DuplexPileupFeatureCounts)> {
+        self.regions
+            .iter()
+            .enumerate()
+            .flat_map(move |(idx, region)| {
+                self.counts
+                    .get(&idx)
+                    .into_iter()
+                    .flat_map(move |base_counts| {
+                        base_counts.iter().map(move |(base, counts)| {
+                            (region, counts.to_feature_counts(*base))
+                        })
+                    })
+            })
+    }
+}
+
+/// How to pair simplex template and complement records so that their base
+/// modification calls are combined into duplex calls.
+#[derive(Debug)]
+pub enum SimplexPairing {
+    /// Pair the parents of duplex records (`dx:i:1`), the duplex record names
+    /// are `template;complement`. The duplex records themselves are skipped.
+    DuplexTag,
+    /// Pair records with a table of read IDs, mapping each read ID to the
+    /// name of the pair.
+    ReadIds(FxHashMap<String, String>),
+}
+
+impl SimplexPairing {
+    /// Parse a file of template and complement read IDs, one pair per line,
+    /// separated by whitespace, a comma, or a semicolon.
+    pub fn from_pairs_file<P: AsRef<Path>>(fp: P) -> anyhow::Result<Self> {
+        let fp = fp.as_ref();
+        let reader = BufReader::new(
+            std::fs::File::open(fp)
+                .with_context(|| format!("failed to open {:?}", fp))?,
+        );
+        let mut read_ids_to_pair = FxHashMap::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts = line
+                .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<&str>>();
+            if parts.len() != 2 {
+                bail!(
+                    "invalid read pair line {line}, should be the template \
+                    and complement read IDs"
+                )
+            }
+            let pair_name = format!("{};{}", parts[0], parts[1]);
+            for read_id in parts {
+                if let Some(other) = read_ids_to_pair
+                    .insert(read_id.to_string(), pair_name.clone())
+                {
+                    bail!("read {read_id} is in more than one pair, {other} and {pair_name}")
+                }
+            }
+        }
+        if read_ids_to_pair.is_empty() {
+            bail!("didn't find any read pairs in {:?}", fp)
+        }
+        info!("loaded {} read pairs", read_ids_to_pair.len() / 2);
+        Ok(Self::ReadIds(read_ids_to_pair))
+    }
+}
+
+fn get_duplex_tag(record: &bam::Record) -> Option<i64> {
+    match record.aux(b"dx").ok()? {
+        Aux::I8(x) => Some(x as i64),
+        Aux::U8(x) => Some(x as i64),
+        Aux::I16(x) => Some(x as i64),
+        Aux::U16(x) => Some(x as i64),
+        Aux::I32(x) => Some(x as i64),
+        Aux::U32(x) => Some(x as i64),
+        _ => None,
+    }
+}
+
+/// Find the simplex parents of duplex records in the region, mapping the
+/// template and complement read IDs to the duplex record name.
+fn get_duplex_parents(
+    bam_reader: &mut bam::IndexedReader,
+    chrom_tid: u32,
+    start_pos: u32,
+    end_pos: u32,
+) -> anyhow::Result<FxHashMap<String, String>> {
+    bam_reader.fetch(FetchDefinition::Region(
+        chrom_tid as i32,
+        start_pos as i64,
+        end_pos as i64,
+    ))?;
+    let mut parents = FxHashMap::default();
+    for record in bam_reader.records() {
+        let record = record?;
+        if get_duplex_tag(&record) != Some(1) {
+            continue;
+        }
+        let name = String::from_utf8_lossy(record.qname()).to_string();
+        match name.split_once(';') {
+            Some((template, complement)) => {
+                parents.insert(template.to_string(), name.clone());
+                parents.insert(complement.to_string(), name.clone());
+            }
+            None => debug!(
+                "duplex record {name} isn't named template;complement, \
+                cannot find parents"
+            ),
+        }
+    }
+    Ok(parents)
+}
+
+/// Base modification calls on the positive and negative strands from the
+/// records of a simplex pair.
+#[derive(new)]
+struct PairedMods {
+    primary_base: char,
+    partition_key: PartitionKey,
+    #[new(default)]
+    pos: Option<BaseModCall>,
+    #[new(default)]
+    neg: Option<BaseModCall>,
+}
+
+impl PairedMods {
+    fn add(&mut self, pos: Option<BaseModCall>, neg: Option<BaseModCall>) {
+        self.pos = self.pos.or(pos);
+        self.neg = self.neg.or(neg);
+    }
+
+    fn into_duplex_mod_call(self) -> DuplexModCall {
+        let primary_base = self.primary_base;
+        match (self.pos, self.neg) {
+            (Some(pos), Some(neg)) => {
+                DuplexModCall::from_base_mod_calls(pos, neg, primary_base)
+            }
+            _ => DuplexModCall::NoCall { primary_base },
+        }
+    }
+}
+
 pub fn process_region_duplex<T: AsRef<Path>>(
     bam_fp: T,
     chrom_tid: u32,
@@ -213,11 +572,29 @@ pub fn process_region_duplex<T: AsRef<Path>>(
     motif_locations: &MultipleMotifLocations,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter>,
+    simplex_pairing: Option<&SimplexPairing>,
+    partition_tags: Option<&Vec<SamTag>>,
 ) -> anyhow::Result<DuplexModBasePileup> {
     let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
     let chrom_name =
         String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
             .to_string();
+    let duplex_parents = match simplex_pairing {
+        Some(SimplexPairing::DuplexTag) => Some(get_duplex_parents(
+            &mut bam_reader,
+            chrom_tid,
+            start_pos,
+            end_pos,
+        )?),
+        _ => None,
+    };
+    let read_ids_to_pair = match simplex_pairing {
+        Some(SimplexPairing::ReadIds(read_ids_to_pair)) => {
+            Some(read_ids_to_pair)
+        }
+        Some(SimplexPairing::DuplexTag) => duplex_parents.as_ref(),
+        None => None,
+    };
     bam_reader.fetch(FetchDefinition::Region(
         chrom_tid as i32,
         start_pos as i64,
@@ -231,6 +608,9 @@ pub fn process_region_duplex<T: AsRef<Path>>(
     );
 
     let mut position_feature_counts = FxHashMap::default();
+    // collection of all partition keys encountered, ordered so
+    // we can can use their index
+    let mut partition_keys = IndexSet::new();
 
     let hts_pileup = {
         let mut tmp_pileup = bam_reader.pileup();
@@ -271,41 +651,91 @@ pub fn process_region_duplex<T: AsRef<Path>>(
         Some((pileup, motif))
     }) {
         let pos = pileup.bam_pileup.pos();
-        let mut feature_vector = DuplexFeatureVector::default();
+        let mut feature_vectors =
+            FxHashMap::<PartitionKey, DuplexFeatureVector>::default();
+        // calls from the records of simplex pairs, combined after all of the
+        // records at this position have been seen
+        let mut paired_mods = FxHashMap::<&str, PairedMods>::default();
         let alignment_iter =
             pileup.bam_pileup.alignments().filter(|alignment| {
                 if alignment.is_refskip() {
                     false
                 } else {
                     let record = alignment.record();
-                    !(record_is_secondary(&record) || record.seq_len() == 0)
+                    let is_duplex_offspring = matches!(
+                        simplex_pairing,
+                        Some(SimplexPairing::DuplexTag)
+                    ) && get_duplex_tag(&record)
+                        == Some(1);
+                    !(record_is_secondary(&record)
+                        || record.seq_len() == 0
+                        || is_duplex_offspring)
                 }
             });
 
         for alignment in alignment_iter {
+            let record = alignment.record();
+            let partition_key =
+                get_partition_key(&record, partition_tags, &mut partition_keys);
             if alignment.is_del() {
-                feature_vector.add_feature(
-                    DuplexFeature::Delete,
-                    &pileup_numeric_options,
-                );
+                feature_vectors
+                    .entry(partition_key)
+                    .or_default()
+                    .add_feature(
+                        DuplexFeature::Delete,
+                        &pileup_numeric_options,
+                    );
                 continue;
             }
-            let record = alignment.record();
             let read_base = get_forward_read_base(&alignment, &record);
             if read_base.is_none() {
                 continue;
             }
             let read_base = read_base.unwrap();
+            let pair_name = read_ids_to_pair.and_then(|lookup| {
+                let read_id = String::from_utf8_lossy(record.qname());
+                lookup.get(read_id.as_ref())
+            });
+            if let Some(pair_name) = pair_name {
+                if let Some((pos_call, neg_call)) = read_cache
+                    .get_stranded_mod_calls(&record, pos, read_base, motif)
+                {
+                    paired_mods
+                        .entry(pair_name.as_str())
+                        .or_insert_with(|| {
+                            PairedMods::new(read_base.char(), partition_key)
+                        })
+                        .add(pos_call, neg_call);
+                }
+                continue;
+            }
             if let Some(duplex_mod_call) =
                 read_cache.get_duplex_mod_call(&record, pos, read_base, motif)
             {
-                feature_vector.add_feature(
-                    DuplexFeature::ModCall(duplex_mod_call),
+                feature_vectors
+                    .entry(partition_key)
+                    .or_default()
+                    .add_feature(
+                        DuplexFeature::ModCall(duplex_mod_call),
+                        &pileup_numeric_options,
+                    );
+            }
+        }
+        for (_, mods) in paired_mods {
+            feature_vectors
+                .entry(mods.partition_key)
+                .or_default()
+                .add_feature(
+                    DuplexFeature::ModCall(mods.into_duplex_mod_call()),
                     &pileup_numeric_options,
                 );
-            }
         }
-        let pileup_counts = feature_vector.decode();
+        let pileup_counts = feature_vectors
+            .into_iter()
+            .map(|(partition_key, feature_vector)| {
+                (partition_key, feature_vector.decode())
+            })
+            .collect::<FxHashMap<PartitionKey, DuplexPileupFeatureCounts>>();
         position_feature_counts.insert(pos, pileup_counts);
     }
 
@@ -316,5 +746,76 @@ pub fn process_region_duplex<T: AsRef<Path>>(
         pileup_counts: position_feature_counts,
         processed_records,
         skipped_records,
+        partition_keys,
     })
 }
+
+#[cfg(test)]
+mod duplex_tests {
+    use crate::pileup::duplex::{
+        DuplexPatternCounts, RegionBaseCounts, SimplexPairing,
+    };
+
+    #[test]
+    fn test_parse_simplex_pairs_file() {
+        let pairing = SimplexPairing::from_pairs_file(
+            "tests/resources/simplex_pairs.txt",
+        )
+        .unwrap();
+        let read_ids_to_pair = match pairing {
+            SimplexPairing::ReadIds(read_ids_to_pair) => read_ids_to_pair,
+            _ => panic!("should be read IDs"),
+        };
+        assert_eq!(read_ids_to_pair.len(), 4);
+        assert_eq!(
+            read_ids_to_pair.get("template_1"),
+            read_ids_to_pair.get("complement_1")
+        );
+        assert_eq!(
+            read_ids_to_pair.get("complement_2").unwrap(),
+            "template_2;complement_2"
+        );
+        assert_ne!(
+            read_ids_to_pair.get("template_1"),
+            read_ids_to_pair.get("template_2")
+        );
+    }
+
+    #[test]
+    fn test_region_base_counts() {
+        let mut region_counts = RegionBaseCounts::default();
+        // position 1, m,m x3 and -,- x1
+        region_counts.add(
+            &[
+                DuplexPatternCounts::new(['m', 'm'], 3, 1, 0, 1, 2, 0),
+                DuplexPatternCounts::new(['-', '-'], 1, 3, 0, 1, 2, 0),
+            ],
+            1,
+        );
+        // position 2, only m,- x2
+        region_counts
+            .add(&[DuplexPatternCounts::new(['m', '-'], 2, 0, 1, 0, 0, 1)], 0);
+        assert_eq!(region_counts.valid_coverage, 6);
+        assert_eq!(region_counts.pattern_count(&['m', 'm']), 3);
+        assert_eq!(region_counts.pattern_count(&['m', '-']), 2);
+        assert_eq!(region_counts.pattern_count(&['-', 'm']), 0);
+        assert_eq!(region_counts.n_other_pattern(&['m', 'm']), 3);
+        assert_eq!(region_counts.n_canonical, 1);
+        assert_eq!(region_counts.n_fail, 2);
+        assert_eq!(region_counts.n_diff, 1);
+        assert_eq!(region_counts.n_nocall, 1);
+        assert_eq!(region_counts.n_delete, 1);
+
+        let feature_counts = region_counts.to_feature_counts('C');
+        assert_eq!(feature_counts.n_delete, 1);
+        let patterns = feature_counts.pattern_counts.get(&'C').unwrap();
+        assert_eq!(
+            patterns,
+            &vec![
+                DuplexPatternCounts::new(['-', '-'], 1, 5, 1, 1, 2, 1),
+                DuplexPatternCounts::new(['m', '-'], 2, 4, 1, 1, 2, 1),
+                DuplexPatternCounts::new(['m', 'm'], 3, 3, 1, 1, 2, 1),
+            ]
+        );
+    }
+}
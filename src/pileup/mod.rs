@@ -9,6 +9,7 @@ use rust_htslib::bam;
 use rust_htslib::bam::{FetchDefinition, Read};
 use rustc_hash::FxHashMap;
 
+use crate::calibrate::CalibrationMap;
 use crate::mod_bam::{BaseModCall, CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{DnaBase, ModCode};
 use crate::motif_bed::MultipleMotifLocations;
@@ -16,7 +17,7 @@ use crate::position_filter::StrandedPositionFilter;
 use crate::read_cache::ReadCache;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
-    get_query_name_string, get_stringable_aux, record_is_secondary, SamTag,
+    get_query_name_string, get_stringable_aux, skip_non_primary, SamTag,
     Strand, StrandRule,
 };
 
@@ -768,6 +769,34 @@ fn parse_tags_from_record(
     Some(key)
 }
 
+/// Get the partition key for a record, adding the tag values to
+/// `partition_keys` the first time they're seen.
+pub(crate) fn get_partition_key(
+    record: &bam::Record,
+    partition_tags: Option<&Vec<SamTag>>,
+    partition_keys: &mut IndexSet<String>,
+) -> PartitionKey {
+    if let Some(tags) = partition_tags {
+        match parse_tags_from_record(record, tags) {
+            Some(s) => {
+                if let Some(idx) = partition_keys.get_index_of(&s) {
+                    PartitionKey::Key(idx)
+                } else {
+                    let inserted = partition_keys.insert(s);
+                    debug_assert!(inserted);
+                    debug_assert!(partition_keys.len() > 0);
+                    PartitionKey::Key(
+                        partition_keys.len().checked_sub(1).unwrap_or(0),
+                    )
+                }
+            }
+            None => PartitionKey::NoKey,
+        }
+    } else {
+        PartitionKey::NoKey
+    }
+}
+
 pub struct ModBasePileup {
     pub chrom_name: String,
     position_feature_counts:
@@ -821,6 +850,8 @@ pub fn process_region<T: AsRef<Path>>(
     edge_filter: Option<&EdgeFilter>,
     partition_tags: Option<&Vec<SamTag>>,
     position_filter: Option<&StrandedPositionFilter>,
+    include_supplementary: bool,
+    calibration: Option<&CalibrationMap>,
 ) -> Result<ModBasePileup, String> {
     let mut bam_reader =
         bam::IndexedReader::from_path(bam_fp).map_err(|e| e.to_string())?;
@@ -843,6 +874,7 @@ pub fn process_region<T: AsRef<Path>>(
         pileup_numeric_options.get_collapse_method(),
         caller,
         edge_filter,
+        calibration,
         force_allow,
     );
     let mut position_feature_counts = HashMap::new();
@@ -882,34 +914,15 @@ pub fn process_region<T: AsRef<Path>>(
                     false
                 } else {
                     let record = alignment.record();
-                    !(record_is_secondary(&record) || record.seq_len() == 0)
+                    !(skip_non_primary(&record, include_supplementary)
+                        || record.seq_len() == 0)
                 }
             });
         for alignment in alignment_iter {
             assert!(!alignment.is_refskip());
             let record = alignment.record();
-            let partition_key = if let Some(tags) = partition_tags {
-                match parse_tags_from_record(&record, tags) {
-                    Some(s) => {
-                        if let Some(idx) = partition_keys.get_index_of(&s) {
-                            PartitionKey::Key(idx)
-                        } else {
-                            let inserted = partition_keys.insert(s);
-                            debug_assert!(inserted);
-                            debug_assert!(partition_keys.len() > 0);
-                            PartitionKey::Key(
-                                partition_keys
-                                    .len()
-                                    .checked_sub(1)
-                                    .unwrap_or(0),
-                            )
-                        }
-                    }
-                    None => PartitionKey::NoKey,
-                }
-            } else {
-                PartitionKey::NoKey
-            };
+            let partition_key =
+                get_partition_key(&record, partition_tags, &mut partition_keys);
 
             // data structures we update per alignment/read
             let mut pos_strand_mod_codes_for_key =
@@ -1,9 +1,9 @@
 use std::collections::HashMap;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail, Context};
-use clap::{Args, ValueEnum};
+use clap::{ArgGroup, Args, ValueEnum};
 use crossbeam_channel::bounded;
 use indicatif::{MultiProgress, ParallelProgressIterator};
 use itertools::Itertools;
@@ -12,10 +12,12 @@ use rayon::prelude::*;
 use rust_htslib::bam::{self, Read};
 use rustc_hash::FxHashSet;
 
+use crate::calibrate::CalibrationMap;
 use crate::command_utils::{
     get_threshold_from_options, parse_edge_filter_input,
     parse_per_mod_thresholds, parse_thresholds,
 };
+use crate::dmr::annotation::AnnotationFeature;
 use crate::interval_chunks::IntervalChunks;
 use crate::logging::init_logging;
 use crate::mod_bam::CollapseMethod;
@@ -23,7 +25,10 @@ use crate::mod_base_code::ModCode;
 use crate::motif_bed::{
     get_masked_sequences, MotifLocations, MultipleMotifLocations, RegexMotif,
 };
-use crate::pileup::duplex::{process_region_duplex, DuplexModBasePileup};
+use crate::pileup::duplex::{
+    process_region_duplex, DuplexModBasePileup, DuplexRegionCounts,
+    SimplexPairing,
+};
 use crate::pileup::{process_region, ModBasePileup, PileupNumericOptions};
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::sampling_schedule::IdxStats;
@@ -32,7 +37,8 @@ use crate::util::{
     get_ticker, parse_partition_tags, reader_is_bam, ReferenceRecord, Region,
 };
 use crate::writers::{
-    BedGraphWriter, BedMethylWriter, PartitioningBedMethylWriter, PileupWriter,
+    BedGraphWriter, BedMethylWriter, OutWriter, ParquetBedMethylWriter,
+    PartitioningBedMethylWriter, PileupWriter,
 };
 
 #[derive(Args)]
@@ -57,6 +63,18 @@ pub struct ModBamPileup {
     /// an error will be raised.
     #[arg(long, default_value_t = 8000, hide_short_help = true)]
     max_depth: u32,
+    /// Include supplementary alignments when their MM/ML tags are valid for
+    /// the stored SEQ, either the MN tag matches the SEQ length or, without an
+    /// MN tag, the alignment is not hard-clipped. Secondary alignments are
+    /// always excluded.
+    #[arg(long, default_value_t = false)]
+    include_supplementary: bool,
+    /// Calibrate the base modification probabilities with a calibration map
+    /// made by `modkit calibrate` before calling. The pass threshold must be
+    /// given with --filter-threshold (or use --no-filtering) because it is
+    /// not estimated from calibrated probabilities.
+    #[arg(long)]
+    calibration: Option<PathBuf>,
 
     // processing args
     /// Number of threads to use while processing chunks concurrently.
@@ -305,6 +323,24 @@ pub struct ModBamPileup {
     /// with the `--prefix` flag.
     #[arg(long)]
     partition_tag: Option<Vec<String>>,
+    /// Write the output as a Parquet file with typed columns instead of a
+    /// bedMethyl file. The score, thickStart, thickEnd, and color columns are
+    /// omitted. Requires an output file path.
+    #[arg(
+        long,
+        conflicts_with_all = ["bedgraph", "partition_tag", "only_tabs"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    parquet: bool,
+    /// Overwrite an existing Parquet output file.
+    #[arg(
+        long,
+        requires = "parquet",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    force: bool,
 }
 
 impl ModBamPileup {
@@ -345,6 +381,21 @@ impl ModBamPileup {
                 parse_edge_filter_input(trims, self.invert_edge_filter)
             })
             .transpose()?;
+        if self.calibration.is_some()
+            && self.filter_threshold.is_none()
+            && !self.no_filtering
+        {
+            bail!(
+                "the pass threshold is not estimated from calibrated \
+                 probabilities, use --filter-threshold or --no-filtering with \
+                 --calibration"
+            )
+        }
+        let calibration = self
+            .calibration
+            .as_ref()
+            .map(CalibrationMap::from_file)
+            .transpose()?;
         let per_mod_thresholds = self
             .mod_thresholds
             .as_ref()
@@ -521,6 +572,15 @@ impl ModBamPileup {
             .unwrap_or(Vec::new());
         let mut writer: Box<dyn PileupWriter<ModBasePileup>> =
             match (self.bedgraph, partition_tags.is_some()) {
+                _ if self.parquet => match out_fp_str.as_str() {
+                    "stdout" | "-" => {
+                        bail!("Parquet output requires an output file path")
+                    }
+                    _ => Box::new(ParquetBedMethylWriter::new(
+                        &out_fp_str,
+                        self.force,
+                    )?),
+                },
                 (true, _) => Box::new(BedGraphWriter::new(
                     &out_fp_str,
                     self.prefix.as_ref(),
@@ -681,6 +741,7 @@ impl ModBamPileup {
 
         let force_allow = self.force_allow_implicit;
         let max_depth = self.max_depth;
+        let include_supplementary = self.include_supplementary;
 
         std::thread::spawn(move || {
             pool.install(|| {
@@ -735,6 +796,8 @@ impl ModBamPileup {
                                             edge_filter.as_ref(),
                                             partition_tags.as_ref(),
                                             position_filter.as_ref(),
+                                            include_supplementary,
+                                            calibration.as_ref(),
                                         )
                                     })
                                     .collect::<Vec<Result<ModBasePileup, String>>>()
@@ -785,6 +848,7 @@ impl ModBamPileup {
                 }
             }
         }
+        writer.finish()?;
         let rows_processed = write_progress.position();
         let n_skipped_reads = skipped_reads.position();
         let n_skipped_message = if n_skipped_reads == 0 {
@@ -809,6 +873,11 @@ enum Presets {
 }
 
 #[derive(Args)]
+#[command(group(
+    ArgGroup::new("prefixed_outputs")
+        .args(["bedgraph", "partition_tag"])
+        .multiple(true)
+))]
 pub struct DuplexModBamPileup {
     // running args
     /// Input BAM, should be sorted and have associated index available.
@@ -1030,17 +1099,99 @@ pub struct DuplexModBamPileup {
     )]
     invert_edge_filter: bool,
 
+    // simplex pairing args
+    /// Combine the base modification calls from simplex template and complement
+    /// records into duplex calls. Pairs are found with the duplex records (those with
+    /// the `dx:i:1` tag) which are named `template;complement`, the duplex records
+    /// themselves are not used.
+    #[arg(long, default_value_t = false, conflicts_with = "simplex_pairs")]
+    pair_simplex: bool,
+    /// Combine the base modification calls from simplex template and complement
+    /// records into duplex calls using a file of read ID pairs. Each line should have
+    /// the template and complement read IDs separated by whitespace, a comma, or a
+    /// semicolon. Records not in a pair are used as usual.
+    #[arg(long)]
+    simplex_pairs: Option<PathBuf>,
+
     // output args
     /// Separate bedMethyl columns with only tabs. The default is
     /// to use tabs for the first 10 fields and spaces thereafter. The
     /// default behavior is more likely to be compatible with genome viewers.
     /// Enabling this option may make it easier to parse the output with
     /// tabular data handlers that expect a single kind of separator.
-    #[arg(long, default_value_t = false, hide_short_help = true)]
+    #[arg(
+        long,
+        conflicts_with = "bedgraph",
+        default_value_t = false,
+        hide_short_help = true
+    )]
     only_tabs: bool,
+    /// Output bedGraph format, see https://genome.ucsc.edu/goldenPath/help/bedgraph.html.
+    /// For this setting, specify a directory for output files to be made in with
+    /// --out-bed. One file is produced for each pattern, containing the fraction of the
+    /// valid call-pairs with that pattern.
+    #[arg(
+        long,
+        requires = "out_bed",
+        conflicts_with = "only_tabs",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bedgraph: bool,
+    /// Prefix to prepend on bedgraph or partitioned output file names, requires
+    /// --bedgraph or --partition-tag.
+    #[arg(long, requires = "prefixed_outputs")]
+    prefix: Option<String>,
+    /// Partition output into multiple bedMethyl files based on tag-value pairs. The output
+    /// will be multiple bedMethyl files in the directory given with --out-bed with the format
+    /// `<prefix>_<tag_value_1>_<tag_value_2>_<tag_value_n>.bed` prefix is optional and set
+    /// with the `--prefix` flag.
+    #[arg(long, requires = "out_bed")]
+    partition_tag: Option<Vec<String>>,
+    /// Sum the pattern counts over the regions in this BED file, output is one bedMethyl
+    /// row per region and pattern instead of one row per position.
+    #[arg(long, conflicts_with_all = ["bedgraph", "partition_tag"])]
+    aggregate_regions: Option<PathBuf>,
+    /// Sum the pattern counts over regions made from a GTF or GFF3 annotation, instead
+    /// of a BED file with --aggregate-regions.
+    #[arg(
+        long,
+        conflicts_with_all = ["bedgraph", "partition_tag", "aggregate_regions"]
+    )]
+    aggregate_annotation: Option<PathBuf>,
+    /// Feature of the --aggregate-annotation to use as regions.
+    #[arg(
+        long,
+        value_enum,
+        requires = "aggregate_annotation",
+        default_value_t = AnnotationFeature::promoter
+    )]
+    annotation_feature: AnnotationFeature,
+    /// Number of bases to extend the annotation regions by on both sides, for promoters
+    /// the region is the TSS plus and minus this many bases. Default is 1000 for promoters
+    /// and 0 for other features.
+    #[arg(long, requires = "aggregate_annotation")]
+    flank: Option<u64>,
 }
 
 impl DuplexModBamPileup {
+    fn get_bedmethyl_writer(
+        &self,
+    ) -> anyhow::Result<BedMethylWriter<Box<dyn Write>>> {
+        let writer: Box<dyn Write> = if let Some(out_fp) = self.out_bed.as_ref()
+        {
+            let fh = std::fs::File::create(out_fp)
+                .context("failed to make output file")?;
+            Box::new(fh)
+        } else {
+            Box::new(std::io::stdout())
+        };
+        Ok(BedMethylWriter::new(
+            BufWriter::new(writer),
+            !self.only_tabs,
+        ))
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         // do this first so we fail when the file isn't readable
@@ -1175,16 +1326,69 @@ impl DuplexModBamPileup {
         if !regex_motif.is_palendrome() {
             bail!("motif must be palindromic for pileup-hemi")
         }
+        let simplex_pairing = if self.pair_simplex {
+            info!("pairing simplex records with duplex (dx) tags");
+            Some(SimplexPairing::DuplexTag)
+        } else {
+            self.simplex_pairs
+                .as_ref()
+                .map(|fp| SimplexPairing::from_pairs_file(fp))
+                .transpose()?
+        };
 
-        let mut writer: Box<dyn PileupWriter<DuplexModBasePileup>> =
-            if let Some(out_fp) = self.out_bed.as_ref() {
-                let fh = std::fs::File::create(out_fp)
-                    .context("failed to make output file")?;
-                let writer = BufWriter::new(fh);
-                Box::new(BedMethylWriter::new(writer, !self.only_tabs))
-            } else {
-                let writer = BufWriter::new(std::io::stdout());
-                Box::new(BedMethylWriter::new(writer, !self.only_tabs))
+        let partition_tags = self
+            .partition_tag
+            .as_ref()
+            .map(|raw_tags| parse_partition_tags(raw_tags))
+            .transpose()?;
+        // when aggregating over regions the pileups are summed and written
+        // after all of them have been processed
+        let region_counts = match (
+            self.aggregate_regions.as_ref(),
+            self.aggregate_annotation.as_ref(),
+        ) {
+            (Some(fp), _) => Some(DuplexRegionCounts::from_bed_file(fp)?),
+            (None, Some(fp)) => Some(DuplexRegionCounts::from_annotation(
+                fp,
+                self.annotation_feature,
+                self.flank,
+            )?),
+            (None, None) => None,
+        };
+        let mut region_counts = region_counts
+            .map(|counts| {
+                self.get_bedmethyl_writer().map(|writer| (counts, writer))
+            })
+            .transpose()?;
+
+        let out_dir = self
+            .out_bed
+            .as_ref()
+            .and_then(|p| p.to_str())
+            .map(|s| s.to_string());
+        let mut writer: Option<Box<dyn PileupWriter<DuplexModBasePileup>>> =
+            match (self.bedgraph, partition_tags.is_some(), out_dir) {
+                _ if region_counts.is_some() => None,
+                (true, _, Some(out_dir)) => {
+                    Some(Box::new(BedGraphWriter::new(
+                        &out_dir,
+                        self.prefix.as_ref(),
+                        partition_tags.is_some(),
+                    )?))
+                }
+                (false, true, Some(out_dir)) => {
+                    Some(Box::new(PartitioningBedMethylWriter::new(
+                        &out_dir,
+                        self.only_tabs,
+                        self.prefix.as_ref(),
+                    )?))
+                }
+                (true, _, None) | (false, true, None) => {
+                    bail!("need an output directory for --bedgraph or --partition-tag")
+                }
+                (false, false, _) => {
+                    Some(Box::new(self.get_bedmethyl_writer()?))
+                }
             };
 
         let pool = rayon::ThreadPoolBuilder::new()
@@ -1361,6 +1565,8 @@ impl DuplexModBamPileup {
                                             &motif_locations,
                                             edge_filter.as_ref(),
                                             position_filter.as_ref(),
+                                            simplex_pairing.as_ref(),
+                                            partition_tags.as_ref(),
                                         )
                                     })
                                     .collect::<Vec<anyhow::Result<DuplexModBasePileup>>>()
@@ -1402,14 +1608,25 @@ impl DuplexModBamPileup {
                     processed_reads
                         .inc(mod_base_pileup.processed_records as u64);
                     skipped_reads.inc(mod_base_pileup.skipped_records as u64);
-                    let rows_written = writer.write(mod_base_pileup, &[])?;
-                    write_progress.inc(rows_written);
+                    if let Some((region_counts, _)) = region_counts.as_mut() {
+                        region_counts.add(&mod_base_pileup);
+                    }
+                    if let Some(writer) = writer.as_mut() {
+                        let rows_written =
+                            writer.write(mod_base_pileup, &[])?;
+                        write_progress.inc(rows_written);
+                    }
                 }
                 Err(message) => {
                     debug!("> unexpected error {message}");
                 }
             }
         }
+        if let Some((region_counts, mut region_writer)) = region_counts {
+            let rows_written =
+                OutWriter::write(&mut region_writer, region_counts)?;
+            write_progress.inc(rows_written);
+        }
         let rows_processed = write_progress.position();
         let n_skipped_reads = skipped_reads.position();
         let n_skipped_message = if n_skipped_reads == 0 {
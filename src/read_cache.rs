@@ -4,6 +4,7 @@ use log::{debug, error};
 use rust_htslib::bam;
 use rustc_hash::{FxHashMap, FxHashSet};
 
+use crate::calibrate::CalibrationMap;
 use crate::errs::RunError;
 use crate::mod_bam::{
     collapse_mod_probs, BaseModCall, CollapseMethod, DuplexModCall, EdgeFilter,
@@ -37,6 +38,8 @@ pub(crate) struct ReadCache<'a> {
     caller: &'a MultipleThresholdModCaller,
     /// Edge filter to remove base mod calls at the ends of reads
     edge_filter: Option<&'a EdgeFilter>,
+    /// Calibration map applied to the probabilities before calling
+    calibration: Option<&'a CalibrationMap>,
 }
 
 impl<'a> ReadCache<'a> {
@@ -44,6 +47,7 @@ impl<'a> ReadCache<'a> {
         method: Option<&'a CollapseMethod>,
         caller: &'a MultipleThresholdModCaller,
         edge_filter: Option<&'a EdgeFilter>,
+        calibration: Option<&'a CalibrationMap>,
         force_allow: bool,
     ) -> Self {
         Self {
@@ -56,6 +60,19 @@ impl<'a> ReadCache<'a> {
             force_allow,
             caller,
             edge_filter,
+            calibration,
+        }
+    }
+
+    /// Key for a record in the cache, the read name for primary alignments.
+    /// Supplementary alignments also have the reference start so that they
+    /// are kept separate from the primary alignment of the same read.
+    fn record_key(record: &bam::Record) -> String {
+        let read_id = String::from_utf8_lossy(record.qname());
+        if record.is_supplementary() {
+            format!("{read_id}:{}:{}", record.tid(), record.pos())
+        } else {
+            read_id.to_string()
         }
     }
 
@@ -122,8 +139,9 @@ impl<'a> ReadCache<'a> {
 
     /// Add a record to the cache.
     fn add_record(&mut self, record: &bam::Record) -> Result<(), RunError> {
-        let record_name = util::get_query_name_string(record)
+        util::get_query_name_string(record)
             .map_err(|e| RunError::new_input_error(e.to_string()))?;
+        let record_name = Self::record_key(record);
 
         let mod_base_info = ModBaseInfo::new_from_record(record)?;
         if mod_base_info.is_empty() {
@@ -153,8 +171,23 @@ impl<'a> ReadCache<'a> {
         // base if they are all filtered out (due to edge filter), return an Err so that we
         // don't re-process this read.
         let mut added_base_mod_probs = false;
+        let calibration_sequence = self
+            .calibration
+            .map(|_| util::get_forward_sequence(record))
+            .transpose()?;
         let (_, mod_prob_iter) = mod_base_info.into_iter_base_mod_probs();
         for (base, mod_strand, seq_base_mod_probs) in mod_prob_iter {
+            let seq_base_mod_probs =
+                match (self.calibration, &calibration_sequence) {
+                    (Some(calibration), Some(forward_sequence)) => calibration
+                        .calibrate_seq_pos_base_mod_probs(
+                            seq_base_mod_probs,
+                            base,
+                            mod_strand,
+                            forward_sequence,
+                        ),
+                    _ => seq_base_mod_probs,
+                };
             match DnaBase::parse(base) {
                 Ok(dna_base) => {
                     let threshold_base = match mod_strand {
@@ -282,7 +315,7 @@ impl<'a> ReadCache<'a> {
         position: u32,
         canonical_base: char, // todo make this DnaBase
     ) -> (Option<BaseModCall>, Option<BaseModCall>) {
-        let read_id = String::from_utf8(record.qname().to_vec()).unwrap();
+        let read_id = Self::record_key(record);
         if self.skip_set.contains(&read_id) {
             (None, None)
         } else {
@@ -351,7 +384,7 @@ impl<'a> ReadCache<'a> {
     ) {
         // optimize, could use a better implementation here - pass the read_id
         // from the calling function perhaps
-        let read_id = String::from_utf8(record.qname().to_vec()).unwrap();
+        let read_id = Self::record_key(record);
         if self.skip_set.contains(&read_id) {
             return;
         } else {
@@ -421,7 +454,7 @@ impl<'a> DuplexReadCache<'a> {
         force_allow: bool,
     ) -> Self {
         let read_cache =
-            ReadCache::new(method, caller, edge_filter, force_allow);
+            ReadCache::new(method, caller, edge_filter, None, force_allow);
 
         Self { read_cache }
     }
@@ -464,13 +497,17 @@ impl<'a> DuplexReadCache<'a> {
         }
     }
 
-    pub(crate) fn get_duplex_mod_call(
+    /// Get the base modification calls on the positive and negative strands
+    /// at a motif position from a single record. Simplex records will
+    /// usually only have a call for one of the strands. Returns `None` when
+    /// the record is skipped.
+    pub(crate) fn get_stranded_mod_calls(
         &mut self,
         record: &bam::Record,
         position: u32,
         read_base: DnaBase,
         motif: &MotifLocations,
-    ) -> Option<DuplexModCall> {
+    ) -> Option<(Option<BaseModCall>, Option<BaseModCall>)> {
         let read_id = util::get_query_name_string(&record).ok()?;
         if self.read_cache.skip_set.contains(&read_id) {
             return None;
@@ -486,21 +523,27 @@ impl<'a> DuplexReadCache<'a> {
             position,
             pos_base.char(),
         );
-        let negative_position =
-            motif.motif().negative_strand_position(position);
-
-        if negative_position.is_none() {
-            return Some(DuplexModCall::NoCall {
-                primary_base: read_base.char(),
+        let neg_strand_base_mod_call = motif
+            .motif()
+            .negative_strand_position(position)
+            .and_then(|negative_position| {
+                self.get_neg_strand_base_mod_call(
+                    record,
+                    negative_position,
+                    neg_base.char(),
+                )
             });
-        }
-        let negative_position = negative_position.unwrap();
-        let neg_strand_base_mod_call = self.get_neg_strand_base_mod_call(
-            record,
-            negative_position,
-            neg_base.char(),
-        );
-        match (pos_base_mod_call, neg_strand_base_mod_call) {
+        Some((pos_base_mod_call, neg_strand_base_mod_call))
+    }
+
+    pub(crate) fn get_duplex_mod_call(
+        &mut self,
+        record: &bam::Record,
+        position: u32,
+        read_base: DnaBase,
+        motif: &MotifLocations,
+    ) -> Option<DuplexModCall> {
+        match self.get_stranded_mod_calls(record, position, read_base, motif)? {
             (Some(pos), Some(neg)) => Some(DuplexModCall::from_base_mod_calls(
                 pos,
                 neg,
@@ -547,7 +590,7 @@ mod read_cache_tests {
             .unwrap();
 
         let caller = MultipleThresholdModCaller::new_passthrough();
-        let mut cache = ReadCache::new(None, &caller, None, false);
+        let mut cache = ReadCache::new(None, &caller, None, None, false);
         cache.add_record(&record).unwrap();
         let converter =
             DeltaListConverter::new_from_record(&record, 'C').unwrap();
@@ -611,7 +654,7 @@ mod read_cache_tests {
                 .unwrap();
 
         let caller = MultipleThresholdModCaller::new_passthrough();
-        let mut cache = ReadCache::new(None, &caller, None, false);
+        let mut cache = ReadCache::new(None, &caller, None, None, false);
         for r in reader.records() {
             let record = r.unwrap();
             assert!(cache.add_record(&record).is_err());
@@ -637,7 +680,7 @@ mod read_cache_tests {
             .unwrap();
 
         let caller = MultipleThresholdModCaller::new_passthrough();
-        let mut read_cache = ReadCache::new(None, &caller, None, false);
+        let mut read_cache = ReadCache::new(None, &caller, None, None, false);
         for p in reader.pileup() {
             let pileup = p.unwrap();
             for alignment in pileup.alignments() {
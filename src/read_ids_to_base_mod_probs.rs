@@ -4,19 +4,22 @@ use crate::mod_bam::{
 };
 use crate::mod_base_code::DnaBase;
 use crate::monoid::Moniod;
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
 use bio::alphabets::dna::{complement, revcomp};
 use derive_new::new;
 use indicatif::ParallelProgressIterator;
 use itertools::Itertools;
 use log::{debug, error};
 use rust_htslib::bam::{self, Read, Records};
+use smallvec::SmallVec;
 use std::collections::{HashMap, HashSet};
 
+use crate::calibrate::CalibrationMap;
 use crate::errs::RunError;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::record_sampler::{Indicator, RecordSampler};
 use crate::record_processor::{RecordProcessor, WithRecords};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util;
 use crate::util::{
     get_aligned_pairs_forward, get_forward_sequence, get_master_progress_bar,
@@ -359,27 +362,132 @@ impl WithRecords for ReadIdsToBaseModProbs {
     }
 }
 
+/// Sequence context around a base, k-mers up to 16 bases are stored inline.
+pub(crate) type Kmer = SmallVec<[u8; 16]>;
+
+/// Sequence context to report around a base, the number of bases upstream
+/// and downstream of the base on the forward strand.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KmerWindow {
+    upstream: usize,
+    downstream: usize,
+}
+
+impl Default for KmerWindow {
+    fn default() -> Self {
+        Self::new(2, 2)
+    }
+}
+
+impl KmerWindow {
+    // used in place of bases past the ends of the sequence
+    const MISSING: u8 = b'-';
+
+    pub(crate) fn new(upstream: usize, downstream: usize) -> Self {
+        Self {
+            upstream,
+            downstream,
+        }
+    }
+
+    /// Symmetric window around the base, `kmer_size` must be odd.
+    pub(crate) fn from_kmer_size(kmer_size: usize) -> anyhow::Result<Self> {
+        if kmer_size % 2 == 0 {
+            bail!("k-mer size must be odd, got {kmer_size}")
+        }
+        let flank = kmer_size / 2;
+        Ok(Self::new(flank, flank))
+    }
+
+    /// Parse an asymmetric window formatted as <upstream>,<downstream>.
+    pub(crate) fn parse_str(raw: &str) -> anyhow::Result<Self> {
+        let parts = raw.split(',').collect::<Vec<&str>>();
+        if parts.len() != 2 {
+            bail!(
+                "illegal k-mer window {raw}, should be upstream,downstream \
+                 (e.g. 4,6)"
+            )
+        }
+        let upstream = parts[0].parse::<usize>().context(format!(
+            "failed to parse k-mer window upstream {raw}, should be a number"
+        ))?;
+        let downstream = parts[1].parse::<usize>().context(format!(
+            "failed to parse k-mer window downstream {raw}, should be a number"
+        ))?;
+        Ok(Self::new(upstream, downstream))
+    }
+
+    pub(crate) fn kmer_size(&self) -> usize {
+        self.upstream + self.downstream + 1
+    }
+
+    fn get_kmer_opt<'a>(
+        &self,
+        seq: &'a [u8],
+        pos: usize,
+    ) -> impl Iterator<Item = Option<u8>> + 'a {
+        let upstream = self.upstream;
+        (pos..pos + self.kmer_size()).map(move |i| {
+            i.checked_sub(upstream)
+                .and_then(|idx| seq.get(idx).copied())
+        })
+    }
+
+    /// Get the k-mer from `seq` with the base at `pos`, positions past the
+    /// ends of the sequence are filled with '-'.
+    fn get_kmer(&self, seq: &[u8], pos: usize) -> Kmer {
+        self.get_kmer_opt(seq, pos)
+            .map(|b| b.unwrap_or(Self::MISSING))
+            .collect()
+    }
+
+    /// Get the k-mer around `forward_position` in the forward read sequence
+    /// oriented to the `mod_strand`, i.e. reverse complemented for
+    /// negative-strand base modification calls. The window is always taken
+    /// on the forward strand, same as the reference k-mer.
+    pub(crate) fn get_mod_strand_kmer(
+        &self,
+        forward_seq: &[u8],
+        forward_position: usize,
+        mod_strand: Strand,
+    ) -> Kmer {
+        match mod_strand {
+            Strand::Positive => self.get_kmer(forward_seq, forward_position),
+            Strand::Negative => {
+                let mut comp = self
+                    .get_kmer_opt(forward_seq, forward_position)
+                    .map(|b| b.map(complement).unwrap_or(Self::MISSING))
+                    .collect::<Kmer>();
+                comp.reverse();
+                comp
+            }
+        }
+    }
+}
+
 #[derive(new, Debug)]
 pub(crate) struct ModProfile {
-    query_position: usize,
+    pub(crate) query_position: usize,
     pub(crate) ref_position: Option<i64>,
-    num_soft_clipped_start: usize,
-    num_soft_clipped_end: usize,
-    read_length: usize,
-    q_mod: f32,
-    raw_mod_code: char,
-    q_base: u8,
-    query_kmer: [u8; 5],
+    pub(crate) num_soft_clipped_start: usize,
+    pub(crate) num_soft_clipped_end: usize,
+    pub(crate) read_length: usize,
+    pub(crate) q_mod: f32,
+    pub(crate) raw_mod_code: char,
+    pub(crate) q_base: u8,
+    query_kmer: Kmer,
     pub(crate) mod_strand: Strand,
     pub(crate) alignment_strand: Option<Strand>,
-    canonical_base: char,
-    inferred: bool,
+    pub(crate) canonical_base: char,
+    pub(crate) inferred: bool,
 }
 
 impl ModProfile {
-    pub(crate) fn header() -> String {
+    /// Header for the `extract` table, `extra_columns` are appended after
+    /// the default columns.
+    pub(crate) fn header(extra_columns: &[&str]) -> String {
         let tab = '\t';
-        format!(
+        let header = format!(
             "\
             read_id{tab}\
             forward_read_position{tab}\
@@ -399,35 +507,44 @@ impl ModProfile {
             canonical_base{tab}\
             modified_primary_base{tab}\
             inferred"
-        )
+        );
+        extra_columns
+            .iter()
+            .fold(header, |acc, column| format!("{acc}{tab}{column}"))
     }
 
-    pub(crate) fn to_row(
+    pub(crate) fn query_kmer(&self) -> String {
+        self.query_kmer.iter().map(|c| *c as char).join("")
+    }
+
+    /// The reference sequence context, None when the base is not aligned or
+    /// the reference sequence isn't available.
+    pub(crate) fn ref_kmer(
         &self,
-        read_id: &str,
         chrom_name: &str,
         reference_seqs: &HashMap<String, Vec<u8>>,
-    ) -> String {
-        let query_kmer = self.query_kmer.iter().map(|c| *c as char).join("");
-        let ref_kmer = if let Some(ref_pos) = self.ref_position {
-            if ref_pos < 0 {
-                ".".to_string()
-            } else {
-                reference_seqs
-                    .get(chrom_name)
-                    .map(|s| {
-                        ReadsBaseModProfile::get_fivemer(s, ref_pos as usize)
-                            .iter()
-                            .map(|b| *b as char)
-                            .join("")
-                    })
-                    .unwrap_or(".".to_string())
-            }
-        } else {
-            ".".to_string()
-        };
-        let sep = '\t';
-        let modified_primary_base = DnaBase::parse(self.canonical_base)
+        kmer_window: &KmerWindow,
+    ) -> Option<String> {
+        self.ref_position
+            .filter(|&ref_pos| ref_pos >= 0)
+            .and_then(|ref_pos| {
+                reference_seqs.get(chrom_name).map(|s| {
+                    kmer_window
+                        .get_kmer(s, ref_pos as usize)
+                        .iter()
+                        .map(|b| *b as char)
+                        .join("")
+                })
+            })
+    }
+
+    pub(crate) fn ref_mod_strand(&self) -> Option<Strand> {
+        self.alignment_strand
+            .map(|s| get_reference_mod_strand(self.mod_strand, s))
+    }
+
+    pub(crate) fn modified_primary_base(&self) -> char {
+        DnaBase::parse(self.canonical_base)
             .map(|b| {
                 if self.mod_strand == Strand::Negative {
                     b.complement().char()
@@ -435,7 +552,23 @@ impl ModProfile {
                     b.char()
                 }
             })
-            .unwrap_or('?');
+            .unwrap_or('?')
+    }
+
+    pub(crate) fn to_row(
+        &self,
+        read_id: &str,
+        chrom_name: &str,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+        kmer_window: &KmerWindow,
+        extra_fields: &[String],
+    ) -> String {
+        let query_kmer = self.query_kmer();
+        let ref_kmer = self
+            .ref_kmer(chrom_name, reference_seqs, kmer_window)
+            .unwrap_or(".".to_string());
+        let sep = '\t';
+        let modified_primary_base = self.modified_primary_base();
 
         format!(
             "\
@@ -456,14 +589,13 @@ impl ModProfile {
             {}{sep}\
             {}{sep}\
             {}{sep}\
+            {}\
             {}\n",
             self.query_position,
             self.ref_position.unwrap_or(-1),
             self.mod_strand.to_char(),
             self.alignment_strand.map(|s| s.to_char()).unwrap_or('.'),
-            self.alignment_strand
-                .map(|s| get_reference_mod_strand(self.mod_strand, s).to_char())
-                .unwrap_or('.'),
+            self.ref_mod_strand().map(|s| s.to_char()).unwrap_or('.'),
             self.num_soft_clipped_start,
             self.num_soft_clipped_end,
             self.read_length,
@@ -475,6 +607,7 @@ impl ModProfile {
             self.canonical_base,
             modified_primary_base,
             self.inferred,
+            extra_fields.iter().map(|x| format!("{sep}{x}")).join(""),
         )
     }
 }
@@ -484,43 +617,24 @@ pub(crate) struct ReadBaseModProfile {
     pub(crate) record_name: String,
     pub(crate) chrom_id: Option<u32>,
     pub(crate) profile: Vec<ModProfile>,
+    /// Reference start of supplementary alignments, None for primary
+    /// alignments.
+    pub(crate) supplementary_start: Option<i64>,
 }
 
 impl ReadBaseModProfile {
-    fn get_fivemer_from_seq(
+    fn get_kmer_from_seq(
         record: &bam::Record,
         forward_position: usize,
         mod_strand: Strand,
-    ) -> [u8; 5] {
+        kmer_window: &KmerWindow,
+    ) -> Kmer {
         let seq = if record.is_reverse() {
             revcomp(record.seq().as_bytes())
         } else {
             record.seq().as_bytes()
         };
-        let missing = 45;
-        let get_back_base_safe = |i| -> Option<u8> {
-            forward_position
-                .checked_sub(i)
-                .and_then(|idx| seq.get(idx).map(|b| *b))
-        };
-        let fivemer = [
-            get_back_base_safe(2),
-            get_back_base_safe(1),
-            seq.get(forward_position).map(|b| *b),
-            seq.get(forward_position + 1).map(|b| *b),
-            seq.get(forward_position + 2).map(|b| *b),
-        ];
-
-        let fivemer = match mod_strand {
-            Strand::Positive => fivemer,
-            Strand::Negative => {
-                let mut comp = fivemer.map(|b| b.map(complement));
-                comp.reverse();
-                comp
-            }
-        };
-
-        fivemer.map(|b| b.unwrap_or(missing))
+        kmer_window.get_mod_strand_kmer(&seq, forward_position, mod_strand)
     }
 
     #[inline]
@@ -531,7 +645,7 @@ impl ReadBaseModProfile {
         base_mod_probs: BaseModProbs,
         collapse_method: Option<&CollapseMethod>,
         base_qual: u8,
-        fivemer: [u8; 5],
+        kmer: Kmer,
         read_length: usize,
         ref_pos: Option<i64>,
         alignment_strand: Option<Strand>,
@@ -556,7 +670,7 @@ impl ReadBaseModProfile {
                     *prob,
                     *raw_mod_code,
                     base_qual,
-                    fivemer,
+                    kmer.clone(),
                     mod_strand,
                     alignment_strand,
                     primary_base,
@@ -574,7 +688,7 @@ impl ReadBaseModProfile {
         num_clip_end: usize,
         read_length: usize,
         base_qual: u8,
-        fivemer: [u8; 5],
+        kmer: Kmer,
         mod_strand: Strand,
         alignment_strand: Option<Strand>,
         primary_base: char,
@@ -597,7 +711,7 @@ impl ReadBaseModProfile {
                     0f32,
                     raw_mod_code,
                     base_qual,
-                    fivemer,
+                    kmer.clone(),
                     mod_strand,
                     alignment_strand,
                     primary_base,
@@ -613,6 +727,8 @@ impl ReadBaseModProfile {
         mod_base_info: ModBaseInfo,
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
+        kmer_window: &KmerWindow,
+        calibration: Option<&CalibrationMap>,
     ) -> Result<Self, RunError> {
         let read_length = record.seq_len();
         let (num_clip_start, num_clip_end) =
@@ -686,7 +802,23 @@ impl ReadBaseModProfile {
         } else {
             record.qual().to_vec()
         };
-        let forward_sequence = util::get_forward_sequence(&record)?
+        let forward_sequence = util::get_forward_sequence(&record)?;
+        let mod_probs_iter = mod_probs_iter.map(
+            |(primary_base, mod_strand, seq_pos_base_mod_probs)| {
+                let seq_pos_base_mod_probs = match calibration {
+                    Some(calibration) => calibration
+                        .calibrate_seq_pos_base_mod_probs(
+                            seq_pos_base_mod_probs,
+                            primary_base,
+                            mod_strand,
+                            &forward_sequence,
+                        ),
+                    None => seq_pos_base_mod_probs,
+                };
+                (primary_base, mod_strand, seq_pos_base_mod_probs)
+            },
+        );
+        let forward_sequence = forward_sequence
             .char_indices()
             .collect::<Vec<(usize, char)>>();
 
@@ -724,8 +856,9 @@ impl ReadBaseModProfile {
                         let ref_pos = forward_query_pos_to_ref_pos
                             .get(forward_pos)
                             .and_then(|(_query_aligned_pos, ref_pos)| *ref_pos);
-                        let fivemer =
-                            Self::get_fivemer_from_seq(&record, *forward_pos, mod_strand);
+                        let kmer = Self::get_kmer_from_seq(
+                            &record, *forward_pos, mod_strand, kmer_window
+                        );
                         let base_qual =
                             quals.get(*forward_pos).map(|q| *q).unwrap_or_else(|| {
                                 error!( "didn't find base quality for position {forward_pos}" );
@@ -740,7 +873,7 @@ impl ReadBaseModProfile {
                                 base_mod_probs,
                                 collapse_method,
                                 base_qual,
-                                fivemer,
+                                kmer,
                                 read_length,
                                 ref_pos,
                                 alignment_strand,
@@ -757,7 +890,7 @@ impl ReadBaseModProfile {
                                 num_clip_end,
                                 read_length,
                                 base_qual,
-                                fivemer,
+                                kmer,
                                 mod_strand,
                                 alignment_strand,
                                 primary_base,
@@ -777,17 +910,105 @@ impl ReadBaseModProfile {
             }
         });
 
+        let supplementary_start = if record.is_supplementary() {
+            Some(record.pos())
+        } else {
+            None
+        };
+
         Ok(Self {
             record_name: record_name.to_owned(),
             chrom_id: chrom_tid,
             profile: mod_profiles,
+            supplementary_start,
         })
     }
 
+    /// Identifies the alignment, the read name for primary alignments and
+    /// the read name with the contig ID and reference start for supplementary
+    /// alignments so they aren't mistaken for a read that has already been
+    /// seen, matches the keys used by the `ReadCache`.
+    pub(crate) fn alignment_id(&self) -> String {
+        match (self.supplementary_start, self.chrom_id) {
+            (Some(start), Some(tid)) => {
+                format!("{}:{tid}:{start}", self.record_name)
+            }
+            (Some(start), None) => format!("{}:-1:{start}", self.record_name),
+            (None, _) => self.record_name.to_owned(),
+        }
+    }
+
     pub(crate) fn remove_inferred(self) -> Self {
         let profile =
             self.profile.into_iter().filter(|p| !p.inferred).collect();
-        Self::new(self.record_name, self.chrom_id, profile)
+        Self::new(
+            self.record_name,
+            self.chrom_id,
+            profile,
+            self.supplementary_start,
+        )
+    }
+
+    /// Gather the per-mod-code probabilities into the base modification
+    /// probabilities at each site (forward query position and mod strand) on
+    /// the read. The first `ModProfile` at the site is kept alongside for the
+    /// position and base information.
+    pub(crate) fn site_base_mod_probs(
+        &self,
+    ) -> FxHashMap<(usize, Strand), (&ModProfile, BaseModProbs)> {
+        self.profile.iter().fold(
+            FxHashMap::default(),
+            |mut acc, mod_profile| {
+                acc.entry((mod_profile.query_position, mod_profile.mod_strand))
+                    .or_insert_with(|| {
+                        (mod_profile, BaseModProbs::new(FxHashMap::default()))
+                    })
+                    .1
+                    .insert_base_mod_prob(
+                        mod_profile.raw_mod_code,
+                        mod_profile.q_mod,
+                    );
+                acc
+            },
+        )
+    }
+
+    /// Make a base modification call at each site on the read, returns the
+    /// called code ('-' for canonical) and whether the call fails the pass
+    /// threshold. For failing calls the code is the most likely class.
+    pub(crate) fn call_sites(
+        &self,
+        threshold_caller: &MultipleThresholdModCaller,
+    ) -> FxHashMap<(usize, Strand), (char, bool)> {
+        self.site_base_mod_probs()
+            .into_iter()
+            .filter_map(|(site, (mod_profile, base_mod_probs))| {
+                let call = DnaBase::parse(mod_profile.canonical_base)
+                    .and_then(|base| {
+                        threshold_caller.call(&base, &base_mod_probs)
+                    })
+                    .and_then(|call| match call {
+                        BaseModCall::Filtered => base_mod_probs
+                            .argmax_base_mod_call()
+                            .map(|argmax| (argmax, true)),
+                        _ => Ok((call, false)),
+                    });
+                match call {
+                    Ok((BaseModCall::Modified(_, mod_code), fail)) => {
+                        Some((site, (mod_code.char(), fail)))
+                    }
+                    Ok((_, fail)) => Some((site, ('-', fail))),
+                    Err(e) => {
+                        debug!(
+                            "failed to call base modification on {}, {}",
+                            &self.record_name,
+                            e.to_string()
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
     }
 }
 
@@ -799,19 +1020,6 @@ pub(crate) struct ReadsBaseModProfile {
 }
 
 impl ReadsBaseModProfile {
-    // todo(arand) need to make these safe subtractions
-    fn get_fivemer(seq: &[u8], pos: usize) -> [u8; 5] {
-        let missing = 45u8;
-        let fivemer = [
-            seq.get(pos - 2).map(|b| *b),
-            seq.get(pos - 1).map(|b| *b),
-            seq.get(pos).map(|b| *b),
-            seq.get(pos + 1).map(|b| *b),
-            seq.get(pos + 2).map(|b| *b),
-        ];
-        fivemer.map(|b| b.unwrap_or(missing))
-    }
-
     fn get_soft_clipped(cigar: &[Cigar]) -> anyhow::Result<(usize, usize)> {
         let mut sc_start = None;
         let mut sc_end = None;
@@ -907,13 +1115,38 @@ impl RecordProcessor for ReadsBaseModProfile {
     fn process_records<T: Read>(
         records: Records<T>,
         with_progress: bool,
-        mut record_sampler: RecordSampler,
+        record_sampler: RecordSampler,
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
         _position_filter: Option<&StrandedPositionFilter>,
         _only_mapped: bool,
     ) -> anyhow::Result<Self::Output> {
-        let mut mod_iter = TrackingModRecordIter::new(records, false);
+        Self::process_records_with_kmer_window(
+            records,
+            with_progress,
+            record_sampler,
+            collapse_method,
+            edge_filter,
+            &KmerWindow::default(),
+            false,
+            None,
+        )
+    }
+}
+
+impl ReadsBaseModProfile {
+    pub(crate) fn process_records_with_kmer_window<T: Read>(
+        records: Records<T>,
+        with_progress: bool,
+        mut record_sampler: RecordSampler,
+        collapse_method: Option<&CollapseMethod>,
+        edge_filter: Option<&EdgeFilter>,
+        kmer_window: &KmerWindow,
+        include_supplementary: bool,
+        calibration: Option<&CalibrationMap>,
+    ) -> anyhow::Result<Self> {
+        let mut mod_iter =
+            TrackingModRecordIter::new(records, false, include_supplementary);
         let mut agg = Vec::new();
         let mut seen = HashSet::new();
         let pb = if with_progress {
@@ -933,12 +1166,16 @@ impl RecordProcessor for ReadsBaseModProfile {
                         modbase_info,
                         collapse_method,
                         edge_filter,
+                        kmer_window,
+                        calibration,
                     ) {
                         Ok(read_base_mod_profile) => {
-                            if seen.contains(&record_name) {
-                                debug!("double add of record {record_name}");
+                            let alignment_id =
+                                read_base_mod_profile.alignment_id();
+                            if seen.contains(&alignment_id) {
+                                debug!("double add of record {alignment_id}");
                             } else {
-                                seen.insert(record_name);
+                                seen.insert(alignment_id);
                             }
                             agg.push(read_base_mod_profile);
 
@@ -986,6 +1223,77 @@ impl WithRecords for ReadsBaseModProfile {
 
 #[cfg(test)]
 mod read_ids_to_base_mod_probs_tests {
+    use crate::read_ids_to_base_mod_probs::{KmerWindow, ReadBaseModProfile};
+    use crate::util::Strand;
+
+    #[test]
+    fn test_kmer_window() {
+        let seq = "ACGTACGTAC".as_bytes();
+        let five = KmerWindow::default();
+        assert_eq!(five, KmerWindow::from_kmer_size(5).unwrap());
+        assert_eq!(five.get_kmer(seq, 4).as_slice(), "GTACG".as_bytes());
+        // positions past the ends are filled in
+        assert_eq!(five.get_kmer(seq, 0).as_slice(), "--ACG".as_bytes());
+        assert_eq!(five.get_kmer(seq, 9).as_slice(), "TAC--".as_bytes());
+
+        let nine = KmerWindow::from_kmer_size(9).unwrap();
+        assert_eq!(nine.kmer_size(), 9);
+        assert_eq!(nine.get_kmer(seq, 4).as_slice(), "ACGTACGTA".as_bytes());
+        assert!(KmerWindow::from_kmer_size(4).is_err());
+
+        let asymmetric = KmerWindow::parse_str("1,3").unwrap();
+        assert_eq!(asymmetric.get_kmer(seq, 4).as_slice(), "TACGT".as_bytes());
+        assert!(KmerWindow::parse_str("1").is_err());
+        assert!(KmerWindow::parse_str("1,a").is_err());
+    }
+
+    #[test]
+    fn test_mod_strand_kmer() {
+        let seq = "ACGTACGTAC".as_bytes();
+        // the window is on the forward strand, negative-strand k-mers are the
+        // reverse complement of the same window
+        let asymmetric = KmerWindow::parse_str("1,3").unwrap();
+        assert_eq!(
+            asymmetric
+                .get_mod_strand_kmer(seq, 4, Strand::Positive)
+                .as_slice(),
+            "TACGT".as_bytes()
+        );
+        assert_eq!(
+            asymmetric
+                .get_mod_strand_kmer(seq, 4, Strand::Negative)
+                .as_slice(),
+            "ACGTA".as_bytes()
+        );
+        let five = KmerWindow::default();
+        assert_eq!(
+            five.get_mod_strand_kmer(seq, 0, Strand::Negative)
+                .as_slice(),
+            "CGT--".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_alignment_id() {
+        let primary =
+            ReadBaseModProfile::new("read".to_string(), Some(0), vec![], None);
+        assert_eq!(primary.alignment_id(), "read");
+        let supplementary = |tid: u32| {
+            ReadBaseModProfile::new(
+                "read".to_string(),
+                Some(tid),
+                vec![],
+                Some(100),
+            )
+        };
+        // same start on different contigs are different alignments
+        assert_eq!(supplementary(0).alignment_id(), "read:0:100");
+        assert_ne!(
+            supplementary(0).alignment_id(),
+            supplementary(1).alignment_id()
+        );
+    }
+
     #[test]
     fn test_cigar_finds_softclips() {
         // todo
@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use derive_new::new;
+use indicatif::MultiProgress;
+use log::{debug, info};
+use rust_htslib::bam::{self, Read};
+use rustc_hash::FxHashMap;
+
+use crate::command_utils::{
+    parse_edge_filter_input, parse_per_mod_thresholds, parse_thresholds,
+};
+use crate::errs::RunError;
+use crate::logging::init_logging;
+use crate::mod_bam::{
+    BaseModCall, BaseModProbs, CollapseMethod, TrackingModRecordIter,
+};
+use crate::mod_base_code::{DnaBase, ModCode};
+use crate::motif_bed::{get_masked_sequences, MotifLocations, RegexMotif};
+use crate::read_ids_to_base_mod_probs::{KmerWindow, ReadBaseModProfile};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{
+    get_reference_mod_strand, get_spinner, get_stringable_aux,
+    parse_partition_tags, record_is_secondary, Region, Strand, StrandRule,
+};
+
+#[derive(Args)]
+pub struct ReadMatrix {
+    /// Input modBAM, must be sorted and have an associated index.
+    in_bam: PathBuf,
+    /// Path to output file, "stdout" or "-" will direct output to standard out.
+    out_path: String,
+    /// Region to export the matrix for, format should be
+    /// <chrom_name>:<start>-<end> or <chrom_name>. Every motif position in
+    /// the region becomes a column in the output.
+    #[arg(long)]
+    region: String,
+    /// Reference sequence in FASTA format, used to find the motif positions
+    /// in the region. (alias: ref)
+    #[arg(long, alias = "ref")]
+    reference_fasta: PathBuf,
+    /// Export sites matching a sequence motif. The first argument should be
+    /// the sequence motif and the second argument is the 0-based offset to
+    /// the modified base in the motif, for example --motif CGCG 0. This
+    /// argument can be passed multiple times.
+    #[arg(long, action = clap::ArgAction::Append, num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Export CpG sites, shorthand for --motif CG 0.
+    #[arg(long, default_value_t = false)]
+    cpg: bool,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, default_value_t = false)]
+    mask: bool,
+    /// Use one column per motif for both strands. Calls on the negative
+    /// strand are reported in the column of the corresponding positive
+    /// strand position. All motifs must be reverse-complement palindromic.
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Report the probability (or call) for only this modification code.
+    /// By default cells contain the summed probability of all modifications
+    /// at the site.
+    #[arg(long)]
+    mod_code: Option<char>,
+    /// Report thresholded base modification calls instead of
+    /// probabilities. Thresholds are specified the same way as for `pileup`,
+    /// for example --filter-threshold C:0.8. Cells will contain the called
+    /// modification code, `-` for canonical calls, or `F` for calls that fail
+    /// the threshold. When --mod-code is also passed, cells contain 1 when
+    /// that modification is called and 0 otherwise.
+    #[arg(long, action = clap::ArgAction::Append)]
+    filter_threshold: Option<Vec<String>>,
+    /// Specify a passing threshold to use for a base modification,
+    /// independent of the threshold for the primary sequence base, for
+    /// example --mod-threshold h:0.8. Requires --filter-threshold.
+    #[arg(
+        long,
+        alias = "mod-threshold",
+        requires = "filter_threshold",
+        action = clap::ArgAction::Append
+    )]
+    mod_thresholds: Option<Vec<String>>,
+    /// Add a column with the value of this SAM tag (e.g. HP) for each read.
+    /// Reads missing the tag will have "." in the column. This argument can
+    /// be passed multiple times.
+    #[arg(long, action = clap::ArgAction::Append)]
+    tag: Option<Vec<String>>,
+    /// Ignore a modified base class  _in_situ_ by redistributing base
+    /// modification probability equally across other options. See
+    /// collapse.md for details.
+    #[arg(long, hide_short_help = true)]
+    ignore: Option<char>,
+    /// Discard base modification calls that are this many bases from the
+    /// start or the end of the read. Two comma-separated values may be
+    /// provided to asymmetrically filter out base modification calls from the
+    /// start and end of the reads.
+    #[arg(long)]
+    edge_filter: Option<String>,
+    /// Invert the edge filter, instead of filtering out base modification
+    /// calls at the ends of reads, only _keep_ base modification calls at the
+    /// ends of reads.
+    #[arg(long, requires = "edge_filter", default_value_t = false)]
+    invert_edge_filter: bool,
+    /// Number of threads to use for reading the modBAM.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Path to file to write run log.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Force overwrite of output file
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+}
+
+impl ReadMatrix {
+    fn get_writer(&self) -> anyhow::Result<Box<dyn Write>> {
+        match self.out_path.as_str() {
+            "stdout" | "-" => Ok(Box::new(BufWriter::new(std::io::stdout()))),
+            fp => {
+                let p = Path::new(fp);
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                }
+                let fh = File::create(p)?;
+                Ok(Box::new(BufWriter::new(fh)))
+            }
+        }
+    }
+
+    fn parse_motifs(&self) -> anyhow::Result<Vec<RegexMotif>> {
+        let mut raw_motif_parts = self.motif.clone().unwrap_or(Vec::new());
+        if raw_motif_parts.len() % 2 != 0 {
+            bail!("illegal number of parts for motif")
+        }
+        if self.cpg
+            && !raw_motif_parts.chunks(2).any(|motif| motif == ["CG", "0"])
+        {
+            raw_motif_parts.extend_from_slice(&["CG".to_string(), "0".into()]);
+        }
+        if raw_motif_parts.is_empty() {
+            bail!("need to specify either --motif or --cpg")
+        }
+        let motifs = raw_motif_parts
+            .chunks(2)
+            .map(|c| {
+                let focus_base = c[1].parse::<usize>().map_err(|e| {
+                    anyhow!("failed to parse motif offset {}, {e}", &c[1])
+                })?;
+                RegexMotif::parse_string(c[0].as_str(), focus_base)
+            })
+            .collect::<anyhow::Result<Vec<RegexMotif>>>()?;
+        if self.combine_strands && motifs.iter().any(|m| !m.is_palendrome()) {
+            bail!(
+                "cannot combine strands with a motif that is not a palindrome"
+            )
+        }
+        Ok(motifs)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let collapse_method = match &self.ignore {
+            Some(raw_mod_code) => {
+                let _ = ModCode::parse_raw_mod_code(*raw_mod_code)?;
+                Some(CollapseMethod::ReDistribute(*raw_mod_code))
+            }
+            None => None,
+        };
+        let edge_filter = self
+            .edge_filter
+            .as_ref()
+            .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
+            .transpose()?;
+        let threshold_caller = self
+            .filter_threshold
+            .as_ref()
+            .map(|raw_thresholds| {
+                let per_mod_thresholds = self
+                    .mod_thresholds
+                    .as_ref()
+                    .map(|raw| parse_per_mod_thresholds(raw))
+                    .transpose()?;
+                parse_thresholds(raw_thresholds, per_mod_thresholds)
+            })
+            .transpose()?;
+        let tags = self
+            .tag
+            .as_ref()
+            .map(|raw_tags| parse_partition_tags(raw_tags))
+            .transpose()?
+            .unwrap_or(Vec::new());
+        let regex_motifs = self.parse_motifs()?;
+
+        let mut reader = bam::IndexedReader::from_path(&self.in_bam)
+            .context("failed to open modBAM, an index is required")?;
+        reader.set_threads(self.threads)?;
+        let header = reader.header().to_owned();
+        let region = Region::parse_str(&self.region, &header)?;
+        let tid = header.tid(region.name.as_bytes()).ok_or_else(|| {
+            anyhow!("didn't find {} in modBAM header", &region.name)
+        })?;
+
+        let master_progress = MultiProgress::new();
+        if self.suppress_progress {
+            master_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        let name_to_tid = HashMap::from([(region.name.as_str(), tid)]);
+        let sequences = get_masked_sequences(
+            &self.reference_fasta,
+            &name_to_tid,
+            self.mask,
+            &master_progress,
+        )?;
+        if sequences.is_empty() {
+            bail!("didn't find {} in reference FASTA", &region.name)
+        }
+        let motif_locations = regex_motifs
+            .into_iter()
+            .map(|regex_motif| {
+                MotifLocations::from_sequences(
+                    regex_motif,
+                    None,
+                    &sequences,
+                    &master_progress,
+                )
+            })
+            .collect::<anyhow::Result<Vec<MotifLocations>>>()?;
+        let columns = MatrixColumns::from_motif_locations(
+            &motif_locations,
+            tid,
+            region.start,
+            region.end,
+            self.combine_strands,
+        );
+        if columns.is_empty() {
+            bail!("no motif positions found in {}", region.to_string())
+        }
+        info!(
+            "exporting {} sites in {}",
+            columns.labels.len(),
+            region.to_string()
+        );
+
+        let cell_formatter =
+            CellFormatter::new(self.mod_code, threshold_caller);
+        let mut writer = self.get_writer()?;
+        let tag_names = self.tag.clone().unwrap_or(Vec::new());
+        writer.write_all(columns.header(&tag_names).as_bytes())?;
+
+        reader.fetch(region.get_fetch_definition(&header)?)?;
+        let pb = master_progress.add(get_spinner());
+        pb.set_message("reads processed");
+        let mut n_rows = 0usize;
+        let mut mod_iter =
+            TrackingModRecordIter::new(reader.records(), true, false);
+        for (record, read_id, mod_base_info) in &mut mod_iter {
+            pb.inc(1);
+            if record_is_secondary(&record) {
+                continue;
+            }
+            let read_base_mod_profile = match ReadBaseModProfile::process_record(
+                &record,
+                &read_id,
+                mod_base_info,
+                collapse_method.as_ref(),
+                edge_filter.as_ref(),
+                &KmerWindow::default(),
+                None,
+            ) {
+                Ok(profile) => profile,
+                Err(RunError::Skipped(reason)) => {
+                    debug!("skipped {read_id}, {reason}");
+                    continue;
+                }
+                Err(e) => {
+                    debug!("failed to process {read_id}, {}", e.to_string());
+                    continue;
+                }
+            };
+            let tag_values = tags
+                .iter()
+                .map(|tag| {
+                    get_stringable_aux(&record, tag).unwrap_or(".".to_string())
+                })
+                .collect::<Vec<String>>();
+            let strand = if record.is_reverse() {
+                Strand::Negative
+            } else {
+                Strand::Positive
+            };
+            let read_row = ReadRow::from_profile(
+                read_base_mod_profile,
+                strand,
+                tag_values,
+                &columns,
+                &cell_formatter,
+            )?;
+            if let Some(row) = read_row.to_row(columns.labels.len()) {
+                writer.write_all(row.as_bytes())?;
+                n_rows += 1;
+            }
+        }
+        writer.flush()?;
+        pb.finish_and_clear();
+        info!(
+            "wrote {n_rows} reads, skipped {}, failed {}",
+            mod_iter.num_skipped, mod_iter.num_failed
+        );
+
+        Ok(())
+    }
+}
+
+/// The motif positions in a region, the columns of the matrix.
+struct MatrixColumns {
+    labels: Vec<String>,
+    lookup: FxHashMap<(u32, Strand), usize>,
+}
+
+impl MatrixColumns {
+    fn from_motif_locations(
+        motif_locations: &[MotifLocations],
+        tid: u32,
+        start: u32,
+        end: u32,
+        combine_strands: bool,
+    ) -> Self {
+        // (position, strand) of every site in the region, and when combining
+        // strands, the negative strand positions pointing at the positive
+        // strand site they belong to
+        let mut sites = Vec::new();
+        let mut negative_to_positive = FxHashMap::default();
+        for locations in motif_locations {
+            let positions = match locations.targets_to_positions().get(&tid) {
+                Some(positions) => positions,
+                None => continue,
+            };
+            for (&pos, strand_rule) in positions
+                .iter()
+                .filter(|(&pos, _)| pos >= start && pos < end)
+            {
+                let (positive, negative) = match strand_rule {
+                    StrandRule::Positive => (true, false),
+                    StrandRule::Negative => (false, true),
+                    StrandRule::Both => (true, true),
+                };
+                if combine_strands {
+                    if positive {
+                        sites.push((pos, Strand::Positive));
+                        if let Some(neg_pos) =
+                            locations.motif().negative_strand_position(pos)
+                        {
+                            negative_to_positive.insert(neg_pos, pos);
+                        }
+                    }
+                } else {
+                    if positive {
+                        sites.push((pos, Strand::Positive));
+                    }
+                    if negative {
+                        sites.push((pos, Strand::Negative));
+                    }
+                }
+            }
+        }
+        sites.sort();
+        sites.dedup();
+
+        let labels = sites
+            .iter()
+            .map(|(pos, strand)| {
+                if combine_strands {
+                    format!("{pos}")
+                } else {
+                    format!("{pos}:{}", strand.to_char())
+                }
+            })
+            .collect::<Vec<String>>();
+        let mut lookup = sites
+            .into_iter()
+            .enumerate()
+            .map(|(idx, site)| (site, idx))
+            .collect::<FxHashMap<(u32, Strand), usize>>();
+        for (neg_pos, pos) in negative_to_positive {
+            if let Some(idx) = lookup.get(&(pos, Strand::Positive)).copied() {
+                lookup.insert((neg_pos, Strand::Negative), idx);
+            }
+        }
+
+        Self { labels, lookup }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    fn get_column(&self, position: u32, strand: Strand) -> Option<usize> {
+        self.lookup.get(&(position, strand)).copied()
+    }
+
+    fn header(&self, tags: &[String]) -> String {
+        let mut fields = vec!["read_id".to_string(), "strand".to_string()];
+        fields.extend(tags.iter().cloned());
+        fields.extend(self.labels.iter().cloned());
+        format!("{}\n", fields.join("\t"))
+    }
+}
+
+/// Converts the base modification probabilities at a site into the value
+/// reported in the cell of the matrix.
+#[derive(new)]
+struct CellFormatter {
+    mod_code: Option<char>,
+    threshold_caller: Option<MultipleThresholdModCaller>,
+}
+
+impl CellFormatter {
+    fn format(
+        &self,
+        canonical_base: &DnaBase,
+        base_mod_probs: &BaseModProbs,
+    ) -> anyhow::Result<String> {
+        if let Some(caller) = self.threshold_caller.as_ref() {
+            let call = caller.call(canonical_base, base_mod_probs)?;
+            let cell = match (call, self.mod_code) {
+                (BaseModCall::Filtered, _) => "F".to_string(),
+                (BaseModCall::Modified(_, mod_code), Some(code)) => {
+                    if mod_code.char() == code {
+                        "1".to_string()
+                    } else {
+                        "0".to_string()
+                    }
+                }
+                (BaseModCall::Canonical(_), Some(_)) => "0".to_string(),
+                (BaseModCall::Modified(_, mod_code), None) => {
+                    mod_code.char().to_string()
+                }
+                (BaseModCall::Canonical(_), None) => "-".to_string(),
+            };
+            Ok(cell)
+        } else {
+            let p = match self.mod_code {
+                Some(code) => base_mod_probs
+                    .iter_probs()
+                    .filter(|(mod_code, _)| **mod_code == code)
+                    .map(|(_, p)| *p)
+                    .sum::<f32>(),
+                None => 1f32 - base_mod_probs.canonical_prob(),
+            };
+            Ok(format!("{p:.3}"))
+        }
+    }
+}
+
+/// One row of the matrix, the cells a read covers and the read's metadata.
+struct ReadRow {
+    read_id: String,
+    strand: Strand,
+    tag_values: Vec<String>,
+    cells: FxHashMap<usize, String>,
+}
+
+impl ReadRow {
+    fn from_profile(
+        read_base_mod_profile: ReadBaseModProfile,
+        strand: Strand,
+        tag_values: Vec<String>,
+        columns: &MatrixColumns,
+        cell_formatter: &CellFormatter,
+    ) -> anyhow::Result<Self> {
+        // gather the per-mod-code probabilities into the column they belong
+        // to, when strands are combined a duplex read may have calls on both
+        // strands for a column, these are averaged
+        let mut column_probs =
+            FxHashMap::<usize, (char, BaseModProbs, usize)>::default();
+        let mut site_probs =
+            FxHashMap::<(usize, u32, Strand), BaseModProbs>::default();
+        for mod_profile in read_base_mod_profile.profile {
+            let (ref_pos, alignment_strand) = match (
+                mod_profile.ref_position,
+                mod_profile.alignment_strand,
+            ) {
+                (Some(ref_pos), Some(alignment_strand)) if ref_pos >= 0 => {
+                    (ref_pos as u32, alignment_strand)
+                }
+                _ => continue,
+            };
+            let ref_mod_strand = get_reference_mod_strand(
+                mod_profile.mod_strand,
+                alignment_strand,
+            );
+            let column = match columns.get_column(ref_pos, ref_mod_strand) {
+                Some(column) => column,
+                None => continue,
+            };
+            site_probs
+                .entry((column, ref_pos, ref_mod_strand))
+                .or_insert_with(|| BaseModProbs::new(FxHashMap::default()))
+                .insert_base_mod_prob(
+                    mod_profile.raw_mod_code,
+                    mod_profile.q_mod,
+                );
+            column_probs.entry(column).or_insert_with(|| {
+                (
+                    mod_profile.canonical_base,
+                    BaseModProbs::new(FxHashMap::default()),
+                    0,
+                )
+            });
+        }
+        for ((column, _, _), probs) in site_probs {
+            let (_, acc, n) = column_probs
+                .get_mut(&column)
+                .expect("should have added column");
+            for (mod_code, p) in probs.iter_probs() {
+                acc.insert_base_mod_prob(*mod_code, *p);
+            }
+            *n += 1;
+        }
+
+        let cells = column_probs
+            .into_iter()
+            .map(|(column, (canonical_base, mut probs, n))| {
+                if n > 1 {
+                    probs.iter_mut_probs().for_each(|p| *p /= n as f32);
+                }
+                let canonical_base = DnaBase::parse(canonical_base)?;
+                cell_formatter
+                    .format(&canonical_base, &probs)
+                    .map(|cell| (column, cell))
+            })
+            .collect::<anyhow::Result<FxHashMap<usize, String>>>()?;
+
+        Ok(Self {
+            read_id: read_base_mod_profile.record_name,
+            strand,
+            tag_values,
+            cells,
+        })
+    }
+
+    /// Make the output row, None when the read doesn't have a call at any
+    /// of the sites.
+    fn to_row(&self, n_columns: usize) -> Option<String> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let mut fields =
+            vec![self.read_id.clone(), self.strand.to_char().into()];
+        fields.extend(self.tag_values.iter().cloned());
+        fields.extend((0..n_columns).map(|idx| {
+            self.cells.get(&idx).cloned().unwrap_or("NA".to_string())
+        }));
+        Some(format!("{}\n", fields.join("\t")))
+    }
+}
+
+#[cfg(test)]
+mod read_matrix_tests {
+    use indicatif::MultiProgress;
+    use rustc_hash::FxHashMap;
+
+    use crate::mod_bam::BaseModProbs;
+    use crate::mod_base_code::DnaBase;
+    use crate::motif_bed::{MotifLocations, RegexMotif};
+    use crate::read_matrix::{CellFormatter, MatrixColumns};
+    use crate::threshold_mod_caller::MultipleThresholdModCaller;
+    use crate::util::Strand;
+
+    fn get_cpg_locations(seq: &str) -> MotifLocations {
+        let master_progress = MultiProgress::new();
+        master_progress
+            .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        MotifLocations::from_sequences(
+            RegexMotif::parse_string("CG", 0).unwrap(),
+            None,
+            &[(seq.to_string(), 0)],
+            &master_progress,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_matrix_columns() {
+        let locations = [get_cpg_locations("ACGTTCGAACG")];
+        let columns =
+            MatrixColumns::from_motif_locations(&locations, 0, 0, 8, false);
+        assert_eq!(columns.labels, vec!["1:+", "2:-", "5:+", "6:-"]);
+        assert_eq!(columns.get_column(6, Strand::Negative), Some(3));
+        assert_eq!(columns.get_column(8, Strand::Positive), None);
+
+        let combined =
+            MatrixColumns::from_motif_locations(&locations, 0, 0, 8, true);
+        assert_eq!(combined.labels, vec!["1", "5"]);
+        assert_eq!(combined.get_column(2, Strand::Negative), Some(0));
+        assert_eq!(combined.get_column(6, Strand::Negative), Some(1));
+        assert_eq!(
+            combined.header(&["HP".to_string()]),
+            "read_id\tstrand\tHP\t1\t5\n"
+        );
+    }
+
+    #[test]
+    fn test_cell_formatter() {
+        let probs = BaseModProbs::new(FxHashMap::from_iter([
+            ('m', 0.7f32),
+            ('h', 0.1f32),
+        ]));
+        let formatter = CellFormatter::new(None, None);
+        assert_eq!(formatter.format(&DnaBase::C, &probs).unwrap(), "0.800");
+        let formatter = CellFormatter::new(Some('h'), None);
+        assert_eq!(formatter.format(&DnaBase::C, &probs).unwrap(), "0.100");
+
+        let caller = MultipleThresholdModCaller::new(
+            Default::default(),
+            Default::default(),
+            0.6,
+        );
+        let formatter = CellFormatter::new(None, Some(caller));
+        assert_eq!(formatter.format(&DnaBase::C, &probs).unwrap(), "m");
+        let caller = MultipleThresholdModCaller::new(
+            Default::default(),
+            Default::default(),
+            0.9,
+        );
+        let formatter = CellFormatter::new(Some('m'), Some(caller));
+        assert_eq!(formatter.format(&DnaBase::C, &probs).unwrap(), "F");
+    }
+}
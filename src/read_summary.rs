@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+
+use derive_new::new;
+use itertools::Itertools;
+use log::debug;
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+
+use crate::mod_bam::{BaseModCall, BaseModProbs};
+use crate::mod_base_code::DnaBase;
+use crate::position_filter::StrandedPositionFilter;
+use crate::read_ids_to_base_mod_probs::ReadBaseModProfile;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{
+    get_reference_mod_strand, get_stringable_aux, SamTag, Strand,
+};
+
+/// Aggregated base modification calls over a set of sites on a read.
+#[derive(Default, Debug, PartialEq)]
+pub(crate) struct CallCounts {
+    n_sites: usize,
+    n_fail: usize,
+    n_modified: usize,
+    sum_mod_prob: f64,
+    // counts of passing calls keyed by the modification code, or the
+    // primary base for canonical calls
+    call_counts: BTreeMap<char, usize>,
+}
+
+impl CallCounts {
+    fn add(
+        &mut self,
+        primary_base: char,
+        base_mod_probs: &BaseModProbs,
+        call: BaseModCall,
+    ) {
+        self.n_sites += 1;
+        self.sum_mod_prob += (1f32 - base_mod_probs.canonical_prob()) as f64;
+        match call {
+            BaseModCall::Modified(_, mod_code) => {
+                self.n_modified += 1;
+                *self.call_counts.entry(mod_code.char()).or_insert(0) += 1;
+            }
+            BaseModCall::Canonical(_) => {
+                *self.call_counts.entry(primary_base).or_insert(0) += 1;
+            }
+            BaseModCall::Filtered => self.n_fail += 1,
+        }
+    }
+
+    fn mean_mod_prob(&self) -> Option<f64> {
+        if self.n_sites == 0 {
+            None
+        } else {
+            Some(self.sum_mod_prob / self.n_sites as f64)
+        }
+    }
+
+    fn frac_modified(&self) -> Option<f64> {
+        let n_pass = self.n_sites - self.n_fail;
+        if n_pass == 0 {
+            None
+        } else {
+            Some(self.n_modified as f64 / n_pass as f64)
+        }
+    }
+
+    fn string_counts(&self) -> String {
+        if self.call_counts.is_empty() {
+            ".".to_string()
+        } else {
+            self.call_counts
+                .iter()
+                .map(|(code, count)| format!("{code}:{count}"))
+                .join(",")
+        }
+    }
+
+    fn to_fields(&self) -> [String; 3] {
+        let fmt = |x: Option<f64>| {
+            x.map(|x| format!("{x:.4}")).unwrap_or(".".to_string())
+        };
+        [
+            format!("{}", self.n_sites),
+            fmt(self.mean_mod_prob()),
+            fmt(self.frac_modified()),
+        ]
+    }
+}
+
+/// One row of the `extract --read-summary` table.
+#[derive(new, Debug)]
+pub(crate) struct ReadSummary {
+    pub(crate) read_id: String,
+    chrom_id: Option<u32>,
+    ref_start: Option<i64>,
+    ref_end: Option<i64>,
+    strand: Option<Strand>,
+    read_length: usize,
+    mapq: u8,
+    tag_values: Vec<String>,
+    counts: CallCounts,
+    region_counts: Option<CallCounts>,
+}
+
+impl ReadSummary {
+    /// Summarize the base modification calls on a read. The
+    /// `read_base_mod_profile` should already have any position filtering
+    /// applied. When `regions` is provided, an additional set of counts is
+    /// made using only the sites overlapping the regions.
+    pub(crate) fn from_record(
+        record: &bam::Record,
+        read_base_mod_profile: ReadBaseModProfile,
+        partition_tags: &[SamTag],
+        threshold_caller: &MultipleThresholdModCaller,
+        regions: Option<&StrandedPositionFilter>,
+    ) -> Self {
+        let chrom_id = read_base_mod_profile.chrom_id;
+        let mut counts = CallCounts::default();
+        let mut region_counts = regions.map(|_| CallCounts::default());
+
+        for (mod_profile, base_mod_probs) in
+            read_base_mod_profile.site_base_mod_probs().into_values()
+        {
+            let primary_base = mod_profile.canonical_base;
+            let ref_pos_and_strand = match (
+                mod_profile.ref_position,
+                mod_profile.alignment_strand,
+            ) {
+                (Some(ref_pos), Some(alignment_strand)) if ref_pos >= 0 => {
+                    Some((
+                        ref_pos,
+                        get_reference_mod_strand(
+                            mod_profile.mod_strand,
+                            alignment_strand,
+                        ),
+                    ))
+                }
+                _ => None,
+            };
+            let call = match DnaBase::parse(primary_base)
+                .and_then(|base| threshold_caller.call(&base, &base_mod_probs))
+            {
+                Ok(call) => call,
+                Err(e) => {
+                    debug!(
+                        "failed to call base modification on {}, {}",
+                        &read_base_mod_profile.record_name,
+                        e.to_string()
+                    );
+                    continue;
+                }
+            };
+            let in_regions = match (regions, chrom_id, ref_pos_and_strand) {
+                (Some(regions), Some(chrom_id), Some((ref_pos, strand))) => {
+                    regions.contains(chrom_id as i32, ref_pos as u64, strand)
+                }
+                _ => false,
+            };
+            if in_regions {
+                if let Some(region_counts) = region_counts.as_mut() {
+                    region_counts.add(primary_base, &base_mod_probs, call);
+                }
+            }
+            counts.add(primary_base, &base_mod_probs, call);
+        }
+
+        let (ref_start, ref_end, strand) = if record.is_unmapped() {
+            (None, None, None)
+        } else {
+            let strand = if record.is_reverse() {
+                Strand::Negative
+            } else {
+                Strand::Positive
+            };
+            (
+                Some(record.reference_start()),
+                Some(record.reference_end()),
+                Some(strand),
+            )
+        };
+        let tag_values = partition_tags
+            .iter()
+            .map(|tag| {
+                get_stringable_aux(record, tag).unwrap_or(".".to_string())
+            })
+            .collect::<Vec<String>>();
+
+        Self::new(
+            read_base_mod_profile.record_name,
+            chrom_id,
+            ref_start,
+            ref_end,
+            strand,
+            record.seq_len(),
+            record.mapq(),
+            tag_values,
+            counts,
+            region_counts,
+        )
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counts.n_sites == 0
+    }
+
+    pub(crate) fn header(tag_names: &[String], with_regions: bool) -> String {
+        let mut fields = [
+            "read_id",
+            "chrom",
+            "ref_start",
+            "ref_end",
+            "ref_strand",
+            "read_length",
+            "mapq",
+            "n_sites",
+            "mean_mod_prob",
+            "frac_modified",
+            "n_fail",
+            "call_counts",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+        fields.extend(tag_names.iter().cloned());
+        if with_regions {
+            fields.extend(
+                [
+                    "n_sites_in_regions",
+                    "mean_mod_prob_in_regions",
+                    "frac_modified_in_regions",
+                ]
+                .into_iter()
+                .map(|s| s.to_string()),
+            );
+        }
+        fields.join("\t")
+    }
+
+    pub(crate) fn to_row(&self, chrom_name: &str) -> String {
+        let fmt_pos =
+            |x: Option<i64>| x.map(|x| x.to_string()).unwrap_or("-1".into());
+        let mut fields = vec![
+            self.read_id.clone(),
+            chrom_name.to_string(),
+            fmt_pos(self.ref_start),
+            fmt_pos(self.ref_end),
+            self.strand.map(|s| s.to_char()).unwrap_or('.').to_string(),
+            self.read_length.to_string(),
+            self.mapq.to_string(),
+        ];
+        fields.extend(self.counts.to_fields());
+        fields.push(self.counts.n_fail.to_string());
+        fields.push(self.counts.string_counts());
+        fields.extend(self.tag_values.iter().cloned());
+        if let Some(region_counts) = self.region_counts.as_ref() {
+            fields.extend(region_counts.to_fields());
+        }
+        format!("{}\n", fields.join("\t"))
+    }
+
+    pub(crate) fn chrom_id(&self) -> Option<u32> {
+        self.chrom_id
+    }
+}
+
+#[cfg(test)]
+mod read_summary_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::mod_bam::{BaseModCall, BaseModProbs};
+    use crate::mod_base_code::ModCode;
+    use crate::read_summary::CallCounts;
+
+    #[test]
+    fn test_call_counts() {
+        let mut counts = CallCounts::default();
+        let modified = BaseModProbs::new(FxHashMap::from_iter([('m', 0.9)]));
+        let mod_code = ModCode::parse_raw_mod_code('m').unwrap();
+        counts.add('C', &modified, BaseModCall::Modified(0.9, mod_code));
+        let canonical = BaseModProbs::new(FxHashMap::from_iter([('m', 0.1)]));
+        counts.add('C', &canonical, BaseModCall::Canonical(0.9));
+        let failed = BaseModProbs::new(FxHashMap::from_iter([('m', 0.5)]));
+        counts.add('C', &failed, BaseModCall::Filtered);
+
+        assert_eq!(counts.n_sites, 3);
+        assert_eq!(counts.n_fail, 1);
+        assert_eq!(counts.frac_modified(), Some(0.5));
+        assert!((counts.mean_mod_prob().unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(counts.string_counts(), "C:1,m:1");
+        assert_eq!(
+            counts.to_fields(),
+            ["3".to_string(), "0.5000".to_string(), "0.5000".to_string()]
+        );
+
+        let empty = CallCounts::default();
+        assert_eq!(empty.frac_modified(), None);
+        assert_eq!(empty.string_counts(), ".");
+    }
+}
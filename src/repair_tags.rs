@@ -1,33 +1,43 @@
-use crate::errs::RunError;
 use crate::logging::init_logging;
 use crate::mod_bam::{
-    format_mm_ml_tag, BaseModProbs, DeltaListConverter, ModBaseInfo,
-    SeqPosBaseModProbs, ML_TAGS, MM_TAGS,
+    format_mm_ml_tag, get_mn_tag, push_mod_tags, remove_all_mod_tags,
+    BaseModProbs, DeltaListConverter, ModBaseInfo, SeqPosBaseModProbs,
+    SkipMode, MM_TAGS, MN_TAG,
 };
 use crate::util::{
     get_forward_sequence, get_query_name_string, get_ticker,
     record_is_secondary,
 };
 use anyhow::{anyhow, bail, Context};
+use bio::alignment::pairwise::banded;
+use bio::alignment::AlignmentOperation;
+use bio::alphabets::dna::revcomp;
 use clap::Args;
 use derive_new::new;
 use indicatif::{MultiProgress, ProgressBar};
+use itertools::Itertools;
 use log::{debug, error, info, warn};
 use rayon::prelude::*;
-use rust_htslib::bam::record::{Aux, AuxArray};
+use rust_htslib::bam::record::{Aux, Cigar};
 use rust_htslib::bam::{self, Read};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 
+use std::collections::hash_map::Entry;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
 
 #[derive(Args)]
 pub struct RepairTags {
-    /// Donor modBAM with original MM/ML tags. Must be sorted by read name.
+    /// Donor modBAM with original MM/ML tags. Must be sorted by read name
+    /// unless --index-donor is used.
     #[arg(long, short = 'd', alias = "donor")]
     donor_bam: PathBuf,
     /// Acceptor modBAM with reads to have MM/ML base modification data projected
-    /// on to. Must be sorted by read name.
+    /// on to. Must be sorted by read name unless --index-donor is used.
     #[arg(long, short = 'a', alias = "acceptor")]
     acceptor_bam: PathBuf,
     /// output modBAM location.
@@ -40,12 +50,61 @@ pub struct RepairTags {
     /// The number of threads to use.
     #[arg(long, short = 't', default_value_t = 4)]
     threads: usize,
+    /// Only repair reads where the acceptor sequence is an exact, unique
+    /// substring of the donor sequence. By default, reads that aren't are
+    /// aligned to the donor sequence and base modification calls are projected
+    /// through the alignment.
+    #[arg(long, default_value_t = false)]
+    exact_only: bool,
+    /// Minimum fraction of alignment columns that are matches for a read to
+    /// be repaired by alignment.
+    #[arg(long, default_value_t = 0.9, conflicts_with = "exact_only")]
+    min_identity: f32,
+    /// Width of the band around the k-mer seed hits used when aligning the
+    /// acceptor sequence to the donor sequence.
+    #[arg(long, default_value_t = 100, hide_short_help = true)]
+    band_width: usize,
+    /// Write a TSV with the read name, how the read was repaired (exact or
+    /// aligned), the alignment identity, and the number of base modification
+    /// calls on the donor and repaired records.
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Index the donor records by read name and look up the donor record for
+    /// each acceptor record. With this option neither BAM needs to be sorted
+    /// by read name, and the output is in the same order as the acceptor
+    /// (e.g. remains coordinate-sorted).
+    #[arg(long, default_value_t = false)]
+    index_donor: bool,
 }
 
 impl RepairTags {
     pub(crate) fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
 
+        if self.min_identity <= 0f32 || self.min_identity > 1f32 {
+            bail!("--min-identity must be in (0, 1]")
+        }
+        let mut report_writer = self
+            .report
+            .as_ref()
+            .map(|fp| {
+                let fh = File::create(fp).with_context(|| {
+                    format!("failed to create report at {fp:?}")
+                })?;
+                let mut writer = BufWriter::new(fh);
+                writeln!(writer, "{}", RepairSummary::header())?;
+                Ok::<_, anyhow::Error>(writer)
+            })
+            .transpose()?;
+        let projector = if self.exact_only {
+            SequenceProjector::ExactOnly
+        } else {
+            SequenceProjector::Align {
+                min_identity: self.min_identity,
+                band_width: self.band_width,
+            }
+        };
+
         let reader_threads = {
             let half = self.threads / 2;
             std::cmp::min(half, 16)
@@ -56,8 +115,6 @@ impl RepairTags {
         debug!("assigning {threads_per_reader} to each reader and using {pool_threads} to process records");
 
         let (pair_snd, pair_rcv) = std::sync::mpsc::sync_channel(1000);
-        let mut donor_records = bam::Reader::from_path(&self.donor_bam)?;
-        donor_records.set_threads(threads_per_reader)?;
         let mut acceptor_records = bam::Reader::from_path(&self.acceptor_bam)?;
         acceptor_records.set_threads(threads_per_reader)?;
         let header = bam::Header::from_template(acceptor_records.header());
@@ -84,25 +141,80 @@ impl RepairTags {
             .add(get_ticker())
             .with_message("~records written");
 
-        std::thread::spawn(move || {
-            let pair_iter = ZipRecordsIter::new(
-                donor_records.records(),
-                acceptor_records.records(),
-                donor_ticker,
-                acceptor_ticker,
-            );
-            for pair in pair_iter {
-                match pair_snd.send(pair) {
-                    Ok(_) => {}
-                    Err(e) => {
+        if self.index_donor {
+            donor_ticker.set_message("~donor records indexed");
+            let donor_index = ReadNameIndex::build(
+                &self.donor_bam,
+                threads_per_reader,
+                &donor_ticker,
+            )?;
+            donor_ticker.finish_and_clear();
+            info!("indexed {} donor records", donor_index.len());
+            let mut donor_reader = bam::Reader::from_path(&self.donor_bam)?;
+            std::thread::spawn(move || {
+                let mut n_missing = 0usize;
+                for acceptor in acceptor_records.records() {
+                    let acceptor = match acceptor {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!(
+                                "failed to parse record from acceptor BAM, {}",
+                                e.to_string()
+                            );
+                            continue;
+                        }
+                    };
+                    acceptor_ticker.inc(1);
+                    let donor =
+                        match donor_index.fetch(&mut donor_reader, &acceptor) {
+                            Ok(Some(donor)) => donor,
+                            Ok(None) => {
+                                n_missing += 1;
+                                continue;
+                            }
+                            Err(e) => {
+                                error!(
+                                    "failed to fetch donor record, {}",
+                                    e.to_string()
+                                );
+                                continue;
+                            }
+                        };
+                    let pair = RecordPair::new(Arc::new(donor), acceptor);
+                    if let Err(e) = pair_snd.send(pair) {
                         error!(
                             "failed to send record pair on channel, {}",
                             e.to_string()
                         );
                     }
                 }
-            }
-        });
+                if n_missing > 0 {
+                    warn!("{n_missing} acceptor records had no donor record");
+                }
+            });
+        } else {
+            let mut donor_records = bam::Reader::from_path(&self.donor_bam)?;
+            donor_records.set_threads(threads_per_reader)?;
+            std::thread::spawn(move || {
+                let pair_iter = ZipRecordsIter::new(
+                    donor_records.records(),
+                    acceptor_records.records(),
+                    donor_ticker,
+                    acceptor_ticker,
+                );
+                for pair in pair_iter {
+                    match pair_snd.send(pair) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!(
+                                "failed to send record pair on channel, {}",
+                                e.to_string()
+                            );
+                        }
+                    }
+                }
+            });
+        }
 
         let (repair_snd, repair_rcv) = std::sync::mpsc::sync_channel(1000);
         let pool = rayon::ThreadPoolBuilder::new()
@@ -110,33 +222,52 @@ impl RepairTags {
             .build()
             .context("failed to make thread pool")?;
         std::thread::spawn(move || {
+            // batches are repaired in parallel, but sent in the order they
+            // were received so that the output has the order of the acceptor
             pool.install(|| {
-                pair_rcv.into_iter().par_bridge().for_each(|record_pair| {
-                    let repaired = repair_record_pair(record_pair);
-                    match repair_snd.send(repaired) {
-                        Ok(_) => repaired_ticker.inc(1),
-                        Err(e) => {
-                            error!(
-                                "failed to send repaired record on channel, {}",
-                                e.to_string()
-                            );
+                for batch in &pair_rcv.into_iter().chunks(REPAIR_BATCH_SIZE) {
+                    let repaired = batch
+                        .collect::<Vec<RecordPair>>()
+                        .into_par_iter()
+                        .map(|record_pair| {
+                            repair_record_pair(record_pair, &projector)
+                        })
+                        .collect::<Vec<_>>();
+                    for res in repaired {
+                        match repair_snd.send(res) {
+                            Ok(_) => repaired_ticker.inc(1),
+                            Err(e) => {
+                                error!(
+                                    "failed to send repaired record on \
+                                     channel, {}",
+                                    e.to_string()
+                                );
+                            }
                         }
                     }
-                })
+                }
             })
         });
 
         let mut n_repaired = 0usize;
         let mut n_failed = 0usize;
+        let mut n_aligned = 0usize;
         for res in repair_rcv {
             match res {
-                Ok(record) => {
+                Ok((record, summary)) => {
                     if let Err(e) = writer.write(&record) {
                         error!("failed to write record {}", e.to_string());
                         n_failed += 1;
                     } else {
                         written_ticker.inc(1);
                         n_repaired += 1;
+                        if summary.aligned {
+                            n_aligned += 1;
+                        }
+                        if let Some(report_writer) = report_writer.as_mut() {
+                            report_writer
+                                .write_all(summary.to_row().as_bytes())?;
+                        }
                     }
                 }
                 Err(e) => {
@@ -146,18 +277,407 @@ impl RepairTags {
             }
         }
 
-        info!("finished, repaired {n_repaired} records, {n_failed} failed.");
+        if let Some(mut report_writer) = report_writer {
+            report_writer.flush()?;
+        }
+
+        info!(
+            "finished, repaired {n_repaired} records ({n_aligned} by \
+             alignment), {n_failed} failed."
+        );
         Ok(())
     }
 }
 
+#[derive(Args)]
+pub struct RepairSupplementary {
+    /// Input modBAM, the primary records must have valid MM/ML tags.
+    in_bam: PathBuf,
+    /// Output modBAM location, the records are written in the same order as
+    /// the input.
+    out_bam: PathBuf,
+    /// File to write logs to, it is recommended to use this option as some
+    /// records may be rejected and logged here.
+    #[arg(long)]
+    log_filepath: Option<PathBuf>,
+    /// The number of threads to use.
+    #[arg(long, short = 't', default_value_t = 4)]
+    threads: usize,
+    /// Only repair supplementary records, secondary records are written
+    /// unchanged.
+    #[arg(long, default_value_t = false)]
+    skip_secondary: bool,
+}
+
+/// Outcome of repairing one record with its primary record.
+enum ClippedRepair {
+    /// Primary records, and records that already have valid tags.
+    Unchanged(bam::Record),
+    Repaired(bam::Record),
+    Failed(bam::Record, anyhow::Error),
+}
+
+impl RepairSupplementary {
+    pub(crate) fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let reader_threads = std::cmp::max(self.threads / 4, 1);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make thread pool")?;
+
+        let master_progress = MultiProgress::new();
+        let index_ticker = master_progress.add(get_ticker());
+        index_ticker.set_message("~primary records indexed");
+        let primary_index =
+            ReadNameIndex::build(&self.in_bam, reader_threads, &index_ticker)?;
+        index_ticker.finish_and_clear();
+        info!("indexed {} primary records", primary_index.len());
+
+        let mut primary_reader = bam::Reader::from_path(&self.in_bam)?;
+        let mut reader = bam::Reader::from_path(&self.in_bam)?;
+        reader.set_threads(reader_threads)?;
+        let header = bam::Header::from_template(reader.header());
+        let mut writer =
+            bam::Writer::from_path(&self.out_bam, &header, bam::Format::Bam)?;
+        let written_ticker = master_progress
+            .add(get_ticker())
+            .with_message("~records written");
+
+        let mut n_repaired = 0usize;
+        let mut n_failed = 0usize;
+        let mut n_missing = 0usize;
+        for batch in &reader.records().chunks(REPAIR_BATCH_SIZE) {
+            let mut pairs = Vec::new();
+            for record in batch {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        warn!("failed to parse record, {}", e.to_string());
+                        continue;
+                    }
+                };
+                let needs_primary = record.is_supplementary()
+                    || (record.is_secondary() && !self.skip_secondary);
+                if !needs_primary || record.is_unmapped() {
+                    pairs.push((None, record));
+                    continue;
+                }
+                match primary_index.fetch(&mut primary_reader, &record) {
+                    Ok(Some(primary)) => pairs.push((Some(primary), record)),
+                    Ok(None) => {
+                        n_missing += 1;
+                        pairs.push((None, record));
+                    }
+                    Err(e) => {
+                        error!(
+                            "failed to fetch primary record, {}",
+                            e.to_string()
+                        );
+                        pairs.push((None, record));
+                    }
+                }
+            }
+            let repaired = pool.install(|| {
+                pairs
+                    .into_par_iter()
+                    .map(|(primary, record)| match primary {
+                        Some(primary) => {
+                            repair_clipped_record(&primary, record)
+                        }
+                        None => ClippedRepair::Unchanged(record),
+                    })
+                    .collect::<Vec<ClippedRepair>>()
+            });
+            for repair in repaired {
+                let record = match repair {
+                    ClippedRepair::Unchanged(record) => record,
+                    ClippedRepair::Repaired(record) => {
+                        n_repaired += 1;
+                        record
+                    }
+                    ClippedRepair::Failed(record, e) => {
+                        debug!("record failed to be repaired: {}", e);
+                        n_failed += 1;
+                        record
+                    }
+                };
+                writer.write(&record)?;
+                written_ticker.inc(1);
+            }
+        }
+        written_ticker.finish_and_clear();
+
+        if n_missing > 0 {
+            warn!("{n_missing} records did not have a primary record");
+        }
+        info!(
+            "finished, repaired {n_repaired} records, {n_failed} failed and \
+             were written unchanged."
+        );
+        Ok(())
+    }
+}
+
+/// Hard clips at the start and end of the alignment (in alignment
+/// orientation), and the number of bases in the CIGAR that should be in the
+/// stored SEQ.
+fn clipping_and_query_length(record: &bam::Record) -> (usize, usize, usize) {
+    let cigar = record.cigar();
+    let hard_clip = |op: Option<&Cigar>| match op {
+        Some(Cigar::HardClip(n)) => *n as usize,
+        _ => 0,
+    };
+    let start_clip = hard_clip(cigar.first());
+    let end_clip = if cigar.len() > 1 {
+        hard_clip(cigar.last())
+    } else {
+        0
+    };
+    let query_length = cigar
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(n)
+            | Cigar::Ins(n)
+            | Cigar::SoftClip(n)
+            | Cigar::Equal(n)
+            | Cigar::Diff(n) => *n as usize,
+            _ => 0,
+        })
+        .sum::<usize>();
+    (start_clip, end_clip, query_length)
+}
+
+/// Write MM/ML (and MN) tags on a supplementary or secondary `record` using
+/// the tags on the `primary` record. The offset of the record's sequence in
+/// the read is found using the hard clipping. Records without SEQ have it
+/// filled in from the primary record.
+fn repair_clipped_record(
+    primary: &bam::Record,
+    record: bam::Record,
+) -> ClippedRepair {
+    // tags are valid when they were computed on the stored SEQ
+    let has_mod_tags =
+        MM_TAGS.iter().any(|tag| record.aux(tag.as_bytes()).is_ok());
+    if has_mod_tags
+        && record.seq_len() > 0
+        && get_mn_tag(&record) == Some(record.seq_len())
+    {
+        return ClippedRepair::Unchanged(record);
+    }
+    match try_repair_clipped_record(primary, record.clone()) {
+        Ok(repaired) => ClippedRepair::Repaired(repaired),
+        Err(e) => ClippedRepair::Failed(record, e),
+    }
+}
+
+fn try_repair_clipped_record(
+    primary: &bam::Record,
+    mut record: bam::Record,
+) -> anyhow::Result<bam::Record> {
+    let read_name = get_query_name_string(&record).unwrap_or_else(|e| {
+        format!("failed to parse query name, {}", e.to_string())
+    });
+    if let Some(mn) = get_mn_tag(primary) {
+        if mn != primary.seq_len() {
+            bail!(
+                "primary record for {read_name} has MN {mn} that doesn't \
+                 match the SEQ length {}",
+                primary.seq_len()
+            )
+        }
+    }
+    let modbase_info = ModBaseInfo::new_from_record(primary)
+        .map_err(|e| anyhow!("record {read_name} failed, {}", e.to_string()))?;
+    let primary_seq = get_forward_sequence(primary).map_err(|e| {
+        anyhow!(
+            "primary sequence for record {read_name} failed, {}",
+            e.to_string()
+        )
+    })?;
+
+    let (start_clip, end_clip, query_length) =
+        clipping_and_query_length(&record);
+    if start_clip + query_length + end_clip != primary_seq.len() {
+        bail!(
+            "clipped length of {read_name} ({}) does not match the primary \
+             record sequence length ({})",
+            start_clip + query_length + end_clip,
+            primary_seq.len()
+        )
+    }
+    // offset of the record in the read, in the read's orientation
+    let start = if record.is_reverse() {
+        end_clip
+    } else {
+        start_clip
+    };
+    let end = start + query_length;
+
+    if record.seq_len() == 0 {
+        let forward_qual = if primary.is_reverse() {
+            primary.qual().iter().rev().copied().collect::<Vec<u8>>()
+        } else {
+            primary.qual().to_vec()
+        };
+        let (seq, qual) = if record.is_reverse() {
+            (
+                revcomp(primary_seq[start..end].as_bytes()),
+                forward_qual[start..end].iter().rev().copied().collect(),
+            )
+        } else {
+            (
+                primary_seq[start..end].as_bytes().to_vec(),
+                forward_qual[start..end].to_vec(),
+            )
+        };
+        let qname = record.qname().to_vec();
+        let cigar = record.cigar().take();
+        record.set(&qname, Some(&cigar), &seq, &qual);
+    }
+
+    let record_seq = get_forward_sequence(&record).map_err(|e| {
+        anyhow!("sequence for record {read_name} failed, {}", e.to_string())
+    })?;
+    if record_seq.len() != query_length {
+        bail!("SEQ length of {read_name} does not match the CIGAR")
+    }
+    if primary_seq[start..end] != record_seq {
+        bail!("sequence of {read_name} does not match the primary record")
+    }
+    let projection = SequenceProjection::from_substring(start, query_length);
+    let (mut repaired, _) = project_mod_tags(
+        modbase_info,
+        &primary_seq,
+        record,
+        &record_seq,
+        &projection,
+        read_name,
+    )?;
+    let _ = repaired.remove_aux(MN_TAG.as_bytes());
+    repaired
+        .push_aux(MN_TAG.as_bytes(), Aux::U32(query_length as u32))
+        .map_err(|e| anyhow!("failed to add MN tag, {}", e.to_string()))?;
+    Ok(repaired)
+}
+
+/// Number of record pairs repaired in parallel at a time.
+const REPAIR_BATCH_SIZE: usize = 5_000;
+
+/// Virtual offsets of the records in a BAM keyed by a hash of the read name.
+/// Only the hash is kept to save memory, so the read name is checked when
+/// the record is fetched. Most hashes map to a single record, any further
+/// records with the same hash (repeated read names or hash collisions) are
+/// kept in `overflow`.
+struct ReadNameIndex {
+    offsets: FxHashMap<u64, i64>,
+    overflow: FxHashMap<u64, Vec<i64>>,
+    n_records: usize,
+}
+
+impl ReadNameIndex {
+    fn hash_name(name: &[u8]) -> u64 {
+        let mut hasher = FxHasher::default();
+        name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn build(
+        bam_fp: &PathBuf,
+        threads: usize,
+        ticker: &ProgressBar,
+    ) -> anyhow::Result<Self> {
+        let mut reader = bam::Reader::from_path(bam_fp)?;
+        reader.set_threads(threads)?;
+        let mut offsets = FxHashMap::<u64, i64>::default();
+        let mut overflow = FxHashMap::<u64, Vec<i64>>::default();
+        let mut n_records = 0usize;
+        let mut record = bam::Record::new();
+        loop {
+            let offset = reader.tell();
+            match reader.read(&mut record) {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    bail!(
+                        "failed to read record while indexing, {}",
+                        e.to_string()
+                    )
+                }
+                None => break,
+            }
+            ticker.inc(1);
+            if record_is_secondary(&record) {
+                continue;
+            }
+            let hash = Self::hash_name(record.qname());
+            match offsets.entry(hash) {
+                Entry::Vacant(entry) => {
+                    entry.insert(offset);
+                }
+                Entry::Occupied(_) => {
+                    overflow.entry(hash).or_insert_with(Vec::new).push(offset)
+                }
+            }
+            n_records += 1;
+        }
+        Ok(Self {
+            offsets,
+            overflow,
+            n_records,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.n_records
+    }
+
+    /// Find the record with the same read name as the `query`. Each lookup
+    /// seeks `reader` to the indexed offset and decompresses the BGZF block,
+    /// when the query records are not in the same order as the indexed BAM
+    /// every lookup is a random read.
+    fn fetch(
+        &self,
+        reader: &mut bam::Reader,
+        query: &bam::Record,
+    ) -> anyhow::Result<Option<bam::Record>> {
+        let hash = Self::hash_name(query.qname());
+        let first = match self.offsets.get(&hash) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let rest = self
+            .overflow
+            .get(&hash)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[]);
+        let mut record = bam::Record::new();
+        for offset in std::iter::once(&first).chain(rest) {
+            reader.seek(*offset)?;
+            match reader.read(&mut record) {
+                Some(Ok(_)) => {
+                    if record.qname() == query.qname() {
+                        return Ok(Some(record));
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => {
+                    bail!("record missing at indexed offset {offset}")
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
 #[derive(new)]
-struct RecordPair {
-    donor: Arc<bam::Record>,
-    acceptor: bam::Record,
+pub(crate) struct RecordPair {
+    pub(crate) donor: Arc<bam::Record>,
+    pub(crate) acceptor: bam::Record,
 }
 
-struct ZipRecordsIter<'a, T: Read> {
+/// Pairs records with the same read name from two name-sorted BAMs. Every
+/// acceptor read must be present in the donor.
+pub(crate) struct ZipRecordsIter<'a, T: Read> {
     donor_records: bam::Records<'a, T>,
     acceptor_records: bam::Records<'a, T>,
     cur_donor_record: Option<Arc<bam::Record>>,
@@ -167,7 +687,7 @@ struct ZipRecordsIter<'a, T: Read> {
 }
 
 impl<'a, T: Read> ZipRecordsIter<'a, T> {
-    fn new(
+    pub(crate) fn new(
         donor: bam::Records<'a, T>,
         acceptor: bam::Records<'a, T>,
         donor_ticker: ProgressBar,
@@ -292,7 +812,305 @@ impl<'a, T: Read> Iterator for ZipRecordsIter<'a, T> {
     }
 }
 
-fn repair_record_pair(record_pair: RecordPair) -> anyhow::Result<bam::Record> {
+/// How the acceptor sequence is placed on the donor sequence.
+enum SequenceProjector {
+    /// Acceptor must be an exact, unique substring of the donor.
+    ExactOnly,
+    /// Fall back to a banded semi-global alignment of the acceptor to the
+    /// donor when the acceptor isn't a substring of the donor.
+    Align {
+        min_identity: f32,
+        band_width: usize,
+    },
+}
+
+/// Seed length for the banded aligner.
+const ALIGNMENT_KMER_SIZE: usize = 12;
+
+/// Positions in the donor sequence and their corresponding position in the
+/// acceptor sequence.
+#[derive(Debug)]
+enum SequenceProjection {
+    /// The acceptor sequence is the substring of the donor sequence starting
+    /// at the first position with the second value as its length.
+    Offset(usize, usize),
+    /// Only the bases that are aligned and identical.
+    Aligned {
+        donor_to_acceptor: FxHashMap<usize, usize>,
+        identity: f32,
+    },
+}
+
+impl SequenceProjection {
+    fn from_substring(start: usize, length: usize) -> Self {
+        Self::Offset(start, length)
+    }
+
+    fn is_exact(&self) -> bool {
+        match self {
+            Self::Offset(..) => true,
+            Self::Aligned { .. } => false,
+        }
+    }
+
+    fn identity(&self) -> f32 {
+        match self {
+            Self::Offset(..) => 1f32,
+            Self::Aligned { identity, .. } => *identity,
+        }
+    }
+
+    fn acceptor_position(&self, donor_pos: usize) -> Option<usize> {
+        match self {
+            Self::Offset(start, length) => donor_pos
+                .checked_sub(*start)
+                .filter(|acceptor_pos| acceptor_pos < length),
+            Self::Aligned {
+                donor_to_acceptor, ..
+            } => donor_to_acceptor.get(&donor_pos).copied(),
+        }
+    }
+
+    /// Semi-global alignment, the acceptor sequence is aligned end-to-end
+    /// and the ends of the donor sequence are free. None when the sequences
+    /// don't share any seed k-mers.
+    fn from_alignment(
+        donor_seq: &[u8],
+        acceptor_seq: &[u8],
+        band_width: usize,
+    ) -> Option<Self> {
+        let acceptor_kmers = acceptor_seq
+            .windows(ALIGNMENT_KMER_SIZE)
+            .collect::<FxHashSet<&[u8]>>();
+        if !donor_seq
+            .windows(ALIGNMENT_KMER_SIZE)
+            .any(|kmer| acceptor_kmers.contains(kmer))
+        {
+            return None;
+        }
+        let score = |a: u8, b: u8| if a == b { 1i32 } else { -1i32 };
+        let mut aligner = banded::Aligner::new(
+            -5,
+            -1,
+            score,
+            ALIGNMENT_KMER_SIZE,
+            band_width,
+        );
+        let alignment = aligner.semiglobal(acceptor_seq, donor_seq);
+
+        let mut donor_to_acceptor = FxHashMap::default();
+        let mut acceptor_pos = alignment.xstart;
+        let mut donor_pos = alignment.ystart;
+        let mut n_matches = 0usize;
+        let mut n_columns = 0usize;
+        for op in alignment.operations.iter() {
+            match op {
+                AlignmentOperation::Match => {
+                    donor_to_acceptor.insert(donor_pos, acceptor_pos);
+                    acceptor_pos += 1;
+                    donor_pos += 1;
+                    n_matches += 1;
+                    n_columns += 1;
+                }
+                AlignmentOperation::Subst => {
+                    acceptor_pos += 1;
+                    donor_pos += 1;
+                    n_columns += 1;
+                }
+                AlignmentOperation::Ins => {
+                    acceptor_pos += 1;
+                    n_columns += 1;
+                }
+                AlignmentOperation::Del => {
+                    donor_pos += 1;
+                    n_columns += 1;
+                }
+                // clipping is accounted for by the start positions
+                AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => {
+                }
+            }
+        }
+        // unaligned ends of the acceptor count against the identity
+        let n_columns = n_columns
+            + acceptor_seq.len().saturating_sub(acceptor_pos)
+            + alignment.xstart;
+        let identity = if n_columns == 0 {
+            0f32
+        } else {
+            n_matches as f32 / n_columns as f32
+        };
+
+        Some(Self::Aligned {
+            donor_to_acceptor,
+            identity,
+        })
+    }
+}
+
+impl SequenceProjector {
+    fn project(
+        &self,
+        donor_seq: &str,
+        acceptor_seq: &str,
+        read_name: &str,
+    ) -> anyhow::Result<SequenceProjection> {
+        let starts = if donor_seq.len() >= acceptor_seq.len() {
+            donor_seq
+                .match_indices(acceptor_seq)
+                .map(|(start, _)| start)
+                .collect::<Vec<usize>>()
+        } else {
+            Vec::new()
+        };
+        if starts.len() > 1 {
+            bail!("multiple potential corrections found for {read_name}")
+        }
+        if let Some(&start) = starts.first() {
+            return Ok(SequenceProjection::from_substring(
+                start,
+                acceptor_seq.len(),
+            ));
+        }
+
+        match self {
+            Self::ExactOnly => {
+                if donor_seq.len() < acceptor_seq.len() {
+                    bail!(
+                        "donor sequence for {read_name} is shorter than \
+                         acceptor sequence"
+                    )
+                } else {
+                    bail!(
+                        "acceptor sequence is not a substring of the donor \
+                         sequence"
+                    )
+                }
+            }
+            Self::Align {
+                min_identity,
+                band_width,
+            } => {
+                let projection = SequenceProjection::from_alignment(
+                    donor_seq.as_bytes(),
+                    acceptor_seq.as_bytes(),
+                    *band_width,
+                )
+                .ok_or_else(|| {
+                    anyhow!(
+                        "acceptor sequence for {read_name} does not share \
+                         any {ALIGNMENT_KMER_SIZE}-mers with the donor \
+                         sequence"
+                    )
+                })?;
+                if projection.identity() < *min_identity {
+                    bail!(
+                        "alignment identity of acceptor to donor for \
+                         {read_name} is {:.3}, below minimum {min_identity}",
+                        projection.identity()
+                    )
+                }
+                Ok(projection)
+            }
+        }
+    }
+}
+
+/// One row of the `repair --report` table.
+struct RepairSummary {
+    read_name: String,
+    aligned: bool,
+    identity: f32,
+    n_donor_calls: usize,
+    n_repaired_calls: usize,
+}
+
+impl RepairSummary {
+    fn header() -> String {
+        [
+            "read_id",
+            "method",
+            "identity",
+            "donor_calls",
+            "repaired_calls",
+        ]
+        .join("\t")
+    }
+
+    fn to_row(&self) -> String {
+        let method = if self.aligned { "aligned" } else { "exact" };
+        format!(
+            "{}\t{method}\t{:.4}\t{}\t{}\n",
+            self.read_name,
+            self.identity,
+            self.n_donor_calls,
+            self.n_repaired_calls
+        )
+    }
+}
+
+/// Move the base modification probabilities on to the acceptor positions.
+/// Calls at donor positions that aren't aligned to an identical acceptor base
+/// are dropped. When the projection isn't exact some acceptor bases have no
+/// information, so positions implied to be canonical in the donor are made
+/// explicit and the mode is changed to '?', even when every explicit call was
+/// dropped. The modification codes are taken from the donor's calls, so if the
+/// donor has no calls at all the donor's mode is kept.
+fn project_base_mod_probs(
+    seq_pos_base_mod_probs: SeqPosBaseModProbs,
+    projection: &SequenceProjection,
+    donor_seq: &[u8],
+    primary_base: char,
+) -> SeqPosBaseModProbs {
+    let skip_mode = seq_pos_base_mod_probs.skip_mode;
+    let mod_codes = seq_pos_base_mod_probs
+        .pos_to_base_mod_probs
+        .values()
+        .flat_map(|base_mod_probs| {
+            base_mod_probs.iter_probs().map(|(mod_code, _)| *mod_code)
+        })
+        .collect::<BTreeSet<char>>();
+    let mut adjusted = seq_pos_base_mod_probs
+        .pos_to_base_mod_probs
+        .into_iter()
+        .filter_map(|(pos, base_mod_probs)| {
+            projection
+                .acceptor_position(pos)
+                .map(|acceptor_pos| (acceptor_pos, base_mod_probs))
+        })
+        .collect::<FxHashMap<usize, BaseModProbs>>();
+    let donor_to_acceptor = match projection {
+        SequenceProjection::Offset(..) => {
+            return SeqPosBaseModProbs::new(adjusted, skip_mode)
+        }
+        SequenceProjection::Aligned {
+            donor_to_acceptor, ..
+        } => donor_to_acceptor,
+    };
+    if skip_mode == SkipMode::Ambiguous || mod_codes.is_empty() {
+        return SeqPosBaseModProbs::new(adjusted, skip_mode);
+    }
+
+    for (donor_pos, acceptor_pos) in donor_to_acceptor.iter() {
+        let is_primary_base = donor_seq
+            .get(*donor_pos)
+            .map(|b| *b as char == primary_base)
+            .unwrap_or(false);
+        if is_primary_base && !adjusted.contains_key(acceptor_pos) {
+            let mut canonical =
+                BaseModProbs::new_init(*mod_codes.iter().next().unwrap(), 0f32);
+            for mod_code in mod_codes.iter().skip(1) {
+                canonical.insert_base_mod_prob(*mod_code, 0f32);
+            }
+            adjusted.insert(*acceptor_pos, canonical);
+        }
+    }
+    SeqPosBaseModProbs::new(adjusted, SkipMode::Ambiguous)
+}
+
+fn repair_record_pair(
+    record_pair: RecordPair,
+    projector: &SequenceProjector,
+) -> anyhow::Result<(bam::Record, RepairSummary)> {
     let read_name =
         get_query_name_string(&record_pair.donor).unwrap_or_else(|e| {
             format!("failed to parse query name, {}", e.to_string())
@@ -316,85 +1134,211 @@ fn repair_record_pair(record_pair: RecordPair) -> anyhow::Result<bam::Record> {
             )
         })?;
 
-    if donor_seq.len() < acceptor_seq.len() {
-        bail!("donor sequence for {read_name} is longer than acceptor sequence")
+    let projection =
+        projector.project(&donor_seq, &acceptor_seq, &read_name)?;
+    if !projection.is_exact() {
+        debug!(
+            "repaired {read_name} by alignment, identity {:.3}",
+            projection.identity()
+        );
     }
-    let matches = donor_seq.match_indices(&acceptor_seq);
 
-    let starts = matches
-        .into_iter()
-        .map(|(start, _)| start)
-        .collect::<Vec<usize>>();
-    if starts.len() > 1 {
-        bail!("multiple potential corrections found for {read_name}")
-    } else if starts.is_empty() {
-        bail!("acceptor sequence is not a substring of the donor sequence")
-    } else {
-        let start = *starts.get(0).unwrap();
-        let end = start + acceptor_seq.len();
+    project_mod_tags(
+        modbase_info,
+        &donor_seq,
+        record_pair.acceptor,
+        &acceptor_seq,
+        &projection,
+        read_name,
+    )
+}
 
-        let mm_style = modbase_info.mm_style;
-        let ml_style = modbase_info.ml_style;
+/// Replace the MM/ML tags on the `acceptor` with the base modification calls
+/// in `modbase_info` projected on to the acceptor sequence.
+fn project_mod_tags(
+    modbase_info: ModBaseInfo,
+    donor_seq: &str,
+    acceptor: bam::Record,
+    acceptor_seq: &str,
+    projection: &SequenceProjection,
+    read_name: String,
+) -> anyhow::Result<(bam::Record, RepairSummary)> {
+    let mm_style = modbase_info.mm_style;
+    let ml_style = modbase_info.ml_style;
 
-        let mut mm_agg = String::new();
-        let mut ml_agg = Vec::new();
+    let mut mm_agg = String::new();
+    let mut ml_agg = Vec::new();
+    let mut n_donor_calls = 0usize;
+    let mut n_repaired_calls = 0usize;
 
-        let (_, base_mod_probs_iter) = modbase_info.into_iter_base_mod_probs();
-        for (primary_base, strand, seq_pos_base_mod_probs) in
-            base_mod_probs_iter
-        {
-            let converter =
-                DeltaListConverter::new(&acceptor_seq, primary_base);
-            let skip_mode = seq_pos_base_mod_probs.skip_mode;
-            let adjusted = seq_pos_base_mod_probs
-                .pos_to_base_mod_probs
-                .into_iter()
-                .filter_map(|(pos, base_mod_probs)| {
-                    if pos >= start && pos < end {
-                        Some((pos - start, base_mod_probs))
-                    } else {
-                        None
-                    }
-                })
-                .collect::<FxHashMap<usize, BaseModProbs>>();
-            let repaired_seq_pos_base_mod_probs =
-                SeqPosBaseModProbs::new(adjusted, skip_mode);
-            let (mm, mut ml) = format_mm_ml_tag(
-                repaired_seq_pos_base_mod_probs,
-                strand,
-                &converter,
-            );
-            mm_agg.push_str(&mm);
-            ml_agg.extend_from_slice(&mut ml);
-        }
+    let (_, base_mod_probs_iter) = modbase_info.into_iter_base_mod_probs();
+    for (primary_base, strand, seq_pos_base_mod_probs) in base_mod_probs_iter {
+        let converter = DeltaListConverter::new(acceptor_seq, primary_base);
+        n_donor_calls += seq_pos_base_mod_probs.pos_to_base_mod_probs.len();
+        let repaired_seq_pos_base_mod_probs = project_base_mod_probs(
+            seq_pos_base_mod_probs,
+            projection,
+            donor_seq.as_bytes(),
+            primary_base,
+        );
+        n_repaired_calls +=
+            repaired_seq_pos_base_mod_probs.pos_to_base_mod_probs.len();
+        let (mm, mut ml) = format_mm_ml_tag(
+            repaired_seq_pos_base_mod_probs,
+            strand,
+            &converter,
+        );
+        mm_agg.push_str(&mm);
+        ml_agg.extend_from_slice(&mut ml);
+    }
 
-        let mm = Aux::String(&mm_agg);
-        let ml_arr: AuxArray<u8> = {
-            let sl = &ml_agg;
-            sl.into()
+    let mut repaired_record = acceptor;
+    remove_all_mod_tags(&mut repaired_record);
+    push_mod_tags(&mut repaired_record, mm_style, ml_style, &mm_agg, &ml_agg)?;
+    let summary = RepairSummary {
+        read_name,
+        aligned: !projection.is_exact(),
+        identity: projection.identity(),
+        n_donor_calls,
+        n_repaired_calls,
+    };
+    Ok((repaired_record, summary))
+}
+
+#[cfg(test)]
+mod repair_tags_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::mod_bam::{BaseModProbs, SeqPosBaseModProbs, SkipMode};
+    use crate::repair_tags::{
+        project_base_mod_probs, SequenceProjection, SequenceProjector,
+    };
+
+    #[test]
+    fn test_sequence_projection() {
+        let projector = SequenceProjector::Align {
+            min_identity: 0.8,
+            band_width: 20,
         };
-        let ml = Aux::ArrayU8(ml_arr);
+        let donor = "TTTTACGATCGGATCCGATTACGCGTAGCTAGGCATCGATCGATCGGGCTTT";
+        // exact substring, offsets only
+        let acceptor = &donor[4..40];
+        let projection = projector.project(donor, acceptor, "read").unwrap();
+        assert!(projection.is_exact());
+        assert_eq!(projection.acceptor_position(4), Some(0));
+        assert_eq!(projection.acceptor_position(39), Some(35));
+        assert_eq!(projection.acceptor_position(3), None);
+        assert_eq!(projection.acceptor_position(40), None);
 
-        let mut repaired_record = record_pair.acceptor;
-        for tag in MM_TAGS.iter().chain(ML_TAGS.iter()) {
-            let _ = repaired_record.remove_aux(tag.as_bytes());
+        // substitution at acceptor position 5 (donor 9) and an inserted base
+        let mut acceptor = donor[4..40].as_bytes().to_vec();
+        acceptor[5] = b'A';
+        acceptor.insert(20, b'T');
+        let acceptor = String::from_utf8(acceptor).unwrap();
+        let projection = projector.project(donor, &acceptor, "read").unwrap();
+        assert!(!projection.is_exact());
+        assert!(projection.identity() < 1f32 && projection.identity() > 0.9);
+        assert_eq!(projection.acceptor_position(4), Some(0));
+        assert_eq!(projection.acceptor_position(9), None);
+        // after the insertion acceptor positions are shifted by one
+        assert_eq!(projection.acceptor_position(30), Some(27));
+
+        let exact_only = SequenceProjector::ExactOnly;
+        assert!(exact_only.project(donor, &acceptor, "read").is_err());
+        // too divergent
+        let mut divergent = donor[4..40].as_bytes().to_vec();
+        for i in (14..36).step_by(3) {
+            divergent[i] = if divergent[i] == b'A' { b'T' } else { b'A' };
         }
-        repaired_record
-            .push_aux(mm_style.as_bytes(), mm)
-            .map_err(|e| {
-                RunError::new_failed(format!(
-                    "failed to add MM tag, {}",
-                    e.to_string()
-                ))
-            })?;
-        repaired_record
-            .push_aux(ml_style.as_bytes(), ml)
-            .map_err(|e| {
-                RunError::new_failed(format!(
-                    "failed to add ML tag, {}",
-                    e.to_string()
-                ))
-            })?;
-        Ok(repaired_record)
+        let divergent = String::from_utf8(divergent).unwrap();
+        assert!(projector.project(donor, &divergent, "read").is_err());
+        // no shared seeds
+        assert!(projector
+            .project(donor, "GGGGGGGGGGGGGGGGGGGGGGGGGGGGGG", "read")
+            .is_err());
+    }
+
+    #[test]
+    fn test_project_base_mod_probs() {
+        let donor = "ACGACGACG";
+        // donor C's at 1, 4, 7, donor position 4 is substituted
+        let projection = SequenceProjection::Aligned {
+            donor_to_acceptor: FxHashMap::from_iter([
+                (0, 0),
+                (1, 1),
+                (2, 2),
+                (6, 6),
+                (7, 7),
+                (8, 8),
+            ]),
+            identity: 0.8,
+        };
+        let probs = SeqPosBaseModProbs::new(
+            FxHashMap::from_iter([
+                (1, BaseModProbs::new_init('m', 0.9)),
+                (4, BaseModProbs::new_init('m', 0.8)),
+            ]),
+            SkipMode::ProbModified,
+        );
+        let projected =
+            project_base_mod_probs(probs, &projection, donor.as_bytes(), 'C');
+        assert_eq!(projected.skip_mode, SkipMode::Ambiguous);
+        assert_eq!(projected.pos_to_base_mod_probs.len(), 2);
+        assert_eq!(
+            projected.pos_to_base_mod_probs.get(&1),
+            Some(&BaseModProbs::new_init('m', 0.9))
+        );
+        // implied canonical in the donor, made explicit
+        assert_eq!(
+            projected.pos_to_base_mod_probs.get(&7),
+            Some(&BaseModProbs::new_init('m', 0f32))
+        );
+
+        // the only call is dropped, the implied canonical calls are still
+        // made explicit
+        let probs = SeqPosBaseModProbs::new(
+            FxHashMap::from_iter([(4, BaseModProbs::new_init('m', 0.8))]),
+            SkipMode::ImplicitProbModified,
+        );
+        let projected =
+            project_base_mod_probs(probs, &projection, donor.as_bytes(), 'C');
+        assert_eq!(projected.skip_mode, SkipMode::Ambiguous);
+        assert_eq!(
+            projected.pos_to_base_mod_probs,
+            FxHashMap::from_iter([
+                (1, BaseModProbs::new_init('m', 0f32)),
+                (7, BaseModProbs::new_init('m', 0f32)),
+            ])
+        );
+
+        // no calls in the donor, there are no codes to make explicit calls
+        // with so the donor's mode is kept
+        let probs = SeqPosBaseModProbs::new(
+            FxHashMap::default(),
+            SkipMode::ImplicitProbModified,
+        );
+        let projected =
+            project_base_mod_probs(probs, &projection, donor.as_bytes(), 'C');
+        assert_eq!(projected.skip_mode, SkipMode::ImplicitProbModified);
+        assert!(projected.pos_to_base_mod_probs.is_empty());
+
+        let probs = SeqPosBaseModProbs::new(
+            FxHashMap::from_iter([
+                (1, BaseModProbs::new_init('m', 0.9)),
+                (7, BaseModProbs::new_init('m', 0.8)),
+            ]),
+            SkipMode::ImplicitProbModified,
+        );
+        let projected = project_base_mod_probs(
+            probs,
+            &SequenceProjection::from_substring(3, 6),
+            donor.as_bytes(),
+            'C',
+        );
+        assert_eq!(projected.skip_mode, SkipMode::ImplicitProbModified);
+        assert_eq!(
+            projected.pos_to_base_mod_probs,
+            FxHashMap::from_iter([(4, BaseModProbs::new_init('m', 0.8))])
+        );
     }
 }
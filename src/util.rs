@@ -10,11 +10,15 @@ use linear_map::LinearMap;
 use log::{debug, error};
 use regex::Regex;
 use rust_htslib::bam::{
-    self, ext::BamRecordExtensions, header::HeaderRecord, record::Aux,
+    self,
+    ext::BamRecordExtensions,
+    header::HeaderRecord,
+    record::{Aux, Cigar},
     HeaderView, Read,
 };
 
 use crate::errs::{InputError, RunError};
+use crate::mod_bam::get_mn_tag;
 
 pub(crate) fn get_ticker() -> ProgressBar {
     let ticker = ProgressBar::new_spinner();
@@ -217,6 +221,36 @@ pub fn record_is_secondary(record: &bam::Record) -> bool {
     record.is_supplementary() || record.is_secondary() || record.is_duplicate()
 }
 
+/// Same as `record_is_secondary` except that, with `include_supplementary`,
+/// supplementary alignments with MM/ML tags that are valid for their SEQ are
+/// kept.
+pub(crate) fn skip_non_primary(
+    record: &bam::Record,
+    include_supplementary: bool,
+) -> bool {
+    if include_supplementary
+        && record.is_supplementary()
+        && !(record.is_secondary() || record.is_duplicate())
+    {
+        !supplementary_mod_tags_valid(record)
+    } else {
+        record_is_secondary(record)
+    }
+}
+
+/// The MM/ML tags on a supplementary alignment are valid when the MN tag
+/// matches the length of the SEQ or, without an MN tag, when the alignment
+/// isn't hard-clipped (so the SEQ is the whole read).
+pub(crate) fn supplementary_mod_tags_valid(record: &bam::Record) -> bool {
+    match get_mn_tag(record) {
+        Some(mn) => mn == record.seq_len(),
+        None => !record
+            .cigar()
+            .iter()
+            .any(|op| matches!(op, Cigar::HardClip(_))),
+    }
+}
+
 pub(crate) fn get_targets(
     header: &HeaderView,
     region: Option<&Region>,
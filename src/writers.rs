@@ -1,30 +1,61 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ffi::CString;
 use std::fs::File;
-use std::io::{BufWriter, Stdout, Write};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Stdout, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Result as AnyhowResult};
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float32Builder, Int64Builder, StringBuilder,
+    StringDictionaryBuilder, UInt32Builder, UInt64Builder, UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
 use derive_new::new;
 use histo_fp::Histogram;
 use itertools::Itertools;
 use log::{debug, info, warn};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
 use prettytable::format::FormatBuilder;
 use prettytable::{cell, row, Table};
+use rust_htslib::{bgzf, htslib};
 use rustc_hash::FxHashMap;
 
-use crate::pileup::duplex::DuplexModBasePileup;
+use crate::motif_bed::MultipleMotifLocations;
+use crate::pileup::duplex::{
+    DuplexModBasePileup, DuplexPileupFeatureCounts, DuplexRegionCounts,
+};
 use crate::pileup::{ModBasePileup, PartitionKey, PileupFeatureCounts};
-use crate::read_ids_to_base_mod_probs::ReadsBaseModProfile;
+use crate::read_ids_to_base_mod_probs::{
+    KmerWindow, ModProfile, ReadsBaseModProfile,
+};
+use crate::read_summary::ReadSummary;
 use crate::summarize::ModSummary;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::thresholds::Percentiles;
+use crate::util::Strand;
 
 pub trait OutwriterWithMemory<T> {
     fn write(&mut self, item: T) -> AnyhowResult<u64>;
     fn num_reads(&self) -> usize;
+    /// Called once after all of the items have been written, for writers
+    /// that need to write a footer.
+    fn finish(&mut self) -> AnyhowResult<()> {
+        Ok(())
+    }
 }
 
 pub trait PileupWriter<T> {
     fn write(&mut self, item: T, motif_labels: &[String]) -> AnyhowResult<u64>;
+    /// Called once after all of the items have been written, for writers
+    /// that need to write a footer.
+    fn finish(&mut self) -> AnyhowResult<()> {
+        Ok(())
+    }
 }
 
 pub trait OutWriter<T> {
@@ -143,78 +174,119 @@ impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
     }
 }
 
+impl<T: Write + Sized> BedMethylWriter<T> {
+    #[inline]
+    fn write_duplex_feature_counts(
+        start: u64,
+        stop: u64,
+        chrom_name: &str,
+        duplex_pileup_counts: &DuplexPileupFeatureCounts,
+        writer: &mut BufWriter<T>,
+        tabs_and_spaces: bool,
+    ) -> AnyhowResult<u64> {
+        let tab = '\t';
+        let space = if !tabs_and_spaces { tab } else { ' ' };
+        let mut rows_written = 0;
+        // sort by base
+        for (base, patterns) in duplex_pileup_counts
+            .pattern_counts
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        {
+            for pattern in patterns.iter().sorted() {
+                let name = pattern.pattern_string(*base);
+                let row = format!(
+                    "{}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{tab}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}{space}\
+                     {}\n",
+                    chrom_name,
+                    start,
+                    stop,
+                    name,
+                    pattern.valid_coverage(),
+                    '.',
+                    start,
+                    stop,
+                    "255,0,0",
+                    pattern.valid_coverage(),
+                    format!("{:.2}", pattern.frac_pattern() * 100f32),
+                    pattern.count,
+                    pattern.n_canonical,
+                    pattern.n_other_pattern,
+                    duplex_pileup_counts.n_delete,
+                    pattern.n_fail,
+                    pattern.n_diff,
+                    pattern.n_nocall,
+                );
+                writer
+                    .write(row.as_bytes())
+                    .with_context(|| "failed to write row")?;
+                rows_written += 1;
+            }
+        }
+        Ok(rows_written)
+    }
+}
+
 impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
     fn write(
         &mut self,
         item: DuplexModBasePileup,
         _motif_labels: &[String],
     ) -> AnyhowResult<u64> {
-        let tab = '\t';
-        let space = if !self.tabs_and_spaces { tab } else { ' ' };
         let mut rows_written = 0;
-        for (pos, duplex_pileup_counts) in item
-            .pileup_counts
-            .iter()
-            // sort by position
-            .sorted_by(|(a, _), (b, _)| a.cmp(b))
-        {
-            // sort by base
-            for (base, patterns) in duplex_pileup_counts
-                .pattern_counts
-                .iter()
-                .sorted_by(|(a, _), (b, _)| a.cmp(b))
-            {
-                for pattern in patterns.iter().sorted() {
-                    let name = pattern.pattern_string(*base);
-                    let row = format!(
-                        "{}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{tab}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}{space}\
-                         {}\n",
-                        item.chrom_name,
-                        pos,
-                        pos + 1,
-                        name,
-                        pattern.valid_coverage(),
-                        '.',
-                        pos,
-                        pos + 1,
-                        "255,0,0",
-                        pattern.valid_coverage(),
-                        format!("{:.2}", pattern.frac_pattern() * 100f32),
-                        pattern.count,
-                        pattern.n_canonical,
-                        pattern.n_other_pattern,
-                        duplex_pileup_counts.n_delete,
-                        pattern.n_fail,
-                        pattern.n_diff,
-                        pattern.n_nocall,
-                    );
-                    self.buf_writer
-                        .write(row.as_bytes())
-                        .with_context(|| "failed to write row")?;
-                    rows_written += 1;
+        for (pos, partitioned_counts) in item.iter_counts_sorted() {
+            match partitioned_counts.get(&PartitionKey::NoKey) {
+                Some(duplex_pileup_counts) => {
+                    rows_written +=
+                        BedMethylWriter::write_duplex_feature_counts(
+                            *pos as u64,
+                            *pos as u64 + 1,
+                            &item.chrom_name,
+                            duplex_pileup_counts,
+                            &mut self.buf_writer,
+                            self.tabs_and_spaces,
+                        )?;
                 }
+                None => {}
             }
         }
         Ok(rows_written)
     }
 }
 
+impl<T: Write> OutWriter<DuplexRegionCounts> for BedMethylWriter<T> {
+    fn write(&mut self, item: DuplexRegionCounts) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        for (region, duplex_pileup_counts) in item.iter_feature_counts() {
+            rows_written += BedMethylWriter::write_duplex_feature_counts(
+                region.start,
+                region.stop,
+                &region.chrom,
+                &duplex_pileup_counts,
+                &mut self.buf_writer,
+                self.tabs_and_spaces,
+            )?;
+        }
+        Ok(rows_written)
+    }
+}
+
 #[derive(new, Hash, Eq, PartialEq, Copy, Clone)]
 struct BedGraphFileKey {
     partition_key: PartitionKey,
@@ -345,6 +417,62 @@ impl PileupWriter<ModBasePileup> for BedGraphWriter {
     }
 }
 
+impl PileupWriter<DuplexModBasePileup> for BedGraphWriter {
+    fn write(
+        &mut self,
+        item: DuplexModBasePileup,
+        _motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        let tab = '\t';
+        for (pos, partitioned_counts) in item.iter_counts_sorted() {
+            for (partition_key, duplex_pileup_counts) in partitioned_counts {
+                let key_name = match partition_key {
+                    PartitionKey::NoKey => {
+                        if self.use_groupings {
+                            UNGROUPED
+                        } else {
+                            ""
+                        }
+                    }
+                    PartitionKey::Key(idx) => item
+                        .partition_keys
+                        .get_index(*idx)
+                        .map(|s| s.as_str())
+                        .unwrap_or(NOT_FOUND),
+                };
+                for (base, patterns) in &duplex_pileup_counts.pattern_counts {
+                    for pattern in patterns {
+                        // duplex patterns are always strand-combined
+                        let key =
+                            BedGraphFileKey::new(*partition_key, '.', *base);
+                        let label =
+                            pattern.pattern_string(*base).replace(",", "");
+                        let fh =
+                            self.get_writer_for_modstrand(key, key_name, label);
+                        let row = format!(
+                            "{}{tab}\
+                             {}{tab}\
+                             {}{tab}\
+                             {}{tab}\
+                             {}\n",
+                            item.chrom_name,
+                            pos,
+                            pos + 1,
+                            pattern.frac_pattern(),
+                            pattern.valid_coverage(),
+                        );
+                        fh.write(row.as_bytes())?;
+                        rows_written += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(rows_written)
+    }
+}
+
 pub struct TableWriter<W: Write> {
     writer: BufWriter<W>,
 }
@@ -700,41 +828,362 @@ impl OutWriter<SampledProbs> for TsvWriter<Stdout> {
     }
 }
 
+/// Formats the rows of the `extract` table, skipping reads that have already
+/// been written.
 #[derive(new)]
-pub struct TsvWriterWithContigNames<W: Write> {
-    tsv_writer: TsvWriter<W>,
+pub struct ExtractRowFormatter {
     tid_to_name: HashMap<u32, String>,
     name_to_seq: HashMap<String, Vec<u8>>,
-    written_reads: HashSet<String>,
+    written_alignments: HashSet<String>,
+    read_names: HashSet<String>,
+    kmer_window: KmerWindow,
+    threshold_caller: Option<MultipleThresholdModCaller>,
+    motif_locations: Option<Arc<MultipleMotifLocations>>,
 }
 
-impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
-    for TsvWriterWithContigNames<W>
-{
-    fn write(&mut self, item: ReadsBaseModProfile) -> AnyhowResult<u64> {
+/// Comma-separated motifs (as <motif>,<offset>) at the site, joined with ';'
+/// when more than one motif matches, None when there are none.
+fn motif_label(
+    motif_locations: &MultipleMotifLocations,
+    chrom_id: Option<u32>,
+    mod_profile: &ModProfile,
+) -> Option<String> {
+    match (
+        chrom_id,
+        mod_profile.ref_position,
+        mod_profile.ref_mod_strand(),
+    ) {
+        (Some(chrom_id), Some(ref_pos), Some(strand)) if ref_pos >= 0 => {
+            motif_locations.motifs_for_position(
+                chrom_id,
+                ref_pos as u32,
+                strand,
+            )
+        }
+        _ => None,
+    }
+    .map(|hits| hits.iter().map(|(_, ml)| ml.motif().to_string()).join(";"))
+}
+
+impl ExtractRowFormatter {
+    /// Rows for each site in the profiles, along with the reference contig ID
+    /// and position when the site is aligned.
+    fn format_rows(
+        &mut self,
+        item: &ReadsBaseModProfile,
+    ) -> Vec<(Option<(u32, u64)>, String)> {
         let missing_chrom = ".".to_string();
-        let mut rows_written = 0u64;
+        let mut rows = Vec::new();
         for profile in item.profiles.iter() {
-            if self.written_reads.contains(&profile.record_name) {
+            let alignment_id = profile.alignment_id();
+            if self.written_alignments.contains(&alignment_id) {
                 continue;
+            }
+            let chrom_name = if let Some(chrom_id) = profile.chrom_id {
+                self.tid_to_name.get(&chrom_id)
             } else {
-                let chrom_name = if let Some(chrom_id) = profile.chrom_id {
-                    self.tid_to_name.get(&chrom_id)
-                } else {
-                    None
-                };
-                for mod_profile in profile.profile.iter() {
-                    let row = mod_profile.to_row(
-                        &profile.record_name,
-                        chrom_name.unwrap_or(&missing_chrom),
-                        &self.name_to_seq,
+                None
+            };
+            let calls = self
+                .threshold_caller
+                .as_ref()
+                .map(|caller| profile.call_sites(caller));
+            for mod_profile in profile.profile.iter() {
+                let mut extra_fields = Vec::new();
+                if let Some(motif_locations) = self.motif_locations.as_ref() {
+                    extra_fields.push(
+                        motif_label(
+                            motif_locations,
+                            profile.chrom_id,
+                            mod_profile,
+                        )
+                        .unwrap_or(".".to_string()),
                     );
-                    self.tsv_writer.buf_writer.write(row.as_bytes())?;
+                }
+                if let Some(calls) = calls.as_ref() {
+                    // sites that could not be called get a '.' so that
+                    // every row has the same number of columns
+                    let (call_code, fail) = calls
+                        .get(&(
+                            mod_profile.query_position,
+                            mod_profile.mod_strand,
+                        ))
+                        .map(|(code, fail)| (code.to_string(), *fail))
+                        .unwrap_or((".".to_string(), true));
+                    extra_fields.push(call_code);
+                    extra_fields.push(fail.to_string());
+                }
+                let row = mod_profile.to_row(
+                    &profile.record_name,
+                    chrom_name.unwrap_or(&missing_chrom),
+                    &self.name_to_seq,
+                    &self.kmer_window,
+                    &extra_fields,
+                );
+                let key = match (profile.chrom_id, mod_profile.ref_position) {
+                    (Some(chrom_id), Some(ref_pos)) if ref_pos >= 0 => {
+                        Some((chrom_id, ref_pos as u64))
+                    }
+                    _ => None,
+                };
+                rows.push((key, row));
+            }
+            self.written_alignments.insert(alignment_id);
+            if !self.read_names.contains(&profile.record_name) {
+                self.read_names.insert(profile.record_name.to_owned());
+            }
+        }
+        rows
+    }
+
+    fn num_reads(&self) -> usize {
+        self.read_names.len()
+    }
+}
+
+#[derive(new)]
+pub struct TsvWriterWithContigNames<W: Write> {
+    tsv_writer: TsvWriter<W>,
+    formatter: ExtractRowFormatter,
+}
+
+impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
+    for TsvWriterWithContigNames<W>
+{
+    fn write(&mut self, item: ReadsBaseModProfile) -> AnyhowResult<u64> {
+        let mut rows_written = 0u64;
+        for (_, row) in self.formatter.format_rows(&item) {
+            self.tsv_writer.buf_writer.write(row.as_bytes())?;
+            rows_written += 1;
+        }
+        Ok(rows_written)
+    }
+
+    fn num_reads(&self) -> usize {
+        self.formatter.num_reads()
+    }
+}
+
+/// Number of rows kept in memory before a sorted run is spilled to disk.
+const SORTED_EXTRACT_RUN_SIZE: usize = 1_000_000;
+
+/// Writes the `extract` table sorted by reference position, BGZF-compressed
+/// and with a tabix index. Rows are buffered and spilled to disk as sorted
+/// runs, which are merged into the output when the writer is finished. Rows
+/// without a reference position can't be indexed and are not written.
+pub(crate) struct SortedBgzfExtractWriter {
+    out_path: PathBuf,
+    header: String,
+    formatter: ExtractRowFormatter,
+    buffer: Vec<((u32, u64), String)>,
+    run_paths: Vec<PathBuf>,
+    n_unplaced: usize,
+}
+
+impl SortedBgzfExtractWriter {
+    pub(crate) fn new(
+        fp: &str,
+        force: bool,
+        header: String,
+        formatter: ExtractRowFormatter,
+    ) -> AnyhowResult<Self> {
+        let out_path = PathBuf::from(fp);
+        let index_path = PathBuf::from(format!("{fp}.tbi"));
+        for p in [&out_path, &index_path] {
+            if p.exists() && !force {
+                return Err(anyhow!(
+                    "refusing to write over existing file {}",
+                    p.display()
+                ));
+            }
+        }
+        Ok(Self {
+            out_path,
+            header,
+            formatter,
+            buffer: Vec::new(),
+            run_paths: Vec::new(),
+            n_unplaced: 0,
+        })
+    }
+
+    fn spill_run(&mut self) -> AnyhowResult<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let run_path = PathBuf::from(format!(
+            "{}.run{}.tmp",
+            self.out_path.display(),
+            self.run_paths.len()
+        ));
+        let fh = File::create(&run_path).with_context(|| {
+            format!("failed to create temp file {}", run_path.display())
+        })?;
+        // track the run before writing so it's removed if writing fails
+        self.run_paths.push(run_path.clone());
+        let mut writer = BufWriter::new(fh);
+        for ((tid, pos), row) in self.buffer.drain(..) {
+            writer.write_all(format!("{tid}\t{pos}\t{row}").as_bytes())?;
+        }
+        writer.flush()?;
+        debug!("spilled sorted run to {}", run_path.display());
+        Ok(())
+    }
+
+    fn remove_runs(&mut self) {
+        for run_path in self.run_paths.drain(..) {
+            if let Err(e) = std::fs::remove_file(&run_path) {
+                warn!(
+                    "failed to remove temp file {}, {}",
+                    run_path.display(),
+                    e.to_string()
+                );
+            }
+        }
+    }
+
+    fn next_run_row(
+        lines: &mut Lines<BufReader<File>>,
+    ) -> AnyhowResult<Option<((u32, u64), String)>> {
+        match lines.next() {
+            Some(line) => {
+                let line = line?;
+                let mut parts = line.splitn(3, '\t');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(tid), Some(pos), Some(row)) => Ok(Some((
+                        (tid.parse::<u32>()?, pos.parse::<u64>()?),
+                        row.to_string(),
+                    ))),
+                    _ => Err(anyhow!("malformed line in sorted run, {line}")),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn merge_runs(&self) -> AnyhowResult<()> {
+        let mut runs = self
+            .run_paths
+            .iter()
+            .map(|p| File::open(p).map(|fh| BufReader::new(fh).lines()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut heap = BinaryHeap::new();
+        for (i, run) in runs.iter_mut().enumerate() {
+            if let Some((key, row)) = Self::next_run_row(run)? {
+                heap.push(Reverse((key, i, row)));
+            }
+        }
+
+        let mut writer =
+            bgzf::Writer::from_path(&self.out_path).with_context(|| {
+                format!("failed to open {}", self.out_path.display())
+            })?;
+        writer.write_all(format!("{}\n", self.header).as_bytes())?;
+        while let Some(Reverse((_, i, row))) = heap.pop() {
+            writer.write_all(row.as_bytes())?;
+            writer.write_all(b"\n")?;
+            if let Some((key, row)) = Self::next_run_row(&mut runs[i])? {
+                heap.push(Reverse((key, i, row)));
+            }
+        }
+        writer.flush()?;
+        // BGZF EOF block is written when the writer is closed
+        drop(writer);
+        Ok(())
+    }
+
+    fn build_index(&self) -> AnyhowResult<()> {
+        let fp = CString::new(self.out_path.to_string_lossy().as_bytes())?;
+        // sc, bc, and ec are 1-based column numbers: chrom is column 4 and
+        // ref_position column 3, TBX_UCSC marks ref_position as 0-based
+        let conf = htslib::tbx_conf_t {
+            preset: (htslib::TBX_GENERIC | htslib::TBX_UCSC) as i32,
+            sc: 4,
+            bc: 3,
+            ec: 3,
+            meta_char: '#' as i32,
+            line_skip: 1,
+        };
+        let ret = unsafe { htslib::tbx_index_build(fp.as_ptr(), 0, &conf) };
+        if ret < 0 {
+            Err(anyhow!(
+                "failed to build tabix index for {}",
+                self.out_path.display()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl OutwriterWithMemory<ReadsBaseModProfile> for SortedBgzfExtractWriter {
+    fn write(&mut self, item: ReadsBaseModProfile) -> AnyhowResult<u64> {
+        let mut rows_written = 0u64;
+        for (key, row) in self.formatter.format_rows(&item) {
+            match key {
+                Some(key) => {
+                    self.buffer.push((key, row));
                     rows_written += 1;
                 }
-                self.written_reads.insert(profile.record_name.to_owned());
+                None => self.n_unplaced += 1,
             }
         }
+        if self.buffer.len() >= SORTED_EXTRACT_RUN_SIZE {
+            self.spill_run()?;
+        }
+        Ok(rows_written)
+    }
+
+    fn num_reads(&self) -> usize {
+        self.formatter.num_reads()
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.spill_run()?;
+        if self.n_unplaced > 0 {
+            info!(
+                "skipped {} rows without a reference position",
+                self.n_unplaced
+            );
+        }
+        let merged = self.merge_runs().and_then(|_| self.build_index());
+        self.remove_runs();
+        merged
+    }
+}
+
+impl Drop for SortedBgzfExtractWriter {
+    // removes the sorted runs when an error stops extract before `finish`
+    fn drop(&mut self) {
+        self.remove_runs();
+    }
+}
+
+#[derive(new)]
+pub(crate) struct ReadSummaryWriter<W: Write> {
+    tsv_writer: TsvWriter<W>,
+    tid_to_name: HashMap<u32, String>,
+    written_reads: HashSet<String>,
+}
+
+impl<W: Write> OutwriterWithMemory<Vec<ReadSummary>> for ReadSummaryWriter<W> {
+    fn write(&mut self, item: Vec<ReadSummary>) -> AnyhowResult<u64> {
+        let missing_chrom = ".".to_string();
+        let mut rows_written = 0u64;
+        for read_summary in item {
+            if self.written_reads.contains(&read_summary.read_id) {
+                continue;
+            }
+            let chrom_name = read_summary
+                .chrom_id()
+                .and_then(|chrom_id| self.tid_to_name.get(&chrom_id))
+                .unwrap_or(&missing_chrom);
+            let row = read_summary.to_row(chrom_name);
+            self.tsv_writer.buf_writer.write(row.as_bytes())?;
+            rows_written += 1;
+            self.written_reads.insert(read_summary.read_id);
+        }
         Ok(rows_written)
     }
 
@@ -826,3 +1275,571 @@ impl PileupWriter<ModBasePileup> for PartitioningBedMethylWriter {
         Ok(rows_written)
     }
 }
+
+impl PileupWriter<DuplexModBasePileup> for PartitioningBedMethylWriter {
+    fn write(
+        &mut self,
+        item: DuplexModBasePileup,
+        _motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let tabs_and_spaces = self.tabs_and_spaces;
+        let mut rows_written = 0u64;
+        for (&pos, partitioned_counts) in item.iter_counts_sorted() {
+            for (&partition_key, duplex_pileup_counts) in partitioned_counts {
+                let key_name = match partition_key {
+                    PartitionKey::NoKey => UNGROUPED,
+                    PartitionKey::Key(idx) => item
+                        .partition_keys
+                        .get_index(idx)
+                        .map(|s| s.as_str())
+                        .unwrap_or(NOT_FOUND),
+                };
+
+                let writer = self.get_writer_for_key(key_name);
+                rows_written += BedMethylWriter::write_duplex_feature_counts(
+                    pos as u64,
+                    pos as u64 + 1,
+                    &item.chrom_name,
+                    duplex_pileup_counts,
+                    writer,
+                    tabs_and_spaces,
+                )?;
+            }
+        }
+
+        Ok(rows_written)
+    }
+}
+
+// Parquet output
+
+// rows are buffered until there are at least this many, then written as a
+// single row group
+const PARQUET_MIN_ROW_GROUP_SIZE: usize = 100_000;
+
+fn parquet_dict_type() -> DataType {
+    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+}
+
+fn new_arrow_writer(
+    fp: &str,
+    force: bool,
+    schema: SchemaRef,
+) -> AnyhowResult<ArrowWriter<File>> {
+    let p = Path::new(fp);
+    if p.exists() && !force {
+        return Err(anyhow!("refusing to write over existing file {fp}"));
+    }
+    let fh = File::create(p)?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    ArrowWriter::try_new(fh, schema, Some(props))
+        .context("failed to make Parquet writer")
+}
+
+/// Column builders that rows are appended to before they are written to a
+/// Parquet file as a row group.
+trait ParquetColumnBuilders {
+    /// Finish the columns in the order of the schema, resets the builders.
+    fn finish(&mut self) -> Vec<ArrayRef>;
+}
+
+/// Buffers rows in the column builders and writes them to the Parquet file
+/// in row groups of at least `PARQUET_MIN_ROW_GROUP_SIZE` rows.
+struct ParquetSink<B: ParquetColumnBuilders> {
+    arrow_writer: Option<ArrowWriter<File>>,
+    schema: SchemaRef,
+    builders: B,
+    rows_buffered: usize,
+}
+
+impl<B: ParquetColumnBuilders> ParquetSink<B> {
+    fn new(
+        fp: &str,
+        force: bool,
+        schema: SchemaRef,
+        builders: B,
+    ) -> AnyhowResult<Self> {
+        let arrow_writer = new_arrow_writer(fp, force, schema.clone())?;
+        Ok(Self {
+            arrow_writer: Some(arrow_writer),
+            schema,
+            builders,
+            rows_buffered: 0,
+        })
+    }
+
+    /// Record that `n_rows` were appended to the builders, writes a row
+    /// group when enough rows are buffered.
+    fn rows_appended(&mut self, n_rows: usize) -> AnyhowResult<()> {
+        self.rows_buffered += n_rows;
+        if self.rows_buffered >= PARQUET_MIN_ROW_GROUP_SIZE {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> AnyhowResult<()> {
+        if self.rows_buffered == 0 {
+            return Ok(());
+        }
+        let batch =
+            RecordBatch::try_new(self.schema.clone(), self.builders.finish())?;
+        let arrow_writer = self
+            .arrow_writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("Parquet writer already finished"))?;
+        arrow_writer.write(&batch)?;
+        arrow_writer.flush()?;
+        self.rows_buffered = 0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.flush_row_group()?;
+        if let Some(arrow_writer) = self.arrow_writer.take() {
+            arrow_writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Column builders for the `extract` table.
+struct ExtractColumnBuilders {
+    read_id: StringDictionaryBuilder<Int32Type>,
+    forward_read_position: UInt64Builder,
+    ref_position: Int64Builder,
+    chrom: StringDictionaryBuilder<Int32Type>,
+    mod_strand: StringBuilder,
+    ref_strand: StringBuilder,
+    ref_mod_strand: StringBuilder,
+    fw_soft_clipped_start: UInt64Builder,
+    fw_soft_clipped_end: UInt64Builder,
+    read_length: UInt64Builder,
+    mod_qual: Float32Builder,
+    mod_code: StringBuilder,
+    base_qual: UInt8Builder,
+    ref_kmer: StringBuilder,
+    query_kmer: StringBuilder,
+    canonical_base: StringBuilder,
+    modified_primary_base: StringBuilder,
+    inferred: BooleanBuilder,
+    motif: Option<StringBuilder>,
+    calls: Option<(StringBuilder, BooleanBuilder)>,
+}
+
+impl ExtractColumnBuilders {
+    fn new(with_motif: bool, with_calls: bool) -> Self {
+        Self {
+            read_id: StringDictionaryBuilder::new(),
+            forward_read_position: UInt64Builder::new(),
+            ref_position: Int64Builder::new(),
+            chrom: StringDictionaryBuilder::new(),
+            mod_strand: StringBuilder::new(),
+            ref_strand: StringBuilder::new(),
+            ref_mod_strand: StringBuilder::new(),
+            fw_soft_clipped_start: UInt64Builder::new(),
+            fw_soft_clipped_end: UInt64Builder::new(),
+            read_length: UInt64Builder::new(),
+            mod_qual: Float32Builder::new(),
+            mod_code: StringBuilder::new(),
+            base_qual: UInt8Builder::new(),
+            ref_kmer: StringBuilder::new(),
+            query_kmer: StringBuilder::new(),
+            canonical_base: StringBuilder::new(),
+            modified_primary_base: StringBuilder::new(),
+            inferred: BooleanBuilder::new(),
+            motif: with_motif.then(StringBuilder::new),
+            calls: with_calls
+                .then(|| (StringBuilder::new(), BooleanBuilder::new())),
+        }
+    }
+
+    /// Same columns as the TSV output, but unmapped positions and missing
+    /// reference contexts are null.
+    fn schema(&self) -> SchemaRef {
+        let mut fields = vec![
+            Field::new("read_id", parquet_dict_type(), false),
+            Field::new("forward_read_position", DataType::UInt64, false),
+            Field::new("ref_position", DataType::Int64, true),
+            Field::new("chrom", parquet_dict_type(), true),
+            Field::new("mod_strand", DataType::Utf8, false),
+            Field::new("ref_strand", DataType::Utf8, true),
+            Field::new("ref_mod_strand", DataType::Utf8, true),
+            Field::new("fw_soft_clipped_start", DataType::UInt64, false),
+            Field::new("fw_soft_clipped_end", DataType::UInt64, false),
+            Field::new("read_length", DataType::UInt64, false),
+            Field::new("mod_qual", DataType::Float32, false),
+            Field::new("mod_code", DataType::Utf8, false),
+            Field::new("base_qual", DataType::UInt8, false),
+            Field::new("ref_kmer", DataType::Utf8, true),
+            Field::new("query_kmer", DataType::Utf8, false),
+            Field::new("canonical_base", DataType::Utf8, false),
+            Field::new("modified_primary_base", DataType::Utf8, false),
+            Field::new("inferred", DataType::Boolean, false),
+        ];
+        if self.motif.is_some() {
+            fields.push(Field::new("motif", DataType::Utf8, true));
+        }
+        if self.calls.is_some() {
+            fields.push(Field::new("call_code", DataType::Utf8, true));
+            fields.push(Field::new("fail", DataType::Boolean, true));
+        }
+        Arc::new(Schema::new(fields))
+    }
+
+    fn append(
+        &mut self,
+        read_id: &str,
+        chrom_name: Option<&str>,
+        mod_profile: &ModProfile,
+        ref_kmer: Option<String>,
+        motif: Option<String>,
+        call: Option<(char, bool)>,
+    ) {
+        let strand_str =
+            |strand: Option<Strand>| strand.map(|s| s.to_char().to_string());
+        self.read_id.append_value(read_id);
+        self.forward_read_position
+            .append_value(mod_profile.query_position as u64);
+        self.ref_position.append_option(
+            mod_profile.ref_position.filter(|&ref_pos| ref_pos >= 0),
+        );
+        self.chrom.append_option(chrom_name);
+        self.mod_strand
+            .append_value(mod_profile.mod_strand.to_char().to_string());
+        self.ref_strand
+            .append_option(strand_str(mod_profile.alignment_strand));
+        self.ref_mod_strand
+            .append_option(strand_str(mod_profile.ref_mod_strand()));
+        self.fw_soft_clipped_start
+            .append_value(mod_profile.num_soft_clipped_start as u64);
+        self.fw_soft_clipped_end
+            .append_value(mod_profile.num_soft_clipped_end as u64);
+        self.read_length
+            .append_value(mod_profile.read_length as u64);
+        self.mod_qual.append_value(mod_profile.q_mod);
+        self.mod_code
+            .append_value(mod_profile.raw_mod_code.to_string());
+        self.base_qual.append_value(mod_profile.q_base);
+        self.ref_kmer.append_option(ref_kmer);
+        self.query_kmer.append_value(mod_profile.query_kmer());
+        self.canonical_base
+            .append_value(mod_profile.canonical_base.to_string());
+        self.modified_primary_base
+            .append_value(mod_profile.modified_primary_base().to_string());
+        self.inferred.append_value(mod_profile.inferred);
+        if let Some(motif_builder) = self.motif.as_mut() {
+            motif_builder.append_option(motif);
+        }
+        if let Some((call_code_builder, fail_builder)) = self.calls.as_mut() {
+            call_code_builder
+                .append_option(call.map(|(code, _)| code.to_string()));
+            fail_builder.append_option(call.map(|(_, fail)| fail));
+        }
+    }
+}
+
+impl ParquetColumnBuilders for ExtractColumnBuilders {
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.read_id.finish()),
+            Arc::new(self.forward_read_position.finish()),
+            Arc::new(self.ref_position.finish()),
+            Arc::new(self.chrom.finish()),
+            Arc::new(self.mod_strand.finish()),
+            Arc::new(self.ref_strand.finish()),
+            Arc::new(self.ref_mod_strand.finish()),
+            Arc::new(self.fw_soft_clipped_start.finish()),
+            Arc::new(self.fw_soft_clipped_end.finish()),
+            Arc::new(self.read_length.finish()),
+            Arc::new(self.mod_qual.finish()),
+            Arc::new(self.mod_code.finish()),
+            Arc::new(self.base_qual.finish()),
+            Arc::new(self.ref_kmer.finish()),
+            Arc::new(self.query_kmer.finish()),
+            Arc::new(self.canonical_base.finish()),
+            Arc::new(self.modified_primary_base.finish()),
+            Arc::new(self.inferred.finish()),
+        ];
+        if let Some(motif_builder) = self.motif.as_mut() {
+            columns.push(Arc::new(motif_builder.finish()));
+        }
+        if let Some((call_code_builder, fail_builder)) = self.calls.as_mut() {
+            columns.push(Arc::new(call_code_builder.finish()));
+            columns.push(Arc::new(fail_builder.finish()));
+        }
+        columns
+    }
+}
+
+/// Writes the `extract` table to a Parquet file with typed columns, the read
+/// IDs and contig names are dictionary encoded.
+pub(crate) struct ParquetExtractWriter {
+    sink: ParquetSink<ExtractColumnBuilders>,
+    tid_to_name: HashMap<u32, String>,
+    name_to_seq: HashMap<String, Vec<u8>>,
+    written_alignments: HashSet<String>,
+    read_names: HashSet<String>,
+    kmer_window: KmerWindow,
+    threshold_caller: Option<MultipleThresholdModCaller>,
+    motif_locations: Option<Arc<MultipleMotifLocations>>,
+}
+
+impl ParquetExtractWriter {
+    pub(crate) fn new(
+        fp: &str,
+        force: bool,
+        tid_to_name: HashMap<u32, String>,
+        name_to_seq: HashMap<String, Vec<u8>>,
+        kmer_window: KmerWindow,
+        threshold_caller: Option<MultipleThresholdModCaller>,
+        motif_locations: Option<Arc<MultipleMotifLocations>>,
+    ) -> AnyhowResult<Self> {
+        let builders = ExtractColumnBuilders::new(
+            motif_locations.is_some(),
+            threshold_caller.is_some(),
+        );
+        let schema = builders.schema();
+        let sink = ParquetSink::new(fp, force, schema, builders)?;
+        Ok(Self {
+            sink,
+            tid_to_name,
+            name_to_seq,
+            written_alignments: HashSet::new(),
+            read_names: HashSet::new(),
+            kmer_window,
+            threshold_caller,
+            motif_locations,
+        })
+    }
+}
+
+impl OutwriterWithMemory<ReadsBaseModProfile> for ParquetExtractWriter {
+    fn write(&mut self, item: ReadsBaseModProfile) -> AnyhowResult<u64> {
+        let mut rows_written = 0u64;
+        for profile in item.profiles.iter() {
+            let alignment_id = profile.alignment_id();
+            if self.written_alignments.contains(&alignment_id) {
+                continue;
+            }
+            let chrom_name = profile
+                .chrom_id
+                .and_then(|chrom_id| self.tid_to_name.get(&chrom_id));
+            let calls = self
+                .threshold_caller
+                .as_ref()
+                .map(|caller| profile.call_sites(caller));
+            for mod_profile in profile.profile.iter() {
+                let ref_kmer = chrom_name.and_then(|name| {
+                    mod_profile.ref_kmer(
+                        name,
+                        &self.name_to_seq,
+                        &self.kmer_window,
+                    )
+                });
+                let motif = self.motif_locations.as_ref().and_then(|mls| {
+                    motif_label(mls, profile.chrom_id, mod_profile)
+                });
+                let call = calls.as_ref().and_then(|calls| {
+                    calls
+                        .get(&(
+                            mod_profile.query_position,
+                            mod_profile.mod_strand,
+                        ))
+                        .copied()
+                });
+                self.sink.builders.append(
+                    &profile.record_name,
+                    chrom_name.map(|s| s.as_str()),
+                    mod_profile,
+                    ref_kmer,
+                    motif,
+                    call,
+                );
+                rows_written += 1;
+            }
+            self.written_alignments.insert(alignment_id);
+            if !self.read_names.contains(&profile.record_name) {
+                self.read_names.insert(profile.record_name.to_owned());
+            }
+        }
+        self.sink.rows_appended(rows_written as usize)?;
+        Ok(rows_written)
+    }
+
+    fn num_reads(&self) -> usize {
+        self.read_names.len()
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.sink.finish()
+    }
+}
+
+/// Column builders for the `pileup` bedMethyl table. The redundant score,
+/// thickStart, thickEnd, and color columns are omitted.
+struct BedMethylColumnBuilders {
+    chrom: StringDictionaryBuilder<Int32Type>,
+    start: UInt32Builder,
+    end: UInt32Builder,
+    mod_code: StringBuilder,
+    motif: StringBuilder,
+    strand: StringBuilder,
+    n_valid_cov: UInt32Builder,
+    percent_modified: Float32Builder,
+    n_mod: UInt32Builder,
+    n_canonical: UInt32Builder,
+    n_other_mod: UInt32Builder,
+    n_delete: UInt32Builder,
+    n_fail: UInt32Builder,
+    n_diff: UInt32Builder,
+    n_no_call: UInt32Builder,
+}
+
+impl BedMethylColumnBuilders {
+    fn new() -> Self {
+        Self {
+            chrom: StringDictionaryBuilder::new(),
+            start: UInt32Builder::new(),
+            end: UInt32Builder::new(),
+            mod_code: StringBuilder::new(),
+            motif: StringBuilder::new(),
+            strand: StringBuilder::new(),
+            n_valid_cov: UInt32Builder::new(),
+            percent_modified: Float32Builder::new(),
+            n_mod: UInt32Builder::new(),
+            n_canonical: UInt32Builder::new(),
+            n_other_mod: UInt32Builder::new(),
+            n_delete: UInt32Builder::new(),
+            n_fail: UInt32Builder::new(),
+            n_diff: UInt32Builder::new(),
+            n_no_call: UInt32Builder::new(),
+        }
+    }
+
+    fn schema() -> SchemaRef {
+        let count_field =
+            |name: &str| Field::new(name, DataType::UInt32, false);
+        Arc::new(Schema::new(vec![
+            Field::new("chrom", parquet_dict_type(), false),
+            Field::new("start", DataType::UInt32, false),
+            Field::new("end", DataType::UInt32, false),
+            Field::new("mod_code", DataType::Utf8, false),
+            Field::new("motif", DataType::Utf8, true),
+            Field::new("strand", DataType::Utf8, false),
+            count_field("n_valid_cov"),
+            Field::new("percent_modified", DataType::Float32, false),
+            count_field("n_mod"),
+            count_field("n_canonical"),
+            count_field("n_other_mod"),
+            count_field("n_delete"),
+            count_field("n_fail"),
+            count_field("n_diff"),
+            count_field("n_no_call"),
+        ]))
+    }
+
+    fn append(
+        &mut self,
+        chrom_name: &str,
+        pos: u32,
+        feature_count: &PileupFeatureCounts,
+        motif: Option<&String>,
+    ) {
+        self.chrom.append_value(chrom_name);
+        self.start.append_value(pos);
+        self.end.append_value(pos + 1);
+        self.mod_code
+            .append_value(feature_count.raw_mod_code.to_string());
+        self.motif.append_option(motif);
+        self.strand
+            .append_value(feature_count.raw_strand.to_string());
+        self.n_valid_cov
+            .append_value(feature_count.filtered_coverage);
+        self.percent_modified
+            .append_value(feature_count.fraction_modified * 100f32);
+        self.n_mod.append_value(feature_count.n_modified);
+        self.n_canonical.append_value(feature_count.n_canonical);
+        self.n_other_mod
+            .append_value(feature_count.n_other_modified);
+        self.n_delete.append_value(feature_count.n_delete);
+        self.n_fail.append_value(feature_count.n_filtered);
+        self.n_diff.append_value(feature_count.n_diff);
+        self.n_no_call.append_value(feature_count.n_nocall);
+    }
+}
+
+impl ParquetColumnBuilders for BedMethylColumnBuilders {
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(self.chrom.finish()),
+            Arc::new(self.start.finish()),
+            Arc::new(self.end.finish()),
+            Arc::new(self.mod_code.finish()),
+            Arc::new(self.motif.finish()),
+            Arc::new(self.strand.finish()),
+            Arc::new(self.n_valid_cov.finish()),
+            Arc::new(self.percent_modified.finish()),
+            Arc::new(self.n_mod.finish()),
+            Arc::new(self.n_canonical.finish()),
+            Arc::new(self.n_other_mod.finish()),
+            Arc::new(self.n_delete.finish()),
+            Arc::new(self.n_fail.finish()),
+            Arc::new(self.n_diff.finish()),
+            Arc::new(self.n_no_call.finish()),
+        ]
+    }
+}
+
+/// Writes `pileup` counts to a Parquet file with typed columns.
+pub(crate) struct ParquetBedMethylWriter {
+    sink: ParquetSink<BedMethylColumnBuilders>,
+}
+
+impl ParquetBedMethylWriter {
+    pub(crate) fn new(fp: &str, force: bool) -> AnyhowResult<Self> {
+        let sink = ParquetSink::new(
+            fp,
+            force,
+            BedMethylColumnBuilders::schema(),
+            BedMethylColumnBuilders::new(),
+        )?;
+        Ok(Self { sink })
+    }
+}
+
+impl PileupWriter<ModBasePileup> for ParquetBedMethylWriter {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0u64;
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            if let Some(feature_counts) =
+                feature_counts.get(&PartitionKey::NoKey)
+            {
+                for feature_count in feature_counts {
+                    let motif = feature_count
+                        .motif_idx
+                        .and_then(|i| motif_labels.get(i));
+                    self.sink.builders.append(
+                        &item.chrom_name,
+                        *pos,
+                        feature_count,
+                        motif,
+                    );
+                    rows_written += 1;
+                }
+            }
+        }
+        self.sink.rows_appended(rows_written as usize)?;
+        Ok(rows_written)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.sink.finish()
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use anyhow::Context;
 use rust_htslib::{bam, bam::Read};
 
@@ -345,3 +347,394 @@ fn test_adjust_edge_filter() {
         }
     }
 }
+
+fn adjust_and_extract_rows(
+    name: &str,
+    adjust_args: &[&str],
+) -> Vec<Vec<String>> {
+    let adjusted_bam = std::env::temp_dir().join(format!("{name}.bam"));
+    let mut args = vec![
+        "adjust-mods",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        adjusted_bam.to_str().unwrap(),
+    ];
+    args.extend_from_slice(adjust_args);
+    run_modkit(&args)
+        .with_context(|| format!("{name} failed to run adjust-mods"))
+        .unwrap();
+    let out_fp = std::env::temp_dir().join(format!("{name}.tsv"));
+    run_modkit(&[
+        "extract",
+        adjusted_bam.to_str().unwrap(),
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--force",
+    ])
+    .with_context(|| format!("{name} failed to run extract"))
+    .unwrap();
+    std::fs::read_to_string(&out_fp)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|l| l.split('\t').map(|x| x.to_string()).collect())
+        .collect()
+}
+
+#[test]
+fn test_adjust_motif_filter_read_context() {
+    let rows =
+        adjust_and_extract_rows("test_adjust_motif_filter_read", &["--cpg"]);
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        // query_kmer column, the call is in the middle of the 5-mer
+        assert_eq!(&row[14][2..4], "CG", "{row:?}");
+    }
+    // all of the calls in these reads are in a CpG context
+    let rows = adjust_and_extract_rows(
+        "test_adjust_motif_filter_read_inverted",
+        &["--cpg", "--invert-motif"],
+    );
+    assert!(rows.is_empty(), "{rows:?}");
+
+    let rows = adjust_and_extract_rows(
+        "test_adjust_motif_filter_read_cga",
+        &["--motif", "CGA", "0"],
+    );
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        assert_eq!(&row[14][2..5], "CGA", "{row:?}");
+    }
+    let rows = adjust_and_extract_rows(
+        "test_adjust_motif_filter_read_cga_inverted",
+        &["--motif", "CGA", "0", "--invert-motif"],
+    );
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        assert_eq!(&row[14][2..4], "CG", "{row:?}");
+        assert_ne!(&row[14][2..5], "CGA", "{row:?}");
+    }
+}
+
+#[test]
+fn test_adjust_motif_filter_reference_context() {
+    let rows = adjust_and_extract_rows(
+        "test_adjust_motif_filter_reference",
+        &[
+            "--motif",
+            "CG",
+            "0",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ],
+    );
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        assert_ne!(row[2], "-1", "{row:?}");
+        assert_eq!(reference_dinucleotide(row), "CG", "{row:?}");
+    }
+
+    // calls at unaligned positions are removed even when the filter is
+    // inverted
+    let rows = adjust_and_extract_rows(
+        "test_adjust_motif_filter_reference_inverted",
+        &[
+            "--motif",
+            "CG",
+            "0",
+            "--invert-motif",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ],
+    );
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        assert_ne!(row[2], "-1", "{row:?}");
+        assert_ne!(reference_dinucleotide(row), "CG", "{row:?}");
+    }
+}
+
+/// The reference dinucleotide starting at the modified base of an extract
+/// row, the ref_kmer column is centered on the base in the orientation of
+/// the reference.
+fn reference_dinucleotide(row: &[String]) -> String {
+    let ref_kmer = row[13].to_ascii_uppercase();
+    match row[6].as_str() {
+        "+" => ref_kmer[2..4].to_string(),
+        "-" => ref_kmer[1..3].to_string(),
+        s => panic!("unexpected strand {s}"),
+    }
+}
+
+#[test]
+fn test_adjust_filter_mismatches() {
+    let original_fp =
+        std::env::temp_dir().join("test_adjust_filter_mismatches_orig.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        original_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--force",
+    ])
+    .unwrap();
+    let n_unaligned = std::fs::read_to_string(&original_fp)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .filter(|l| l.split('\t').nth(2) == Some("-1"))
+        .count();
+    assert!(n_unaligned > 0);
+
+    let rows = adjust_and_extract_rows(
+        "test_adjust_filter_mismatches",
+        &[
+            "--filter-mismatches",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ],
+    );
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        assert_ne!(row[2], "-1", "{row:?}");
+        // the ref_kmer is in the orientation of the reference and the
+        // query_kmer in the orientation of the read
+        let ref_base = row[13].as_bytes()[2].to_ascii_uppercase();
+        let ref_base = match row[5].as_str() {
+            "-" => match ref_base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                b => b,
+            },
+            _ => ref_base,
+        };
+        let read_base = row[14].as_bytes()[2].to_ascii_uppercase();
+        assert_eq!(read_base as char, ref_base as char, "{row:?}");
+    }
+}
+
+#[test]
+fn test_adjust_invert_motif_requires_motif() {
+    let adjusted_bam =
+        std::env::temp_dir().join("test_adjust_invert_motif_requires.bam");
+    assert!(run_modkit(&[
+        "adjust-mods",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        adjusted_bam.to_str().unwrap(),
+        "--invert-motif",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_adjust_include_reads_strip_unselected() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let mut reader = bam::Reader::from_path(in_bam).unwrap();
+    let selected = reader
+        .records()
+        .next()
+        .unwrap()
+        .map(|record| String::from_utf8(record.qname().to_vec()).unwrap())
+        .unwrap();
+    let read_list =
+        std::env::temp_dir().join("test_adjust_include_reads_list.txt");
+    std::fs::write(&read_list, format!("{selected}\n")).unwrap();
+
+    for strip in [false, true] {
+        let adjusted_bam = std::env::temp_dir()
+            .join(format!("test_adjust_include_reads_{strip}.bam"));
+        let mut args = vec![
+            "adjust-mods",
+            in_bam,
+            adjusted_bam.to_str().unwrap(),
+            "--ignore",
+            "h",
+            "--include-reads",
+            read_list.to_str().unwrap(),
+        ];
+        if strip {
+            args.push("--strip-unselected");
+        }
+        run_modkit(&args).unwrap();
+
+        let original_tags = bam::Reader::from_path(in_bam)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+            .map(|record| {
+                let raw_mod_tags =
+                    parse_raw_mod_tags(&record).unwrap().unwrap();
+                (
+                    String::from_utf8(record.qname().to_vec()).unwrap(),
+                    raw_mod_tags.get_raw_mm().to_string(),
+                )
+            })
+            .collect::<HashMap<String, String>>();
+        let mut n_unselected = 0usize;
+        for record in bam::Reader::from_path(&adjusted_bam)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+        {
+            let name = String::from_utf8(record.qname().to_vec()).unwrap();
+            let tags = parse_raw_mod_tags(&record);
+            if name == selected {
+                let raw_mod_tags = tags.unwrap().unwrap();
+                assert!(!raw_mod_tags.get_raw_mm().contains('h'));
+            } else if strip {
+                n_unselected += 1;
+                assert!(tags.is_none());
+            } else {
+                n_unselected += 1;
+                let raw_mod_tags = tags.unwrap().unwrap();
+                assert_eq!(
+                    raw_mod_tags.get_raw_mm(),
+                    original_tags.get(&name).unwrap()
+                );
+            }
+        }
+        assert!(n_unselected > 0);
+    }
+}
+
+#[test]
+fn test_adjust_strip_unselected_requires_selection() {
+    let adjusted_bam =
+        std::env::temp_dir().join("test_adjust_strip_unselected_fail.bam");
+    assert!(run_modkit(&[
+        "adjust-mods",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        adjusted_bam.to_str().unwrap(),
+        "--strip-unselected",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_adjust_exclude_bed_masks_calls() {
+    let mask_bed = std::env::temp_dir().join("test_adjust_exclude_bed.bed");
+    std::fs::write(&mask_bed, "oligo_1512_adapters\t0\t100\t.\t0\t.\n")
+        .unwrap();
+    let adjusted_bam =
+        std::env::temp_dir().join("test_adjust_exclude_bed_masked.bam");
+    run_modkit(&[
+        "adjust-mods",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        adjusted_bam.to_str().unwrap(),
+        "--exclude-bed",
+        mask_bed.to_str().unwrap(),
+    ])
+    .unwrap();
+    let mod_profile_fp =
+        std::env::temp_dir().join("test_adjust_exclude_bed_masked.tsv");
+    run_modkit(&[
+        "extract",
+        adjusted_bam.to_str().unwrap(),
+        mod_profile_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let mod_profile = parse_mod_profile(&mod_profile_fp).unwrap();
+    let mut n_calls = 0usize;
+    for (_read_name, mod_datas) in mod_profile {
+        for mod_data in mod_datas {
+            n_calls += 1;
+            assert!(
+                mod_data.ref_pos < 0 || mod_data.ref_pos >= 100,
+                "{}",
+                mod_data.ref_pos
+            );
+        }
+    }
+    assert!(n_calls > 0);
+}
+
+#[test]
+fn test_adjust_parallel_preserves_order() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let read_names = |fp: &str| {
+        bam::Reader::from_path(fp)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+            .filter(|record| !record.is_secondary())
+            .map(|record| {
+                let raw_mm = parse_raw_mod_tags(&record)
+                    .map(|tags| tags.unwrap().get_raw_mm().to_string());
+                (record.qname().to_vec(), raw_mm)
+            })
+            .collect::<Vec<_>>()
+    };
+    let single_bam =
+        std::env::temp_dir().join("test_adjust_parallel_order_single.bam");
+    let multi_bam =
+        std::env::temp_dir().join("test_adjust_parallel_order_multi.bam");
+    // a small batch size so that the records are processed in several
+    // batches, compared to a single batch on one thread
+    for (fp, threads, batch_size) in
+        [(&single_bam, "1", "5000"), (&multi_bam, "8", "3")]
+    {
+        run_modkit(&[
+            "adjust-mods",
+            in_bam,
+            fp.to_str().unwrap(),
+            "--ignore",
+            "h",
+            "--threads",
+            threads,
+            "--batch-size",
+            batch_size,
+        ])
+        .unwrap();
+    }
+    let expected = read_names(in_bam)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert!(expected.len() > 3 * 2, "need more than two batches");
+    let single = read_names(single_bam.to_str().unwrap());
+    let multi = read_names(multi_bam.to_str().unwrap());
+    assert_eq!(
+        multi
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    assert_eq!(single, multi);
+}
+
+#[test]
+fn test_update_tags_parallel_preserves_order() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let updated_bam =
+        std::env::temp_dir().join("test_update_tags_parallel_order.bam");
+    run_modkit(&[
+        "update-tags",
+        in_bam,
+        updated_bam.to_str().unwrap(),
+        "--mode",
+        "ambiguous",
+        "--threads",
+        "8",
+        "--batch-size",
+        "2",
+    ])
+    .unwrap();
+    let read_names = |fp: &str| {
+        bam::Reader::from_path(fp)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+            .filter(|record| !record.is_secondary())
+            .map(|record| record.qname().to_vec())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        read_names(updated_bam.to_str().unwrap()),
+        read_names(in_bam)
+    );
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use rust_htslib::bam::{self, Read};
+
+use common::run_modkit;
+use mod_kit::mod_bam::ModBaseInfo;
+
+mod common;
+
+#[test]
+fn test_calibrate_help() {
+    run_modkit(&["calibrate", "--help"]).unwrap();
+}
+
+fn mod_probs_by_read(fp: &str) -> HashMap<Vec<u8>, Vec<(usize, char, f32)>> {
+    bam::Reader::from_path(fp)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .filter_map(|record| {
+            let info = ModBaseInfo::new_from_record(&record).ok()?;
+            let mut probs = info
+                .iter_seq_base_mod_probs()
+                .flat_map(|(_, _, seq_pos_probs)| {
+                    seq_pos_probs.pos_to_base_mod_probs.iter().flat_map(
+                        |(pos, probs)| {
+                            probs
+                                .iter_probs()
+                                .map(|(code, p)| (*pos, *code, *p))
+                                .collect::<Vec<_>>()
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+            probs.sort_by(|(a, x, _), (b, y, _)| (a, x).cmp(&(b, y)));
+            Some((record.qname().to_vec(), probs))
+        })
+        .collect()
+}
+
+#[test]
+fn test_calibrate_unmodified_control_adjust() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let calibration_fp =
+        std::env::temp_dir().join("test_calibrate_unmodified_control.tsv");
+    run_modkit(&[
+        "calibrate",
+        in_bam,
+        calibration_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let calibration = std::fs::read_to_string(&calibration_fp).unwrap();
+    let mut lines = calibration.lines();
+    assert_eq!(
+        lines.next(),
+        Some("primary_base\tmod_code\tkmer\tmethod\tparameters")
+    );
+    let codes = lines
+        .map(|l| {
+            let parts = l.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts[2], "*");
+            assert_eq!(parts[3], "isotonic");
+            (parts[0].to_string(), parts[1].to_string())
+        })
+        .collect::<Vec<(String, String)>>();
+    assert!(codes.contains(&("C".to_string(), "m".to_string())));
+    assert!(codes.contains(&("C".to_string(), "h".to_string())));
+
+    let out_bam =
+        std::env::temp_dir().join("test_calibrate_unmodified_control.bam");
+    run_modkit(&[
+        "adjust-mods",
+        in_bam,
+        out_bam.to_str().unwrap(),
+        "--calibration",
+        calibration_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // with only an unmodified control, calibrated probabilities are
+    // never higher than the original probabilities
+    let original = mod_probs_by_read(in_bam);
+    let calibrated = mod_probs_by_read(out_bam.to_str().unwrap());
+    assert_eq!(original.len(), calibrated.len());
+    let mut n_lowered = 0usize;
+    for (read_id, probs) in calibrated {
+        let original_probs = original.get(&read_id).unwrap();
+        assert_eq!(original_probs.len(), probs.len());
+        for ((pos, code, p), (o_pos, o_code, o_p)) in
+            probs.iter().zip(original_probs)
+        {
+            assert_eq!((pos, code), (o_pos, o_code));
+            assert!(*p <= *o_p + 1e-6);
+            if *p < *o_p {
+                n_lowered += 1;
+            }
+        }
+    }
+    assert!(n_lowered > 0);
+}
+
+#[test]
+fn test_calibrate_pileup_requires_threshold() {
+    let calibration_fp =
+        std::env::temp_dir().join("test_calibrate_pileup_threshold.tsv");
+    std::fs::write(
+        &calibration_fp,
+        "primary_base\tmod_code\tkmer\tmethod\tparameters\n\
+         C\tm\t*\tisotonic\t0.0:0.0,1.0:1.0\n",
+    )
+    .unwrap();
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let out_bed =
+        std::env::temp_dir().join("test_calibrate_pileup_threshold.bed");
+    assert!(run_modkit(&[
+        "pileup",
+        in_bam,
+        out_bed.to_str().unwrap(),
+        "--calibration",
+        calibration_fp.to_str().unwrap(),
+    ])
+    .is_err());
+    // an identity calibration map gives the same pileup
+    run_modkit(&[
+        "pileup",
+        in_bam,
+        out_bed.to_str().unwrap(),
+        "--calibration",
+        calibration_fp.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+    ])
+    .unwrap();
+    let expected_bed =
+        std::env::temp_dir().join("test_calibrate_pileup_threshold_ref.bed");
+    run_modkit(&[
+        "pileup",
+        in_bam,
+        expected_bed.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+    ])
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&out_bed).unwrap(),
+        std::fs::read_to_string(&expected_bed).unwrap()
+    );
+}
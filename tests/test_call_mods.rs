@@ -169,3 +169,107 @@ fn test_call_mods_same_pileup() {
         in_situ_threshold_pileup.to_str().unwrap(),
     );
 }
+
+fn get_string_tag(record: &bam::Record, tag: &[u8]) -> Option<String> {
+    match record.aux(tag) {
+        Ok(bam::record::Aux::String(s)) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+fn get_u8_array_tag(record: &bam::Record, tag: &[u8]) -> Option<Vec<u8>> {
+    match record.aux(tag) {
+        Ok(bam::record::Aux::ArrayU8(arr)) => Some(arr.iter().collect()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_call_mods_alternate_tags() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let original = bam::Reader::from_path(in_bam)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .filter(|record| !record.is_secondary())
+        .collect::<Vec<bam::Record>>();
+
+    let kept_bam =
+        std::env::temp_dir().join("test_call_mods_keep_original_tags.bam");
+    run_modkit(&[
+        "call-mods",
+        in_bam,
+        kept_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+        "--keep-original-tags",
+        "XM",
+        "XL",
+    ])
+    .unwrap();
+    let separate_bam =
+        std::env::temp_dir().join("test_call_mods_output_tags.bam");
+    run_modkit(&[
+        "call-mods",
+        in_bam,
+        separate_bam.to_str().unwrap(),
+        "--filter-threshold",
+        "0.7",
+        "--output-tags",
+        "XM",
+        "XL",
+    ])
+    .unwrap();
+
+    let kept = bam::Reader::from_path(&kept_bam)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .collect::<Vec<bam::Record>>();
+    let separate = bam::Reader::from_path(&separate_bam)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .collect::<Vec<bam::Record>>();
+    assert_eq!(original.len(), kept.len());
+    assert_eq!(original.len(), separate.len());
+    for ((original, kept), separate) in
+        original.iter().zip(kept.iter()).zip(separate.iter())
+    {
+        let original_mm = get_string_tag(original, b"MM").unwrap();
+        let original_ml = get_u8_array_tag(original, b"ML").unwrap();
+        // original tags are kept under the alternate names
+        assert_eq!(get_string_tag(kept, b"XM").unwrap(), original_mm);
+        assert_eq!(get_u8_array_tag(kept, b"XL").unwrap(), original_ml);
+        // MM/ML are left alone, the calls are in the alternate tags
+        assert_eq!(get_string_tag(separate, b"MM").unwrap(), original_mm);
+        assert_eq!(get_u8_array_tag(separate, b"ML").unwrap(), original_ml);
+        // the calls are the same either way
+        assert_eq!(
+            get_string_tag(kept, b"MM").unwrap(),
+            get_string_tag(separate, b"XM").unwrap()
+        );
+        let calls = get_u8_array_tag(separate, b"XL").unwrap();
+        assert_eq!(calls, get_u8_array_tag(kept, b"ML").unwrap());
+        assert!(calls.iter().all(|&q| q == 0 || q == 255));
+    }
+}
+
+#[test]
+fn test_call_mods_alternate_tags_invalid() {
+    let out_bam =
+        std::env::temp_dir().join("test_call_mods_alternate_tags_invalid.bam");
+    for tags in [["MM", "XL"], ["XM", "XM"], ["X", "XL"]] {
+        assert!(run_modkit(&[
+            "call-mods",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_bam.to_str().unwrap(),
+            "--filter-threshold",
+            "0.7",
+            "--output-tags",
+            tags[0],
+            tags[1],
+        ])
+        .is_err());
+    }
+}
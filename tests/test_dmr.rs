@@ -54,6 +54,66 @@ fn test_dmr_regression() {
     );
 }
 
+#[test]
+fn test_dmr_multi_skips_regions_with_failed_samples() {
+    // the third sample is the only one with chrX, the other samples fail to
+    // get counts for the chrX region so it is skipped instead of comparing
+    // the third sample to empty counts
+    let regions_bed = std::env::temp_dir()
+        .join("test_dmr_multi_skips_regions_with_failed_samples.bed");
+    let mut regions = std::fs::read_to_string(
+        "tests/resources/cpg_chr20_with_orig_names_selection.bed",
+    )
+    .unwrap()
+    .lines()
+    .take(3)
+    .map(|l| format!("{l}\n"))
+    .collect::<String>();
+    regions.push_str("chrX\t0\t2000\tchrX_region\n");
+    std::fs::write(&regions_bed, regions).unwrap();
+    let out_dir = std::env::temp_dir()
+        .join("test_dmr_multi_skips_regions_with_failed_samples");
+    run_modkit(&[
+        "dmr",
+        "multi",
+        "-s",
+        "tests/resources/lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "normal",
+        "-s",
+        "tests/resources/lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        "tumour",
+        "-s",
+        "tests/resources/dmr_multi_extra_contig.bed",
+        "extra",
+        "--mode",
+        "one-vs-rest",
+        "-o",
+        out_dir.to_str().unwrap(),
+        "-r",
+        regions_bed.to_str().unwrap(),
+        "--ref",
+        "tests/resources/GRCh38_chr20.fa",
+        "--base",
+        "C",
+        "-f",
+    ])
+    .expect("failed to run modkit dmr multi");
+
+    let output =
+        std::fs::read_to_string(out_dir.join("one_vs_rest.bed")).unwrap();
+    let mut lines = output.lines();
+    assert_eq!(
+        lines.next(),
+        Some("#chrom\tstart\tend\tname\tscore\tlabels\tcounts\ttotals\tfractions")
+    );
+    let rows = lines
+        .map(|l| l.split('\t').collect::<Vec<&str>>())
+        .collect::<Vec<Vec<&str>>>();
+    // one row per chr20 region and sample
+    assert_eq!(rows.len(), 9);
+    assert!(rows.iter().all(|row| row[0] == "chr20"));
+}
+
 // todo
 //  test pair with explicit index
 //  test multi
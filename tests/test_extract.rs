@@ -361,3 +361,445 @@ fn test_extract_implicit_mod_calls() {
     .context("test_extract_implicit_mod_calls, output didn't match")
     .unwrap();
 }
+
+#[test]
+fn test_extract_call_codes() {
+    let out_fp = std::env::temp_dir().join("test_extract_call_codes.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--call-codes",
+        "--filter-threshold",
+        "0.8",
+        "--force",
+    ])
+    .unwrap();
+
+    let reader = BufReader::new(File::open(&out_fp).unwrap());
+    let mut lines = reader.lines().map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    let columns = header.split('\t').collect::<Vec<&str>>();
+    let col_idx = |name: &str| {
+        columns
+            .iter()
+            .position(|c| *c == name)
+            .expect(&format!("should find column {name}"))
+    };
+    let (mod_qual_idx, mod_code_idx, call_code_idx, fail_idx) = (
+        col_idx("mod_qual"),
+        col_idx("mod_code"),
+        col_idx("call_code"),
+        col_idx("fail"),
+    );
+    let mut n_rows = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), columns.len());
+        let mod_qual = parts[mod_qual_idx].parse::<f32>().unwrap();
+        let fail = parts[fail_idx].parse::<bool>().unwrap();
+        // a passing modified call must have a probability above the threshold
+        if !fail && parts[call_code_idx] == parts[mod_code_idx] {
+            assert!(mod_qual >= 0.8, "{line}");
+        }
+        n_rows += 1;
+    }
+    assert!(n_rows > 0);
+}
+
+#[test]
+fn test_extract_cpg_motif() {
+    let out_fp = std::env::temp_dir().join("test_extract_cpg_motif.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--force",
+    ])
+    .unwrap();
+
+    let reader = BufReader::new(File::open(&out_fp).unwrap());
+    let mut lines = reader.lines().map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    let columns = header.split('\t').collect::<Vec<&str>>();
+    let col_idx = |name: &str| {
+        columns
+            .iter()
+            .position(|c| *c == name)
+            .expect(&format!("should find column {name}"))
+    };
+    let (ref_position_idx, ref_mod_strand_idx, ref_kmer_idx, motif_idx) = (
+        col_idx("ref_position"),
+        col_idx("ref_mod_strand"),
+        col_idx("ref_kmer"),
+        col_idx("motif"),
+    );
+    let mut n_rows = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), columns.len());
+        assert_ne!(parts[ref_position_idx], "-1", "{line}");
+        assert_eq!(parts[motif_idx], "CG,0", "{line}");
+        // the reference k-mer is centered on the modified base
+        let ref_kmer = parts[ref_kmer_idx].to_ascii_uppercase();
+        let dinucleotide = match parts[ref_mod_strand_idx] {
+            "+" => &ref_kmer[2..4],
+            "-" => &ref_kmer[1..3],
+            s => panic!("unexpected strand {s}"),
+        };
+        assert_eq!(dinucleotide, "CG", "{line}");
+        n_rows += 1;
+    }
+    assert!(n_rows > 0);
+}
+
+#[test]
+fn test_extract_motif_without_hits_keeps_contigs() {
+    // a contig is processed when any of the motifs has a hit on it
+    let count_rows = |name: &str, motif_args: &[&str]| {
+        let out_fp = std::env::temp_dir().join(name);
+        let mut args = vec![
+            "extract",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--force",
+        ];
+        args.extend_from_slice(motif_args);
+        run_modkit(&args).unwrap();
+        BufReader::new(File::open(&out_fp).unwrap()).lines().count() - 1
+    };
+    let n_cpg = count_rows("test_extract_motif_hits_cpg.tsv", &["--cpg"]);
+    let n_both = count_rows(
+        "test_extract_motif_hits_both.tsv",
+        &["--cpg", "--motif", "GGGGGGGGGGGGGGGGGGGGGGGGGGGGGG", "0"],
+    );
+    assert!(n_cpg > 0);
+    assert_eq!(n_cpg, n_both);
+}
+
+#[test]
+fn test_extract_kmer_size() {
+    let out_fp = std::env::temp_dir().join("test_extract_kmer_size.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--kmer-size",
+        "9",
+        "--force",
+    ])
+    .unwrap();
+
+    let reader = BufReader::new(File::open(&out_fp).unwrap());
+    let mut lines = reader.lines().map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    let columns = header.split('\t').collect::<Vec<&str>>();
+    let ref_kmer_idx = columns.iter().position(|c| *c == "ref_kmer").unwrap();
+    let query_kmer_idx =
+        columns.iter().position(|c| *c == "query_kmer").unwrap();
+    let mut n_rows = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts[query_kmer_idx].len(), 9, "{line}");
+        let ref_kmer = parts[ref_kmer_idx];
+        assert!(ref_kmer == "." || ref_kmer.len() == 9, "{line}");
+        n_rows += 1;
+    }
+    assert!(n_rows > 0);
+
+    let err = run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        out_fp.to_str().unwrap(),
+        "--kmer-size",
+        "4",
+        "--force",
+    ]);
+    assert!(err.is_err());
+}
+
+#[test]
+fn test_extract_parquet_output() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let tsv_fp = std::env::temp_dir().join("test_extract_parquet_output.tsv");
+    let parquet_fp =
+        std::env::temp_dir().join("test_extract_parquet_output.parquet");
+    for (out_fp, parquet) in [(&tsv_fp, false), (&parquet_fp, true)] {
+        let mut args = vec![
+            "extract",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ];
+        if parquet {
+            args.push("--parquet");
+        }
+        run_modkit(&args).unwrap();
+    }
+
+    let n_tsv_rows = BufReader::new(File::open(&tsv_fp).unwrap())
+        .lines()
+        .skip(1)
+        .count();
+    let reader =
+        SerializedFileReader::new(File::open(&parquet_fp).unwrap()).unwrap();
+    let metadata = reader.metadata();
+    assert_eq!(metadata.file_metadata().num_rows() as usize, n_tsv_rows);
+    let columns = metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|c| c.name().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(columns.first().map(|s| s.as_str()), Some("read_id"));
+    assert_eq!(columns.last().map(|s| s.as_str()), Some("inferred"));
+
+    // Parquet output needs a file
+    assert!(run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-",
+        "--parquet",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_sorted_bgzf_output() {
+    use flate2::read::MultiGzDecoder;
+    use rust_htslib::tbx::{self, Read};
+
+    let tsv_fp = std::env::temp_dir().join("test_extract_sorted_bgzf.tsv");
+    let bgzf_fp = std::env::temp_dir().join("test_extract_sorted_bgzf.tsv.gz");
+    for (out_fp, bgzf) in [(&tsv_fp, false), (&bgzf_fp, true)] {
+        let mut args = vec![
+            "extract",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--force",
+        ];
+        if bgzf {
+            args.push("--bgzf");
+        }
+        run_modkit(&args).unwrap();
+    }
+    let index_fp = PathBuf::from(format!("{}.tbi", bgzf_fp.to_str().unwrap()));
+    assert!(index_fp.exists());
+
+    // chrom and ref_position for each row, unaligned sites are not written
+    let parse_positions = |lines: Vec<String>| {
+        lines
+            .into_iter()
+            .skip(1)
+            .map(|line| {
+                let parts = line.split('\t').collect::<Vec<&str>>();
+                (parts[3].to_string(), parts[2].parse::<i64>().unwrap())
+            })
+            .collect::<Vec<(String, i64)>>()
+    };
+    let expected = parse_positions(
+        BufReader::new(File::open(&tsv_fp).unwrap())
+            .lines()
+            .map(|l| l.unwrap())
+            .collect(),
+    )
+    .into_iter()
+    .filter(|(chrom, pos)| chrom != "." && *pos >= 0)
+    .collect::<Vec<(String, i64)>>();
+    let observed = parse_positions(
+        BufReader::new(MultiGzDecoder::new(File::open(&bgzf_fp).unwrap()))
+            .lines()
+            .map(|l| l.unwrap())
+            .collect(),
+    );
+    assert!(!observed.is_empty());
+    assert_eq!(observed.len(), expected.len());
+    // rows for each contig are contiguous and sorted by position
+    let mut seen_chroms = HashSet::new();
+    for (prev, curr) in observed.iter().zip(observed.iter().skip(1)) {
+        if prev.0 == curr.0 {
+            assert!(prev.1 <= curr.1, "{prev:?} should come before {curr:?}");
+        } else {
+            assert!(seen_chroms.insert(prev.0.clone()));
+        }
+    }
+
+    // the index can be used to fetch rows for a locus
+    let (chrom, pos) = observed[observed.len() / 2].clone();
+    let mut reader = tbx::Reader::from_path(&bgzf_fp).unwrap();
+    let tid = reader.tid(&chrom).unwrap();
+    reader.fetch(tid, pos as u64, pos as u64 + 1).unwrap();
+    let n_fetched = reader
+        .records()
+        .map(|r| String::from_utf8(r.unwrap()).unwrap())
+        .filter(|line| {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            parts[3] == chrom && parts[2].parse::<i64>().unwrap() == pos
+        })
+        .count();
+    let n_expected = observed
+        .iter()
+        .filter(|(c, p)| c == &chrom && *p == pos)
+        .count();
+    assert_eq!(n_fetched, n_expected);
+
+    // sorted output needs a file
+    assert!(run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        "-",
+        "--bgzf",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_read_summary() {
+    let in_bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let calls_fp = std::env::temp_dir().join("test_extract_read_summary.tsv");
+    let summary_fp =
+        std::env::temp_dir().join("test_extract_read_summary_reads.tsv");
+    let regions_fp =
+        std::env::temp_dir().join("test_extract_read_summary_regions.bed");
+    let (region_start, region_stop) = (0i64, 60i64);
+    std::fs::write(
+        &regions_fp,
+        format!(
+            "oligo_1512_adapters\t{region_start}\t{region_stop}\tr1\t0\t+\n"
+        ),
+    )
+    .unwrap();
+    run_modkit(&[
+        "extract",
+        in_bam,
+        calls_fp.to_str().unwrap(),
+        "--call-codes",
+        "--no-filtering",
+        "--force",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "extract",
+        in_bam,
+        summary_fp.to_str().unwrap(),
+        "--read-summary",
+        "--summary-regions",
+        regions_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--force",
+    ])
+    .unwrap();
+
+    let read_table = |fp: &PathBuf| {
+        let mut lines = BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .map(|l| l.unwrap());
+        let header = lines
+            .next()
+            .unwrap()
+            .split('\t')
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        lines
+            .map(|line| {
+                header
+                    .iter()
+                    .cloned()
+                    .zip(line.split('\t').map(|s| s.to_string()))
+                    .collect::<HashMap<String, String>>()
+            })
+            .collect::<Vec<HashMap<String, String>>>()
+    };
+
+    // the calls table has one row per mod code at each site, all of the rows
+    // for a site have the same call
+    let mut expected_sites =
+        HashMap::<String, HashSet<(String, String)>>::new();
+    let mut expected_calls = HashMap::<String, HashMap<String, usize>>::new();
+    let mut expected_region_sites = HashMap::<String, usize>::new();
+    for row in read_table(&calls_fp) {
+        let read_id = row["read_id"].clone();
+        let site = (
+            row["forward_read_position"].clone(),
+            row["mod_strand"].clone(),
+        );
+        if !expected_sites
+            .entry(read_id.clone())
+            .or_default()
+            .insert(site)
+        {
+            continue;
+        }
+        let call = match row["call_code"].as_str() {
+            "-" => row["canonical_base"].clone(),
+            code => code.to_string(),
+        };
+        *expected_calls
+            .entry(read_id.clone())
+            .or_default()
+            .entry(call)
+            .or_insert(0) += 1;
+        let ref_position = row["ref_position"].parse::<i64>().unwrap();
+        if ref_position >= region_start
+            && ref_position < region_stop
+            && row["ref_mod_strand"] == "+"
+        {
+            *expected_region_sites.entry(read_id).or_insert(0) += 1;
+        }
+    }
+
+    let summaries = read_table(&summary_fp);
+    assert_eq!(summaries.len(), expected_sites.len());
+    for summary in summaries {
+        let read_id = &summary["read_id"];
+        assert_eq!(
+            summary["n_sites"].parse::<usize>().unwrap(),
+            expected_sites[read_id].len(),
+            "{read_id}"
+        );
+        let call_counts = expected_calls[read_id]
+            .iter()
+            .map(|(code, count)| (code.clone(), *count))
+            .collect::<std::collections::BTreeMap<String, usize>>()
+            .into_iter()
+            .map(|(code, count)| format!("{code}:{count}"))
+            .collect::<Vec<String>>()
+            .join(",");
+        assert_eq!(summary["call_counts"], call_counts, "{read_id}");
+        assert_eq!(summary["n_fail"], "0");
+        assert_eq!(
+            summary["n_sites_in_regions"].parse::<usize>().unwrap(),
+            expected_region_sites.get(read_id).copied().unwrap_or(0),
+            "{read_id}"
+        );
+    }
+    assert!(expected_region_sites.values().sum::<usize>() > 0);
+
+    // the threshold options only apply to the read summary and call codes
+    assert!(run_modkit(&[
+        "extract",
+        in_bam,
+        calls_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--force",
+    ])
+    .is_err());
+    assert!(run_modkit(&[
+        "extract",
+        in_bam,
+        calls_fp.to_str().unwrap(),
+        "--filter-threshold",
+        "0.8",
+        "--force",
+    ])
+    .is_err());
+}
@@ -769,3 +769,111 @@ fn test_pileup_motifs_cg0_cgcg2_combined() {
         "tests/resources/cgcg2_cg0_test2_combine_strands.bed",
     );
 }
+
+#[test]
+fn test_pileup_parquet_output() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let temp_file = std::env::temp_dir().join("test_pileup_nofilt.parquet");
+    let args = [
+        "pileup",
+        "-i",
+        "25",
+        "--no-filtering",
+        "--parquet",
+        "--force",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+    ];
+    run_modkit(&args).unwrap();
+    // an existing Parquet file is only overwritten with --force
+    let no_force = args
+        .iter()
+        .filter(|arg| **arg != "--force")
+        .copied()
+        .collect::<Vec<&str>>();
+    assert!(run_modkit(&no_force).is_err());
+
+    let n_expected = BufReader::new(
+        File::open("tests/resources/modbam.modpileup_nofilt.methyl.bed")
+            .unwrap(),
+    )
+    .lines()
+    .count();
+    let reader =
+        SerializedFileReader::new(File::open(&temp_file).unwrap()).unwrap();
+    assert_eq!(
+        reader.metadata().file_metadata().num_rows() as usize,
+        n_expected
+    );
+}
+
+#[test]
+fn test_pileup_include_supplementary() {
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use rust_htslib::bam::Read;
+
+    let in_bam =
+        std::env::temp_dir().join("test_pileup_include_supplementary.bam");
+    let mut reader = bam::Reader::from_path(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+    )
+    .unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let primary = reader
+        .records()
+        .map(|r| r.unwrap())
+        .find(|r| !r.is_unmapped() && r.seq_len() > 100)
+        .unwrap();
+    // supplementary alignment with the full SEQ, so the tags are valid
+    let mut supplementary = primary.clone();
+    supplementary.set_flags(primary.flags() | 0x800);
+    // hard-clipped supplementary alignment without an MN tag, the tags are
+    // not valid for the SEQ
+    let clip = 10usize;
+    let seq = primary.seq().as_bytes();
+    let mut clipped = primary.clone();
+    clipped.set(
+        primary.qname(),
+        Some(&CigarString(vec![
+            Cigar::HardClip(clip as u32),
+            Cigar::Match((seq.len() - clip) as u32),
+        ])),
+        &seq[clip..],
+        &primary.qual()[clip..],
+    );
+    clipped.set_flags(primary.flags() | 0x800);
+    {
+        let mut writer =
+            bam::Writer::from_path(&in_bam, &header, bam::Format::Bam).unwrap();
+        for record in [&primary, &supplementary, &clipped] {
+            writer.write(record).unwrap();
+        }
+    }
+    bam::index::build(&in_bam, None, bam::index::Type::Bai, 1).unwrap();
+
+    let total_valid_coverage = |include_supplementary: bool| {
+        let out_fp = std::env::temp_dir().join(format!(
+            "test_pileup_include_supplementary_{include_supplementary}.bed"
+        ));
+        let mut args = vec![
+            "pileup",
+            "--no-filtering",
+            "--only-tabs",
+            in_bam.to_str().unwrap(),
+            out_fp.to_str().unwrap(),
+        ];
+        if include_supplementary {
+            args.push("--include-supplementary");
+        }
+        run_modkit(&args).unwrap();
+        BufReader::new(File::open(&out_fp).unwrap())
+            .lines()
+            .map(|l| l.unwrap())
+            .map(|l| l.split('\t').nth(9).unwrap().parse::<usize>().unwrap())
+            .sum::<usize>()
+    };
+    let primary_only = total_valid_coverage(false);
+    assert!(primary_only > 0);
+    assert_eq!(total_valid_coverage(true), primary_only * 2);
+}
@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rust_htslib::bam::{self, record::Aux, Read};
+
 use crate::common::{check_against_expected_text_file, run_modkit};
 
 mod common;
@@ -57,4 +63,396 @@ fn test_pileup_hemi_preset() {
     );
 }
 
+/// Only keep the MM groups for `primary_base` along with their ML
+/// probabilities.
+fn keep_mod_tags_for_base(record: &mut bam::Record, primary_base: char) {
+    let mm = match record.aux(b"MM").unwrap() {
+        Aux::String(mm) => mm.to_string(),
+        _ => panic!("MM should be a string"),
+    };
+    let ml = match record.aux(b"ML").unwrap() {
+        Aux::ArrayU8(ml) => ml.iter().collect::<Vec<u8>>(),
+        _ => panic!("ML should be an array of u8"),
+    };
+    let mut kept_mm = String::new();
+    let mut kept_ml = Vec::new();
+    let mut ml_offset = 0usize;
+    for group in mm.split(';').filter(|g| !g.is_empty()) {
+        let n_calls = group.split(',').count() - 1;
+        if group.starts_with(primary_base) {
+            kept_mm.push_str(group);
+            kept_mm.push(';');
+            kept_ml.extend_from_slice(&ml[ml_offset..ml_offset + n_calls]);
+        }
+        ml_offset += n_calls;
+    }
+    assert_eq!(ml_offset, ml.len());
+    record.remove_aux(b"MM").unwrap();
+    record.remove_aux(b"ML").unwrap();
+    record.push_aux(b"MM", Aux::String(&kept_mm)).unwrap();
+    record
+        .push_aux(b"ML", Aux::ArrayU8((&kept_ml).into()))
+        .unwrap();
+}
+
+/// Split each duplex record into a simplex template record with the
+/// calls on the positive strand and a complement record with the calls on
+/// the negative strand. The duplex records are kept when
+/// `keep_duplex_records` is true. Returns the BAM and a pairs file.
+fn write_simplex_pairs(
+    name: &str,
+    keep_duplex_records: bool,
+) -> (PathBuf, PathBuf) {
+    let bam_fp = std::env::temp_dir().join(format!("{name}.bam"));
+    let pairs_fp = std::env::temp_dir().join(format!("{name}_pairs.txt"));
+    let mut reader =
+        bam::Reader::from_path("tests/resources/duplex_modcalls_sort.bam")
+            .unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+    let mut pairs = std::fs::File::create(&pairs_fp).unwrap();
+    for record in reader.records().map(|r| r.unwrap()) {
+        let name = String::from_utf8(record.qname().to_vec()).unwrap();
+        let (template_id, complement_id) = name.split_once(';').unwrap();
+        writeln!(pairs, "{template_id}\t{complement_id}").unwrap();
+        if keep_duplex_records {
+            writer.write(&record).unwrap();
+        }
+        for (read_id, primary_base) in
+            [(template_id, 'C'), (complement_id, 'G')]
+        {
+            let mut simplex = record.clone();
+            simplex.set_qname(read_id.as_bytes());
+            simplex.remove_aux(b"dx").unwrap();
+            simplex.push_aux(b"dx", Aux::U8(0)).unwrap();
+            keep_mod_tags_for_base(&mut simplex, primary_base);
+            writer.write(&simplex).unwrap();
+        }
+    }
+    drop(writer);
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+    (bam_fp, pairs_fp)
+}
+
+fn run_pileup_hemi_nofilt(bam_fp: &Path, out_fp: &Path, extra_args: &[&str]) {
+    let mut args = vec![
+        "pileup-hemi",
+        bam_fp.to_str().unwrap(),
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-r",
+        "tests/resources/GRCh38_chr20.fa",
+        "--motif",
+        "CG",
+        "0",
+        "--region",
+        "chr20:22,613,835-22,640,468",
+        "--no-filtering",
+    ];
+    args.extend_from_slice(extra_args);
+    run_modkit(&args).unwrap();
+}
+
+#[test]
+fn test_pileup_hemi_simplex_pairs() {
+    // pairing the simplex records should give the same patterns as the
+    // duplex records they were split from
+    let (bam_fp, pairs_fp) =
+        write_simplex_pairs("test_pileup_hemi_simplex_pairs", false);
+    let temp_file =
+        std::env::temp_dir().join("test_pileup_hemi_simplex_pairs.bed");
+    run_pileup_hemi_nofilt(
+        &bam_fp,
+        &temp_file,
+        &["--simplex-pairs", pairs_fp.to_str().unwrap()],
+    );
+    check_against_expected_text_file(
+        temp_file.to_str().unwrap(),
+        "tests/resources/duplex_hemi_nofilt.bed",
+    );
+
+    // without pairing each simplex record only has a call on one strand
+    let unpaired_file =
+        std::env::temp_dir().join("test_pileup_hemi_simplex_unpaired.bed");
+    run_pileup_hemi_nofilt(&bam_fp, &unpaired_file, &[]);
+    assert_ne!(
+        std::fs::read_to_string(&unpaired_file).unwrap(),
+        std::fs::read_to_string("tests/resources/duplex_hemi_nofilt.bed")
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_pileup_hemi_pair_simplex_duplex_tag() {
+    // the simplex parents are found with the dx:i:1 records, which are
+    // themselves skipped, so each molecule is only counted once
+    let (bam_fp, _) =
+        write_simplex_pairs("test_pileup_hemi_pair_simplex_duplex_tag", true);
+    let temp_file = std::env::temp_dir()
+        .join("test_pileup_hemi_pair_simplex_duplex_tag.bed");
+    run_pileup_hemi_nofilt(&bam_fp, &temp_file, &["--pair-simplex"]);
+    check_against_expected_text_file(
+        temp_file.to_str().unwrap(),
+        "tests/resources/duplex_hemi_nofilt.bed",
+    );
+}
+
+#[test]
+fn test_pileup_hemi_aggregate_annotation() {
+    // a gene body is the same region as the BED record with the gene's
+    // coordinates
+    let gtf_fp =
+        std::env::temp_dir().join("test_pileup_hemi_aggregate_annotation.gtf");
+    std::fs::write(
+        &gtf_fp,
+        "chr20\ttest\tgene\t22614001\t22620000\t.\t-\t.\tgene_id \"G1\"; gene_name \"GENE_1\";\n\
+         chr20\ttest\tgene\t22630001\t22640000\t.\t+\t.\tgene_id \"G2\"; gene_name \"GENE_2\";\n",
+    )
+    .unwrap();
+    let bed_fp =
+        std::env::temp_dir().join("test_pileup_hemi_aggregate_annotation.bed");
+    std::fs::write(
+        &bed_fp,
+        "chr20\t22614000\t22620000\nchr20\t22630000\t22640000\n",
+    )
+    .unwrap();
+    let from_annotation = std::env::temp_dir()
+        .join("test_pileup_hemi_aggregate_annotation_out.bed");
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &from_annotation,
+        &[
+            "--aggregate-annotation",
+            gtf_fp.to_str().unwrap(),
+            "--annotation-feature",
+            "gene-body",
+        ],
+    );
+    let from_bed = std::env::temp_dir()
+        .join("test_pileup_hemi_aggregate_annotation_bed_out.bed");
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &from_bed,
+        &["--aggregate-regions", bed_fp.to_str().unwrap()],
+    );
+    let output = std::fs::read_to_string(&from_annotation).unwrap();
+    assert!(!output.is_empty());
+    assert_eq!(output, std::fs::read_to_string(&from_bed).unwrap());
+    // --flank needs an annotation
+    assert!(run_modkit(&[
+        "pileup-hemi",
+        "tests/resources/duplex_modcalls_sort.bam",
+        "-r",
+        "tests/resources/GRCh38_chr20.fa",
+        "--cpg",
+        "--flank",
+        "100",
+    ])
+    .is_err());
+}
+
+/// Rows of a pileup-hemi bedMethyl as (chrom, start, end, pattern name)
+/// and the columns after the color column.
+fn read_hemi_bedmethyl(
+    fp: &Path,
+) -> Vec<((String, u64, u64, String), Vec<f32>)> {
+    std::fs::read_to_string(fp)
+        .unwrap()
+        .lines()
+        .map(|l| {
+            let parts = l.split_whitespace().collect::<Vec<&str>>();
+            let key = (
+                parts[0].to_string(),
+                parts[1].parse::<u64>().unwrap(),
+                parts[2].parse::<u64>().unwrap(),
+                parts[3].to_string(),
+            );
+            let counts = parts[9..]
+                .iter()
+                .map(|x| x.parse::<f32>().unwrap())
+                .collect::<Vec<f32>>();
+            (key, counts)
+        })
+        .collect()
+}
+
+#[test]
+fn test_pileup_hemi_partition_tag() {
+    let control = Path::new("tests/resources/duplex_hemi_nofilt.bed");
+    // the test BAM has a single read group, so the partitioned output is the
+    // same as the control
+    let out_dir =
+        std::env::temp_dir().join("test_pileup_hemi_partition_tag_rg");
+    let _ = std::fs::remove_dir_all(&out_dir);
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &out_dir,
+        &["--partition-tag", "RG", "--prefix", "hemi"],
+    );
+    let files = out_dir
+        .read_dir()
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+    assert_eq!(files.len(), 1);
+    let file_name = files[0].file_name().unwrap().to_str().unwrap();
+    assert!(file_name.starts_with("hemi_") && file_name.ends_with(".bed"));
+    check_against_expected_text_file(
+        files[0].to_str().unwrap(),
+        control.to_str().unwrap(),
+    );
+
+    // the pattern counts of each partition sum to the control counts
+    let out_dir =
+        std::env::temp_dir().join("test_pileup_hemi_partition_tag_mx");
+    let _ = std::fs::remove_dir_all(&out_dir);
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &out_dir,
+        &["--partition-tag", "mx"],
+    );
+    let mut summed = HashMap::new();
+    let mut n_files = 0;
+    for entry in out_dir.read_dir().unwrap() {
+        for (key, counts) in read_hemi_bedmethyl(&entry.unwrap().path()) {
+            *summed.entry(key).or_insert(0f32) += counts[2];
+        }
+        n_files += 1;
+    }
+    assert!(n_files > 1);
+    let expected = read_hemi_bedmethyl(control)
+        .into_iter()
+        .map(|(key, counts)| (key, counts[2]))
+        .collect::<HashMap<_, f32>>();
+    assert_eq!(summed, expected);
+
+    // --prefix is only used with --partition-tag or --bedgraph
+    assert!(run_modkit(&[
+        "pileup-hemi",
+        "tests/resources/duplex_modcalls_sort.bam",
+        "-r",
+        "tests/resources/GRCh38_chr20.fa",
+        "--cpg",
+        "--prefix",
+        "hemi",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_hemi_bedgraph() {
+    let out_dir = std::env::temp_dir().join("test_pileup_hemi_bedgraph");
+    let _ = std::fs::remove_dir_all(&out_dir);
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &out_dir,
+        &["--bedgraph", "--prefix", "hemi"],
+    );
+    let control = read_hemi_bedmethyl(Path::new(
+        "tests/resources/duplex_hemi_nofilt.bed",
+    ));
+    let mut n_files = 0;
+    let mut n_rows = 0;
+    for entry in out_dir.read_dir().unwrap() {
+        let fp = entry.unwrap().path();
+        let file_name = fp.file_name().unwrap().to_str().unwrap();
+        // e.g. hemi_m-C_combined.bedgraph for the m,-,C pattern
+        let label = file_name
+            .strip_prefix("hemi_")
+            .and_then(|s| s.strip_suffix("_combined.bedgraph"))
+            .unwrap();
+        let expected = control
+            .iter()
+            .filter(|((_, _, _, name), _)| name.replace(',', "") == label)
+            .map(|((chrom, start, end, _), counts)| {
+                // valid coverage and percent of the pattern
+                (
+                    chrom.to_string(),
+                    *start,
+                    *end,
+                    format!("{:.2}", counts[1]),
+                    counts[0] as usize,
+                )
+            })
+            .collect::<Vec<_>>();
+        let observed = std::fs::read_to_string(&fp)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let parts = l.split('\t').collect::<Vec<&str>>();
+                (
+                    parts[0].to_string(),
+                    parts[1].parse::<u64>().unwrap(),
+                    parts[2].parse::<u64>().unwrap(),
+                    format!("{:.2}", parts[3].parse::<f32>().unwrap() * 100f32),
+                    parts[4].parse::<usize>().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(!observed.is_empty());
+        assert_eq!(observed, expected);
+        n_rows += observed.len();
+        n_files += 1;
+    }
+    assert!(n_files > 1);
+    assert_eq!(n_rows, control.len());
+}
+
+#[test]
+fn test_pileup_hemi_aggregate_regions() {
+    let regions = [(22613835u64, 22620000u64), (22620000u64, 22640468u64)];
+    let bed_fp =
+        std::env::temp_dir().join("test_pileup_hemi_aggregate_regions.bed");
+    std::fs::write(
+        &bed_fp,
+        regions
+            .iter()
+            .map(|(start, end)| format!("chr20\t{start}\t{end}\n"))
+            .collect::<String>(),
+    )
+    .unwrap();
+    let out_fp =
+        std::env::temp_dir().join("test_pileup_hemi_aggregate_regions_out.bed");
+    run_pileup_hemi_nofilt(
+        Path::new("tests/resources/duplex_modcalls_sort.bam"),
+        &out_fp,
+        &["--aggregate-regions", bed_fp.to_str().unwrap()],
+    );
+
+    // sum the per-position counts over each region, the valid coverage is
+    // counted once per position
+    let control = read_hemi_bedmethyl(Path::new(
+        "tests/resources/duplex_hemi_nofilt.bed",
+    ));
+    let mut expected = HashMap::new();
+    for (start, end) in regions {
+        let in_region = control
+            .iter()
+            .filter(|((_, pos, _, _), _)| *pos >= start && *pos < end)
+            .collect::<Vec<_>>();
+        let valid_coverage = in_region
+            .iter()
+            .map(|((_, pos, _, _), counts)| (*pos, counts[0] as usize))
+            .collect::<HashMap<u64, usize>>()
+            .values()
+            .sum::<usize>();
+        for ((_, _, _, name), counts) in in_region {
+            expected
+                .entry((start, end, name.to_string()))
+                .or_insert((valid_coverage, 0usize))
+                .1 += counts[2] as usize;
+        }
+    }
+    let observed = read_hemi_bedmethyl(&out_fp)
+        .into_iter()
+        .map(|((chrom, start, end, name), counts)| {
+            assert_eq!(chrom, "chr20");
+            ((start, end, name), (counts[0] as usize, counts[2] as usize))
+        })
+        .collect::<HashMap<_, _>>();
+    assert!(!observed.is_empty());
+    assert_eq!(observed, expected);
+}
+
 // todo test with combine mods
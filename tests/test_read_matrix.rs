@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::common::run_modkit;
+
+mod common;
+
+const DUPLEX_BAM: &str = "tests/resources/duplex_modbam.sorted.bam";
+const DUPLEX_CALLS: &str =
+    "tests/resources/test_extract_include_sites_duplex_regression_expected.bed";
+const REGION: &str = "chr17:7689000-7715300";
+
+/// Make a reference for the duplex reads with the CpG sites in
+/// hg38_chr17_CG0_snip.bed, all other bases are N.
+fn write_duplex_reference(name: &str) -> PathBuf {
+    let mut seq = vec![b'N'; 7_715_300];
+    let sites =
+        std::fs::read_to_string("tests/resources/hg38_chr17_CG0_snip.bed")
+            .unwrap();
+    for line in sites.lines() {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let pos = parts[1].parse::<usize>().unwrap();
+        seq[pos] = if parts[5] == "+" { b'C' } else { b'G' };
+    }
+    let fp = std::env::temp_dir().join(name);
+    let fasta = format!(">chr17\n{}\n", String::from_utf8(seq).unwrap());
+    std::fs::write(&fp, fasta).unwrap();
+    fp
+}
+
+/// Summed modification probability for each (read_id, ref_position,
+/// ref_mod_strand) from the extract table of the duplex reads, and the
+/// strand each read is aligned to.
+fn load_duplex_calls(
+) -> (HashMap<(String, u64, String), f32>, HashMap<String, String>) {
+    let table = std::fs::read_to_string(DUPLEX_CALLS).unwrap();
+    let mut calls = HashMap::new();
+    let mut read_strands = HashMap::new();
+    for line in table.lines().skip(1) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let key = (
+            parts[0].to_string(),
+            parts[2].parse::<u64>().unwrap(),
+            parts[6].to_string(),
+        );
+        *calls.entry(key).or_insert(0f32) += parts[10].parse::<f32>().unwrap();
+        read_strands.insert(parts[0].to_string(), parts[5].to_string());
+    }
+    (calls, read_strands)
+}
+
+fn read_matrix(fp: &PathBuf) -> (Vec<String>, Vec<Vec<String>>) {
+    let matrix = std::fs::read_to_string(fp).unwrap();
+    let mut lines = matrix.lines();
+    let header = lines
+        .next()
+        .unwrap()
+        .split('\t')
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+    let rows = lines
+        .map(|l| l.split('\t').map(|s| s.to_string()).collect())
+        .collect::<Vec<Vec<String>>>();
+    (header, rows)
+}
+
+fn assert_cell(cell: &str, expected: Option<f32>) {
+    match expected {
+        Some(p) => {
+            let obs = cell.parse::<f32>().unwrap();
+            assert!((obs - p).abs() < 1e-3, "{obs} != {p}");
+        }
+        None => assert_eq!(cell, "NA"),
+    }
+}
+
+#[test]
+fn test_read_matrix_duplex() {
+    let ref_fp = write_duplex_reference("test_read_matrix_duplex.fa");
+    let out_fp = std::env::temp_dir().join("test_read_matrix_duplex.tsv");
+    run_modkit(&[
+        "read-matrix",
+        DUPLEX_BAM,
+        out_fp.to_str().unwrap(),
+        "--region",
+        REGION,
+        "--ref",
+        ref_fp.to_str().unwrap(),
+        "--cpg",
+        "--tag",
+        "RG",
+        "--tag",
+        "HP",
+        "--force",
+    ])
+    .unwrap();
+    let (calls, read_strands) = load_duplex_calls();
+    let (header, rows) = read_matrix(&out_fp);
+    assert_eq!(&header[..4], &["read_id", "strand", "RG", "HP"]);
+    // 570 CpGs in the snippet, one column per strand
+    assert_eq!(header.len(), 4 + 570 * 2);
+    assert_eq!(rows.len(), read_strands.len());
+
+    let mut n_na = 0usize;
+    for row in rows.iter() {
+        assert_eq!(row.len(), header.len());
+        let read_id = &row[0];
+        assert_eq!(&row[1], &read_strands[read_id]);
+        assert_ne!(&row[2], ".");
+        // the reads don't have an HP tag
+        assert_eq!(&row[3], ".");
+        for (label, cell) in header[4..].iter().zip(row[4..].iter()) {
+            let (pos, strand) = label.split_once(':').unwrap();
+            let key = (
+                read_id.to_string(),
+                pos.parse().unwrap(),
+                strand.to_string(),
+            );
+            let expected = calls.get(&key).copied();
+            n_na += expected.is_none() as usize;
+            assert_cell(cell, expected);
+        }
+    }
+    // one read doesn't cover all of the sites
+    assert!(n_na > 0);
+    // the read groups are different for the two reads
+    assert_ne!(rows[0][2], rows[1][2]);
+}
+
+#[test]
+fn test_read_matrix_duplex_combine_strands() {
+    let ref_fp = write_duplex_reference("test_read_matrix_duplex_combined.fa");
+    let out_fp =
+        std::env::temp_dir().join("test_read_matrix_duplex_combined.tsv");
+    run_modkit(&[
+        "read-matrix",
+        DUPLEX_BAM,
+        out_fp.to_str().unwrap(),
+        "--region",
+        REGION,
+        "--ref",
+        ref_fp.to_str().unwrap(),
+        "--cpg",
+        "--combine-strands",
+        "--force",
+    ])
+    .unwrap();
+    let (calls, read_strands) = load_duplex_calls();
+    let (header, rows) = read_matrix(&out_fp);
+    assert_eq!(&header[..2], &["read_id", "strand"]);
+    assert_eq!(header.len(), 2 + 570);
+    assert_eq!(rows.len(), read_strands.len());
+
+    let mut n_averaged = 0usize;
+    for row in rows.iter() {
+        let read_id = &row[0];
+        assert_eq!(&row[1], &read_strands[read_id]);
+        for (label, cell) in header[2..].iter().zip(row[2..].iter()) {
+            let pos = label.parse::<u64>().unwrap();
+            // the duplex reads have calls on both strands of a CpG, these
+            // are averaged
+            let strand_probs = [
+                calls.get(&(read_id.to_string(), pos, "+".to_string())),
+                calls.get(&(read_id.to_string(), pos + 1, "-".to_string())),
+            ]
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<f32>>();
+            let expected = if strand_probs.is_empty() {
+                None
+            } else {
+                Some(
+                    strand_probs.iter().sum::<f32>()
+                        / strand_probs.len() as f32,
+                )
+            };
+            if strand_probs.len() == 2 {
+                n_averaged += 1;
+            }
+            assert_cell(cell, expected);
+        }
+    }
+    assert!(n_averaged > 0);
+}
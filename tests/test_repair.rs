@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use mod_kit::mod_bam::{ModBaseInfo, SeqPosBaseModProbs};
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
 
@@ -69,3 +70,265 @@ fn test_repair_regression() {
         );
     }
 }
+
+#[test]
+fn test_repair_report() {
+    let out_bam = std::env::temp_dir().join("test_repair_report.bam");
+    let report_fp = std::env::temp_dir().join("test_repair_report.tsv");
+    run_modkit(&[
+        "repair",
+        "--donor",
+        "tests/resources/donor_read_sort.bam",
+        "--acceptor",
+        "tests/resources/trimmed_read_sort.mapped.bam",
+        "-o",
+        out_bam.to_str().unwrap(),
+        "--report",
+        report_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    let n_records = bam::Reader::from_path(&out_bam).unwrap().records().count();
+    let report = std::fs::read_to_string(&report_fp).unwrap();
+    let mut lines = report.lines();
+    assert_eq!(
+        lines.next(),
+        Some("read_id\tmethod\tidentity\tdonor_calls\trepaired_calls")
+    );
+    let rows = lines
+        .map(|l| l.split('\t').map(|s| s.to_string()).collect::<Vec<_>>())
+        .collect::<Vec<Vec<String>>>();
+    assert_eq!(rows.len(), n_records);
+    // these reads were only trimmed
+    for row in rows {
+        assert_eq!(row[1], "exact");
+        assert_eq!(row[2], "1.0000");
+        let donor_calls = row[3].parse::<usize>().unwrap();
+        let repaired_calls = row[4].parse::<usize>().unwrap();
+        assert!(repaired_calls <= donor_calls);
+    }
+}
+
+/// Reads can have more than one alignment, so records are matched by name and
+/// alignment.
+fn record_key(record: &bam::Record) -> (Vec<u8>, u16, i32, i64) {
+    (
+        record.qname().to_vec(),
+        record.flags(),
+        record.tid(),
+        record.pos(),
+    )
+}
+
+#[test]
+fn test_repair_index_donor() {
+    let out_bam = std::env::temp_dir().join("test_repair_index_donor.bam");
+    let acceptor_fp = "tests/resources/trimmed_read_sort.mapped.bam";
+    run_modkit(&[
+        "repair",
+        "--donor",
+        "tests/resources/donor_read_sort.bam",
+        "--acceptor",
+        acceptor_fp,
+        "-o",
+        out_bam.to_str().unwrap(),
+        "--index-donor",
+    ])
+    .unwrap();
+
+    let qnames = |fp: &str| {
+        bam::Reader::from_path(fp)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap().qname().to_vec())
+            .collect::<Vec<Vec<u8>>>()
+    };
+    // output is in the same order as the acceptor
+    assert_eq!(qnames(out_bam.to_str().unwrap()), qnames(acceptor_fp));
+
+    let expected_records = bam::Reader::from_path(
+        "tests/resources/trimmed_read_sort_mods.mapped.bam",
+    )
+    .unwrap()
+    .records()
+    .map(|r| r.unwrap())
+    .map(|record| (record_key(&record), record))
+    .collect::<HashMap<_, _>>();
+    for record in bam::Reader::from_path(&out_bam).unwrap().records() {
+        let record = record.unwrap();
+        assert_eq!(
+            expected_records.get(&record_key(&record)).unwrap(),
+            &record
+        );
+    }
+}
+
+#[test]
+fn test_repair_supplementary() {
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    let in_bam = std::env::temp_dir().join("test_repair_supplementary.in.bam");
+    let out_bam = std::env::temp_dir().join("test_repair_supplementary.bam");
+    let mut reader = bam::Reader::from_path(
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+    )
+    .unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let primary = reader
+        .records()
+        .map(|r| r.unwrap())
+        .find(|r| !r.is_unmapped() && r.seq_len() > 100)
+        .unwrap();
+    let seq = primary.seq().as_bytes();
+    let qual = primary.qual().to_vec();
+    let clip = 10usize;
+
+    // hard-clipped supplementary record without valid tags
+    let mut supplementary = primary.clone();
+    supplementary.set(
+        primary.qname(),
+        Some(&CigarString(vec![
+            Cigar::HardClip(clip as u32),
+            Cigar::Match((seq.len() - clip) as u32),
+        ])),
+        &seq[clip..],
+        &qual[clip..],
+    );
+    supplementary.set_flags(primary.flags() | 0x800);
+    // secondary record without SEQ
+    let mut secondary = primary.clone();
+    secondary.set(
+        primary.qname(),
+        Some(&CigarString(vec![Cigar::Match(seq.len() as u32)])),
+        &[],
+        &[],
+    );
+    secondary.set_flags(primary.flags() | 0x100);
+    for record in [&mut supplementary, &mut secondary] {
+        for tag in ["MM", "ML", "Mm", "Ml"] {
+            let _ = record.remove_aux(tag.as_bytes());
+        }
+    }
+    {
+        let mut writer =
+            bam::Writer::from_path(&in_bam, &header, bam::Format::Bam).unwrap();
+        for record in [&primary, &supplementary, &secondary] {
+            writer.write(record).unwrap();
+        }
+    }
+
+    run_modkit(&[
+        "repair-supplementary",
+        in_bam.to_str().unwrap(),
+        out_bam.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    let n_probs = |record: &bam::Record| match record.aux(b"ML") {
+        Ok(Aux::ArrayU8(arr)) => arr.len(),
+        _ => 0,
+    };
+    let mn = |record: &bam::Record| match record.aux(b"MN") {
+        Ok(Aux::U32(x)) => Some(x as usize),
+        _ => None,
+    };
+    let records = bam::Reader::from_path(&out_bam)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .collect::<Vec<bam::Record>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0], primary);
+    let n_primary_probs = n_probs(&primary);
+    let repaired_supplementary = &records[1];
+    assert!(repaired_supplementary.is_supplementary());
+    assert_eq!(mn(repaired_supplementary), Some(seq.len() - clip));
+    let n_supplementary_probs = n_probs(repaired_supplementary);
+    assert!(n_supplementary_probs > 0);
+    assert!(n_supplementary_probs <= n_primary_probs);
+    let repaired_secondary = &records[2];
+    assert_eq!(repaired_secondary.seq().as_bytes(), seq);
+    assert_eq!(mn(repaired_secondary), Some(seq.len()));
+    assert_eq!(n_probs(repaired_secondary), n_primary_probs);
+}
+
+#[test]
+fn test_merge_tags_help() {
+    run_modkit(&["merge-tags", "--help"]).unwrap();
+}
+
+fn mod_calls_by_read(
+    fp: &str,
+) -> Vec<(Vec<u8>, Vec<(char, char, SeqPosBaseModProbs)>)> {
+    bam::Reader::from_path(fp)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .map(|record| {
+            let info = ModBaseInfo::new_from_record(&record).unwrap();
+            let mut calls = info
+                .iter_seq_base_mod_probs()
+                .map(|(base, strand, probs)| {
+                    (*base, strand.to_char(), probs.clone())
+                })
+                .collect::<Vec<_>>();
+            calls.sort_by(|(a, s, _), (b, t, _)| (a, s).cmp(&(b, t)));
+            (record.qname().to_vec(), calls)
+        })
+        .collect()
+}
+
+#[test]
+fn test_merge_tags_same_bam() {
+    let out_bam = std::env::temp_dir().join("test_merge_tags_same_bam.bam");
+    let in_fp = "tests/resources/donor_read_sort.bam";
+    run_modkit(&[
+        "merge-tags",
+        "-a",
+        in_fp,
+        "-b",
+        in_fp,
+        "-o",
+        out_bam.to_str().unwrap(),
+        "--conflict",
+        "max",
+    ])
+    .unwrap();
+    // merging a modBAM with itself gives back the same calls
+    assert_eq!(
+        mod_calls_by_read(out_bam.to_str().unwrap()),
+        mod_calls_by_read(in_fp)
+    );
+}
+
+#[test]
+fn test_merge_tags_union() {
+    let donor_fp = "tests/resources/donor_read_sort.bam";
+    let only_5mc = std::env::temp_dir().join("test_merge_tags_union_5mc.bam");
+    run_modkit(&[
+        "adjust-mods",
+        "--ignore",
+        "h",
+        donor_fp,
+        only_5mc.to_str().unwrap(),
+    ])
+    .unwrap();
+    let out_bam = std::env::temp_dir().join("test_merge_tags_union.bam");
+    run_modkit(&[
+        "merge-tags",
+        "-a",
+        only_5mc.to_str().unwrap(),
+        "-b",
+        donor_fp,
+        "-o",
+        out_bam.to_str().unwrap(),
+        "--conflict",
+        "prefer-b",
+    ])
+    .unwrap();
+    // the 5hmC calls are added back from B and the 5mC calls are taken from B
+    assert_eq!(
+        mod_calls_by_read(out_bam.to_str().unwrap()),
+        mod_calls_by_read(donor_fp)
+    );
+}